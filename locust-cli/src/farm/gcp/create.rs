@@ -4,7 +4,7 @@ use std::{
     thread,
 };
 
-use locust_core::models::proxies::NewProxy;
+use locust_core::models::proxies::{NewProxy, ProxyType, DEFAULT_WEIGHT};
 use uuid::Uuid;
 
 use crate::farm::{gcp::DEFAULT_SQUID_PORT, CreatedVM};
@@ -99,6 +99,10 @@ sudo systemctl restart squid
                         username: Some(username.to_string()),
                         password: Some(pwd.to_string()),
                         provider: "squid".into(),
+                        proxy_type: Some(ProxyType::Datacenter),
+                        weight: DEFAULT_WEIGHT,
+                        priority: 0,
+                        geo: Default::default(),
                     };
                     proxies_a.lock().unwrap().push(p);
                 }
@@ -1,13 +1,12 @@
 mod farm;
-mod providers;
 mod proxy_table;
 
-use crate::{
-    providers::{webshare::WebshareParser, ProxyFileParser},
-    proxy_table::ProxyTable,
+use crate::proxy_table::{
+    AsnTable, BanRuleTable, BreakerTable, CheckTable, ExitTable, ProxyTable, ScoreTable,
+    SubnetTable,
 };
 
-use std::{fs, str::FromStr, thread};
+use std::{fs, num::NonZeroU16, path::PathBuf, str::FromStr, thread, time::Duration};
 
 use farm::gcp::{
     config::config_firewall,
@@ -16,15 +15,34 @@ use farm::gcp::{
     query::query_vms,
 };
 use locust_core::{
-    crud::proxies::{
-        add_proxies, delete_proxies_by_ids, delete_proxies_by_tags, get_proxies_by_tags,
+    bans::{validate_ban_rule, BanRuleKind},
+    crud::{
+        bans::{add_ban_rule, delete_ban_rule, get_ban_rules},
+        breakers::get_proxy_breakers,
+        domains::{
+            add_domain_tags, remove_domain_tags, set_domain_action, set_domain_fallbacks,
+            set_domain_limit, set_domain_tag_expr,
+        },
+        exits::get_proxy_exits,
+        proxies::{get_all_proxies, set_proxy_geo, update_proxies},
+        scores::get_proxy_domain_scores,
     },
-    get_conn_string, new_pool,
+    diversity::Composition,
+    domains::DomainPattern,
+    exits::{discover_exits, ExitConfig},
+    geo::GeoDb,
+    health::{check_proxies, CheckConfig, CheckUrl},
+    models::proxies::{NewProxy, ProxyType},
+    new_pool,
+    providers::{infatica::InfaticaParser, webshare::WebshareParser, ProxyFileParser},
+    routing::{DomainLimit, RouteAction},
+    storage::{connect, database_url, is_postgres, SqliteStore},
+    tag_expr::TagExpr,
 };
 
 use clap::{Parser, Subcommand, ValueEnum};
-use providers::infatica::InfaticaParser;
 use refinery::config::Config;
+use sqlx::PgPool;
 
 mod embedded {
     use refinery::embed_migrations;
@@ -51,6 +69,25 @@ enum Command {
 
         #[arg(short, long)]
         provider: ProxyProvider,
+
+        /// Local MaxMind (.mmdb) or IP2Location (.csv) databases that
+        /// proxies are located with, by their host, when their
+        /// username does not say where they are
+        #[arg(short, long)]
+        geo_db: Vec<PathBuf>,
+
+        /// What kind of proxies they are: datacenter,
+        /// residential, mobile or isp
+        #[arg(short = 't', long = "type")]
+        proxy_type: Option<ProxyType>,
+
+        /// The share of requests they get among proxies of the same priority
+        #[arg(short, long, default_value = "1")]
+        weight: NonZeroU16,
+
+        /// Proxies of a higher priority are picked first
+        #[arg(long, default_value_t = 0, allow_negative_numbers = true)]
+        priority: i32,
     },
     /// A subquery for querying existing proxies
     Query {
@@ -58,6 +95,11 @@ enum Command {
         #[arg(short, long, value_delimiter = ',')]
         tags: Vec<String>,
     },
+    /// A subcommand for maintaining existing proxies
+    Proxies {
+        #[command(subcommand)]
+        command: ProxiesCommand,
+    },
     /// A subcommand for managing proxy farms
     Farm {
         #[command(subcommand)]
@@ -92,6 +134,162 @@ enum ConfigureDomainCmd {
         #[arg(short, long, default_value_t = false)]
         remove: bool,
     },
+    /// Sets a tag expression, e.g. "residential AND us AND NOT infatica",
+    /// that the proxies for the domain must satisfy instead of having
+    /// any of its tags
+    Expr {
+        #[arg(required_unless_present = "clear")]
+        expr: Option<String>,
+
+        /// Clears the expression, going back to the domain's tags
+        #[arg(short, long, default_value_t = false, conflicts_with = "expr")]
+        clear: bool,
+    },
+    /// Sets the tag expressions tried in order once none of the
+    /// domain's own proxies is left, e.g. "squid" "webshare".
+    /// Without any, the fallbacks are cleared
+    Fallback {
+        exprs: Vec<String>,
+
+        /// Turns requests away once the fallbacks are exhausted,
+        /// instead of using any proxy
+        #[arg(short, long, default_value_t = false)]
+        none: bool,
+    },
+    /// Sets what is done with requests to the domain: "proxy",
+    /// optionally with a tag expression as in "proxy(residential)",
+    /// "direct", optionally from a local address as in
+    /// "direct(10.0.0.5)", or "block", optionally with a reason as in
+    /// "block(internal only)"
+    Action {
+        #[arg(required_unless_present = "clear")]
+        action: Option<String>,
+
+        /// Clears the action, going back to proxying requests
+        #[arg(short, long, default_value_t = false, conflicts_with = "action")]
+        clear: bool,
+    },
+    /// Sets how many requests to each host matching the domain may be
+    /// in flight at once and how many may start each second, instead
+    /// of the server's domain limit
+    Limit {
+        #[arg(short, long, required_unless_present_any = ["rps", "clear"])]
+        concurrency: Option<u32>,
+
+        #[arg(short, long)]
+        rps: Option<f64>,
+
+        /// Clears the limit, going back to the server's
+        #[arg(long, default_value_t = false, conflicts_with_all = ["concurrency", "rps"])]
+        clear: bool,
+    },
+    /// Manages the rules that mark responses from the domain as banned
+    Bans {
+        #[command(subcommand)]
+        command: BanRulesCmd,
+    },
+    /// Shows how requests to the domain went through each proxy
+    Scores {},
+}
+
+#[derive(Debug, Clone, Subcommand)]
+enum BanRulesCmd {
+    /// Adds a rule. Status patterns are comma separated codes, header
+    /// patterns look like `name: regex`, and body and redirect patterns
+    /// are regexes matched against the body and the redirect location
+    Add {
+        #[arg(short, long)]
+        kind: BanRuleKind,
+
+        #[arg(short, long)]
+        pattern: String,
+    },
+    List {},
+    Remove {
+        id: i32,
+    },
+}
+
+#[derive(Debug, Clone, Subcommand)]
+enum ProxiesCommand {
+    /// Runs a single health check of every proxy, quarantining
+    /// and reinstating them like the server does
+    Check {
+        /// The URL requested through every proxy
+        #[arg(short, long)]
+        url: CheckUrl,
+
+        /// Seconds before a check times out
+        #[arg(short, long, default_value_t = 10)]
+        timeout: u64,
+
+        /// Failed checks in a row before a proxy is quarantined
+        #[arg(short, long, default_value_t = 3)]
+        failures: i32,
+
+        /// Proxies checked at the same time
+        #[arg(short, long, default_value_t = 32)]
+        concurrency: usize,
+    },
+    /// Locates every proxy, by its exit IP or else its host, with local
+    /// MaxMind (.mmdb) or IP2Location (.csv) databases, tried in order
+    Locate {
+        #[arg(required = true)]
+        geo_dbs: Vec<PathBuf>,
+
+        /// Also locate the proxies that already have a location
+        #[arg(short, long, default_value_t = false)]
+        all: bool,
+    },
+    /// Sets the type, weight or priority of proxies
+    Set {
+        #[arg(required = true, value_delimiter = ',')]
+        ids: Vec<i32>,
+
+        /// datacenter, residential, mobile or isp
+        #[arg(short = 't', long = "type")]
+        proxy_type: Option<ProxyType>,
+
+        #[arg(short, long)]
+        weight: Option<NonZeroU16>,
+
+        #[arg(short, long, allow_negative_numbers = true)]
+        priority: Option<i32>,
+    },
+    /// Shows how many proxies share each /24 (IPv4) or
+    /// /48 (IPv6) subnet and each autonomous system
+    Composition {
+        /// How many of the largest subnets and systems to show
+        #[arg(short, long, default_value_t = 20)]
+        top: usize,
+    },
+    /// Shows the address every proxy exits from and the proxies
+    /// sharing one, optionally discovering them first
+    Exits {
+        /// An echo endpoint requested through every proxy, answering
+        /// with the address the request came from, e.g.
+        /// http://api.ipify.org
+        #[arg(short, long)]
+        url: Option<CheckUrl>,
+
+        /// Seconds before a check times out
+        #[arg(short, long, default_value_t = 10)]
+        timeout: u64,
+
+        /// Proxies checked at the same time
+        #[arg(short, long, default_value_t = 32)]
+        concurrency: usize,
+
+        /// Only show the proxies sharing an exit IP
+        #[arg(short, long, default_value_t = false)]
+        shared: bool,
+    },
+    /// Shows the circuit breaker states last reported by the servers
+    Breakers {
+        /// Also show breakers that are closed
+        #[arg(short, long, default_value_t = false)]
+        all: bool,
+    },
 }
 
 #[derive(Debug, Clone, Subcommand)]
@@ -131,42 +329,219 @@ enum ProxyProvider {
     Infatica,
 }
 
+fn open_geo_dbs(paths: &[PathBuf]) -> Vec<GeoDb> {
+    paths
+        .iter()
+        .map(|path| match GeoDb::open(path) {
+            Ok(db) => db,
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        })
+        .collect()
+}
+
+/// Fills in where proxies are located from the
+/// databases, in order, by their host.
+fn locate_proxies(proxies: &mut [NewProxy], dbs: &[GeoDb]) {
+    for proxy in proxies {
+        for db in dbs {
+            let geo = std::mem::take(&mut proxy.geo);
+            proxy.geo = geo.or(db.lookup_host(&proxy.host));
+        }
+    }
+}
+
+/// Connects to Postgres for the commands that
+/// the SQLite store does not support.
+async fn postgres_pool(url: &str) -> PgPool {
+    if !is_postgres(url) {
+        eprintln!("This command needs a Postgres database");
+        std::process::exit(1);
+    }
+    new_pool().await.expect("error creating db pool")
+}
+
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
-    let db_pool = new_pool().await.expect("error creating db pool");
+    let url = database_url();
     match cli.command {
         Command::Configure { command } => match command {
-            ConfigureCommand::Domain { host, command } => match command {
-                ConfigureDomainCmd::Tags { tags, remove } => {
-                    println!("{} {:?}, {}", host, tags, remove);
+            ConfigureCommand::Domain { host, command } => {
+                let db_pool = postgres_pool(&url).await;
+                match command {
+                    ConfigureDomainCmd::Tags { tags, remove } => {
+                        if let Err(e) = DomainPattern::parse(&host) {
+                            eprintln!("Invalid domain {host}: {e}");
+                            std::process::exit(1);
+                        }
+                        let tags: Vec<&str> = tags.iter().map(AsRef::as_ref).collect();
+                        if remove {
+                            remove_domain_tags(&db_pool, &host, &tags)
+                                .await
+                                .expect("error removing domain tags");
+                        } else {
+                            add_domain_tags(&db_pool, &host, &tags)
+                                .await
+                                .expect("error adding domain tags");
+                        }
+                        println!("Done!");
+                    }
+                    ConfigureDomainCmd::Expr { expr, clear: _ } => {
+                        if let Err(e) = DomainPattern::parse(&host) {
+                            eprintln!("Invalid domain {host}: {e}");
+                            std::process::exit(1);
+                        }
+                        let expr = expr.map(|expr| match TagExpr::parse(&expr) {
+                            Ok(expr) => expr.to_string(),
+                            Err(e) => {
+                                eprintln!("Invalid tag expression: {e}");
+                                std::process::exit(1);
+                            }
+                        });
+                        set_domain_tag_expr(&db_pool, &host, expr.as_deref())
+                            .await
+                            .expect("error setting domain tag expression");
+                        println!("Done!");
+                    }
+                    ConfigureDomainCmd::Action { action, clear: _ } => {
+                        if let Err(e) = DomainPattern::parse(&host) {
+                            eprintln!("Invalid domain {host}: {e}");
+                            std::process::exit(1);
+                        }
+                        let action = action.map(|action| match RouteAction::parse(&action) {
+                            Ok(action) => action.to_string(),
+                            Err(e) => {
+                                eprintln!("Invalid action: {e}");
+                                std::process::exit(1);
+                            }
+                        });
+                        set_domain_action(&db_pool, &host, action.as_deref())
+                            .await
+                            .expect("error setting domain action");
+                        println!("Done!");
+                    }
+                    ConfigureDomainCmd::Limit {
+                        concurrency,
+                        rps,
+                        clear: _,
+                    } => {
+                        if let Err(e) = DomainPattern::parse(&host) {
+                            eprintln!("Invalid domain {host}: {e}");
+                            std::process::exit(1);
+                        }
+                        let limit = DomainLimit { concurrency, rps };
+                        if let Err(e) = limit.validate() {
+                            eprintln!("Invalid limit: {e}");
+                            std::process::exit(1);
+                        }
+                        set_domain_limit(&db_pool, &host, limit)
+                            .await
+                            .expect("error setting domain limit");
+                        println!("Done!");
+                    }
+                    ConfigureDomainCmd::Fallback { exprs, none } => {
+                        if let Err(e) = DomainPattern::parse(&host) {
+                            eprintln!("Invalid domain {host}: {e}");
+                            std::process::exit(1);
+                        }
+                        let exprs: Vec<String> = exprs
+                            .iter()
+                            .map(|expr| match TagExpr::parse(expr) {
+                                Ok(expr) => expr.to_string(),
+                                Err(e) => {
+                                    eprintln!("Invalid tag expression {expr}: {e}");
+                                    std::process::exit(1);
+                                }
+                            })
+                            .collect();
+                        let exprs: Vec<&str> = exprs.iter().map(AsRef::as_ref).collect();
+                        set_domain_fallbacks(&db_pool, &host, &exprs, !none)
+                            .await
+                            .expect("error setting domain fallbacks");
+                        println!("Done!");
+                    }
+                    ConfigureDomainCmd::Bans { command } => match command {
+                        BanRulesCmd::Add { kind, pattern } => {
+                            if let Err(e) = validate_ban_rule(kind, &pattern) {
+                                eprintln!("Invalid {kind} rule: {e}");
+                                std::process::exit(1);
+                            }
+                            let rule = add_ban_rule(&db_pool, &host, kind, &pattern)
+                                .await
+                                .expect("error adding ban rule");
+                            println!("Added ban rule {}!", rule.id);
+                        }
+                        BanRulesCmd::List {} => {
+                            let rules = get_ban_rules(&db_pool, &host)
+                                .await
+                                .expect("error fetching ban rules");
+                            println!("{}", BanRuleTable(rules));
+                        }
+                        BanRulesCmd::Remove { id } => {
+                            delete_ban_rule(&db_pool, id)
+                                .await
+                                .expect("error removing ban rule");
+                            println!("Done!");
+                        }
+                    },
+                    ConfigureDomainCmd::Scores {} => {
+                        let scores = get_proxy_domain_scores(&db_pool, &host)
+                            .await
+                            .expect("error fetching proxy scores");
+                        println!("{}", ScoreTable(scores));
+                    }
                 }
-            },
+            }
             ConfigureCommand::Firewall {} => {
                 config_firewall();
             }
         },
-        Command::Import { file, provider } => {
+        Command::Import {
+            file,
+            provider,
+            geo_db,
+            proxy_type,
+            weight,
+            priority,
+        } => {
+            let store = connect(&url).await.expect("error connecting to db");
             let content = fs::read_to_string(file).expect("error reading import file");
+            let geo_dbs = open_geo_dbs(&geo_db);
+            let rank = |proxies: &mut [NewProxy]| {
+                for proxy in proxies {
+                    proxy.proxy_type = proxy_type.or(proxy.proxy_type);
+                    proxy.weight = weight.get().into();
+                    proxy.priority = priority;
+                }
+            };
             match provider {
                 ProxyProvider::Webshare => {
                     let parser = WebshareParser {};
-                    let proxies = parser.parse_file(&content);
+                    let mut proxies = parser.parse_file(&content);
+                    locate_proxies(&mut proxies, &geo_dbs);
+                    rank(&mut proxies);
                     let n_proxies = proxies.len();
 
                     let tags = vec!["webshare"];
-                    add_proxies(&db_pool, &proxies, &tags)
+                    store
+                        .add_proxies(&proxies, &tags)
                         .await
                         .expect("error adding proxies");
                     println!("Successfully added {} proxies!", n_proxies);
                 }
                 ProxyProvider::Infatica => {
                     let parser = InfaticaParser {};
-                    let proxies = parser.parse_file(&content);
+                    let mut proxies = parser.parse_file(&content);
+                    locate_proxies(&mut proxies, &geo_dbs);
+                    rank(&mut proxies);
                     let n_proxies = proxies.len();
 
                     let tags = vec!["infatica"];
-                    add_proxies(&db_pool, &proxies, &tags)
+                    store
+                        .add_proxies(&proxies, &tags)
                         .await
                         .expect("error adding proxies");
                     println!("Successfully added {} proxies!", n_proxies);
@@ -174,78 +549,210 @@ async fn main() {
             }
         }
         Command::Query { tags } => {
+            let store = connect(&url).await.expect("error connecting to db");
             let tags: Vec<&str> = tags.iter().map(AsRef::as_ref).collect();
-            let proxies = get_proxies_by_tags(&db_pool, &tags)
+            let proxies = store
+                .get_proxies_by_tags(&tags)
                 .await
                 .expect("error fetching proxies");
             let table = ProxyTable(proxies);
             println!("{}", table);
         }
+        Command::Proxies { command } => {
+            let db_pool = postgres_pool(&url).await;
+            match command {
+                ProxiesCommand::Check {
+                    url,
+                    timeout,
+                    failures,
+                    concurrency,
+                } => {
+                    let config = CheckConfig {
+                        url,
+                        timeout: Duration::from_secs(timeout),
+                        quarantine_after: failures,
+                        concurrency,
+                    };
+                    let results = check_proxies(&db_pool, &config)
+                        .await
+                        .expect("error checking proxies");
+                    println!("{}", CheckTable(results));
+                }
+                ProxiesCommand::Locate { geo_dbs, all } => {
+                    let geo_dbs = open_geo_dbs(&geo_dbs);
+                    let proxies = get_all_proxies(&db_pool)
+                        .await
+                        .expect("error fetching proxies");
+                    let mut located = 0;
+                    for proxy in proxies {
+                        let known = if all {
+                            Default::default()
+                        } else {
+                            proxy.geo.clone()
+                        };
+                        let geo = match proxy.exit_addr() {
+                            Some(ip) => geo_dbs.iter().fold(known, |geo, db| geo.or(db.lookup(ip))),
+                            None => known,
+                        };
+                        if geo != proxy.geo {
+                            set_proxy_geo(&db_pool, proxy.id, &geo)
+                                .await
+                                .expect("error setting proxy location");
+                            located += 1;
+                        }
+                    }
+                    println!("Located {located} proxies!");
+                }
+                ProxiesCommand::Set {
+                    ids,
+                    proxy_type,
+                    weight,
+                    priority,
+                } => {
+                    if proxy_type.is_none() && weight.is_none() && priority.is_none() {
+                        eprintln!("Nothing to set, pass --type, --weight or --priority");
+                        std::process::exit(1);
+                    }
+                    let weight = weight.map(|weight| weight.get().into());
+                    let updated = update_proxies(&db_pool, &ids, proxy_type, weight, priority)
+                        .await
+                        .expect("error updating proxies");
+                    println!("Updated {updated} proxies!");
+                }
+                ProxiesCommand::Composition { top } => {
+                    let proxies = get_all_proxies(&db_pool)
+                        .await
+                        .expect("error fetching proxies");
+                    let composition = Composition::of(&proxies);
+                    println!("{}", SubnetTable(&composition, top));
+                    println!("{}", AsnTable(&composition, top));
+                }
+                ProxiesCommand::Exits {
+                    url,
+                    timeout,
+                    concurrency,
+                    shared,
+                } => {
+                    if let Some(url) = url {
+                        let config = ExitConfig {
+                            url,
+                            timeout: Duration::from_secs(timeout),
+                            concurrency,
+                        };
+                        let results = discover_exits(&db_pool, &config)
+                            .await
+                            .expect("error discovering exit IPs");
+                        let failed: Vec<_> = results.iter().filter(|r| r.exit.is_err()).collect();
+                        for result in &failed {
+                            if let Err(e) = &result.exit {
+                                eprintln!("Proxy {}: {e}", result.proxy.id);
+                            }
+                        }
+                        println!(
+                            "Discovered {} exit IPs, {} failed",
+                            results.len() - failed.len(),
+                            failed.len()
+                        );
+                    }
+                    let mut exits = get_proxy_exits(&db_pool)
+                        .await
+                        .expect("error fetching exit IPs");
+                    if shared {
+                        exits.retain(|e| e.is_shared());
+                    }
+                    println!("{}", ExitTable(exits));
+                }
+                ProxiesCommand::Breakers { all } => {
+                    let mut breakers = get_proxy_breakers(&db_pool)
+                        .await
+                        .expect("error fetching breakers");
+                    if !all {
+                        breakers.retain(|b| b.state != "closed");
+                    }
+                    println!("{}", BreakerTable(breakers));
+                }
+            }
+        }
         Command::Farm {
             command,
             project,
             zone,
-        } => match command {
-            FarmCommand::Create { num, username, pwd } => {
-                let vms = create_vms(&project, &zone, &username, &pwd, num);
-
-                let tags = vec!["squid"];
-                add_proxies(&db_pool, &vms, &tags)
-                    .await
-                    .expect("error adding proxies");
-                println!("Successfully added {} proxies!", vms.len());
-            }
-            FarmCommand::Delete { zone } => {
-                query_and_delete_vms(zone);
-                let tags = vec!["squid"];
-                delete_proxies_by_tags(&db_pool, &tags)
-                    .await
-                    .expect("error deleting proxies from db");
-                println!("Done!");
-            }
-            FarmCommand::Cycle {
-                num,
-                username,
-                pwd,
-                zone,
-            } => {
-                // We add new vms and delete from the db
-                // before deleting the old vms to minimize
-                // downtime. If you dont do this youd either have
-                // no proxies or hitting vms that dont exist anymore
-
-                let tags = vec!["squid"];
-
-                // Get existing vm proxies
-                let old_vms = query_vms(&zone);
-                let old_proxies = get_proxies_by_tags(&db_pool, &tags)
-                    .await
-                    .expect("error getting old proxies from db");
+        } => {
+            let store = connect(&url).await.expect("error connecting to db");
+            match command {
+                FarmCommand::Create { num, username, pwd } => {
+                    let vms = create_vms(&project, &zone, &username, &pwd, num);
+
+                    let tags = vec!["squid"];
+                    store
+                        .add_proxies(&vms, &tags)
+                        .await
+                        .expect("error adding proxies");
+                    println!("Successfully added {} proxies!", vms.len());
+                }
+                FarmCommand::Delete { zone } => {
+                    query_and_delete_vms(zone);
+                    let tags = vec!["squid"];
+                    store
+                        .delete_proxies_by_tags(&tags)
+                        .await
+                        .expect("error deleting proxies from db");
+                    println!("Done!");
+                }
+                FarmCommand::Cycle {
+                    num,
+                    username,
+                    pwd,
+                    zone,
+                } => {
+                    // We add new vms and delete from the db
+                    // before deleting the old vms to minimize
+                    // downtime. If you dont do this youd either have
+                    // no proxies or hitting vms that dont exist anymore
+
+                    let tags = vec!["squid"];
+
+                    // Get existing vm proxies
+                    let old_vms = query_vms(&zone);
+                    let old_proxies = store
+                        .get_proxies_by_tags(&tags)
+                        .await
+                        .expect("error getting old proxies from db");
 
-                // Create new VMs
-                let new_vms = create_vms(&project, &zone, &username, &pwd, num);
+                    // Create new VMs
+                    let new_vms = create_vms(&project, &zone, &username, &pwd, num);
 
-                // Add new proxies
-                add_proxies(&db_pool, &new_vms, &tags)
-                    .await
-                    .expect("error adding proxies");
+                    // Add new proxies
+                    store
+                        .add_proxies(&new_vms, &tags)
+                        .await
+                        .expect("error adding proxies");
 
-                // Delete old proxies from DB
-                let ids_delete: Vec<i32> = old_proxies.iter().map(|p| p.id).collect();
-                delete_proxies_by_ids(&db_pool, &ids_delete)
-                    .await
-                    .expect("error deleting old proxies");
+                    // Delete old proxies from DB
+                    let ids_delete: Vec<i32> = old_proxies.iter().map(|p| p.id).collect();
+                    store
+                        .delete_proxies_by_ids(&ids_delete)
+                        .await
+                        .expect("error deleting old proxies");
 
-                // Delete old vms
-                delete_vms(zone, old_vms);
+                    // Delete old vms
+                    delete_vms(zone, old_vms);
 
-                println!("Successfully added {} proxies!", new_vms.len());
-                println!("Done!");
+                    println!("Successfully added {} proxies!", new_vms.len());
+                    println!("Done!");
+                }
             }
-        },
+        }
         Command::Migrate {} => {
-            let conn_string = get_conn_string();
-            let mut conf = Config::from_str(&conn_string).expect("Invalid connection string");
+            if !is_postgres(&url) {
+                let store = SqliteStore::connect(&url)
+                    .await
+                    .expect("error connecting to db");
+                store.migrate().await.expect("error applying migrations");
+                println!("Migrations applied!");
+                return;
+            }
+            let mut conf = Config::from_str(&url).expect("Invalid connection string");
             thread::spawn(move || {
                 embedded::migrations::runner().run(&mut conf).unwrap();
             })
@@ -1,6 +1,13 @@
 use std::fmt::Display;
 
-use locust_core::models::proxies::Proxy;
+use locust_core::{
+    diversity::Composition,
+    health::CheckResult,
+    models::{
+        bans::BanRule, breakers::ProxyBreaker, exits::ProxyExit, proxies::Proxy,
+        scores::ProxyDomainScore,
+    },
+};
 use tabled::builder;
 
 pub struct ProxyTable(pub Vec<Proxy>);
@@ -8,7 +15,10 @@ pub struct ProxyTable(pub Vec<Proxy>);
 impl Display for ProxyTable {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut builder = builder::Builder::new();
-        builder.push_record(["id", "protocol", "host", "port", "provider"]);
+        builder.push_record([
+            "id", "protocol", "host", "port", "provider", "type", "weight", "priority", "exit ip",
+            "country", "city", "asn",
+        ]);
         for proxy in &self.0 {
             builder.push_record([
                 &proxy.id.to_string(),
@@ -16,6 +26,20 @@ impl Display for ProxyTable {
                 &proxy.host,
                 &proxy.port.to_string(),
                 &proxy.provider,
+                &proxy
+                    .proxy_type
+                    .map(|t| t.to_string())
+                    .unwrap_or("-".into()),
+                &proxy.weight.to_string(),
+                &proxy.priority.to_string(),
+                proxy.exit_ip.as_deref().unwrap_or("-"),
+                proxy.geo.country.as_deref().unwrap_or("-"),
+                proxy.geo.city.as_deref().unwrap_or("-"),
+                &proxy
+                    .geo
+                    .asn
+                    .map(|asn| asn.to_string())
+                    .unwrap_or("-".into()),
             ]);
         }
 
@@ -23,3 +47,182 @@ impl Display for ProxyTable {
         write!(f, "{}", table)
     }
 }
+
+pub struct CheckTable(pub Vec<CheckResult>);
+
+impl Display for CheckTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut builder = builder::Builder::new();
+        builder.push_record([
+            "id", "host", "port", "status", "latency", "failures", "state", "error",
+        ]);
+        for result in &self.0 {
+            let state = if result.quarantined() {
+                "quarantined (new)"
+            } else if result.reinstated() {
+                "reinstated"
+            } else if result.health.is_quarantined() {
+                "quarantined"
+            } else {
+                "ok"
+            };
+            builder.push_record([
+                &result.proxy.id.to_string(),
+                &result.proxy.host,
+                &result.proxy.port.to_string(),
+                &result
+                    .check
+                    .status
+                    .map(|s| s.to_string())
+                    .unwrap_or("-".into()),
+                &result
+                    .check
+                    .latency
+                    .map(|l| format!("{}ms", l.as_millis()))
+                    .unwrap_or("-".into()),
+                &result.health.check_failures.to_string(),
+                state,
+                result.check.error.as_deref().unwrap_or(""),
+            ]);
+        }
+
+        let table = builder.build().to_string();
+        write!(f, "{}", table)
+    }
+}
+
+pub struct ExitTable(pub Vec<ProxyExit>);
+
+impl Display for ExitTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut builder = builder::Builder::new();
+        builder.push_record(["id", "host", "port", "exit ip", "seen", "shared with"]);
+        for exit in &self.0 {
+            let shared_with: Vec<String> =
+                exit.shared_with.iter().map(|id| id.to_string()).collect();
+            builder.push_record([
+                &exit.proxy_id.to_string(),
+                &exit.host,
+                &exit.port.to_string(),
+                exit.exit_ip.as_deref().unwrap_or("-"),
+                &exit
+                    .date_exit_seen
+                    .map(|d| d.to_string())
+                    .unwrap_or("-".into()),
+                &shared_with.join(", "),
+            ]);
+        }
+
+        let table = builder.build().to_string();
+        write!(f, "{}", table)
+    }
+}
+
+pub struct BanRuleTable(pub Vec<BanRule>);
+
+impl Display for BanRuleTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut builder = builder::Builder::new();
+        builder.push_record(["id", "kind", "pattern"]);
+        for rule in &self.0 {
+            builder.push_record([&rule.id.to_string(), &rule.kind, &rule.pattern]);
+        }
+
+        let table = builder.build().to_string();
+        write!(f, "{}", table)
+    }
+}
+
+pub struct ScoreTable(pub Vec<ProxyDomainScore>);
+
+impl Display for ScoreTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut builder = builder::Builder::new();
+        builder.push_record(["proxy id", "successes", "failures", "bans"]);
+        for score in &self.0 {
+            builder.push_record([
+                score.proxy_id.to_string(),
+                score.successes.to_string(),
+                score.failures.to_string(),
+                score.bans.to_string(),
+            ]);
+        }
+
+        let table = builder.build().to_string();
+        write!(f, "{}", table)
+    }
+}
+
+pub struct BreakerTable(pub Vec<ProxyBreaker>);
+
+impl Display for BreakerTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut builder = builder::Builder::new();
+        builder.push_record([
+            "proxy id", "domain", "state", "failures", "opened", "updated",
+        ]);
+        for breaker in &self.0 {
+            builder.push_record([
+                breaker.proxy_id.to_string(),
+                breaker.domain.clone(),
+                breaker.state.clone(),
+                breaker.consecutive_failures.to_string(),
+                breaker
+                    .date_opened
+                    .map(|d| d.to_string())
+                    .unwrap_or("-".into()),
+                breaker.date_modified.to_string(),
+            ]);
+        }
+
+        let table = builder.build().to_string();
+        write!(f, "{}", table)
+    }
+}
+
+/// The largest subnets of a [`Composition`], up to the given number.
+pub struct SubnetTable<'a>(pub &'a Composition, pub usize);
+
+impl Display for SubnetTable<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let SubnetTable(composition, top) = self;
+        let mut builder = builder::Builder::new();
+        builder.push_record(["subnet", "proxies", "share"]);
+        for (subnet, count) in composition.subnets.iter().take(*top) {
+            builder.push_record([
+                subnet.to_string(),
+                count.to_string(),
+                share(*count, composition.total),
+            ]);
+        }
+
+        let table = builder.build().to_string();
+        write!(f, "{}", table)
+    }
+}
+
+/// The largest autonomous systems of a [`Composition`],
+/// up to the given number.
+pub struct AsnTable<'a>(pub &'a Composition, pub usize);
+
+impl Display for AsnTable<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let AsnTable(composition, top) = self;
+        let mut builder = builder::Builder::new();
+        builder.push_record(["asn", "proxies", "share"]);
+        for (asn, count) in composition.asns.iter().take(*top) {
+            builder.push_record([
+                asn.map(|asn| asn.to_string()).unwrap_or("unknown".into()),
+                count.to_string(),
+                share(*count, composition.total),
+            ]);
+        }
+
+        let table = builder.build().to_string();
+        write!(f, "{}", table)
+    }
+}
+
+fn share(count: usize, total: usize) -> String {
+    format!("{:.1}%", 100.0 * count as f64 / total.max(1) as f64)
+}
@@ -0,0 +1,52 @@
+-- The tables behind `ProxyStore`, matching the Postgres
+-- migrations up to V8. Timestamps are RFC 3339 text.
+CREATE TABLE IF NOT EXISTS locust_proxies (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  protocol TEXT NOT NULL,
+  host TEXT NOT NULL,
+  port INTEGER,
+  username TEXT,
+  password TEXT,
+  provider TEXT,
+  date_created TEXT,
+  date_modified TEXT,
+  date_deleted TEXT NULL,
+  date_last_used TEXT NULL,
+  date_quarantined TEXT NULL
+);
+
+CREATE TABLE IF NOT EXISTS locust_tags (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  name TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE IF NOT EXISTS locust_proxy_tag_map (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  proxy_id INTEGER NOT NULL REFERENCES locust_proxies(id),
+  tag_id INTEGER NOT NULL REFERENCES locust_tags(id)
+);
+
+CREATE TABLE IF NOT EXISTS locust_domains (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  host TEXT NOT NULL UNIQUE,
+  date_created TEXT,
+  date_modified TEXT,
+  date_deleted TEXT NULL
+);
+
+CREATE TABLE IF NOT EXISTS locust_domain_tag_map (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  domain_id INTEGER NOT NULL REFERENCES locust_domains(id),
+  tag_id INTEGER NOT NULL REFERENCES locust_tags(id)
+);
+
+CREATE TABLE IF NOT EXISTS locust_sessions (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  proxy_id INTEGER NOT NULL REFERENCES locust_proxies(id)
+);
+
+CREATE INDEX idx_proxy_map_proxy_id ON locust_proxy_tag_map(proxy_id);
+CREATE INDEX idx_proxy_map_tag_id ON locust_proxy_tag_map(tag_id);
+CREATE INDEX idx_domain_map_domain_id ON locust_domain_tag_map(domain_id);
+CREATE INDEX idx_domain_map_tag_id ON locust_domain_tag_map(tag_id);
+CREATE INDEX idx_date_last_used ON locust_proxies(date_last_used);
@@ -0,0 +1,2 @@
+-- Matches V9__domain_tag_expr.sql.
+ALTER TABLE locust_domains ADD COLUMN tag_expr TEXT NULL;
@@ -0,0 +1,10 @@
+-- Matches V10__domain_fallbacks.sql.
+ALTER TABLE locust_domains ADD COLUMN fallback_any INTEGER NOT NULL DEFAULT 1;
+
+CREATE TABLE IF NOT EXISTS locust_domain_fallbacks (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  domain_id INTEGER NOT NULL REFERENCES locust_domains(id),
+  position INTEGER NOT NULL,
+  tag_expr TEXT NOT NULL,
+  UNIQUE (domain_id, position)
+);
@@ -0,0 +1,2 @@
+-- Matches V11__domain_actions.sql.
+ALTER TABLE locust_domains ADD COLUMN action TEXT NULL;
@@ -0,0 +1,5 @@
+-- Matches V12__proxy_geo.sql.
+ALTER TABLE locust_proxies ADD COLUMN country TEXT NULL;
+ALTER TABLE locust_proxies ADD COLUMN region TEXT NULL;
+ALTER TABLE locust_proxies ADD COLUMN city TEXT NULL;
+ALTER TABLE locust_proxies ADD COLUMN asn INTEGER NULL;
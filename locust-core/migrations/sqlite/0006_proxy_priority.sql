@@ -0,0 +1,4 @@
+-- Matches V13__proxy_priority.sql.
+ALTER TABLE locust_proxies ADD COLUMN type TEXT NULL;
+ALTER TABLE locust_proxies ADD COLUMN weight INTEGER NOT NULL DEFAULT 1 CHECK (weight > 0);
+ALTER TABLE locust_proxies ADD COLUMN priority INTEGER NOT NULL DEFAULT 0;
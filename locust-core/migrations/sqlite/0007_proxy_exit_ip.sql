@@ -0,0 +1,5 @@
+-- Matches V14__proxy_exit_ip.sql.
+ALTER TABLE locust_proxies ADD COLUMN exit_ip TEXT NULL;
+ALTER TABLE locust_proxies ADD COLUMN date_exit_seen TEXT NULL;
+
+CREATE INDEX idx_proxies_exit_ip ON locust_proxies(exit_ip);
@@ -0,0 +1,3 @@
+-- Matches V15__domain_limits.sql.
+ALTER TABLE locust_domains ADD COLUMN max_concurrency INTEGER NULL;
+ALTER TABLE locust_domains ADD COLUMN max_rps REAL NULL;
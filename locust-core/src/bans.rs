@@ -0,0 +1,252 @@
+use std::{fmt, str::FromStr};
+
+use http::{header::LOCATION, HeaderMap, HeaderName, StatusCode};
+use regex::{bytes, Regex};
+
+use crate::models::bans::BanRule;
+
+/// What a ban rule looks at, and how its pattern is read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BanRuleKind {
+    /// A comma separated list of status codes, e.g. `403,429`.
+    Status,
+    /// A header name and a regex for its value, separated
+    /// by a colon, e.g. `server: ^captcha`.
+    Header,
+    /// A regex matched against the start of the body.
+    Body,
+    /// A regex matched against the `Location` of redirects.
+    Redirect,
+}
+
+impl BanRuleKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BanRuleKind::Status => "status",
+            BanRuleKind::Header => "header",
+            BanRuleKind::Body => "body",
+            BanRuleKind::Redirect => "redirect",
+        }
+    }
+}
+
+impl fmt::Display for BanRuleKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for BanRuleKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "status" => Ok(BanRuleKind::Status),
+            "header" => Ok(BanRuleKind::Header),
+            "body" => Ok(BanRuleKind::Body),
+            "redirect" => Ok(BanRuleKind::Redirect),
+            _ => Err(format!("unknown ban rule kind: {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    Status(Vec<StatusCode>),
+    Header(HeaderName, Regex),
+    Body(bytes::Regex),
+    Redirect(Regex),
+}
+
+impl Matcher {
+    fn new(kind: BanRuleKind, pattern: &str) -> Result<Self, String> {
+        let regex = |p: &str| Regex::new(p.trim()).map_err(|e| e.to_string());
+        match kind {
+            BanRuleKind::Status => pattern
+                .split(',')
+                .map(|s| {
+                    s.trim()
+                        .parse::<u16>()
+                        .ok()
+                        .and_then(|s| StatusCode::from_u16(s).ok())
+                        .ok_or_else(|| format!("invalid status code: {s}"))
+                })
+                .collect::<Result<_, _>>()
+                .map(Matcher::Status),
+            BanRuleKind::Header => {
+                let (name, value) = pattern
+                    .split_once(':')
+                    .ok_or("header rules look like `name: regex`")?;
+                let name = HeaderName::from_str(name.trim()).map_err(|e| e.to_string())?;
+                Ok(Matcher::Header(name, regex(value)?))
+            }
+            BanRuleKind::Body => bytes::Regex::new(pattern)
+                .map(Matcher::Body)
+                .map_err(|e| e.to_string()),
+            BanRuleKind::Redirect => Ok(Matcher::Redirect(regex(pattern)?)),
+        }
+    }
+}
+
+/// Checks that a pattern is valid for the kind of rule.
+pub fn validate_ban_rule(kind: BanRuleKind, pattern: &str) -> Result<(), String> {
+    Matcher::new(kind, pattern).map(|_| ())
+}
+
+/// The rule a response was banned by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BanMatch {
+    pub rule_id: i32,
+    pub kind: BanRuleKind,
+}
+
+/// The compiled ban rules of a domain.
+#[derive(Debug, Clone, Default)]
+pub struct BanRules(Vec<(i32, BanRuleKind, Matcher)>);
+
+impl BanRules {
+    /// Compiles the given rules, returning the
+    /// ones that are invalid separately.
+    pub fn compile(rules: &[BanRule]) -> (Self, Vec<(BanRule, String)>) {
+        let mut compiled = Vec::new();
+        let mut invalid = Vec::new();
+        for rule in rules {
+            let matcher = BanRuleKind::from_str(&rule.kind)
+                .and_then(|kind| Matcher::new(kind, &rule.pattern).map(|m| (kind, m)));
+            match matcher {
+                Ok((kind, matcher)) => compiled.push((rule.id, kind, matcher)),
+                Err(e) => invalid.push((rule.clone(), e)),
+            }
+        }
+
+        (Self(compiled), invalid)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Whether any rule needs to look at the body.
+    pub fn needs_body(&self) -> bool {
+        self.0.iter().any(|(_, _, m)| matches!(m, Matcher::Body(_)))
+    }
+
+    /// Checks the status and headers of a response.
+    pub fn check_head(&self, status: StatusCode, headers: &HeaderMap) -> Option<BanMatch> {
+        self.0.iter().find_map(|(id, kind, matcher)| {
+            let matched = match matcher {
+                Matcher::Status(codes) => codes.contains(&status),
+                Matcher::Header(name, regex) => headers
+                    .get_all(name)
+                    .iter()
+                    .any(|v| v.to_str().is_ok_and(|v| regex.is_match(v))),
+                Matcher::Redirect(regex) => {
+                    status.is_redirection()
+                        && headers
+                            .get(LOCATION)
+                            .and_then(|v| v.to_str().ok())
+                            .is_some_and(|v| regex.is_match(v))
+                }
+                Matcher::Body(_) => false,
+            };
+            matched.then_some(BanMatch {
+                rule_id: *id,
+                kind: *kind,
+            })
+        })
+    }
+
+    /// Checks the start of a response body.
+    pub fn check_body(&self, body: &[u8]) -> Option<BanMatch> {
+        self.0.iter().find_map(|(id, kind, matcher)| match matcher {
+            Matcher::Body(regex) if regex.is_match(body) => Some(BanMatch {
+                rule_id: *id,
+                kind: *kind,
+            }),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(rules: &[(&str, &str)]) -> BanRules {
+        let rules: Vec<BanRule> = rules
+            .iter()
+            .enumerate()
+            .map(|(i, (kind, pattern))| BanRule {
+                id: i as i32 + 1,
+                host: "example.com".into(),
+                kind: kind.to_string(),
+                pattern: pattern.to_string(),
+            })
+            .collect();
+        let (compiled, invalid) = BanRules::compile(&rules);
+        assert!(invalid.is_empty(), "{invalid:?}");
+        compiled
+    }
+
+    #[test]
+    fn test_head_rules() {
+        let rules = rules(&[
+            ("status", "403, 429"),
+            ("header", "x-block-reason: .+"),
+            ("redirect", "/blocked"),
+        ]);
+        let empty = HeaderMap::new();
+        assert_eq!(rules.check_head(StatusCode::OK, &empty), None);
+        assert_eq!(
+            rules.check_head(StatusCode::TOO_MANY_REQUESTS, &empty),
+            Some(BanMatch {
+                rule_id: 1,
+                kind: BanRuleKind::Status
+            })
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-block-reason", "bot".parse().unwrap());
+        assert_eq!(
+            rules.check_head(StatusCode::OK, &headers).map(|m| m.kind),
+            Some(BanRuleKind::Header)
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(LOCATION, "https://example.com/blocked?r=1".parse().unwrap());
+        assert_eq!(
+            rules
+                .check_head(StatusCode::FOUND, &headers)
+                .map(|m| m.kind),
+            Some(BanRuleKind::Redirect)
+        );
+        // Only redirects are checked for a location.
+        assert_eq!(rules.check_head(StatusCode::CREATED, &headers), None);
+    }
+
+    #[test]
+    fn test_body_rules() {
+        let rules = rules(&[("body", "(?i)are you a robot")]);
+        assert!(rules.needs_body());
+        assert_eq!(rules.check_head(StatusCode::OK, &HeaderMap::new()), None);
+        assert!(rules.check_body(b"<h1>Are you a robot?</h1>").is_some());
+        assert!(rules.check_body(b"<h1>Welcome</h1>").is_none());
+    }
+
+    #[test]
+    fn test_invalid_rules() {
+        assert!(validate_ban_rule(BanRuleKind::Status, "200,abc").is_err());
+        assert!(validate_ban_rule(BanRuleKind::Header, "no colon").is_err());
+        assert!(validate_ban_rule(BanRuleKind::Body, "(unclosed").is_err());
+        assert!(validate_ban_rule(BanRuleKind::Redirect, "/blocked").is_ok());
+
+        let (compiled, invalid) = BanRules::compile(&[BanRule {
+            id: 1,
+            host: "example.com".into(),
+            kind: "cookie".into(),
+            pattern: "x".into(),
+        }]);
+        assert!(compiled.is_empty());
+        assert_eq!(invalid.len(), 1);
+    }
+}
@@ -0,0 +1,145 @@
+//! The HTTP client requests go through an upstream proxy with. The
+//! proxy server sends traffic with it, and health and exit checks
+//! probe proxies with it, so that they see what real traffic does.
+
+use std::{error::Error as StdError, io};
+
+use headers::{Authorization, HeaderMapExt, ProxyAuthorization};
+use hyper::{client::HttpConnector, Client, Request};
+use hyper_proxy::{Intercept, Proxy as UpstreamProxy, ProxyConnector};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+
+use crate::models::proxies::Proxy;
+
+pub type ProxyClient = Client<ProxyConnector<HttpsConnector<HttpConnector>>>;
+
+/// Whether requests can go through proxies of this protocol. The
+/// client speaks to `http` proxies, and to `https` ones over TLS.
+pub fn is_supported(proxy: &Proxy) -> bool {
+    proxy.protocol.eq_ignore_ascii_case("http") || proxy.protocol.eq_ignore_ascii_case("https")
+}
+
+/// Connects to proxies, over TLS for `https` ones.
+pub fn https_connector() -> HttpsConnector<HttpConnector> {
+    HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .build()
+}
+
+/// Wraps a connector so that its connections go through the proxy.
+/// HTTPS origins are reached through a CONNECT tunnel carrying the
+/// proxy's credentials, see [`authorize`] for plain HTTP ones.
+pub fn proxy_connector<C>(connector: C, proxy: &Proxy) -> io::Result<ProxyConnector<C>> {
+    let uri = format!("{}://{}:{}", proxy.protocol, proxy.host, proxy.port)
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut upstream = UpstreamProxy::new(Intercept::All, uri);
+    if let (Some(usr), Some(pwd)) = (&proxy.username, &proxy.password) {
+        upstream.set_authorization(Authorization::basic(usr, pwd));
+    }
+    ProxyConnector::from_proxy(connector, upstream)
+}
+
+/// Builds clients the way origins expect requests, keeping
+/// the case of the headers they are sent.
+pub fn client_builder() -> hyper::client::Builder {
+    let mut builder = Client::builder();
+    builder
+        .http1_title_case_headers(true)
+        .http1_preserve_header_case(true);
+    builder
+}
+
+/// A client sending every request through the proxy.
+pub fn build_proxy_client(proxy: &Proxy) -> io::Result<ProxyClient> {
+    let connector = proxy_connector(https_connector(), proxy)?;
+    Ok(client_builder().build(connector))
+}
+
+/// Adds the proxy's credentials to a plain HTTP request, which the
+/// proxy reads itself. HTTPS requests are left alone, their tunnel
+/// carries the credentials instead.
+pub fn authorize<B>(req: &mut Request<B>, proxy: &Proxy) {
+    let (Some(usr), Some(pwd)) = (&proxy.username, &proxy.password) else {
+        return;
+    };
+    if req.uri().scheme_str() == Some("http") {
+        let credentials = Authorization::basic(usr, pwd).0;
+        req.headers_mut()
+            .typed_insert(ProxyAuthorization(credentials));
+    }
+}
+
+/// The status a proxy turned down a CONNECT tunnel with. The
+/// connector only reports it in the message of its error.
+pub fn tunnel_status(err: &hyper::Error) -> Option<u16> {
+    let mut source: Option<&(dyn StdError + 'static)> = err.source();
+    while let Some(e) = source {
+        if let Some(response) = e.to_string().strip_prefix("unsuccessful tunnel (") {
+            return parse_status(response);
+        }
+        source = e.source();
+    }
+    None
+}
+
+/// The error along with its causes, which say what actually failed.
+pub fn error_chain(err: &hyper::Error) -> String {
+    let mut msg = err.to_string();
+    let mut source = err.source();
+    while let Some(e) = source {
+        msg.push_str(&format!(": {e}"));
+        source = e.source();
+    }
+    msg
+}
+
+/// The status code of an HTTP status line.
+pub(crate) fn parse_status(line: &str) -> Option<u16> {
+    let mut parts = line.split_whitespace();
+    if !parts.next()?.starts_with("HTTP/") {
+        return None;
+    }
+    parts.next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_status() {
+        assert_eq!(parse_status("HTTP/1.1 200 OK\r\n"), Some(200));
+        assert_eq!(parse_status("HTTP/1.0 407 Proxy Auth\r\n"), Some(407));
+        assert_eq!(parse_status("HTTP/1.1 407 Pro)"), Some(407));
+        assert_eq!(parse_status("garbage\r\n"), None);
+        assert_eq!(parse_status(""), None);
+    }
+
+    #[test]
+    fn test_authorize() {
+        let proxy = Proxy {
+            id: 1,
+            protocol: "http".into(),
+            host: "127.0.0.1".into(),
+            port: 8080,
+            username: Some("user".into()),
+            password: Some("pass".into()),
+            provider: "local".into(),
+            proxy_type: None,
+            weight: 1,
+            priority: 0,
+            exit_ip: None,
+            geo: Default::default(),
+        };
+        let mut req = Request::get("http://example.com").body(()).unwrap();
+        authorize(&mut req, &proxy);
+        assert_eq!(req.headers()["proxy-authorization"], "Basic dXNlcjpwYXNz");
+        // The tunnel carries them for HTTPS.
+        let mut req = Request::get("https://example.com").body(()).unwrap();
+        authorize(&mut req, &proxy);
+        assert!(req.headers().get("proxy-authorization").is_none());
+    }
+}
@@ -0,0 +1,156 @@
+use std::{env, time::Duration};
+
+use http::{header::RETRY_AFTER, HeaderMap, StatusCode};
+use time::{format_description::well_known::Rfc2822, OffsetDateTime};
+
+use crate::models::cooldowns::ProxyCooldown;
+
+/// How long proxies are kept away from a domain that rate limited them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CooldownConfig {
+    /// The first cooldown when the response has no `Retry-After`,
+    /// doubled for every rate limit in a row after that.
+    pub base: Duration,
+    /// The longest cooldown, whatever `Retry-After` asks for. Rate
+    /// limits stop counting as in a row once this long has passed.
+    pub max: Duration,
+}
+
+impl Default for CooldownConfig {
+    fn default() -> Self {
+        Self {
+            base: Duration::from_secs(30),
+            max: Duration::from_secs(900),
+        }
+    }
+}
+
+impl CooldownConfig {
+    /// Reads `LOCUST_COOLDOWN_BASE_SECS` and `LOCUST_COOLDOWN_MAX_SECS`.
+    pub fn from_env() -> Self {
+        let secs = |name: &str| {
+            env::var(name).ok().map(|v| {
+                Duration::from_secs(v.parse().unwrap_or_else(|_| panic!("Invalid {name}")))
+            })
+        };
+        let default = Self::default();
+        Self {
+            base: secs("LOCUST_COOLDOWN_BASE_SECS").unwrap_or(default.base),
+            max: secs("LOCUST_COOLDOWN_MAX_SECS").unwrap_or(default.max),
+        }
+    }
+
+    /// How many rate limits in a row a new one makes, given the
+    /// last cooldown of the proxy for the same domain.
+    pub fn strikes(&self, previous: Option<&ProxyCooldown>, now: OffsetDateTime) -> i32 {
+        match previous {
+            Some(p) if p.date_until + self.max > now => p.strikes + 1,
+            _ => 1,
+        }
+    }
+
+    /// How long a proxy cools down for after its `strikes`-th
+    /// rate limit in a row.
+    pub fn cooldown(&self, strikes: i32, retry_after: Option<Duration>) -> Duration {
+        let backoff = || {
+            let doublings = strikes.saturating_sub(1).clamp(0, 31) as u32;
+            self.base.saturating_mul(1 << doublings)
+        };
+        retry_after.unwrap_or_else(backoff).min(self.max)
+    }
+}
+
+/// The rate limit a response signals, if any: every `429`, and
+/// `503`s that say when to retry. Holds the `Retry-After` delay.
+pub fn rate_limit(
+    status: StatusCode,
+    headers: &HeaderMap,
+    now: OffsetDateTime,
+) -> Option<Option<Duration>> {
+    let retry_after = headers
+        .get(RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| parse_retry_after(v, now));
+    match status {
+        StatusCode::TOO_MANY_REQUESTS => Some(retry_after),
+        StatusCode::SERVICE_UNAVAILABLE if retry_after.is_some() => Some(retry_after),
+        _ => None,
+    }
+}
+
+/// Parses a `Retry-After` value, which is either
+/// a number of seconds or an HTTP date.
+pub fn parse_retry_after(value: &str, now: OffsetDateTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = OffsetDateTime::parse(value, &Rfc2822).ok()?;
+    Some((date - now).try_into().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = datetime!(2015-10-21 07:27:00 UTC);
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT", now),
+            Some(Duration::from_secs(60))
+        );
+        // Dates in the past mean retrying right away.
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let now = OffsetDateTime::now_utc();
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            rate_limit(StatusCode::TOO_MANY_REQUESTS, &headers, now),
+            Some(None)
+        );
+        assert_eq!(
+            rate_limit(StatusCode::SERVICE_UNAVAILABLE, &headers, now),
+            None
+        );
+
+        headers.insert(RETRY_AFTER, "5".parse().unwrap());
+        assert_eq!(
+            rate_limit(StatusCode::SERVICE_UNAVAILABLE, &headers, now),
+            Some(Some(Duration::from_secs(5)))
+        );
+        assert_eq!(rate_limit(StatusCode::OK, &headers, now), None);
+    }
+
+    #[test]
+    fn test_cooldown_backoff() {
+        let config = CooldownConfig {
+            base: Duration::from_secs(10),
+            max: Duration::from_secs(60),
+        };
+        let secs = |strikes, retry_after: Option<u64>| {
+            config
+                .cooldown(strikes, retry_after.map(Duration::from_secs))
+                .as_secs()
+        };
+        assert_eq!(secs(1, None), 10);
+        assert_eq!(secs(2, None), 20);
+        assert_eq!(secs(3, None), 40);
+        assert_eq!(secs(4, None), 60);
+        assert_eq!(secs(40, None), 60);
+        assert_eq!(secs(3, Some(5)), 5);
+        assert_eq!(secs(1, Some(3600)), 60);
+    }
+}
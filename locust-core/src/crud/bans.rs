@@ -0,0 +1,156 @@
+use sqlx::{postgres::PgPool, Error};
+
+use crate::{bans::BanRuleKind, domains::DomainRules, models::bans::BanRule};
+
+/// Gets the ban rules added for the domain as is, e.g. `*.example.com`.
+pub async fn get_ban_rules(pool: &PgPool, host: &str) -> Result<Vec<BanRule>, Error> {
+    let rules = sqlx::query_as::<_, BanRule>(
+        r#"
+            SELECT id, host, kind, pattern
+            FROM locust_ban_rules
+            WHERE host = $1
+            ORDER BY id
+        "#,
+    )
+    .bind(host)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rules)
+}
+
+/// Gets the ban rules that apply to requests to the host, those of the
+/// most specific domain matching it, like domain routes are matched.
+pub async fn get_host_ban_rules(pool: &PgPool, host: &str) -> Result<Vec<BanRule>, Error> {
+    let rules = get_all_ban_rules(pool).await?;
+    let (rules, _) = DomainRules::compile(rules.into_iter().map(|rule| (rule.host.clone(), rule)));
+    Ok(rules
+        .find(host)
+        .map(<[BanRule]>::to_vec)
+        .unwrap_or_default())
+}
+
+pub async fn get_all_ban_rules(pool: &PgPool) -> Result<Vec<BanRule>, Error> {
+    let rules = sqlx::query_as::<_, BanRule>(
+        r#"
+            SELECT id, host, kind, pattern
+            FROM locust_ban_rules
+            ORDER BY host, id
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(rules)
+}
+
+/// Adds a ban rule for a domain. The pattern is expected
+/// to have been validated against its kind already.
+pub async fn add_ban_rule(
+    pool: &PgPool,
+    host: &str,
+    kind: BanRuleKind,
+    pattern: &str,
+) -> Result<BanRule, Error> {
+    let rule = sqlx::query_as::<_, BanRule>(
+        r#"
+            INSERT INTO locust_ban_rules (host, kind, pattern)
+            VALUES ($1, $2, $3)
+            RETURNING id, host, kind, pattern
+        "#,
+    )
+    .bind(host)
+    .bind(kind.as_str())
+    .bind(pattern)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(rule)
+}
+
+pub async fn delete_ban_rule(pool: &PgPool, id: i32) -> Result<(), Error> {
+    let result = sqlx::query(
+        r#"
+            DELETE FROM locust_ban_rules WHERE id = $1
+        "#,
+    )
+    .bind(id)
+    .execute(pool)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::RowNotFound);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::{
+        postgres::{PgConnectOptions, PgPoolOptions},
+        Executor,
+    };
+    use std::{env, str::FromStr};
+
+    /// Runs against the Postgres at `LOCUST_TEST_DATABASE_URL`, like
+    /// the storage tests. Skipped when the variable is not set.
+    #[tokio::test]
+    async fn test_host_ban_rules() {
+        let Ok(url) = env::var("LOCUST_TEST_DATABASE_URL") else {
+            eprintln!("LOCUST_TEST_DATABASE_URL is not set, skipping");
+            return;
+        };
+        let schema = format!("locust_bans_test_{}", std::process::id());
+        let admin = PgPoolOptions::new().connect(&url).await.unwrap();
+        admin
+            .execute(
+                format!("DROP SCHEMA IF EXISTS {schema} CASCADE; CREATE SCHEMA {schema}").as_str(),
+            )
+            .await
+            .unwrap();
+        let options = PgConnectOptions::from_str(&url)
+            .unwrap()
+            .options([("search_path", schema.as_str())]);
+        let pool = PgPoolOptions::new().connect_with(options).await.unwrap();
+        pool.execute(
+            "CREATE TABLE locust_ban_rules (
+                id SERIAL PRIMARY KEY,
+                host varchar NOT NULL,
+                kind varchar NOT NULL,
+                pattern varchar NOT NULL
+            )",
+        )
+        .await
+        .unwrap();
+
+        let status = BanRuleKind::Status;
+        add_ban_rule(&pool, "example.com", status, "403")
+            .await
+            .unwrap();
+        add_ban_rule(&pool, "*.example.com", status, "429")
+            .await
+            .unwrap();
+        add_ban_rule(&pool, "api.example.com", status, "503")
+            .await
+            .unwrap();
+
+        let patterns = |rules: Vec<BanRule>| -> Vec<String> {
+            rules.into_iter().map(|rule| rule.pattern).collect()
+        };
+        let rules = |host| get_host_ban_rules(&pool, host);
+        assert_eq!(patterns(rules("Example.com:443").await.unwrap()), ["403"]);
+        assert_eq!(patterns(rules("www.example.com").await.unwrap()), ["429"]);
+        assert_eq!(patterns(rules("api.example.com").await.unwrap()), ["503"]);
+        assert!(rules("example.org").await.unwrap().is_empty());
+        // Rules are still listed by the domain they were added for.
+        let exact = get_ban_rules(&pool, "*.example.com").await.unwrap();
+        assert_eq!(patterns(exact), ["429"]);
+
+        admin
+            .execute(format!("DROP SCHEMA {schema} CASCADE").as_str())
+            .await
+            .unwrap();
+    }
+}
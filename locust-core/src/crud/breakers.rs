@@ -0,0 +1,48 @@
+use sqlx::{postgres::PgPool, Error};
+
+use crate::models::breakers::ProxyBreaker;
+
+/// Stores the latest state of a circuit breaker.
+pub async fn set_proxy_breaker(pool: &PgPool, breaker: &ProxyBreaker) -> Result<(), Error> {
+    sqlx::query(
+        r#"
+            INSERT INTO locust_proxy_breakers
+                (proxy_id, domain, state, consecutive_failures, date_opened, date_modified)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (proxy_id, domain) DO UPDATE SET
+                state = excluded.state,
+                consecutive_failures = excluded.consecutive_failures,
+                date_opened = excluded.date_opened,
+                date_modified = excluded.date_modified
+        "#,
+    )
+    .bind(breaker.proxy_id)
+    .bind(&breaker.domain)
+    .bind(&breaker.state)
+    .bind(breaker.consecutive_failures)
+    .bind(breaker.date_opened)
+    .bind(breaker.date_modified)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Gets the breakers of live proxies, those that
+/// are not closed first.
+pub async fn get_proxy_breakers(pool: &PgPool) -> Result<Vec<ProxyBreaker>, Error> {
+    let breakers = sqlx::query_as::<_, ProxyBreaker>(
+        r#"
+            SELECT b.proxy_id, b.domain, b.state, b.consecutive_failures,
+                b.date_opened, b.date_modified
+            FROM locust_proxy_breakers b
+            JOIN locust_proxies p ON p.id = b.proxy_id
+            WHERE p.date_deleted IS NULL
+            ORDER BY b.state = 'closed', b.proxy_id, b.domain
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(breakers)
+}
@@ -0,0 +1,47 @@
+use sqlx::{postgres::PgPool, Error};
+use time::OffsetDateTime;
+
+use crate::models::cooldowns::ProxyCooldown;
+
+/// Stores a cooldown a proxy got for a domain. A cooldown stored by
+/// another server that lasts longer is kept.
+pub async fn set_proxy_cooldown(pool: &PgPool, cooldown: &ProxyCooldown) -> Result<(), Error> {
+    sqlx::query(
+        r#"
+            INSERT INTO locust_proxy_cooldowns (proxy_id, domain, strikes, date_until)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (proxy_id, domain) DO UPDATE SET
+                strikes = excluded.strikes,
+                date_until = GREATEST(locust_proxy_cooldowns.date_until, excluded.date_until),
+                date_modified = now()
+        "#,
+    )
+    .bind(cooldown.proxy_id)
+    .bind(&cooldown.domain)
+    .bind(cooldown.strikes)
+    .bind(cooldown.date_until)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// Gets the cooldowns of every domain that end after `since`.
+pub async fn get_proxy_cooldowns(
+    pool: &PgPool,
+    since: OffsetDateTime,
+) -> Result<Vec<ProxyCooldown>, Error> {
+    let cooldowns = sqlx::query_as::<_, ProxyCooldown>(
+        r#"
+            SELECT proxy_id, domain, strikes, date_until
+            FROM locust_proxy_cooldowns
+            WHERE date_until > $1
+            ORDER BY date_until
+        "#,
+    )
+    .bind(since)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(cooldowns)
+}
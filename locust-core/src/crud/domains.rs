@@ -0,0 +1,327 @@
+use sqlx::{postgres::PgPool, Error};
+
+use crate::{
+    crud::tags::upsert_tags,
+    models::domains::Domain,
+    routing::{domain_routes, DomainLimit, DomainRoute},
+};
+
+pub async fn get_domains(pool: &PgPool) -> Result<Vec<Domain>, Error> {
+    let domains = sqlx::query_as::<_, Domain>(
+        r#"
+            SELECT
+                d.id, d.host, d.tag_expr, d.fallback_any, d.action,
+                d.max_concurrency, d.max_rps,
+                array_remove(array_agg(t.name ORDER BY t.name), NULL) as tags,
+                ARRAY(
+                    SELECT f.tag_expr FROM locust_domain_fallbacks as f
+                    WHERE f.domain_id = d.id ORDER BY f.position
+                ) as fallbacks
+            FROM locust_domains as d
+            LEFT JOIN locust_domain_tag_map as dtm ON d.id = dtm.domain_id
+            LEFT JOIN locust_tags as t ON t.id = dtm.tag_id
+            WHERE d.date_deleted IS NULL
+            GROUP BY d.id, d.host, d.tag_expr, d.fallback_any, d.action,
+                d.max_concurrency, d.max_rps
+            ORDER BY d.host
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(domains)
+}
+
+pub async fn get_domain(pool: &PgPool, host: &str) -> Result<Domain, Error> {
+    let domain = sqlx::query_as::<_, Domain>(
+        r#"
+            SELECT
+                d.id, d.host, d.tag_expr, d.fallback_any, d.action,
+                d.max_concurrency, d.max_rps,
+                array_remove(array_agg(t.name ORDER BY t.name), NULL) as tags,
+                ARRAY(
+                    SELECT f.tag_expr FROM locust_domain_fallbacks as f
+                    WHERE f.domain_id = d.id ORDER BY f.position
+                ) as fallbacks
+            FROM locust_domains as d
+            LEFT JOIN locust_domain_tag_map as dtm ON d.id = dtm.domain_id
+            LEFT JOIN locust_tags as t ON t.id = dtm.tag_id
+            WHERE d.host = $1 AND d.date_deleted IS NULL
+            GROUP BY d.id, d.host, d.tag_expr, d.fallback_any, d.action,
+                d.max_concurrency, d.max_rps
+        "#,
+    )
+    .bind(host)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(domain)
+}
+
+/// Maps the given tags to a domain, creating the domain
+/// and any missing tags along the way.
+pub async fn add_domain_tags(pool: &PgPool, host: &str, locust_tags: &[&str]) -> Result<(), Error> {
+    let mut tx = pool.begin().await?;
+
+    let domain_id: i32 = sqlx::query_scalar(
+        r#"
+            INSERT INTO locust_domains (host)
+            values ($1) ON CONFLICT (host) DO UPDATE
+            SET date_deleted = NULL, date_modified = now()
+            RETURNING id
+        "#,
+    )
+    .bind(host)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    let tag_ids = upsert_tags(&mut tx, locust_tags).await?;
+    for tag_id in &tag_ids {
+        sqlx::query(
+            r#"
+                INSERT INTO
+                locust_domain_tag_map (domain_id, tag_id)
+                SELECT $1, $2
+                WHERE NOT EXISTS (
+                    SELECT 1 FROM locust_domain_tag_map
+                    WHERE domain_id = $1 AND tag_id = $2
+                )
+            "#,
+        )
+        .bind(domain_id)
+        .bind(tag_id)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn remove_domain_tags(
+    pool: &PgPool,
+    host: &str,
+    locust_tags: &[&str],
+) -> Result<(), Error> {
+    sqlx::query(
+        r#"
+            DELETE FROM locust_domain_tag_map
+            WHERE domain_id IN (
+                SELECT id FROM locust_domains WHERE host = $1
+            )
+            AND tag_id IN (
+                SELECT id FROM locust_tags WHERE name = any($2)
+            )
+        "#,
+    )
+    .bind(host)
+    .bind(locust_tags)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Sets the tag expression the proxies for a domain must satisfy,
+/// creating the domain if needed, or clears it with `None`. See
+/// [`crate::tag_expr`].
+pub async fn set_domain_tag_expr(
+    pool: &PgPool,
+    host: &str,
+    tag_expr: Option<&str>,
+) -> Result<(), Error> {
+    match tag_expr {
+        Some(tag_expr) => {
+            sqlx::query(
+                r#"
+                    INSERT INTO locust_domains (host, tag_expr)
+                    values ($1, $2) ON CONFLICT (host) DO UPDATE
+                    SET tag_expr = $2, date_deleted = NULL, date_modified = now()
+                "#,
+            )
+            .bind(host)
+            .bind(tag_expr)
+            .execute(pool)
+            .await?;
+        }
+        None => {
+            sqlx::query(
+                r#"
+                    UPDATE locust_domains
+                    SET tag_expr = NULL, date_modified = now()
+                    WHERE host = $1
+                "#,
+            )
+            .bind(host)
+            .execute(pool)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Sets what is done with requests to a domain, creating the domain
+/// if needed, or goes back to proxying them with `None`. See
+/// [`crate::routing::RouteAction`].
+pub async fn set_domain_action(
+    pool: &PgPool,
+    host: &str,
+    action: Option<&str>,
+) -> Result<(), Error> {
+    match action {
+        Some(action) => {
+            sqlx::query(
+                r#"
+                    INSERT INTO locust_domains (host, action)
+                    values ($1, $2) ON CONFLICT (host) DO UPDATE
+                    SET action = $2, date_deleted = NULL, date_modified = now()
+                "#,
+            )
+            .bind(host)
+            .bind(action)
+            .execute(pool)
+            .await?;
+        }
+        None => {
+            sqlx::query(
+                r#"
+                    UPDATE locust_domains
+                    SET action = NULL, date_modified = now()
+                    WHERE host = $1
+                "#,
+            )
+            .bind(host)
+            .execute(pool)
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Sets the limit on requests to a domain, creating the domain if
+/// needed, or goes back to the server's default with an unset limit.
+/// See [`DomainLimit`].
+pub async fn set_domain_limit(pool: &PgPool, host: &str, limit: DomainLimit) -> Result<(), Error> {
+    sqlx::query(
+        r#"
+            INSERT INTO locust_domains (host, max_concurrency, max_rps)
+            values ($1, $2, $3) ON CONFLICT (host) DO UPDATE
+            SET max_concurrency = $2, max_rps = $3, date_deleted = NULL, date_modified = now()
+        "#,
+    )
+    .bind(host)
+    .bind(
+        limit
+            .concurrency
+            .map(|n| i32::try_from(n).unwrap_or(i32::MAX)),
+    )
+    .bind(limit.rps)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// Sets the tag expressions tried in order once none of a domain's
+/// own proxies is left, creating the domain if needed, and whether
+/// any proxy is used after them. See [`crate::routing`].
+pub async fn set_domain_fallbacks(
+    pool: &PgPool,
+    host: &str,
+    fallbacks: &[&str],
+    fallback_any: bool,
+) -> Result<(), Error> {
+    let mut tx = pool.begin().await?;
+
+    let domain_id: i32 = sqlx::query_scalar(
+        r#"
+            INSERT INTO locust_domains (host, fallback_any)
+            values ($1, $2) ON CONFLICT (host) DO UPDATE
+            SET fallback_any = $2, date_deleted = NULL, date_modified = now()
+            RETURNING id
+        "#,
+    )
+    .bind(host)
+    .bind(fallback_any)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query("DELETE FROM locust_domain_fallbacks WHERE domain_id = $1")
+        .bind(domain_id)
+        .execute(&mut *tx)
+        .await?;
+    for (position, tag_expr) in fallbacks.iter().enumerate() {
+        sqlx::query(
+            r#"
+                INSERT INTO locust_domain_fallbacks (domain_id, position, tag_expr)
+                VALUES ($1, $2, $3)
+            "#,
+        )
+        .bind(domain_id)
+        .bind(position as i32)
+        .bind(tag_expr)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Gets the route of every domain, from the expression set for it or
+/// else the tags mapped to it, and its fallbacks. See [`domain_routes`].
+pub async fn get_domain_routes(pool: &PgPool) -> Result<Vec<(String, DomainRoute)>, Error> {
+    let rows = sqlx::query_as(
+        r#"
+            SELECT d.host, d.tag_expr, d.fallback_any, d.action,
+                d.max_concurrency, d.max_rps, t.name as tag
+            FROM locust_domains as d
+            LEFT JOIN locust_domain_tag_map as dtm ON d.id = dtm.domain_id
+            LEFT JOIN locust_tags as t ON t.id = dtm.tag_id
+            WHERE d.date_deleted IS NULL
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+    let fallbacks = sqlx::query_as(
+        r#"
+            SELECT d.host, f.tag_expr
+            FROM locust_domain_fallbacks as f
+            JOIN locust_domains as d ON d.id = f.domain_id
+            WHERE d.date_deleted IS NULL
+            ORDER BY f.domain_id, f.position
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(domain_routes(rows, fallbacks))
+}
+
+/// Removes every tag mapping and fallback of a domain and marks it deleted.
+pub async fn delete_domain(pool: &PgPool, host: &str) -> Result<(), Error> {
+    let mut tx = pool.begin().await?;
+
+    let domain_id: i32 = sqlx::query_scalar(
+        r#"
+            UPDATE locust_domains
+            SET date_deleted = now(), tag_expr = NULL, fallback_any = true, action = NULL,
+                max_concurrency = NULL, max_rps = NULL
+            WHERE host = $1 AND date_deleted IS NULL
+            RETURNING id
+        "#,
+    )
+    .bind(host)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query("DELETE FROM locust_domain_tag_map WHERE domain_id = $1")
+        .bind(domain_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM locust_domain_fallbacks WHERE domain_id = $1")
+        .bind(domain_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
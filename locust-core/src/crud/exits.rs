@@ -0,0 +1,46 @@
+use sqlx::{postgres::PgPool, Error};
+
+use crate::models::exits::ProxyExit;
+
+/// Records the address a proxy was seen exiting from.
+pub async fn set_proxy_exit(pool: &PgPool, proxy_id: i32, exit_ip: &str) -> Result<(), Error> {
+    sqlx::query(
+        r#"
+            UPDATE locust_proxies
+            SET exit_ip = $2, date_exit_seen = now()
+            WHERE id = $1
+        "#,
+    )
+    .bind(proxy_id)
+    .bind(exit_ip)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+/// The exit addresses of the live proxies, along with
+/// the other proxies exiting from the same address.
+pub async fn get_proxy_exits(pool: &PgPool) -> Result<Vec<ProxyExit>, Error> {
+    let exits = sqlx::query_as::<_, ProxyExit>(
+        r#"
+            SELECT
+                p.id as proxy_id, p.host, p.port, p.exit_ip, p.date_exit_seen,
+                ARRAY(
+                    SELECT o.id
+                    FROM locust_proxies as o
+                    WHERE o.exit_ip = p.exit_ip
+                    AND o.id <> p.id
+                    AND o.date_deleted IS NULL
+                    ORDER BY o.id
+                ) as shared_with
+            FROM locust_proxies as p
+            WHERE p.date_deleted IS NULL
+            ORDER BY p.id
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(exits)
+}
@@ -0,0 +1,75 @@
+use sqlx::{postgres::PgPool, Error};
+
+use crate::models::health::ProxyHealth;
+
+/// Records the result of a health check. A successful check
+/// reinstates the proxy, while `quarantine_after` failed checks
+/// in a row take it out of rotation.
+pub async fn record_proxy_check(
+    pool: &PgPool,
+    proxy_id: i32,
+    status: Option<i16>,
+    latency: Option<i32>,
+    healthy: bool,
+    quarantine_after: i32,
+) -> Result<ProxyHealth, Error> {
+    let health = sqlx::query_as::<_, ProxyHealth>(
+        r#"
+            UPDATE locust_proxies
+            SET
+                date_last_checked = now(),
+                last_check_status = $2,
+                last_check_latency = $3,
+                check_failures = CASE WHEN $4 THEN 0 ELSE check_failures + 1 END,
+                date_quarantined = CASE
+                    WHEN $4 THEN NULL
+                    WHEN check_failures + 1 >= $5 THEN COALESCE(date_quarantined, now())
+                    ELSE date_quarantined
+                END
+            WHERE id = $1
+            RETURNING
+                id as proxy_id, date_last_checked, last_check_status,
+                last_check_latency, check_failures, date_quarantined
+        "#,
+    )
+    .bind(proxy_id)
+    .bind(status)
+    .bind(latency)
+    .bind(healthy)
+    .bind(quarantine_after)
+    .fetch_one(pool)
+    .await?;
+
+    Ok(health)
+}
+
+pub async fn get_proxies_health(pool: &PgPool) -> Result<Vec<ProxyHealth>, Error> {
+    let health = sqlx::query_as::<_, ProxyHealth>(
+        r#"
+            SELECT
+                id as proxy_id, date_last_checked, last_check_status,
+                last_check_latency, check_failures, date_quarantined
+            FROM locust_proxies
+            WHERE date_deleted IS NULL
+            ORDER BY id
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(health)
+}
+
+pub async fn count_quarantined_proxies(pool: &PgPool) -> Result<i64, Error> {
+    let count = sqlx::query_scalar(
+        r#"
+            SELECT count(*)
+            FROM locust_proxies
+            WHERE date_deleted IS NULL AND date_quarantined IS NOT NULL
+        "#,
+    )
+    .fetch_one(pool)
+    .await?;
+
+    Ok(count)
+}
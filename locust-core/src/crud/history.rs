@@ -0,0 +1,334 @@
+use sqlx::{postgres::PgPool, Error};
+use time::{Date, Duration, Month};
+
+use crate::models::history::{RequestHistoryFilter, RequestRecord};
+
+const HISTORY_TABLE: &str = "locust_request_history";
+
+/// Inserts a batch of request outcomes with a single statement.
+pub async fn add_request_history(pool: &PgPool, records: &[RequestRecord]) -> Result<u64, Error> {
+    if records.is_empty() {
+        return Ok(0);
+    }
+
+    let mut date_created = Vec::with_capacity(records.len());
+    let mut proxy_id = Vec::with_capacity(records.len());
+    let mut session_id = Vec::with_capacity(records.len());
+    let mut domain = Vec::with_capacity(records.len());
+    let mut status = Vec::with_capacity(records.len());
+    let mut error_class = Vec::with_capacity(records.len());
+    let mut response_time = Vec::with_capacity(records.len());
+    let mut total_time = Vec::with_capacity(records.len());
+    let mut request_bytes = Vec::with_capacity(records.len());
+    let mut response_bytes = Vec::with_capacity(records.len());
+    let mut username = Vec::with_capacity(records.len());
+    for r in records {
+        date_created.push(r.date_created);
+        proxy_id.push(r.proxy_id);
+        session_id.push(r.session_id);
+        domain.push(r.domain.clone());
+        status.push(r.status);
+        error_class.push(r.error_class.clone());
+        response_time.push(r.response_time);
+        total_time.push(r.total_time);
+        request_bytes.push(r.request_bytes);
+        response_bytes.push(r.response_bytes);
+        username.push(r.username.clone());
+    }
+
+    let result = sqlx::query(
+        r#"
+            INSERT INTO locust_request_history (
+                date_created, proxy_id, session_id, domain, status, error_class,
+                response_time, total_time, request_bytes, response_bytes, username
+            )
+            SELECT * FROM UNNEST(
+                $1::timestamptz[], $2::integer[], $3::integer[], $4::varchar[],
+                $5::smallint[], $6::varchar[], $7::integer[], $8::integer[],
+                $9::bigint[], $10::bigint[], $11::varchar[]
+            )
+        "#,
+    )
+    .bind(date_created)
+    .bind(proxy_id)
+    .bind(session_id)
+    .bind(domain)
+    .bind(status)
+    .bind(error_class)
+    .bind(response_time)
+    .bind(total_time)
+    .bind(request_bytes)
+    .bind(response_bytes)
+    .bind(username)
+    .execute(pool)
+    .await?;
+
+    Ok(result.rows_affected())
+}
+
+/// Gets the most recent requests matching the filter.
+pub async fn get_request_history(
+    pool: &PgPool,
+    filter: &RequestHistoryFilter,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<RequestRecord>, Error> {
+    let records = sqlx::query_as::<_, RequestRecord>(
+        r#"
+            SELECT
+                date_created, proxy_id, session_id, domain, status, error_class,
+                response_time, total_time, request_bytes, response_bytes, username
+            FROM locust_request_history
+            WHERE ($1::integer IS NULL OR proxy_id = $1)
+            AND ($2::varchar IS NULL OR domain = $2)
+            AND ($3::timestamptz IS NULL OR date_created >= $3)
+            AND ($4::timestamptz IS NULL OR date_created < $4)
+            AND (NOT $5 OR error_class IS NOT NULL OR status >= 400)
+            ORDER BY date_created DESC
+            LIMIT $6
+            OFFSET $7
+        "#,
+    )
+    .bind(filter.proxy_id)
+    .bind(&filter.domain)
+    .bind(filter.since)
+    .bind(filter.until)
+    .bind(filter.failed)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(records)
+}
+
+/// Creates the daily partitions of the request history
+/// for `days` days starting at `from`, if missing. Rows of
+/// those days already in the default partition are moved
+/// to theirs. A day that fails does not hold back the next
+/// ones, the first error is returned once all were tried.
+pub async fn create_request_history_partitions(
+    pool: &PgPool,
+    from: Date,
+    days: u16,
+) -> Result<(), Error> {
+    let mut result = Ok(());
+    for offset in 0..days {
+        let day = from + Duration::days(offset.into());
+        if let Err(e) = create_request_history_partition(pool, day).await {
+            result = result.and(Err(e));
+        }
+    }
+
+    result
+}
+
+async fn create_request_history_partition(pool: &PgPool, day: Date) -> Result<(), Error> {
+    let name = partition_name(day);
+    let exists: bool = sqlx::query_scalar("SELECT to_regclass($1) IS NOT NULL")
+        .bind(&name)
+        .fetch_one(pool)
+        .await?;
+    if exists {
+        return Ok(());
+    }
+
+    // A partition can't be created for a range the default partition
+    // holds rows of, so the table is filled first and attached after.
+    // DDL can't take bind parameters, but both the name and the
+    // bounds are built from dates.
+    let next = day + Duration::days(1);
+    let (start, end) = (
+        format!("'{day} 00:00:00+00'"),
+        format!("'{next} 00:00:00+00'"),
+    );
+    let mut tx = pool.begin().await?;
+    sqlx::query(&format!(
+        "CREATE TABLE {name} (LIKE {HISTORY_TABLE} INCLUDING DEFAULTS INCLUDING CONSTRAINTS)"
+    ))
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(&format!(
+        "WITH moved AS ( \
+             DELETE FROM {HISTORY_TABLE}_default \
+             WHERE date_created >= {start} AND date_created < {end} \
+             RETURNING * \
+         ) \
+         INSERT INTO {name} SELECT * FROM moved"
+    ))
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(&format!(
+        "ALTER TABLE {HISTORY_TABLE} ATTACH PARTITION {name} FOR VALUES FROM ({start}) TO ({end})"
+    ))
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await
+}
+
+/// Drops the request history partitions entirely older than
+/// `before`, along with any old rows in the default partition.
+/// Returns the names of the dropped partitions.
+pub async fn drop_request_history_partitions(
+    pool: &PgPool,
+    before: Date,
+) -> Result<Vec<String>, Error> {
+    let partitions: Vec<String> = sqlx::query_scalar(
+        r#"
+            SELECT c.relname::varchar
+            FROM pg_inherits as i
+            JOIN pg_class as c ON c.oid = i.inhrelid
+            JOIN pg_class as p ON p.oid = i.inhparent
+            WHERE p.relname = $1
+        "#,
+    )
+    .bind(HISTORY_TABLE)
+    .fetch_all(pool)
+    .await?;
+
+    let mut dropped = Vec::new();
+    for name in partitions {
+        match partition_date(&name) {
+            Some(day) if day < before => {
+                sqlx::query(&format!("DROP TABLE IF EXISTS {name}"))
+                    .execute(pool)
+                    .await?;
+                dropped.push(name);
+            }
+            _ => {}
+        }
+    }
+
+    sqlx::query(&format!(
+        "DELETE FROM {HISTORY_TABLE}_default WHERE date_created < '{before} 00:00:00+00'"
+    ))
+    .execute(pool)
+    .await?;
+
+    Ok(dropped)
+}
+
+fn partition_name(day: Date) -> String {
+    format!(
+        "{HISTORY_TABLE}_p{:04}{:02}{:02}",
+        day.year(),
+        day.month() as u8,
+        day.day()
+    )
+}
+
+fn partition_date(name: &str) -> Option<Date> {
+    let suffix = name.strip_prefix(HISTORY_TABLE)?.strip_prefix("_p")?;
+    if suffix.len() != 8 || !suffix.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+
+    let year = suffix[..4].parse().ok()?;
+    let month = Month::try_from(suffix[4..6].parse::<u8>().ok()?).ok()?;
+    let day = suffix[6..].parse().ok()?;
+    Date::from_calendar_date(year, month, day).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::{
+        postgres::{PgConnectOptions, PgPoolOptions},
+        Executor,
+    };
+    use std::{env, str::FromStr};
+    use time::macros::{date, datetime};
+
+    #[test]
+    fn test_partition_names() {
+        let day = Date::from_calendar_date(2024, Month::March, 5).unwrap();
+        let name = partition_name(day);
+        assert_eq!(name, "locust_request_history_p20240305");
+        assert_eq!(partition_date(&name), Some(day));
+
+        assert_eq!(partition_date("locust_request_history_default"), None);
+        assert_eq!(partition_date("locust_request_history_p20241340"), None);
+        assert_eq!(partition_date("locust_sessions"), None);
+    }
+
+    /// Runs against the Postgres at `LOCUST_TEST_DATABASE_URL`, like
+    /// the storage tests. Skipped when the variable is not set.
+    #[tokio::test]
+    async fn test_partitions_take_rows_from_default() {
+        let Ok(url) = env::var("LOCUST_TEST_DATABASE_URL") else {
+            eprintln!("LOCUST_TEST_DATABASE_URL is not set, skipping");
+            return;
+        };
+        let schema = format!("locust_history_test_{}", std::process::id());
+        let admin = PgPoolOptions::new().connect(&url).await.unwrap();
+        admin
+            .execute(
+                format!("DROP SCHEMA IF EXISTS {schema} CASCADE; CREATE SCHEMA {schema}").as_str(),
+            )
+            .await
+            .unwrap();
+        let options = PgConnectOptions::from_str(&url)
+            .unwrap()
+            .options([("search_path", schema.as_str())]);
+        let pool = PgPoolOptions::new().connect_with(options).await.unwrap();
+        pool.execute(include_str!("../../../migrations/V3__request_history.sql"))
+            .await
+            .unwrap();
+
+        let record = |date_created| RequestRecord {
+            date_created,
+            proxy_id: 1,
+            session_id: None,
+            domain: Some("example.com".into()),
+            status: Some(200),
+            error_class: None,
+            response_time: 10,
+            total_time: 12,
+            request_bytes: 100,
+            response_bytes: 1000,
+            username: None,
+        };
+        // Without partitions yet, today's rows land in the default one.
+        add_request_history(
+            &pool,
+            &[
+                record(datetime!(2024-03-05 10:00 UTC)),
+                record(datetime!(2024-03-06 10:00 UTC)),
+                record(datetime!(2024-03-01 10:00 UTC)),
+            ],
+        )
+        .await
+        .unwrap();
+
+        create_request_history_partitions(&pool, date!(2024 - 03 - 05), 3)
+            .await
+            .unwrap();
+        let count = |table: &'static str| {
+            let pool = pool.clone();
+            async move {
+                sqlx::query_scalar::<_, i64>(&format!("SELECT count(*) FROM {table}"))
+                    .fetch_one(&pool)
+                    .await
+                    .unwrap()
+            }
+        };
+        assert_eq!(count("locust_request_history_p20240305").await, 1);
+        assert_eq!(count("locust_request_history_p20240306").await, 1);
+        assert_eq!(count("locust_request_history_p20240307").await, 0);
+        assert_eq!(count("locust_request_history_default").await, 1);
+        assert_eq!(count("locust_request_history").await, 3);
+
+        // Partitions that exist already are left alone.
+        create_request_history_partitions(&pool, date!(2024 - 03 - 06), 3)
+            .await
+            .unwrap();
+        assert_eq!(count("locust_request_history_p20240306").await, 1);
+        assert_eq!(count("locust_request_history_p20240308").await, 0);
+
+        pool.close().await;
+        admin
+            .execute(format!("DROP SCHEMA {schema} CASCADE").as_str())
+            .await
+            .unwrap();
+    }
+}
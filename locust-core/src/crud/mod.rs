@@ -1 +1,10 @@
+pub mod bans;
+pub mod breakers;
+pub mod cooldowns;
+pub mod domains;
+pub mod exits;
+pub mod health;
+pub mod history;
 pub mod proxies;
+pub mod scores;
+pub mod tags;
@@ -1,97 +1,205 @@
-use sqlx::{postgres::PgPool, Error, Row};
+use std::collections::HashMap;
 
-use crate::models::proxies::{NewProxy, Proxy, ProxySession};
+use sqlx::{postgres::PgPool, Error, FromRow, Row};
+use time::OffsetDateTime;
 
-/// Gets the appropriate proxy for a given domain.
-/// If no proxy is attached to the given domain via locust_tags,
-/// then it gets a general proxy ordered by the date
-/// of its last use.
-///
-/// Updates the date_last_used value of the returned proxy.
-pub async fn get_proxy_by_domain(pool: &PgPool, domain: &str) -> Result<Proxy, Error> {
+use crate::{
+    crud::{domains::get_domain_routes, tags::upsert_tags},
+    models::proxies::{Geo, NewProxy, Proxy, ProxySession, ProxyType},
+    pool::ProxyPool,
+};
+
+pub async fn get_proxy_by_id(pool: &PgPool, id: i32) -> Result<Proxy, Error> {
     let proxy = sqlx::query_as::<_, Proxy>(
         r#"
             SELECT
-                p.id, p.protocol, p.host, p.port, p.username, p.password, p.provider
-            FROM locust_proxies as p
-            JOIN locust_proxy_tag_map as ptm ON p.id = ptm.proxy_id
-            JOIN locust_tags as t ON ptm.tag_id = t.id
-            JOIN locust_domain_tag_map as dtm ON t.id = dtm.tag_id
-            JOIN locust_domains as d ON d.id = dtm.domain_id
-            WHERE d.host = $1 AND p.date_deleted IS NULL
-            ORDER BY p.date_last_used DESC
+                id, protocol, host, port, username, password, provider,
+                type, weight, priority, exit_ip, country, region, city, asn
+            FROM locust_proxies
+            WHERE id = $1
         "#,
     )
-    .bind(domain)
-    .fetch_optional(pool)
+    .bind(id)
+    .fetch_one(pool)
     .await?;
 
-    let proxy = match proxy {
-        Some(p) => p,
-        None => return get_general_proxy(pool).await,
-    };
-
-    update_proxy_last_used(pool, proxy.id).await?;
+    update_proxy_last_used(pool, id).await?;
 
     Ok(proxy)
 }
 
-pub async fn get_general_proxy(pool: &PgPool) -> Result<Proxy, Error> {
-    let proxy = sqlx::query_as::<_, Proxy>(
+/// Finds a proxy that is not deleted, quarantined or not, without
+/// marking it as used like [`get_proxy_by_id`] does.
+pub async fn find_proxy_by_id(pool: &PgPool, id: i32) -> Result<Proxy, Error> {
+    sqlx::query_as::<_, Proxy>(
         r#"
             SELECT
-                p.id, p.protocol, p.host, p.port, p.username, p.password, p.provider
-            FROM locust_proxies as p
-            WHERE p.date_deleted IS NULL
-            ORDER BY p.date_last_used DESC
+                id, protocol, host, port, username, password, provider,
+                type, weight, priority, exit_ip, country, region, city, asn
+            FROM locust_proxies
+            WHERE id = $1
+            AND date_deleted IS NULL
         "#,
     )
+    .bind(id)
     .fetch_one(pool)
-    .await?;
+    .await
+}
 
-    update_proxy_last_used(pool, proxy.id).await?;
+async fn update_proxy_last_used(pool: &PgPool, id: i32) -> Result<(), Error> {
+    sqlx::query(
+        r#"
+            UPDATE locust_proxies
+            SET date_last_used = now()
+            WHERE id = $1
+        "#,
+    )
+    .bind(id)
+    .execute(pool)
+    .await?;
 
-    Ok(proxy)
+    Ok(())
 }
 
-pub async fn get_proxy_by_id(pool: &PgPool, id: i32) -> Result<Proxy, Error> {
-    let proxy = sqlx::query_as::<_, Proxy>(
+/// Loads the live proxies along with their tags
+/// and the tag expressions of the domains.
+pub async fn get_proxy_pool(pool: &PgPool) -> Result<ProxyPool, Error> {
+    let rows = sqlx::query(
         r#"
             SELECT
-                id, protocol, host, port, username, password, provider
+                id, protocol, host, port, username, password, provider,
+                type, weight, priority, exit_ip, country, region, city, asn,
+                date_last_used::timestamptz as date_last_used
             FROM locust_proxies
-            WHERE id = $1
+            WHERE date_deleted IS NULL
+            AND date_quarantined IS NULL
         "#,
     )
-    .bind(id)
-    .fetch_one(pool)
+    .fetch_all(pool)
     .await?;
+    let proxies = rows
+        .iter()
+        .map(|row| {
+            let proxy = Proxy::from_row(row)?;
+            Ok((proxy, row.try_get("date_last_used")?))
+        })
+        .collect::<Result<_, Error>>()?;
 
-    update_proxy_last_used(pool, id).await?;
+    let rows: Vec<(i32, String)> = sqlx::query_as(
+        r#"
+            SELECT ptm.proxy_id, t.name
+            FROM locust_proxy_tag_map as ptm
+            JOIN locust_tags as t ON ptm.tag_id = t.id
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut tags: HashMap<i32, Vec<String>> = HashMap::new();
+    for (proxy_id, name) in rows {
+        tags.entry(proxy_id).or_default().push(name);
+    }
 
-    Ok(proxy)
+    let domains = get_domain_routes(pool).await?;
+    Ok(ProxyPool::new(proxies, tags, domains))
 }
 
-async fn update_proxy_last_used(pool: &PgPool, id: i32) -> Result<(), Error> {
+/// Stores when proxies were last used, for uses
+/// that were recorded in memory.
+pub async fn set_proxies_last_used(
+    pool: &PgPool,
+    used: &[(i32, OffsetDateTime)],
+) -> Result<(), Error> {
+    let (ids, dates): (Vec<i32>, Vec<OffsetDateTime>) = used.iter().copied().unzip();
+    sqlx::query(
+        r#"
+            UPDATE locust_proxies as p
+            SET date_last_used = u.date_last_used
+            FROM UNNEST($1::integer[], $2::timestamptz[]) as u(id, date_last_used)
+            WHERE p.id = u.id
+        "#,
+    )
+    .bind(ids)
+    .bind(dates)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_all_proxies(pool: &PgPool) -> Result<Vec<Proxy>, Error> {
+    let proxies = sqlx::query_as::<_, Proxy>(
+        r#"
+            SELECT
+                id, protocol, host, port, username, password, provider,
+                type, weight, priority, exit_ip, country, region, city, asn
+            FROM locust_proxies
+            WHERE date_deleted IS NULL
+            ORDER BY id
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(proxies)
+}
+
+/// Sets where a proxy is located.
+pub async fn set_proxy_geo(pool: &PgPool, id: i32, geo: &Geo) -> Result<(), Error> {
     sqlx::query(
         r#"
             UPDATE locust_proxies
-            SET date_last_used = now()
+            SET country = $2, region = $3, city = $4, asn = $5, date_modified = now()
             WHERE id = $1
         "#,
     )
     .bind(id)
+    .bind(&geo.country)
+    .bind(&geo.region)
+    .bind(&geo.city)
+    .bind(geo.asn)
     .execute(pool)
     .await?;
 
     Ok(())
 }
 
+/// Sets the type, weight and priority of proxies, leaving
+/// the ones that are `None` as they are.
+pub async fn update_proxies(
+    pool: &PgPool,
+    ids: &[i32],
+    proxy_type: Option<ProxyType>,
+    weight: Option<i32>,
+    priority: Option<i32>,
+) -> Result<u64, Error> {
+    let res = sqlx::query(
+        r#"
+            UPDATE locust_proxies
+            SET
+                type = COALESCE($2, type),
+                weight = COALESCE($3, weight),
+                priority = COALESCE($4, priority),
+                date_modified = now()
+            WHERE id = any($1)
+            AND date_deleted IS NULL
+        "#,
+    )
+    .bind(ids)
+    .bind(proxy_type.map(|t| t.as_str()))
+    .bind(weight)
+    .bind(priority)
+    .execute(pool)
+    .await?;
+
+    Ok(res.rows_affected())
+}
+
 pub async fn get_proxies_by_tags(pool: &PgPool, locust_tags: &[&str]) -> Result<Vec<Proxy>, Error> {
     let proxies = sqlx::query_as::<_, Proxy>(
         r#"
             SELECT
-                p.id, p.protocol, p.host, p.port, p.username, p.password, p.provider
+                p.id, p.protocol, p.host, p.port, p.username, p.password, p.provider,
+                p.type, p.weight, p.priority, p.exit_ip, p.country, p.region, p.city, p.asn
             FROM locust_proxies as p
             JOIN locust_proxy_tag_map as ptm ON p.id = ptm.proxy_id
             JOIN locust_tags as t ON ptm.tag_id = t.id
@@ -148,29 +256,17 @@ pub async fn add_proxies(
 ) -> Result<(), Error> {
     let mut tx = pool.begin().await?;
 
-    let mut tag_ids: Vec<i32> = Vec::new();
-    for tag in locust_tags {
-        let id = sqlx::query(
-            r#"
-                INSERT INTO locust_tags (name)
-                values ($1) ON CONFLICT (name) DO UPDATE
-                SET name=EXCLUDED.name
-                RETURNING id
-            "#,
-        )
-        .bind(tag)
-        .fetch_one(&mut *tx)
-        .await?
-        .try_get("id")?;
-        tag_ids.push(id);
-    }
+    let tag_ids = upsert_tags(&mut tx, locust_tags).await?;
 
     for proxy in proxies {
         let proxy_id: i32 = sqlx::query(
             r#"
                 INSERT INTO
-                locust_proxies (protocol, host, port, username, password, provider)
-                values ($1, $2, $3, $4, $5, $6)
+                locust_proxies (
+                    protocol, host, port, username, password, provider,
+                    type, weight, priority, country, region, city, asn
+                )
+                values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
                 RETURNING id
             "#,
         )
@@ -180,6 +276,13 @@ pub async fn add_proxies(
         .bind(&proxy.username)
         .bind(&proxy.password)
         .bind(&proxy.provider)
+        .bind(proxy.proxy_type.map(|t| t.as_str()))
+        .bind(proxy.weight)
+        .bind(proxy.priority)
+        .bind(&proxy.geo.country)
+        .bind(&proxy.geo.region)
+        .bind(&proxy.geo.city)
+        .bind(proxy.geo.asn)
         .fetch_one(&mut *tx)
         .await?
         .try_get("id")?;
@@ -203,6 +306,103 @@ pub async fn add_proxies(
     Ok(())
 }
 
+/// Attaches the given tags to an existing proxy, creating
+/// any tags that do not exist yet.
+pub async fn add_proxy_tags(
+    pool: &PgPool,
+    proxy_id: i32,
+    locust_tags: &[&str],
+) -> Result<(), Error> {
+    let mut tx = pool.begin().await?;
+
+    let tag_ids = upsert_tags(&mut tx, locust_tags).await?;
+    for tag_id in &tag_ids {
+        sqlx::query(
+            r#"
+                INSERT INTO
+                locust_proxy_tag_map (proxy_id, tag_id)
+                SELECT $1, $2
+                WHERE NOT EXISTS (
+                    SELECT 1 FROM locust_proxy_tag_map
+                    WHERE proxy_id = $1 AND tag_id = $2
+                )
+            "#,
+        )
+        .bind(proxy_id)
+        .bind(tag_id)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn remove_proxy_tags(
+    pool: &PgPool,
+    proxy_id: i32,
+    locust_tags: &[&str],
+) -> Result<(), Error> {
+    sqlx::query(
+        r#"
+            DELETE FROM locust_proxy_tag_map
+            WHERE proxy_id = $1
+            AND tag_id IN (
+                SELECT id FROM locust_tags WHERE name = any($2)
+            )
+        "#,
+    )
+    .bind(proxy_id)
+    .bind(locust_tags)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+pub async fn get_proxy_tags(pool: &PgPool, proxy_id: i32) -> Result<Vec<String>, Error> {
+    let tags = sqlx::query_scalar(
+        r#"
+            SELECT t.name
+            FROM locust_tags as t
+            JOIN locust_proxy_tag_map as ptm ON t.id = ptm.tag_id
+            WHERE ptm.proxy_id = $1
+            ORDER BY t.name
+        "#,
+    )
+    .bind(proxy_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(tags)
+}
+
+/// Gets the tags of several proxies at once, keyed by proxy id.
+/// Proxies without tags are left out.
+pub async fn get_proxies_tags(
+    pool: &PgPool,
+    proxy_ids: &[i32],
+) -> Result<HashMap<i32, Vec<String>>, Error> {
+    let rows: Vec<(i32, String)> = sqlx::query_as(
+        r#"
+            SELECT ptm.proxy_id, t.name
+            FROM locust_tags as t
+            JOIN locust_proxy_tag_map as ptm ON t.id = ptm.tag_id
+            WHERE ptm.proxy_id = any($1)
+            ORDER BY ptm.proxy_id, t.name
+        "#,
+    )
+    .bind(proxy_ids)
+    .fetch_all(pool)
+    .await?;
+
+    let mut tags: HashMap<i32, Vec<String>> = HashMap::new();
+    for (proxy_id, name) in rows {
+        tags.entry(proxy_id).or_default().push(name);
+    }
+
+    Ok(tags)
+}
+
 pub async fn get_proxy_session(pool: &PgPool, id: i32) -> Result<ProxySession, Error> {
     let session = sqlx::query_as::<_, ProxySession>(
         r#"
@@ -231,3 +431,83 @@ pub async fn create_proxy_session(pool: &PgPool, proxy_id: i32) -> Result<ProxyS
 
     Ok(session)
 }
+
+/// Takes `count` session ids from the sequence, so that
+/// sessions can be handed out before they are stored.
+pub async fn reserve_session_ids(pool: &PgPool, count: i32) -> Result<Vec<i32>, Error> {
+    let ids = sqlx::query_scalar(
+        r#"
+            SELECT nextval(pg_get_serial_sequence('locust_sessions', 'id'))::integer
+            FROM generate_series(1, $1)
+        "#,
+    )
+    .bind(count)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(ids)
+}
+
+/// Stores sessions whose ids were reserved
+/// with [`reserve_session_ids`].
+pub async fn add_proxy_sessions(pool: &PgPool, sessions: &[ProxySession]) -> Result<(), Error> {
+    let (ids, proxy_ids): (Vec<i32>, Vec<i32>) =
+        sessions.iter().map(|s| (s.id, s.proxy_id)).unzip();
+    sqlx::query(
+        r#"
+            INSERT INTO
+            locust_sessions (id, proxy_id)
+            SELECT * FROM UNNEST($1::integer[], $2::integer[])
+            ON CONFLICT (id) DO NOTHING
+        "#,
+    )
+    .bind(ids)
+    .bind(proxy_ids)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_proxy_sessions(
+    pool: &PgPool,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<ProxySession>, Error> {
+    let sessions = sqlx::query_as::<_, ProxySession>(
+        r#"
+            SELECT id, proxy_id FROM locust_sessions
+            ORDER BY id DESC
+            LIMIT $1 OFFSET $2
+        "#,
+    )
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(sessions)
+}
+
+pub async fn delete_proxy_session(pool: &PgPool, id: i32) -> Result<(), Error> {
+    let res = sqlx::query(
+        r#"
+            DELETE FROM locust_sessions WHERE id = $1
+        "#,
+    )
+    .bind(id)
+    .execute(pool)
+    .await?;
+
+    if res.rows_affected() == 0 {
+        return Err(Error::RowNotFound);
+    }
+
+    Ok(())
+}
+
+pub async fn count_proxy_sessions(pool: &PgPool) -> Result<i64, Error> {
+    sqlx::query_scalar("SELECT count(*) FROM locust_sessions")
+        .fetch_one(pool)
+        .await
+}
@@ -0,0 +1,63 @@
+use sqlx::{postgres::PgPool, Error};
+
+use crate::models::scores::ProxyDomainScore;
+
+/// Adds the given counts onto the stored scores,
+/// with a single statement for the whole batch.
+pub async fn add_proxy_domain_scores(
+    pool: &PgPool,
+    scores: &[ProxyDomainScore],
+) -> Result<(), Error> {
+    if scores.is_empty() {
+        return Ok(());
+    }
+
+    let proxy_ids: Vec<i32> = scores.iter().map(|s| s.proxy_id).collect();
+    let domains: Vec<&str> = scores.iter().map(|s| s.domain.as_str()).collect();
+    let successes: Vec<i64> = scores.iter().map(|s| s.successes).collect();
+    let failures: Vec<i64> = scores.iter().map(|s| s.failures).collect();
+    let bans: Vec<i64> = scores.iter().map(|s| s.bans).collect();
+
+    sqlx::query(
+        r#"
+            INSERT INTO locust_proxy_domain_scores
+                (proxy_id, domain, successes, failures, bans)
+            SELECT * FROM UNNEST(
+                $1::integer[], $2::varchar[], $3::bigint[], $4::bigint[], $5::bigint[]
+            )
+            ON CONFLICT (proxy_id, domain) DO UPDATE SET
+                successes = locust_proxy_domain_scores.successes + excluded.successes,
+                failures = locust_proxy_domain_scores.failures + excluded.failures,
+                bans = locust_proxy_domain_scores.bans + excluded.bans,
+                date_modified = now()
+        "#,
+    )
+    .bind(proxy_ids)
+    .bind(domains)
+    .bind(successes)
+    .bind(failures)
+    .bind(bans)
+    .execute(pool)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_proxy_domain_scores(
+    pool: &PgPool,
+    domain: &str,
+) -> Result<Vec<ProxyDomainScore>, Error> {
+    let scores = sqlx::query_as::<_, ProxyDomainScore>(
+        r#"
+            SELECT proxy_id, domain, successes, failures, bans
+            FROM locust_proxy_domain_scores
+            WHERE domain = $1
+            ORDER BY proxy_id
+        "#,
+    )
+    .bind(domain)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(scores)
+}
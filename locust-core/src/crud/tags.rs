@@ -0,0 +1,98 @@
+use sqlx::{postgres::PgPool, Error, Postgres, Row, Transaction};
+
+use crate::models::tags::{Tag, TagPoolSize};
+
+/// Inserts the given tags if they do not exist yet and
+/// returns their ids, in the same order as the input.
+pub(crate) async fn upsert_tags(
+    tx: &mut Transaction<'_, Postgres>,
+    locust_tags: &[&str],
+) -> Result<Vec<i32>, Error> {
+    let mut tag_ids: Vec<i32> = Vec::new();
+    for tag in locust_tags {
+        let id = sqlx::query(
+            r#"
+                INSERT INTO locust_tags (name)
+                values ($1) ON CONFLICT (name) DO UPDATE
+                SET name=EXCLUDED.name
+                RETURNING id
+            "#,
+        )
+        .bind(tag)
+        .fetch_one(&mut **tx)
+        .await?
+        .try_get("id")?;
+        tag_ids.push(id);
+    }
+
+    Ok(tag_ids)
+}
+
+pub async fn get_tags(pool: &PgPool) -> Result<Vec<Tag>, Error> {
+    let tags = sqlx::query_as::<_, Tag>(
+        r#"
+            SELECT id, name FROM locust_tags ORDER BY name
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(tags)
+}
+
+pub async fn create_tag(pool: &PgPool, name: &str) -> Result<Tag, Error> {
+    let mut tx = pool.begin().await?;
+    let id = upsert_tags(&mut tx, &[name]).await?[0];
+    tx.commit().await?;
+
+    Ok(Tag {
+        id,
+        name: name.into(),
+    })
+}
+
+/// Deletes a tag along with every proxy and domain mapping
+/// that references it.
+pub async fn delete_tag(pool: &PgPool, name: &str) -> Result<(), Error> {
+    let mut tx = pool.begin().await?;
+
+    let id: i32 = sqlx::query_scalar("SELECT id FROM locust_tags WHERE name = $1")
+        .bind(name)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    sqlx::query("DELETE FROM locust_proxy_tag_map WHERE tag_id = $1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM locust_domain_tag_map WHERE tag_id = $1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM locust_tags WHERE id = $1")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Counts the live proxies attached to every tag.
+pub async fn get_tag_pool_sizes(pool: &PgPool) -> Result<Vec<TagPoolSize>, Error> {
+    let sizes = sqlx::query_as::<_, TagPoolSize>(
+        r#"
+            SELECT t.name, count(p.id) as proxies
+            FROM locust_tags as t
+            LEFT JOIN locust_proxy_tag_map as ptm ON t.id = ptm.tag_id
+            LEFT JOIN locust_proxies as p
+                ON p.id = ptm.proxy_id AND p.date_deleted IS NULL
+            GROUP BY t.name
+            ORDER BY t.name
+        "#,
+    )
+    .fetch_all(pool)
+    .await?;
+
+    Ok(sizes)
+}
@@ -0,0 +1,300 @@
+//! Keeps the proxies picked for a domain spread over subnets and
+//! autonomous systems, so that a block of a whole subnet or network
+//! does not take out every proxy a domain went through recently.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    env, fmt,
+    net::IpAddr,
+    time::Duration,
+};
+
+use time::OffsetDateTime;
+
+use crate::{domains::normalize_host, models::proxies::Proxy};
+
+/// What picking a proxy for a domain avoids reusing. Nothing is
+/// avoided unless configured.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiversityConfig {
+    /// Avoid the /24 (IPv4) or /48 (IPv6) of a recent proxy.
+    pub subnet: bool,
+    /// Avoid the autonomous system of a recent proxy.
+    pub asn: bool,
+    /// How long a proxy counts as recent for a domain.
+    pub window: Duration,
+}
+
+impl Default for DiversityConfig {
+    fn default() -> Self {
+        Self {
+            subnet: false,
+            asn: false,
+            window: Duration::from_secs(300),
+        }
+    }
+}
+
+impl DiversityConfig {
+    /// Reads `LOCUST_DIVERSITY`, e.g. `subnet,asn`, and
+    /// `LOCUST_DIVERSITY_WINDOW_SECS`.
+    pub fn from_env() -> Self {
+        let mut config = Self::default();
+        if let Ok(var) = env::var("LOCUST_DIVERSITY") {
+            for name in var.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+                match name {
+                    "subnet" => config.subnet = true,
+                    "asn" => config.asn = true,
+                    _ => panic!("Invalid LOCUST_DIVERSITY: unknown {name}"),
+                }
+            }
+        }
+        if let Ok(secs) = env::var("LOCUST_DIVERSITY_WINDOW_SECS") {
+            let secs = secs.parse().expect("Invalid LOCUST_DIVERSITY_WINDOW_SECS");
+            config.window = Duration::from_secs(secs);
+        }
+        config
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.subnet || self.asn
+    }
+}
+
+/// The /24 of an IPv4 address or the /48 of an IPv6 one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Subnet {
+    V4([u8; 3]),
+    V6([u16; 3]),
+}
+
+impl Subnet {
+    pub fn of(ip: IpAddr) -> Self {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => {
+                let [a, b, c, _] = ip.octets();
+                Subnet::V4([a, b, c])
+            }
+            IpAddr::V6(ip) => {
+                let [a, b, c, ..] = ip.segments();
+                Subnet::V6([a, b, c])
+            }
+        }
+    }
+
+    /// The subnet of the address the proxy exits from, see
+    /// [`Proxy::exit_addr`].
+    pub fn of_proxy(proxy: &Proxy) -> Option<Self> {
+        proxy.exit_addr().map(Self::of)
+    }
+}
+
+impl fmt::Display for Subnet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Subnet::V4([a, b, c]) => write!(f, "{a}.{b}.{c}.0/24"),
+            Subnet::V6([a, b, c]) => write!(f, "{a:x}:{b:x}:{c:x}::/48"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RecentUse {
+    date: OffsetDateTime,
+    subnet: Option<Subnet>,
+    asn: Option<i64>,
+}
+
+/// The subnets and autonomous systems of the proxies
+/// picked for each domain within the window.
+#[derive(Debug, Clone, Default)]
+pub struct RecentUses {
+    /// Oldest first, by normalized host.
+    uses: HashMap<String, VecDeque<RecentUse>>,
+}
+
+impl RecentUses {
+    pub fn record(
+        &mut self,
+        config: &DiversityConfig,
+        domain: &str,
+        proxy: &Proxy,
+        now: OffsetDateTime,
+    ) {
+        let uses = self.uses.entry(normalize_host(domain)).or_default();
+        prune(uses, now - config.window);
+        uses.push_back(RecentUse {
+            date: now,
+            subnet: Subnet::of_proxy(proxy),
+            asn: proxy.geo.asn,
+        });
+    }
+
+    /// Whether a proxy in the same subnet or autonomous system, as
+    /// configured, was picked for the domain within the window.
+    pub fn is_recent(
+        &self,
+        config: &DiversityConfig,
+        domain: &str,
+        proxy: &Proxy,
+        now: OffsetDateTime,
+    ) -> bool {
+        let Some(uses) = self.uses.get(&normalize_host(domain)) else {
+            return false;
+        };
+        let subnet = Subnet::of_proxy(proxy);
+        let since = now - config.window;
+        uses.iter().filter(|u| u.date > since).any(|u| {
+            (config.subnet && subnet.is_some() && u.subnet == subnet)
+                || (config.asn && proxy.geo.asn.is_some() && u.asn == proxy.geo.asn)
+        })
+    }
+
+    /// Forgets the uses that fell out of the window.
+    pub fn prune(&mut self, config: &DiversityConfig, now: OffsetDateTime) {
+        self.uses.retain(|_, uses| {
+            prune(uses, now - config.window);
+            !uses.is_empty()
+        });
+    }
+}
+
+fn prune(uses: &mut VecDeque<RecentUse>, since: OffsetDateTime) {
+    while uses.front().is_some_and(|u| u.date <= since) {
+        uses.pop_front();
+    }
+}
+
+/// How many proxies share each subnet and autonomous system.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Composition {
+    pub total: usize,
+    /// Largest first. Proxies without a known address are left out.
+    pub subnets: Vec<(Subnet, usize)>,
+    /// Largest first, with `None` for proxies without a known ASN.
+    pub asns: Vec<(Option<i64>, usize)>,
+}
+
+impl Composition {
+    pub fn of(proxies: &[Proxy]) -> Self {
+        let mut subnets: HashMap<Subnet, usize> = HashMap::new();
+        let mut asns: HashMap<Option<i64>, usize> = HashMap::new();
+        for proxy in proxies {
+            if let Some(subnet) = Subnet::of_proxy(proxy) {
+                *subnets.entry(subnet).or_default() += 1;
+            }
+            *asns.entry(proxy.geo.asn).or_default() += 1;
+        }
+        let mut subnets: Vec<_> = subnets.into_iter().collect();
+        subnets.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        let mut asns: Vec<_> = asns.into_iter().collect();
+        asns.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        Self {
+            total: proxies.len(),
+            subnets,
+            asns,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::proxies::Geo;
+    use time::macros::datetime;
+
+    fn proxy(host: &str, asn: Option<i64>) -> Proxy {
+        Proxy {
+            id: 1,
+            protocol: "http".into(),
+            host: host.into(),
+            port: 8080,
+            username: None,
+            password: None,
+            provider: "local".into(),
+            proxy_type: None,
+            weight: 1,
+            priority: 0,
+            exit_ip: None,
+            geo: Geo {
+                asn,
+                ..Geo::default()
+            },
+        }
+    }
+
+    #[test]
+    fn test_subnet() {
+        let subnet = |host| Subnet::of_proxy(&proxy(host, None)).map(|s| s.to_string());
+        assert_eq!(subnet("10.1.2.3").as_deref(), Some("10.1.2.0/24"));
+        assert_eq!(subnet("::ffff:10.1.2.3").as_deref(), Some("10.1.2.0/24"));
+        assert_eq!(
+            subnet("[2001:db8:1:2::1]").as_deref(),
+            Some("2001:db8:1::/48")
+        );
+        assert_eq!(subnet("proxy.example.com"), None);
+
+        // The exit IP wins over the host once it is known.
+        let mut rotating = proxy("proxy.example.com", None);
+        rotating.exit_ip = Some("192.0.2.7".into());
+        assert_eq!(Subnet::of_proxy(&rotating), Some(Subnet::V4([192, 0, 2])));
+    }
+
+    #[test]
+    fn test_recent_uses() {
+        let config = DiversityConfig {
+            subnet: true,
+            asn: false,
+            window: Duration::from_secs(60),
+        };
+        let now = datetime!(2024-01-01 00:00 UTC);
+        let mut recent = RecentUses::default();
+        recent.record(
+            &config,
+            "Example.com:443",
+            &proxy("10.0.0.1", Some(7922)),
+            now,
+        );
+
+        let later = now + Duration::from_secs(30);
+        assert!(recent.is_recent(&config, "example.com", &proxy("10.0.0.2", None), later));
+        assert!(!recent.is_recent(
+            &config,
+            "example.com",
+            &proxy("10.0.1.1", Some(7922)),
+            later
+        ));
+        assert!(!recent.is_recent(&config, "other.com", &proxy("10.0.0.2", None), later));
+        let asn = DiversityConfig {
+            asn: true,
+            ..config.clone()
+        };
+        assert!(recent.is_recent(&asn, "example.com", &proxy("10.0.1.1", Some(7922)), later));
+        assert!(!recent.is_recent(&asn, "example.com", &proxy("10.0.1.1", None), later));
+
+        // Uses are forgotten once the window is over.
+        let much_later = now + Duration::from_secs(60);
+        assert!(!recent.is_recent(&config, "example.com", &proxy("10.0.0.2", None), much_later));
+        recent.prune(&config, much_later);
+        assert!(recent.uses.is_empty());
+    }
+
+    #[test]
+    fn test_composition() {
+        let composition = Composition::of(&[
+            proxy("10.0.0.1", Some(7922)),
+            proxy("10.0.0.2", Some(7922)),
+            proxy("10.0.1.1", None),
+            proxy("proxy.example.com", Some(3320)),
+        ]);
+        assert_eq!(composition.total, 4);
+        assert_eq!(
+            composition.subnets,
+            [(Subnet::V4([10, 0, 0]), 2), (Subnet::V4([10, 0, 1]), 1)]
+        );
+        assert_eq!(
+            composition.asns,
+            [(Some(7922), 2), (None, 1), (Some(3320), 1)]
+        );
+    }
+}
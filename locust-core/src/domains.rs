@@ -0,0 +1,286 @@
+//! Matching of request hosts against the domains that proxy tags are
+//! configured for. A domain is one of:
+//!
+//! - `example.com`, the host itself;
+//! - `*.example.com`, any subdomain of `example.com`, at any depth,
+//!   but not `example.com` itself;
+//! - `site:example.com`, any host whose registrable domain (by the
+//!   public suffix list) is `example.com`, the domain itself included;
+//! - `re:^api[0-9]+\.example\.com$`, a regex matched against the host.
+//!
+//! When several domains match a host, the most specific one wins:
+//! the host itself, then wildcards and sites by the number of labels
+//! in their domain, with wildcards first on a tie, and regexes last,
+//! the longest first. Ports are ignored on both sides.
+
+use std::{collections::HashMap, net::IpAddr, sync::OnceLock};
+
+use publicsuffix::{List, Psl};
+use regex::Regex;
+
+const WILDCARD_PREFIX: &str = "*.";
+const SITE_PREFIX: &str = "site:";
+const REGEX_PREFIX: &str = "re:";
+
+fn suffix_list() -> &'static List {
+    static LIST: OnceLock<List> = OnceLock::new();
+    LIST.get_or_init(|| {
+        include_str!("../data/public_suffix_list.dat")
+            .parse()
+            .expect("Invalid public suffix list")
+    })
+}
+
+/// Lowercases a host and strips its port and trailing dot,
+/// e.g. `WWW.Example.com.:8443` becomes `www.example.com`.
+pub fn normalize_host(host: &str) -> String {
+    let host = host.trim();
+    let host = match host.strip_prefix('[') {
+        // An IPv6 address, which keeps its brackets.
+        Some(rest) => match rest.find(']') {
+            Some(end) => &host[..end + 2],
+            None => host,
+        },
+        None => match host.split_once(':') {
+            Some((name, port)) if !port.contains(':') => name,
+            _ => host,
+        },
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// The registrable domain of a host, e.g. `example.co.uk`
+/// for `www.example.co.uk`. IP addresses have none.
+pub fn registrable_domain(host: &str) -> Option<String> {
+    let host = normalize_host(host);
+    if host.starts_with('[') || host.parse::<IpAddr>().is_ok() {
+        return None;
+    }
+    let domain = suffix_list().domain(host.as_bytes())?;
+    std::str::from_utf8(domain.as_bytes())
+        .ok()
+        .map(String::from)
+}
+
+#[derive(Debug, Clone)]
+pub enum DomainPattern {
+    Exact(String),
+    /// The domain the subdomains belong to.
+    Wildcard(String),
+    /// A registrable domain.
+    Site(String),
+    Regex(Regex),
+}
+
+impl DomainPattern {
+    pub fn parse(pattern: &str) -> Result<Self, String> {
+        let pattern = pattern.trim();
+        if let Some(regex) = pattern.strip_prefix(REGEX_PREFIX) {
+            return Regex::new(regex)
+                .map(DomainPattern::Regex)
+                .map_err(|e| e.to_string());
+        }
+
+        let (make, domain): (fn(String) -> Self, _) =
+            if let Some(domain) = pattern.strip_prefix(WILDCARD_PREFIX) {
+                (DomainPattern::Wildcard, domain)
+            } else if let Some(domain) = pattern.strip_prefix(SITE_PREFIX) {
+                (DomainPattern::Site, domain)
+            } else {
+                (DomainPattern::Exact, pattern)
+            };
+        let domain = normalize_host(domain);
+        if domain.is_empty() || domain.contains(['*', '/', ' ']) {
+            return Err(format!("invalid domain: {pattern}"));
+        }
+        match make(domain) {
+            DomainPattern::Site(domain) => registrable_domain(&domain)
+                .map(DomainPattern::Site)
+                .ok_or_else(|| format!("{domain} has no registrable domain")),
+            pattern => Ok(pattern),
+        }
+    }
+
+    /// Whether the pattern matches a normalized host.
+    pub fn matches(&self, host: &str) -> bool {
+        match self {
+            DomainPattern::Exact(domain) => host == domain,
+            DomainPattern::Wildcard(domain) => host
+                .strip_suffix(domain.as_str())
+                .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+            DomainPattern::Site(domain) => registrable_domain(host).as_ref() == Some(domain),
+            DomainPattern::Regex(regex) => regex.is_match(host),
+        }
+    }
+
+    /// Sorts the most specific patterns first.
+    fn specificity(&self) -> (u8, usize, u8) {
+        let labels = |domain: &str| domain.split('.').count();
+        match self {
+            DomainPattern::Exact(domain) => (2, labels(domain), 0),
+            DomainPattern::Wildcard(domain) => (1, labels(domain), 1),
+            DomainPattern::Site(domain) => (1, labels(domain), 0),
+            DomainPattern::Regex(regex) => (0, regex.as_str().len(), 0),
+        }
+    }
+}
+
+/// Domain patterns along with the values configured for them,
+/// e.g. the proxies tagged for each domain.
+#[derive(Debug, Clone)]
+pub struct DomainRules<V> {
+    exact: HashMap<String, Vec<V>>,
+    /// The other patterns, the most specific first.
+    patterns: Vec<(DomainPattern, Vec<V>)>,
+}
+
+impl<V> Default for DomainRules<V> {
+    fn default() -> Self {
+        Self {
+            exact: HashMap::new(),
+            patterns: Vec::new(),
+        }
+    }
+}
+
+impl<V> DomainRules<V> {
+    /// Compiles the patterns, merging the values of the ones that are
+    /// the same once normalized. Returns the invalid patterns, which
+    /// are left out, along with why.
+    pub fn compile(rules: impl IntoIterator<Item = (String, V)>) -> (Self, Vec<(String, String)>) {
+        let mut compiled = Self::default();
+        let mut patterns: HashMap<String, (DomainPattern, Vec<V>)> = HashMap::new();
+        let mut invalid = Vec::new();
+        for (raw, value) in rules {
+            match DomainPattern::parse(&raw) {
+                Ok(DomainPattern::Exact(host)) => {
+                    compiled.exact.entry(host).or_default().push(value);
+                }
+                Ok(pattern) => {
+                    let key = match &pattern {
+                        DomainPattern::Wildcard(d) => format!("{WILDCARD_PREFIX}{d}"),
+                        DomainPattern::Site(d) => format!("{SITE_PREFIX}{d}"),
+                        _ => raw.trim().to_string(),
+                    };
+                    patterns
+                        .entry(key)
+                        .or_insert_with(|| (pattern, Vec::new()))
+                        .1
+                        .push(value);
+                }
+                Err(e) => invalid.push((raw, e)),
+            }
+        }
+
+        let mut patterns: Vec<_> = patterns.into_iter().collect();
+        patterns.sort_by(|(a_key, (a, _)), (b_key, (b, _))| {
+            b.specificity()
+                .cmp(&a.specificity())
+                .then_with(|| a_key.cmp(b_key))
+        });
+        compiled.patterns = patterns.into_iter().map(|(_, rule)| rule).collect();
+        (compiled, invalid)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.exact.is_empty() && self.patterns.is_empty()
+    }
+
+    /// The values of the most specific pattern matching the host.
+    pub fn find(&self, host: &str) -> Option<&[V]> {
+        let host = normalize_host(host);
+        if let Some(values) = self.exact.get(&host) {
+            return Some(values);
+        }
+        self.patterns
+            .iter()
+            .find(|(pattern, _)| pattern.matches(&host))
+            .map(|(_, values)| values.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_host() {
+        assert_eq!(normalize_host("WWW.Example.com.:8443"), "www.example.com");
+        assert_eq!(normalize_host("example.com"), "example.com");
+        assert_eq!(normalize_host("[::1]:8080"), "[::1]");
+        assert_eq!(normalize_host("::1"), "::1");
+        assert_eq!(normalize_host("10.0.0.1:80"), "10.0.0.1");
+    }
+
+    #[test]
+    fn test_registrable_domain() {
+        let domain = |host| registrable_domain(host);
+        assert_eq!(
+            domain("www.example.co.uk").as_deref(),
+            Some("example.co.uk")
+        );
+        assert_eq!(
+            domain("a.b.example.com:443").as_deref(),
+            Some("example.com")
+        );
+        assert_eq!(domain("user.github.io").as_deref(), Some("user.github.io"));
+        assert_eq!(domain("co.uk"), None);
+        assert_eq!(domain("10.0.0.1"), None);
+    }
+
+    #[test]
+    fn test_patterns() {
+        let matches = |pattern, host| DomainPattern::parse(pattern).unwrap().matches(host);
+        assert!(matches("Example.com:8443", "example.com"));
+        assert!(!matches("example.com", "www.example.com"));
+        assert!(matches("*.example.com", "a.b.example.com"));
+        assert!(!matches("*.example.com", "example.com"));
+        assert!(!matches("*.example.com", "badexample.com"));
+        assert!(matches("site:www.example.co.uk", "example.co.uk"));
+        assert!(matches("site:example.co.uk", "api.example.co.uk"));
+        assert!(!matches("site:example.co.uk", "other.co.uk"));
+        assert!(matches(r"re:^api\d+\.", "api12.example.com"));
+
+        assert!(DomainPattern::parse("re:(").is_err());
+        assert!(DomainPattern::parse("site:co.uk").is_err());
+        assert!(DomainPattern::parse("*.").is_err());
+        assert!(DomainPattern::parse("a.*.com").is_err());
+    }
+
+    #[test]
+    fn test_most_specific_rule_wins() {
+        let (rules, invalid) = DomainRules::compile([
+            (r"re:example\.com$".to_string(), "regex"),
+            (r"re:^api\.example\.com$".to_string(), "long regex"),
+            ("site:example.com".to_string(), "site"),
+            ("*.example.com".to_string(), "wildcard"),
+            ("*.api.example.com".to_string(), "api wildcard"),
+            ("api.example.com".to_string(), "exact"),
+            ("API.example.com:443".to_string(), "exact port"),
+            ("*.co.uk".to_string(), "uk wildcard"),
+            ("site:example.co.uk".to_string(), "uk site"),
+            ("re:(".to_string(), "invalid"),
+        ]);
+        assert_eq!(invalid.len(), 1);
+
+        let find = |host| rules.find(host).map(<[_]>::to_vec);
+        assert_eq!(
+            find("api.example.com:8443"),
+            Some(vec!["exact", "exact port"])
+        );
+        assert_eq!(find("v1.api.example.com"), Some(vec!["api wildcard"]));
+        assert_eq!(find("www.example.com"), Some(vec!["wildcard"]));
+        assert_eq!(find("example.com"), Some(vec!["site"]));
+        assert_eq!(find("notexample.com"), Some(vec!["regex"]));
+        // The site has more labels than the wildcard.
+        assert_eq!(find("www.example.co.uk"), Some(vec!["uk site"]));
+        assert_eq!(find("www.other.co.uk"), Some(vec!["uk wildcard"]));
+        assert_eq!(find("other.org"), None);
+
+        let (rules, _) = DomainRules::compile([
+            (r"re:^api\.example\.com$".to_string(), "long regex"),
+            (r"re:example\.com$".to_string(), "regex"),
+        ]);
+        assert_eq!(rules.find("api.example.com"), Some(&["long regex"][..]));
+    }
+}
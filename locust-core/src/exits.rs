@@ -0,0 +1,224 @@
+//! Finds the address each proxy exits from, which for rotating and
+//! backconnect providers says more than the host it is reached at,
+//! by asking an echo endpoint where requests through it come from.
+
+use std::{net::IpAddr, time::Duration};
+
+use futures::{stream, StreamExt};
+use hyper::{body::HttpBody, header::ACCEPT, Body, Request};
+use sqlx::PgPool;
+use tokio::time::timeout;
+
+use crate::{
+    client::{authorize, build_proxy_client, error_chain, tunnel_status},
+    crud::{exits::set_proxy_exit, proxies::get_all_proxies},
+    health::{is_checkable, CheckUrl},
+    models::proxies::Proxy,
+};
+
+/// Echo responses are small, anything past this is not read.
+const MAX_RESPONSE_BYTES: u64 = 64 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct ExitConfig {
+    /// An endpoint answering with the address the request came
+    /// from, as plain text or JSON, e.g. `http://api.ipify.org`.
+    pub url: CheckUrl,
+    /// How long a single check may take.
+    pub timeout: Duration,
+    /// How many proxies are checked at the same time.
+    pub concurrency: usize,
+}
+
+/// A proxy along with the address it was seen exiting from.
+#[derive(Debug, Clone)]
+pub struct ExitResult {
+    pub proxy: Proxy,
+    pub exit: Result<IpAddr, String>,
+}
+
+/// Asks the echo endpoint where a request through the proxy comes
+/// from, with the client traffic goes through proxies with. Only
+/// proxies that can be probed are, see [`is_checkable`].
+pub async fn discover_exit(
+    proxy: &Proxy,
+    url: &CheckUrl,
+    limit: Duration,
+) -> Result<IpAddr, String> {
+    if !is_checkable(proxy) {
+        return Err(format!("unsupported protocol {}", proxy.protocol));
+    }
+    let body = match timeout(limit, fetch(proxy, url)).await {
+        Ok(Ok(body)) => body,
+        Ok(Err(e)) => return Err(e),
+        Err(_) => return Err("timed out".into()),
+    };
+    parse_exit_ip(&body).ok_or_else(|| format!("no address in response: {:?}", body.trim()))
+}
+
+/// Requests the URL through the proxy and returns
+/// the body of a successful response.
+async fn fetch(proxy: &Proxy, url: &CheckUrl) -> Result<String, String> {
+    let client = build_proxy_client(proxy).map_err(|e| e.to_string())?;
+    let mut req = Request::get(url.to_string())
+        .header(ACCEPT, "text/plain, application/json")
+        .body(Body::empty())
+        .map_err(|e| e.to_string())?;
+    authorize(&mut req, proxy);
+    let mut res = match client.request(req).await {
+        Ok(res) => res,
+        Err(e) => match tunnel_status(&e) {
+            Some(status) => return Err(format!("proxy refused the tunnel with {status}")),
+            None => return Err(error_chain(&e)),
+        },
+    };
+    let status = res.status();
+    if !status.is_success() {
+        return Err(format!("echo endpoint answered {}", status.as_u16()));
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = res.data().await {
+        body.extend_from_slice(&chunk.map_err(|e| e.to_string())?);
+        if body.len() as u64 >= MAX_RESPONSE_BYTES {
+            body.truncate(MAX_RESPONSE_BYTES as usize);
+            break;
+        }
+    }
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+/// Finds the first address in an echo response, be it plain text
+/// like `203.0.113.7` or JSON like `{"ip": "203.0.113.7"}`.
+pub fn parse_exit_ip(body: &str) -> Option<IpAddr> {
+    body.split(|c: char| !(c.is_ascii_hexdigit() || c == '.' || c == ':'))
+        .filter_map(|token| token.parse::<IpAddr>().ok())
+        .find(|ip| !ip.is_unspecified())
+        .map(|ip| ip.to_canonical())
+}
+
+/// Discovers the exit address of every proxy in the pool that can be
+/// probed and records the ones found. Proxies that fail keep the
+/// address last seen.
+pub async fn discover_exits(
+    pool: &PgPool,
+    config: &ExitConfig,
+) -> Result<Vec<ExitResult>, sqlx::Error> {
+    let proxies = get_all_proxies(pool).await?;
+    let mut results: Vec<ExitResult> = stream::iter(proxies.into_iter().filter(is_checkable))
+        .map(|proxy| async move {
+            let exit = discover_exit(&proxy, &config.url, config.timeout).await;
+            ExitResult { proxy, exit }
+        })
+        .buffer_unordered(config.concurrency.max(1))
+        .collect()
+        .await;
+
+    for result in &results {
+        if let Ok(ip) = result.exit {
+            set_proxy_exit(pool, result.proxy.id, &ip.to_string()).await?;
+        }
+    }
+
+    results.sort_by_key(|r| r.proxy.id);
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    /// A stand-in proxy that answers every request with `status`
+    /// and `body`, and hands back the request it received.
+    async fn stand_in_proxy(
+        status: u16,
+        body: &'static str,
+    ) -> (u16, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 1024];
+            let n = socket.read(&mut buf).await.unwrap();
+            let response = format!("HTTP/1.0 {status} Whatever\r\n\r\n{body}");
+            socket.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&buf[..n]).to_string()
+        });
+        (port, handle)
+    }
+
+    fn proxy(port: u16) -> Proxy {
+        Proxy {
+            id: 1,
+            protocol: "http".into(),
+            host: "127.0.0.1".into(),
+            port: port.into(),
+            username: Some("user".into()),
+            password: Some("pass".into()),
+            provider: "local".into(),
+            proxy_type: None,
+            weight: 1,
+            priority: 0,
+            exit_ip: None,
+            geo: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_parse_exit_ip() {
+        let ip = |body| parse_exit_ip(body).map(|ip| ip.to_string());
+        assert_eq!(ip("203.0.113.7\n").as_deref(), Some("203.0.113.7"));
+        assert_eq!(
+            ip(r#"{"ip":"203.0.113.7"}"#).as_deref(),
+            Some("203.0.113.7")
+        );
+        assert_eq!(
+            ip(r#"{"origin": "203.0.113.7, 198.51.100.1"}"#).as_deref(),
+            Some("203.0.113.7")
+        );
+        assert_eq!(ip("2001:db8::7").as_deref(), Some("2001:db8::7"));
+        assert_eq!(ip("::ffff:203.0.113.7").as_deref(), Some("203.0.113.7"));
+        assert_eq!(ip("0.0.0.0 203.0.113.7").as_deref(), Some("203.0.113.7"));
+        assert_eq!(ip("<html>blocked</html>"), None);
+        assert_eq!(ip(""), None);
+    }
+
+    #[tokio::test]
+    async fn test_discover_exit() {
+        let (port, request) = stand_in_proxy(200, r#"{"ip": "198.51.100.23"}"#).await;
+        let url = "http://echo.local/ip".parse().unwrap();
+        let exit = discover_exit(&proxy(port), &url, Duration::from_secs(2)).await;
+
+        assert_eq!(exit, Ok("198.51.100.23".parse().unwrap()));
+        let request = request.await.unwrap();
+        assert!(request.starts_with("GET http://echo.local:80/ip HTTP/1.1\r\n"));
+        assert!(request.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_discover_exit_failures() {
+        let url: CheckUrl = "http://echo.local/ip".parse().unwrap();
+        let limit = Duration::from_secs(2);
+
+        let (port, _) = stand_in_proxy(407, "198.51.100.23").await;
+        let exit = discover_exit(&proxy(port), &url, limit).await;
+        assert_eq!(exit, Err("echo endpoint answered 407".into()));
+
+        let (port, _) = stand_in_proxy(200, "no address here").await;
+        assert!(discover_exit(&proxy(port), &url, limit).await.is_err());
+
+        // HTTPS endpoints are reached through a tunnel.
+        let (port, request) = stand_in_proxy(407, "").await;
+        let https = "https://echo.local/ip".parse().unwrap();
+        let exit = discover_exit(&proxy(port), &https, limit).await;
+        assert_eq!(exit, Err("proxy refused the tunnel with 407".into()));
+        assert!(request
+            .await
+            .unwrap()
+            .starts_with("CONNECT echo.local:443 HTTP/1.1\r\n"));
+    }
+}
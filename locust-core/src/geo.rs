@@ -0,0 +1,307 @@
+//! Where proxies are located, from the targeting providers encode in
+//! proxy usernames, or from a local MaxMind (`.mmdb`) or IP2Location
+//! (`.csv`) database.
+
+use std::{
+    collections::BTreeMap,
+    fmt, fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use maxminddb::{MaxMindDBError, Reader};
+use serde::Deserialize;
+
+use crate::models::proxies::Geo;
+
+/// Reads the location providers encode in usernames, as `-`
+/// separated keys and values, e.g. `user-country-us-city-london`,
+/// `customer-zone-res-cc-de` or `user-region-california-asn-7922`.
+pub fn geo_from_username(username: &str) -> Geo {
+    let parts: Vec<&str> = username.split('-').collect();
+    let mut geo = Geo::default();
+    for pair in parts.windows(2) {
+        let value = pair[1];
+        match pair[0].to_ascii_lowercase().as_str() {
+            "country" | "cc"
+                if value.len() == 2 && value.chars().all(|c| c.is_ascii_alphabetic()) =>
+            {
+                geo.country.get_or_insert(value.to_ascii_uppercase());
+            }
+            "region" | "state" if !value.is_empty() => {
+                geo.region.get_or_insert(value.replace('_', " "));
+            }
+            "city" if !value.is_empty() => {
+                geo.city.get_or_insert(value.replace('_', " "));
+            }
+            "asn" => {
+                if let Ok(asn) = value.trim_start_matches("AS").parse() {
+                    geo.asn.get_or_insert(asn);
+                }
+            }
+            _ => {}
+        }
+    }
+    geo
+}
+
+#[derive(Debug)]
+pub enum GeoDbError {
+    Io(PathBuf, io::Error),
+    MaxMind(PathBuf, MaxMindDBError),
+    Parse(PathBuf, usize, String),
+    UnknownFormat(PathBuf),
+}
+
+impl fmt::Display for GeoDbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GeoDbError::Io(path, e) => write!(f, "error reading {}: {e}", path.display()),
+            GeoDbError::MaxMind(path, e) => write!(f, "error reading {}: {e}", path.display()),
+            GeoDbError::Parse(path, line, e) => {
+                write!(f, "error parsing {} at line {line}: {e}", path.display())
+            }
+            GeoDbError::UnknownFormat(path) => write!(
+                f,
+                "unknown geo database format for {}, expected .mmdb or .csv",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for GeoDbError {}
+
+/// A local database of IP locations.
+pub enum GeoDb {
+    /// A MaxMind City, Country or ASN database.
+    MaxMind(Reader<Vec<u8>>),
+    /// An IP2Location location (DB1 and up) or ASN CSV
+    /// database, as ranges sorted by their start.
+    Ip2Location(Vec<IpRange>),
+}
+
+/// A range of IP2Location addresses, as the numbers of the first
+/// and last ones. IPv4 addresses of IPv6 databases are mapped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IpRange {
+    from: u128,
+    to: u128,
+    geo: Geo,
+}
+
+/// The parts of MaxMind records that make up a [`Geo`].
+#[derive(Deserialize)]
+struct MaxMindRecord {
+    country: Option<MaxMindCountry>,
+    subdivisions: Option<Vec<MaxMindPlace>>,
+    city: Option<MaxMindPlace>,
+    autonomous_system_number: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct MaxMindCountry {
+    iso_code: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct MaxMindPlace {
+    names: Option<BTreeMap<String, String>>,
+}
+
+impl MaxMindPlace {
+    fn name(self) -> Option<String> {
+        self.names?.remove("en")
+    }
+}
+
+impl GeoDb {
+    /// Opens a database by the extension of its file.
+    pub fn open(path: &Path) -> Result<Self, GeoDbError> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("mmdb") => Reader::open_readfile(path)
+                .map(GeoDb::MaxMind)
+                .map_err(|e| GeoDbError::MaxMind(path.into(), e)),
+            Some("csv" | "CSV") => {
+                let content =
+                    fs::read_to_string(path).map_err(|e| GeoDbError::Io(path.into(), e))?;
+                parse_ip2location(&content)
+                    .map(GeoDb::Ip2Location)
+                    .map_err(|(line, e)| GeoDbError::Parse(path.into(), line, e))
+            }
+            _ => Err(GeoDbError::UnknownFormat(path.into())),
+        }
+    }
+
+    /// The location of the address, empty when it is not found.
+    pub fn lookup(&self, ip: IpAddr) -> Geo {
+        match self {
+            GeoDb::MaxMind(reader) => match reader.lookup::<MaxMindRecord>(ip) {
+                Ok(record) => Geo {
+                    country: record
+                        .country
+                        .and_then(|country| country.iso_code)
+                        .map(|code| code.to_ascii_uppercase()),
+                    region: record
+                        .subdivisions
+                        .and_then(|subdivisions| subdivisions.into_iter().next())
+                        .and_then(MaxMindPlace::name),
+                    city: record.city.and_then(MaxMindPlace::name),
+                    asn: record.autonomous_system_number.map(i64::from),
+                },
+                Err(_) => Geo::default(),
+            },
+            GeoDb::Ip2Location(ranges) => {
+                let numbers = match ip {
+                    IpAddr::V4(ip) => {
+                        let n = u128::from(u32::from(ip));
+                        vec![n, 0xffff_0000_0000 + n]
+                    }
+                    IpAddr::V6(ip) => vec![u128::from(ip)],
+                };
+                numbers
+                    .into_iter()
+                    .find_map(|n| {
+                        let i = ranges.partition_point(|range| range.from <= n);
+                        let range = ranges.get(i.checked_sub(1)?)?;
+                        (n <= range.to).then(|| range.geo.clone())
+                    })
+                    .unwrap_or_default()
+            }
+        }
+    }
+
+    /// The location of the host, if it is an IP address.
+    pub fn lookup_host(&self, host: &str) -> Geo {
+        host.parse().map(|ip| self.lookup(ip)).unwrap_or_default()
+    }
+}
+
+/// Parses the rows of an IP2Location CSV database. Location databases
+/// start with `ip_from, ip_to, country_code, country_name`, followed
+/// by `region, city` from DB3 on, and ASN databases are
+/// `ip_from, ip_to, cidr, asn, as`. Errors come with their line.
+fn parse_ip2location(content: &str) -> Result<Vec<IpRange>, (usize, String)> {
+    let known = |value: &str| (!value.is_empty() && value != "-").then(|| value.to_string());
+    let mut ranges = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let fields = split_csv(line);
+        let number = |n: usize| -> Result<u128, (usize, String)> {
+            let field = fields.get(n).ok_or((i + 1, "missing column".into()))?;
+            field
+                .parse()
+                .map_err(|_| (i + 1, format!("invalid address number {field}")))
+        };
+        let (from, to) = (number(0)?, number(1)?);
+        let field = |n: usize| fields.get(n).and_then(|field| known(field));
+        let geo = match fields.get(2) {
+            Some(cidr) if cidr.contains('/') => Geo {
+                asn: field(3).and_then(|asn| asn.parse().ok()),
+                ..Geo::default()
+            },
+            Some(_) => Geo {
+                country: field(2).map(|code| code.to_ascii_uppercase()),
+                region: field(4),
+                city: field(5),
+                asn: None,
+            },
+            None => return Err((i + 1, "missing column".into())),
+        };
+        ranges.push(IpRange { from, to, geo });
+    }
+    ranges.sort_by_key(|range| range.from);
+    Ok(ranges)
+}
+
+/// Splits a line of comma separated, optionally quoted, fields.
+fn split_csv(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_geo_from_username() {
+        assert_eq!(
+            geo_from_username("user-country-us-region-new_york-city-brooklyn-asn-7922"),
+            Geo {
+                country: Some("US".into()),
+                region: Some("new york".into()),
+                city: Some("brooklyn".into()),
+                asn: Some(7922),
+            }
+        );
+        assert_eq!(
+            geo_from_username("brd-customer-hl_1-zone-res-cc-de").country,
+            Some("DE".into())
+        );
+        assert_eq!(geo_from_username("user"), Geo::default());
+        assert_eq!(geo_from_username("user-country-usa"), Geo::default());
+    }
+
+    #[test]
+    fn test_ip2location() {
+        let db = GeoDb::Ip2Location(
+            parse_ip2location(
+                r#"
+"16777216","16777471","US","United States of America","California","Los Angeles"
+"16777472","16778239","CN","China","Fujian","Fuzhou"
+"16778240","16779263","-","-","-","-"
+"281470698586112","281470698586367","AU","Australia","Queensland","Brisbane, City"
+"#,
+            )
+            .unwrap(),
+        );
+        let lookup = |ip: &str| db.lookup(ip.parse().unwrap());
+        assert_eq!(
+            lookup("1.0.0.1"),
+            Geo {
+                country: Some("US".into()),
+                region: Some("California".into()),
+                city: Some("Los Angeles".into()),
+                asn: None,
+            }
+        );
+        assert_eq!(lookup("1.0.1.0").country.as_deref(), Some("CN"));
+        assert_eq!(lookup("1.0.4.0"), Geo::default());
+        assert_eq!(lookup("0.255.255.255"), Geo::default());
+        // IPv4 addresses are looked up mapped in IPv6 databases.
+        assert_eq!(lookup("1.1.0.1").city.as_deref(), Some("Brisbane, City"));
+        assert_eq!(db.lookup_host("example.com"), Geo::default());
+
+        let asn = GeoDb::Ip2Location(
+            parse_ip2location(r#""16777216","16777471","1.0.0.0/24","13335","CloudFlare Inc""#)
+                .unwrap(),
+        );
+        assert_eq!(asn.lookup_host("1.0.0.200").asn, Some(13335));
+
+        assert_eq!(
+            parse_ip2location("\"1\",\"2\",\"US\"\nfirst,last,US")
+                .unwrap_err()
+                .0,
+            2
+        );
+    }
+}
@@ -0,0 +1,325 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use futures::{stream, StreamExt};
+use http::Uri;
+use hyper::{Body, Request};
+use sqlx::PgPool;
+use tokio::time::timeout;
+
+use crate::{
+    client::{self, authorize, build_proxy_client, error_chain, tunnel_status},
+    crud::{
+        health::{get_proxies_health, record_proxy_check},
+        proxies::get_all_proxies,
+    },
+    models::{health::ProxyHealth, proxies::Proxy},
+};
+
+/// The URL proxies are probed against.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CheckUrl {
+    pub(crate) https: bool,
+    pub(crate) host: String,
+    pub(crate) port: u16,
+    pub(crate) path: String,
+}
+
+impl FromStr for CheckUrl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let uri: Uri = s.parse().map_err(|e| format!("invalid check url: {e}"))?;
+        let https = match uri.scheme_str() {
+            Some("http") => false,
+            Some("https") => true,
+            _ => return Err("check url must be http or https".into()),
+        };
+        let host = uri.host().ok_or("check url is missing a host")?.to_string();
+        let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+        let path = uri
+            .path_and_query()
+            .map(|p| p.to_string())
+            .unwrap_or("/".into());
+
+        Ok(Self {
+            https,
+            host,
+            port,
+            path,
+        })
+    }
+}
+
+impl fmt::Display for CheckUrl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = if self.https { "https" } else { "http" };
+        write!(f, "{scheme}://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CheckConfig {
+    pub url: CheckUrl,
+    /// How long a single check may take.
+    pub timeout: Duration,
+    /// Failed checks in a row before a proxy is quarantined.
+    pub quarantine_after: i32,
+    /// How many proxies are checked at the same time.
+    pub concurrency: usize,
+}
+
+/// The result of probing a single proxy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProxyCheck {
+    /// Missing when no response came back.
+    pub status: Option<u16>,
+    pub latency: Option<Duration>,
+    pub error: Option<String>,
+}
+
+impl ProxyCheck {
+    fn failed(error: impl ToString) -> Self {
+        Self {
+            status: None,
+            latency: None,
+            error: Some(error.to_string()),
+        }
+    }
+
+    /// Any response below 400 counts as healthy.
+    pub fn is_healthy(&self) -> bool {
+        self.status.is_some_and(|s| s < 400)
+    }
+}
+
+/// A proxy along with its health before and after a check.
+#[derive(Debug, Clone)]
+pub struct CheckResult {
+    pub proxy: Proxy,
+    pub check: ProxyCheck,
+    pub health: ProxyHealth,
+    pub was_quarantined: bool,
+}
+
+impl CheckResult {
+    /// Whether this check put the proxy in quarantine.
+    pub fn quarantined(&self) -> bool {
+        !self.was_quarantined && self.health.is_quarantined()
+    }
+
+    /// Whether this check took the proxy out of quarantine.
+    pub fn reinstated(&self) -> bool {
+        self.was_quarantined && !self.health.is_quarantined()
+    }
+}
+
+/// Whether proxies of this protocol can be probed, the ones traffic
+/// can go through. Others are left out of health checks.
+pub fn is_checkable(proxy: &Proxy) -> bool {
+    client::is_supported(proxy)
+}
+
+/// Probes a proxy by requesting the check URL through it, with the
+/// client traffic goes through proxies with.
+///
+/// For HTTPS check URLs the proxy is asked to open a tunnel to the
+/// origin, which is then requested over TLS through it. A tunnel
+/// the proxy turns down is reported with the status of the CONNECT.
+pub async fn check_proxy(proxy: &Proxy, url: &CheckUrl, limit: Duration) -> ProxyCheck {
+    let start = Instant::now();
+    match timeout(limit, probe(proxy, url)).await {
+        Ok(Ok(status)) => ProxyCheck {
+            status: Some(status),
+            latency: Some(start.elapsed()),
+            error: None,
+        },
+        Ok(Err(e)) => ProxyCheck::failed(e),
+        Err(_) => ProxyCheck::failed("timed out"),
+    }
+}
+
+async fn probe(proxy: &Proxy, url: &CheckUrl) -> Result<u16, String> {
+    if !is_checkable(proxy) {
+        return Err(format!("unsupported protocol {}", proxy.protocol));
+    }
+    let client = build_proxy_client(proxy).map_err(|e| e.to_string())?;
+    let mut req = Request::get(url.to_string())
+        .body(Body::empty())
+        .map_err(|e| e.to_string())?;
+    authorize(&mut req, proxy);
+    match client.request(req).await {
+        Ok(res) => Ok(res.status().as_u16()),
+        Err(e) => tunnel_status(&e).ok_or_else(|| error_chain(&e)),
+    }
+}
+
+/// Checks every proxy in the pool, quarantined ones included,
+/// and records the results. Proxies that can't be probed, see
+/// [`is_checkable`], are left alone.
+pub async fn check_proxies(
+    pool: &PgPool,
+    config: &CheckConfig,
+) -> Result<Vec<CheckResult>, sqlx::Error> {
+    let quarantined: HashSet<i32> = get_proxies_health(pool)
+        .await?
+        .into_iter()
+        .filter(ProxyHealth::is_quarantined)
+        .map(|h| h.proxy_id)
+        .collect();
+    let proxies = get_all_proxies(pool).await?;
+    let checks: Vec<(Proxy, ProxyCheck)> = stream::iter(proxies.into_iter().filter(is_checkable))
+        .map(|proxy| async move {
+            let check = check_proxy(&proxy, &config.url, config.timeout).await;
+            (proxy, check)
+        })
+        .buffer_unordered(config.concurrency.max(1))
+        .collect()
+        .await;
+
+    let mut results = Vec::with_capacity(checks.len());
+    for (proxy, check) in checks {
+        let health = record_proxy_check(
+            pool,
+            proxy.id,
+            check.status.map(|s| s as i16),
+            check.latency.map(|l| l.as_millis() as i32),
+            check.is_healthy(),
+            config.quarantine_after,
+        )
+        .await?;
+        results.push(CheckResult {
+            was_quarantined: quarantined.contains(&proxy.id),
+            proxy,
+            check,
+            health,
+        });
+    }
+
+    results.sort_by_key(|r| r.proxy.id);
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    /// A stand-in proxy that answers every request with `status`
+    /// and hands back the request it received.
+    async fn stand_in_proxy(status: u16) -> (u16, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let handle = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0; 1024];
+            let n = socket.read(&mut buf).await.unwrap();
+            let response = format!("HTTP/1.1 {status} Whatever\r\nContent-Length: 0\r\n\r\n");
+            socket.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8_lossy(&buf[..n]).to_string()
+        });
+        (port, handle)
+    }
+
+    fn proxy(port: u16) -> Proxy {
+        Proxy {
+            id: 1,
+            protocol: "http".into(),
+            host: "127.0.0.1".into(),
+            port: port.into(),
+            username: Some("user".into()),
+            password: Some("pass".into()),
+            provider: "local".into(),
+            proxy_type: None,
+            weight: 1,
+            priority: 0,
+            exit_ip: None,
+            geo: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_check_url() {
+        let url: CheckUrl = "http://example.com/status?x=1".parse().unwrap();
+        assert_eq!(url.to_string(), "http://example.com:80/status?x=1");
+        let url: CheckUrl = "https://example.com".parse().unwrap();
+        assert_eq!(url.to_string(), "https://example.com:443/");
+        assert!("ftp://example.com".parse::<CheckUrl>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_check_proxy_http() {
+        let (port, request) = stand_in_proxy(204).await;
+        let url = "http://check.local/ping".parse().unwrap();
+        let check = check_proxy(&proxy(port), &url, Duration::from_secs(2)).await;
+
+        assert_eq!(check.status, Some(204));
+        assert!(check.is_healthy());
+        let request = request.await.unwrap();
+        assert!(request.starts_with("GET http://check.local:80/ping HTTP/1.1\r\n"));
+        assert!(request.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_check_proxy_https_tunnel() {
+        let (port, request) = stand_in_proxy(407).await;
+        let url = "https://check.local".parse().unwrap();
+        let check = check_proxy(&proxy(port), &url, Duration::from_secs(2)).await;
+
+        assert_eq!(check.status, Some(407));
+        assert!(!check.is_healthy());
+        assert!(request
+            .await
+            .unwrap()
+            .starts_with("CONNECT check.local:443 HTTP/1.1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_check_proxy_https_origin() {
+        // The tunnel opens, but the origin never answers through it.
+        let (port, _) = stand_in_proxy(200).await;
+        let url = "https://check.local".parse().unwrap();
+        let check = check_proxy(&proxy(port), &url, Duration::from_secs(2)).await;
+
+        assert_eq!(check.status, None);
+        assert!(!check.is_healthy());
+    }
+
+    #[tokio::test]
+    async fn test_check_proxy_unsupported_protocol() {
+        let socks = Proxy {
+            protocol: "socks5".into(),
+            ..proxy(1080)
+        };
+        assert!(!is_checkable(&socks));
+        assert!(is_checkable(&proxy(8080)));
+        let https = Proxy {
+            protocol: "https".into(),
+            ..proxy(443)
+        };
+        assert!(is_checkable(&https));
+        let url = "http://check.local".parse().unwrap();
+        let check = check_proxy(&socks, &url, Duration::from_secs(2)).await;
+        assert_eq!(check.error.as_deref(), Some("unsupported protocol socks5"));
+    }
+
+    #[tokio::test]
+    async fn test_check_proxy_unreachable() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let url = "http://check.local".parse().unwrap();
+        let check = check_proxy(&proxy(port), &url, Duration::from_secs(2)).await;
+        assert_eq!(check.status, None);
+        assert!(check.error.is_some());
+        assert!(!check.is_healthy());
+    }
+}
@@ -3,11 +3,34 @@ use std::env;
 use sqlx::{postgres::PgPoolOptions, Error, PgPool};
 use urlencoding::encode;
 
+pub mod bans;
+pub mod client;
+pub mod cooldowns;
 pub mod crud;
+pub mod diversity;
+pub mod domains;
+pub mod exits;
+pub mod geo;
+pub mod health;
 pub mod models;
+pub mod pool;
+pub mod pool_file;
+pub mod providers;
+pub mod routing;
+pub mod storage;
+pub mod tag_expr;
 
+/// Connects to Postgres, which is needed for more than
+/// the [`storage::ProxyStore`] covers.
 pub async fn new_pool() -> Result<PgPool, Error> {
-    let conn_string = get_conn_string();
+    let conn_string = storage::database_url();
+    if !storage::is_postgres(&conn_string) {
+        // The rest of the URL may hold credentials.
+        let scheme = conn_string.split(':').next().unwrap_or_default();
+        return Err(Error::Configuration(
+            format!("a Postgres database is required, not {scheme}").into(),
+        ));
+    }
     let pool = PgPoolOptions::new().connect(&conn_string).await?;
 
     Ok(pool)
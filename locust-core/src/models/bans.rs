@@ -0,0 +1,13 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+/// A rule that marks responses from a domain as banned.
+/// See [`crate::bans::BanRuleKind`] for the kinds of rules
+/// and the format of their patterns.
+#[derive(Debug, Clone, FromRow, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BanRule {
+    pub id: i32,
+    pub host: String,
+    pub kind: String,
+    pub pattern: String,
+}
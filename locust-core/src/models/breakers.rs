@@ -0,0 +1,19 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use time::OffsetDateTime;
+
+/// The last reported state of a circuit breaker, either for
+/// a proxy as a whole or for its requests to one domain.
+#[derive(Debug, Clone, FromRow, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProxyBreaker {
+    pub proxy_id: i32,
+    /// Empty for the breaker of the proxy as a whole.
+    pub domain: String,
+    /// One of `closed`, `open` or `half_open`.
+    pub state: String,
+    pub consecutive_failures: i32,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub date_opened: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub date_modified: OffsetDateTime,
+}
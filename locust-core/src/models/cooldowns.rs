@@ -0,0 +1,15 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use time::OffsetDateTime;
+
+/// A proxy that was rate limited by a domain, and
+/// is not used for it until the cooldown is over.
+#[derive(Debug, Clone, FromRow, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProxyCooldown {
+    pub proxy_id: i32,
+    pub domain: String,
+    /// Rate limits in a row.
+    pub strikes: i32,
+    #[serde(with = "time::serde::rfc3339")]
+    pub date_until: OffsetDateTime,
+}
@@ -0,0 +1,19 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+/// A domain along with the names of the tags mapped to it, the
+/// tag expression that is used instead of them when set, the
+/// tag expressions of its fallbacks in order, what is done with
+/// requests to it when set, and the limits on them when set.
+#[derive(Debug, Clone, FromRow, PartialEq, Serialize, Deserialize)]
+pub struct Domain {
+    pub id: i32,
+    pub host: String,
+    pub tags: Vec<String>,
+    pub tag_expr: Option<String>,
+    pub fallbacks: Vec<String>,
+    pub fallback_any: bool,
+    pub action: Option<String>,
+    pub max_concurrency: Option<i32>,
+    pub max_rps: Option<f64>,
+}
@@ -0,0 +1,23 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use time::OffsetDateTime;
+
+/// The address a proxy was last seen exiting from.
+#[derive(Debug, Clone, FromRow, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProxyExit {
+    pub proxy_id: i32,
+    pub host: String,
+    pub port: i32,
+    /// Missing until an exit check succeeds.
+    pub exit_ip: Option<String>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub date_exit_seen: Option<OffsetDateTime>,
+    /// The other live proxies exiting from the same address.
+    pub shared_with: Vec<i32>,
+}
+
+impl ProxyExit {
+    pub fn is_shared(&self) -> bool {
+        !self.shared_with.is_empty()
+    }
+}
@@ -0,0 +1,26 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use time::OffsetDateTime;
+
+/// The outcome of the latest health checks of a proxy.
+#[derive(Debug, Clone, FromRow, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProxyHealth {
+    pub proxy_id: i32,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub date_last_checked: Option<OffsetDateTime>,
+    /// Missing when the last check got no response.
+    pub last_check_status: Option<i16>,
+    /// Milliseconds until the last check got a response.
+    pub last_check_latency: Option<i32>,
+    /// Checks failed in a row.
+    pub check_failures: i32,
+    /// Set while the proxy is left out of rotation.
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub date_quarantined: Option<OffsetDateTime>,
+}
+
+impl ProxyHealth {
+    pub fn is_quarantined(&self) -> bool {
+        self.date_quarantined.is_some()
+    }
+}
@@ -0,0 +1,38 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use time::OffsetDateTime;
+
+/// The outcome of a single proxied request.
+#[derive(Debug, Clone, FromRow, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RequestRecord {
+    #[serde(with = "time::serde::rfc3339")]
+    pub date_created: OffsetDateTime,
+    pub proxy_id: i32,
+    pub session_id: Option<i32>,
+    pub domain: Option<String>,
+    /// Missing when no response came back from the origin.
+    pub status: Option<i16>,
+    pub error_class: Option<String>,
+    /// Milliseconds until the response headers arrived.
+    pub response_time: i32,
+    /// Milliseconds until the response body was fully sent.
+    pub total_time: i32,
+    pub request_bytes: i64,
+    pub response_bytes: i64,
+    pub username: Option<String>,
+}
+
+/// Narrows down a request history lookup. Every
+/// field that is set has to match.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct RequestHistoryFilter {
+    pub proxy_id: Option<i32>,
+    pub domain: Option<String>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub since: Option<OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub until: Option<OffsetDateTime>,
+    /// Only requests that failed or got an error status.
+    #[serde(default)]
+    pub failed: bool,
+}
@@ -1,17 +1,54 @@
-use sqlx::FromRow;
+use std::{fmt, net::IpAddr, str::FromStr};
 
-#[derive(Debug, Clone, FromRow, PartialEq, Eq)]
+use serde::{Deserialize, Serialize};
+use sqlx::{
+    database::{Database, HasValueRef},
+    error::BoxDynError,
+    Decode, FromRow, Type,
+};
+
+/// The weight of proxies that were not given one.
+pub const DEFAULT_WEIGHT: i32 = 1;
+
+#[derive(Debug, Clone, FromRow, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Proxy {
     pub id: i32,
     pub protocol: String,
     pub host: String,
     pub port: i32,
     pub username: Option<String>,
+    #[serde(skip_serializing)]
     pub password: Option<String>,
     pub provider: String,
+    #[sqlx(rename = "type")]
+    #[serde(rename = "type")]
+    pub proxy_type: Option<ProxyType>,
+    /// The share of requests the proxy gets among the
+    /// proxies of the same priority it is picked from.
+    pub weight: i32,
+    /// Proxies of a higher priority are picked first.
+    pub priority: i32,
+    /// The address targets last saw requests through the proxy come
+    /// from, which for rotating providers is not its host.
+    pub exit_ip: Option<String>,
+    #[sqlx(flatten)]
+    #[serde(flatten)]
+    pub geo: Geo,
+}
+
+impl Proxy {
+    /// The address requests through the proxy come from: its exit IP
+    /// when it is known, else its host, unless that is a name.
+    pub fn exit_addr(&self) -> Option<IpAddr> {
+        if let Some(ip) = self.exit_ip.as_deref().and_then(|ip| ip.parse().ok()) {
+            return Some(ip);
+        }
+        let host = self.host.trim_start_matches('[').trim_end_matches(']');
+        host.parse().ok()
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct NewProxy {
     pub protocol: String,
     pub host: String,
@@ -19,9 +56,111 @@ pub struct NewProxy {
     pub username: Option<String>,
     pub password: Option<String>,
     pub provider: String,
+    #[serde(default, rename = "type")]
+    pub proxy_type: Option<ProxyType>,
+    #[serde(default = "default_weight")]
+    pub weight: i32,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default, flatten)]
+    pub geo: Geo,
+}
+
+fn default_weight() -> i32 {
+    DEFAULT_WEIGHT
+}
+
+/// Where the addresses a proxy exits from come from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyType {
+    Datacenter,
+    Residential,
+    Mobile,
+    /// Static residential addresses hosted in a datacenter.
+    Isp,
+}
+
+impl ProxyType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProxyType::Datacenter => "datacenter",
+            ProxyType::Residential => "residential",
+            ProxyType::Mobile => "mobile",
+            ProxyType::Isp => "isp",
+        }
+    }
+}
+
+impl fmt::Display for ProxyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ProxyType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "datacenter" => Ok(ProxyType::Datacenter),
+            "residential" => Ok(ProxyType::Residential),
+            "mobile" => Ok(ProxyType::Mobile),
+            "isp" => Ok(ProxyType::Isp),
+            _ => Err(format!(
+                "unknown proxy type {s}, expected datacenter, residential, mobile or isp"
+            )),
+        }
+    }
+}
+
+/// Stored as text, in Postgres and SQLite alike.
+impl<DB: Database> Type<DB> for ProxyType
+where
+    String: Type<DB>,
+{
+    fn type_info() -> DB::TypeInfo {
+        String::type_info()
+    }
+
+    fn compatible(ty: &DB::TypeInfo) -> bool {
+        String::compatible(ty)
+    }
+}
+
+impl<'r, DB: Database> Decode<'r, DB> for ProxyType
+where
+    String: Decode<'r, DB>,
+{
+    fn decode(value: <DB as HasValueRef<'r>>::ValueRef) -> Result<Self, BoxDynError> {
+        Ok(String::decode(value)?.parse()?)
+    }
+}
+
+/// Where a proxy is located, as far as is known.
+#[derive(Debug, Clone, Default, FromRow, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Geo {
+    /// An upper case ISO 3166 country code.
+    pub country: Option<String>,
+    pub region: Option<String>,
+    pub city: Option<String>,
+    /// The number of the autonomous system the proxy is in.
+    pub asn: Option<i64>,
+}
+
+impl Geo {
+    /// Fills in what is not known yet from `other`.
+    pub fn or(self, other: Geo) -> Geo {
+        Geo {
+            country: self.country.or(other.country),
+            region: self.region.or(other.region),
+            city: self.city.or(other.city),
+            asn: self.asn.or(other.asn),
+        }
+    }
 }
 
-#[derive(Debug, Clone, FromRow, PartialEq, Eq)]
+#[derive(Debug, Clone, FromRow, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ProxySession {
     pub id: i32,
     pub proxy_id: i32,
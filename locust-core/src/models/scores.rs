@@ -0,0 +1,12 @@
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+/// How requests to a domain went through a proxy.
+#[derive(Debug, Clone, Default, FromRow, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProxyDomainScore {
+    pub proxy_id: i32,
+    pub domain: String,
+    pub successes: i64,
+    pub failures: i64,
+    pub bans: i64,
+}
@@ -0,0 +1,658 @@
+use std::collections::{BTreeMap, HashMap};
+
+use rand::{distributions::WeightedIndex, prelude::Distribution};
+use time::OffsetDateTime;
+
+use crate::{
+    diversity::{DiversityConfig, RecentUses},
+    domains::DomainRules,
+    models::proxies::Proxy,
+    routing::{DomainLimit, DomainRoute, Fallback, RouteAction},
+    tag_expr::TagExpr,
+};
+
+/// What a single request asks of its proxy, instead of going through
+/// the route of its domain. Empty filters leave the route be.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProxyFilter {
+    pub tags: Option<TagExpr>,
+    pub proxy_id: Option<i32>,
+    pub provider: Option<String>,
+    /// An ISO 3166 country code, matched case insensitively.
+    pub country: Option<String>,
+    pub asn: Option<i64>,
+}
+
+impl ProxyFilter {
+    pub fn is_empty(&self) -> bool {
+        *self == ProxyFilter::default()
+    }
+}
+
+/// The live proxies along with their tags and the routes of the
+/// domains, so that proxies can be picked without going to the
+/// database.
+#[derive(Debug, Clone, Default)]
+pub struct ProxyPool {
+    proxies: HashMap<i32, Proxy>,
+    /// Tags by proxy id.
+    tags: HashMap<i32, Vec<String>>,
+    /// The proxies for each domain and its fallbacks.
+    domains: DomainRules<DomainRoute>,
+    last_used: HashMap<i32, OffsetDateTime>,
+    diversity: DiversityConfig,
+    recent: RecentUses,
+}
+
+impl ProxyPool {
+    /// Domains that are not valid patterns are left out,
+    /// see [`crate::domains`].
+    pub fn new(
+        proxies: Vec<(Proxy, Option<OffsetDateTime>)>,
+        mut tags: HashMap<i32, Vec<String>>,
+        domains: Vec<(String, DomainRoute)>,
+    ) -> Self {
+        let mut pool = Self::default();
+        for (proxy, last_used) in proxies {
+            if let Some(last_used) = last_used {
+                pool.last_used.insert(proxy.id, last_used);
+            }
+            if let Some(tags) = tags.remove(&proxy.id) {
+                pool.tags.insert(proxy.id, tags);
+            }
+            pool.proxies.insert(proxy.id, proxy);
+        }
+        pool.domains = DomainRules::compile(domains).0;
+        pool
+    }
+
+    pub fn len(&self) -> usize {
+        self.proxies.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.proxies.is_empty()
+    }
+
+    /// How many proxies carry each tag.
+    pub fn tag_sizes(&self) -> BTreeMap<String, i64> {
+        let mut sizes = BTreeMap::new();
+        for tags in self.tags.values() {
+            for tag in tags {
+                *sizes.entry(tag.clone()).or_insert(0) += 1;
+            }
+        }
+        sizes
+    }
+
+    /// Spreads the proxies picked for each domain over subnets and
+    /// autonomous systems as configured, see [`crate::diversity`].
+    pub fn set_diversity(&mut self, config: DiversityConfig) {
+        self.diversity = config;
+    }
+
+    /// Replaces the proxies and domains with the ones of a freshly
+    /// loaded pool. Uses that have not been stored yet are kept,
+    /// as are the recent uses of each domain.
+    pub fn refresh(&mut self, fresh: ProxyPool) {
+        let mut last_used = fresh.last_used;
+        for (id, used) in self.last_used.drain() {
+            let stored = last_used.entry(id).or_insert(used);
+            *stored = (*stored).max(used);
+        }
+        last_used.retain(|id, _| fresh.proxies.contains_key(id));
+
+        self.proxies = fresh.proxies;
+        self.tags = fresh.tags;
+        self.domains = fresh.domains;
+        self.last_used = last_used;
+        self.recent
+            .prune(&self.diversity, OffsetDateTime::now_utc());
+    }
+
+    /// Picks a proxy matching `filter`, unless it is empty, or else goes
+    /// through the route of the most specific domain matching the
+    /// host: its own proxies, then its fallbacks in order, then any
+    /// proxy unless the domain has no fallback. Returns the fallback
+    /// the proxy was picked from, if any, and marks it as used at
+    /// `now`. Proxies in `exclude` are never picked.
+    ///
+    /// Proxies of the highest priority are picked first. Among them,
+    /// the ones whose subnet or autonomous system was not picked for
+    /// the domain lately go first, when diversity is configured. Then
+    /// proxies are picked at random in proportion to their weight,
+    /// unless their weights are all the same: then proxies that were
+    /// never used come first, followed by the ones that were used
+    /// least recently, so that picks go around all of them.
+    pub fn pick(
+        &mut self,
+        domain: Option<&str>,
+        filter: &ProxyFilter,
+        exclude: &[i32],
+        now: OffsetDateTime,
+    ) -> Option<(Proxy, Option<Fallback>)> {
+        let available = |id: &&i32| !exclude.contains(id);
+        let tagged = |tags: &TagExpr| {
+            let ids = self.proxies.keys().filter(|id| self.has_tags(**id, tags));
+            self.choose(ids.filter(available), domain, now)
+        };
+        let (id, fallback) = match filter.is_empty() {
+            false => {
+                let ids = self.proxies.keys().filter(|id| self.matches(**id, filter));
+                (self.choose(ids.filter(available), domain, now)?, None)
+            }
+            true => {
+                let route = domain
+                    .and_then(|domain| self.domains.find(domain))
+                    .and_then(|routes| routes.first());
+                let picked = route.and_then(|route| {
+                    route
+                        .tiers()
+                        .find_map(|(tags, fallback)| Some((tagged(tags)?, fallback)))
+                });
+                match (picked, route) {
+                    (Some(picked), _) => picked,
+                    (None, Some(route)) if !route.fallback_any => return None,
+                    (None, route) => {
+                        let ids = self.proxies.keys().filter(available);
+                        let id = self.choose(ids, domain, now)?;
+                        (id, route.map(|_| Fallback::Any))
+                    }
+                }
+            }
+        };
+
+        self.last_used.insert(id, now);
+        let proxy = self.proxies.get(&id).cloned()?;
+        if let Some(domain) = domain.filter(|_| self.diversity.is_enabled()) {
+            self.recent.record(&self.diversity, domain, &proxy, now);
+        }
+        Some((proxy, fallback))
+    }
+
+    /// What is done with requests to the host, from the route of
+    /// the most specific domain matching it.
+    pub fn action(&self, domain: &str) -> RouteAction {
+        self.domains
+            .find(domain)
+            .and_then(|routes| routes.first())
+            .map(|route| route.action.clone())
+            .unwrap_or_default()
+    }
+
+    /// The limit on requests to the host, from the route of the most
+    /// specific domain matching it.
+    pub fn limit(&self, domain: &str) -> DomainLimit {
+        self.domains
+            .find(domain)
+            .and_then(|routes| routes.first())
+            .map(|route| route.limit)
+            .unwrap_or_default()
+    }
+
+    /// Gets a live proxy by its id, marking it as used at `now`.
+    pub fn get(&mut self, id: i32, now: OffsetDateTime) -> Option<Proxy> {
+        let proxy = self.proxies.get(&id).cloned()?;
+        self.last_used.insert(id, now);
+        Some(proxy)
+    }
+
+    /// Whether a proxy in the pool has tags satisfying the expression.
+    pub fn has_tags(&self, id: i32, tags: &TagExpr) -> bool {
+        self.proxies.get(&id).is_some_and(|proxy| {
+            tags.matches(
+                self.tags.get(&id).map_or(&[][..], Vec::as_slice),
+                &proxy.geo,
+            )
+        })
+    }
+
+    /// Whether a proxy in the pool matches everything the filter asks.
+    pub fn matches(&self, id: i32, filter: &ProxyFilter) -> bool {
+        let Some(proxy) = self.proxies.get(&id) else {
+            return false;
+        };
+        let tags = self.tags.get(&id).map_or(&[][..], Vec::as_slice);
+        filter
+            .tags
+            .as_ref()
+            .is_none_or(|expr| expr.matches(tags, &proxy.geo))
+            && filter.proxy_id.is_none_or(|proxy_id| proxy_id == id)
+            && filter
+                .provider
+                .as_ref()
+                .is_none_or(|provider| *provider == proxy.provider)
+            && filter.country.as_ref().is_none_or(|country| {
+                proxy
+                    .geo
+                    .country
+                    .as_ref()
+                    .is_some_and(|c| c.eq_ignore_ascii_case(country))
+            })
+            && filter.asn.is_none_or(|asn| proxy.geo.asn == Some(asn))
+    }
+
+    /// Picks among the proxies of the highest priority, see [`Self::pick`].
+    fn choose<'a>(
+        &self,
+        ids: impl Iterator<Item = &'a i32>,
+        domain: Option<&str>,
+        now: OffsetDateTime,
+    ) -> Option<i32> {
+        let proxies: Vec<&Proxy> = ids.filter_map(|id| self.proxies.get(id)).collect();
+        let priority = proxies.iter().map(|proxy| proxy.priority).max()?;
+        let mut tier: Vec<&Proxy> = proxies
+            .into_iter()
+            .filter(|proxy| proxy.priority == priority)
+            .collect();
+        if let Some(domain) = domain.filter(|_| self.diversity.is_enabled()) {
+            let fresh: Vec<&Proxy> = tier
+                .iter()
+                .copied()
+                .filter(|proxy| !self.recent.is_recent(&self.diversity, domain, proxy, now))
+                .collect();
+            // Recent proxies are still picked rather than none.
+            if !fresh.is_empty() {
+                tier = fresh;
+            }
+        }
+        if tier.iter().all(|proxy| proxy.weight == tier[0].weight) {
+            return self.least_recent(tier.iter().map(|proxy| &proxy.id));
+        }
+        let weights = WeightedIndex::new(tier.iter().map(|proxy| proxy.weight.max(1))).ok()?;
+        Some(tier[weights.sample(&mut rand::thread_rng())].id)
+    }
+
+    fn least_recent<'a>(&self, ids: impl Iterator<Item = &'a i32>) -> Option<i32> {
+        // Proxies that were never used come first, ties go to the lowest id.
+        ids.min_by_key(|id| (self.last_used.get(id), **id)).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::proxies::Geo;
+    use std::time::Duration;
+    use time::macros::datetime;
+
+    fn proxy(id: i32) -> Proxy {
+        Proxy {
+            id,
+            protocol: "http".into(),
+            host: format!("10.0.0.{id}"),
+            port: 8080,
+            username: None,
+            password: None,
+            provider: "local".into(),
+            proxy_type: None,
+            weight: 1,
+            priority: 0,
+            exit_ip: None,
+            geo: Geo::default(),
+        }
+    }
+
+    fn expr(expr: &str) -> TagExpr {
+        TagExpr::parse(expr).unwrap()
+    }
+
+    fn route(tags: &str) -> DomainRoute {
+        DomainRoute {
+            action: RouteAction::Proxy(None),
+            tags: Some(expr(tags)),
+            fallbacks: vec![],
+            fallback_any: true,
+            limit: DomainLimit::default(),
+        }
+    }
+
+    fn pool() -> ProxyPool {
+        let tags = |tags: &[&str]| tags.iter().map(|t| t.to_string()).collect();
+        let located = Proxy {
+            geo: Geo {
+                country: Some("US".into()),
+                region: None,
+                city: None,
+                asn: Some(7922),
+            },
+            ..proxy(1)
+        };
+        ProxyPool::new(
+            vec![
+                (located, Some(datetime!(2024-01-01 10:00 UTC))),
+                (proxy(2), Some(datetime!(2024-01-01 12:00 UTC))),
+                (proxy(3), Some(datetime!(2024-01-01 11:00 UTC))),
+            ],
+            HashMap::from([
+                (1, tags(&["a", "us"])),
+                (2, tags(&["b"])),
+                (3, tags(&["a", "infatica"])),
+            ]),
+            vec![
+                ("example.com".into(), route("a")),
+                ("*.example.com".into(), route("b")),
+                ("api.example.com".into(), route("a AND NOT infatica")),
+            ],
+        )
+    }
+
+    #[test]
+    fn test_pick_prefers_tagged_proxies() {
+        let mut pool = pool();
+        let now = datetime!(2024-01-02 00:00 UTC);
+
+        let mut pick = |domain, exclude: &[i32]| {
+            let (proxy, _) = pool
+                .pick(Some(domain), &ProxyFilter::default(), exclude, now)
+                .unwrap();
+            proxy.id
+        };
+        assert_eq!(pick("example.com", &[]), 1);
+        assert_eq!(pick("example.com", &[]), 3);
+        assert_eq!(pick("example.com", &[3]), 1);
+        // Without tagged proxies left, any proxy is picked.
+        assert_eq!(pick("example.com", &[1, 3]), 2);
+        // Ties go to the lowest id.
+        assert_eq!(pick("other.com", &[2]), 1);
+        // Subdomains go through the proxies of the wildcard.
+        assert_eq!(pick("www.example.com", &[]), 2);
+        assert_eq!(pick("Example.com:8443", &[1]), 3);
+        assert_eq!(pick("api.example.com", &[]), 1);
+        assert!(pool
+            .pick(None, &ProxyFilter::default(), &[1, 2, 3], now)
+            .is_none());
+    }
+
+    #[test]
+    fn test_tag_sizes() {
+        let sizes = pool().tag_sizes();
+        assert_eq!(
+            sizes.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("a".to_string(), 2),
+                ("b".to_string(), 1),
+                ("infatica".to_string(), 1),
+                ("us".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_pick_with_filter() {
+        let mut pool = pool();
+        let now = datetime!(2024-01-02 00:00 UTC);
+
+        // The tags of the request win over the ones of the domain.
+        let tags = ProxyFilter {
+            tags: Some(expr("NOT a OR us")),
+            ..ProxyFilter::default()
+        };
+        let mut pick = |filter: &ProxyFilter, exclude: &[i32]| {
+            pool.pick(Some("example.com"), filter, exclude, now)
+                .map(|(proxy, _)| proxy.id)
+        };
+        assert_eq!(pick(&tags, &[]), Some(1));
+        assert_eq!(pick(&tags, &[1]), Some(2));
+        // There is no falling back to other proxies.
+        assert_eq!(pick(&tags, &[1, 2]), None);
+
+        let id = ProxyFilter {
+            proxy_id: Some(2),
+            ..ProxyFilter::default()
+        };
+        assert_eq!(pick(&id, &[]), Some(2));
+        assert_eq!(pick(&id, &[2]), None);
+        let country = ProxyFilter {
+            country: Some("US".into()),
+            provider: Some("local".into()),
+            ..ProxyFilter::default()
+        };
+        assert_eq!(pick(&country, &[]), Some(1));
+        let asn = ProxyFilter {
+            asn: Some(7922),
+            tags: Some(expr("country:us")),
+            ..ProxyFilter::default()
+        };
+        assert_eq!(pick(&asn, &[]), Some(1));
+        assert_eq!(pick(&asn, &[1]), None);
+        let provider = ProxyFilter {
+            provider: Some("webshare".into()),
+            ..ProxyFilter::default()
+        };
+        assert_eq!(pick(&provider, &[]), None);
+
+        assert!(pool.has_tags(3, &expr("a AND infatica")));
+        assert!(!pool.has_tags(4, &expr("NOT a")));
+        assert!(!pool.matches(4, &ProxyFilter::default()));
+    }
+
+    #[test]
+    fn test_pick_order() {
+        let mut pool = pool();
+        let mut pick = |exclude: &[i32], now| {
+            pool.pick(None, &ProxyFilter::default(), exclude, now)
+                .unwrap()
+                .0
+                .id
+        };
+        assert_eq!(pick(&[], datetime!(2024-01-02 00:00 UTC)), 1);
+        assert_eq!(pick(&[1], datetime!(2024-01-02 01:00 UTC)), 3);
+        assert_eq!(pick(&[], datetime!(2024-01-02 02:00 UTC)), 2);
+        assert_eq!(pick(&[], datetime!(2024-01-02 03:00 UTC)), 1);
+
+        // Proxies that were never used go first.
+        pool.refresh(ProxyPool::new(
+            vec![(proxy(2), None), (proxy(3), None), (proxy(4), None)],
+            HashMap::new(),
+            vec![],
+        ));
+        let now = datetime!(2024-01-02 04:00 UTC);
+        assert_eq!(
+            pool.pick(None, &ProxyFilter::default(), &[], now)
+                .unwrap()
+                .0
+                .id,
+            4
+        );
+    }
+
+    #[test]
+    fn test_pick_by_priority_and_weight() {
+        let ranked = |id, weight, priority| Proxy {
+            weight,
+            priority,
+            ..proxy(id)
+        };
+        let mut pool = ProxyPool::new(
+            vec![
+                (ranked(1, 1, 1), None),
+                (ranked(2, 3, 1), None),
+                (ranked(3, 1, 1), None),
+                (ranked(4, 10, 0), None),
+            ],
+            HashMap::from([(3, vec!["a".to_string()])]),
+            vec![("example.com".into(), route("a"))],
+        );
+        let now = datetime!(2024-01-02 00:00 UTC);
+        let mut picks = HashMap::new();
+        for _ in 0..2000 {
+            let (proxy, _) = pool.pick(None, &ProxyFilter::default(), &[], now).unwrap();
+            *picks.entry(proxy.id).or_insert(0) += 1;
+        }
+        // Proxies of a lower priority are left alone, whatever their weight.
+        assert!(!picks.contains_key(&4));
+        assert!((1050..1350).contains(&picks[&2]), "{picks:?}");
+        assert!((650..950).contains(&(picks[&1] + picks[&3])), "{picks:?}");
+
+        // Priorities only apply among the proxies a route picks from.
+        let mut pick = |domain, exclude: &[i32]| {
+            pool.pick(Some(domain), &ProxyFilter::default(), exclude, now)
+                .map(|(proxy, _)| proxy.id)
+        };
+        assert_eq!(pick("example.com", &[]), Some(3));
+        assert_eq!(pick("other.com", &[1, 2, 3]), Some(4));
+    }
+
+    #[test]
+    fn test_equal_weights_spread_picks() {
+        let mut pool = ProxyPool::new(
+            vec![(proxy(1), None), (proxy(2), None), (proxy(3), None)],
+            HashMap::new(),
+            vec![],
+        );
+        let start = datetime!(2024-01-02 00:00 UTC);
+        let mut picks: HashMap<i32, i32> = HashMap::new();
+        for secs in 0..300 {
+            let now = start + Duration::from_secs(secs);
+            let (proxy, _) = pool.pick(None, &ProxyFilter::default(), &[], now).unwrap();
+            *picks.entry(proxy.id).or_default() += 1;
+        }
+        assert_eq!(picks, HashMap::from([(1, 100), (2, 100), (3, 100)]));
+    }
+
+    #[test]
+    fn test_pick_with_diversity() {
+        let at = |id, host: &str, asn| Proxy {
+            host: host.into(),
+            geo: Geo {
+                asn,
+                ..Geo::default()
+            },
+            ..proxy(id)
+        };
+        let mut pool = ProxyPool::new(
+            vec![
+                (at(1, "10.0.0.1", Some(1)), None),
+                (at(2, "10.0.0.2", Some(1)), None),
+                (at(3, "10.0.1.1", Some(1)), None),
+                (at(4, "10.0.2.1", Some(2)), None),
+            ],
+            HashMap::new(),
+            vec![],
+        );
+        pool.set_diversity(DiversityConfig {
+            subnet: true,
+            asn: false,
+            window: Duration::from_secs(60),
+        });
+        let start = datetime!(2024-01-02 00:00 UTC);
+        let pick = |pool: &mut ProxyPool, domain, secs| {
+            let now = start + Duration::from_secs(secs);
+            pool.pick(domain, &ProxyFilter::default(), &[], now)
+                .unwrap()
+                .0
+                .id
+        };
+        assert_eq!(pick(&mut pool, Some("example.com"), 0), 1);
+        // Proxy 2 was never used, but shares the subnet of 1.
+        assert_eq!(pick(&mut pool, Some("example.com"), 1), 3);
+        assert_eq!(pick(&mut pool, Some("example.com"), 2), 4);
+        // Every subnet was used lately, so recent ones are picked again.
+        assert_eq!(pick(&mut pool, Some("example.com"), 3), 2);
+        // Other domains are not held back.
+        assert_eq!(pick(&mut pool, Some("example.org"), 4), 1);
+        assert_eq!(pick(&mut pool, None, 5), 3);
+        // Once the window is over, the subnet can be used again.
+        assert_eq!(pick(&mut pool, Some("example.com"), 90), 4);
+        assert_eq!(pick(&mut pool, Some("example.com"), 91), 2);
+
+        pool.set_diversity(DiversityConfig {
+            subnet: false,
+            asn: true,
+            window: Duration::from_secs(60),
+        });
+        assert_eq!(pick(&mut pool, Some("example.net"), 100), 1);
+        assert_eq!(pick(&mut pool, Some("example.net"), 101), 4);
+    }
+
+    #[test]
+    fn test_pick_fallbacks() {
+        let mut pool = pool();
+        let now = datetime!(2024-01-02 00:00 UTC);
+        pool.domains = DomainRules::compile(vec![
+            (
+                "example.com".to_string(),
+                DomainRoute {
+                    action: RouteAction::Proxy(None),
+                    tags: Some(expr("infatica")),
+                    fallbacks: vec![expr("us"), expr("b")],
+                    fallback_any: false,
+                    limit: DomainLimit::default(),
+                },
+            ),
+            (
+                "example.org".to_string(),
+                DomainRoute {
+                    action: RouteAction::Proxy(None),
+                    tags: None,
+                    fallbacks: vec![expr("b")],
+                    fallback_any: true,
+                    limit: DomainLimit::default(),
+                },
+            ),
+            (
+                "*.example.org".to_string(),
+                DomainRoute {
+                    action: RouteAction::Block(None),
+                    tags: None,
+                    fallbacks: vec![],
+                    fallback_any: true,
+                    limit: DomainLimit::default(),
+                },
+            ),
+        ])
+        .0;
+
+        let mut pick = |domain, exclude: &[i32]| {
+            pool.pick(Some(domain), &ProxyFilter::default(), exclude, now)
+                .map(|(proxy, fallback)| (proxy.id, fallback))
+        };
+        assert_eq!(pick("example.com", &[]), Some((3, None)));
+        assert_eq!(
+            pick("example.com", &[3]),
+            Some((1, Some(Fallback::Chain(1))))
+        );
+        assert_eq!(
+            pick("example.com", &[1, 3]),
+            Some((2, Some(Fallback::Chain(2))))
+        );
+        // Without a fallback to any proxy, the request is turned away.
+        assert_eq!(pick("example.com", &[1, 2, 3]), None);
+
+        // Domains without proxies of their own go straight to the fallbacks.
+        assert_eq!(
+            pick("example.org", &[]),
+            Some((2, Some(Fallback::Chain(1))))
+        );
+        assert_eq!(pick("example.org", &[2]), Some((1, Some(Fallback::Any))));
+        assert_eq!(pick("other.org", &[]), Some((1, None)));
+
+        assert_eq!(pool.action("www.example.org"), RouteAction::Block(None));
+        assert_eq!(pool.action("example.org"), RouteAction::Proxy(None));
+        assert_eq!(pool.action("other.org"), RouteAction::Proxy(None));
+    }
+
+    #[test]
+    fn test_refresh_keeps_recent_uses() {
+        let mut pool = pool();
+        let now = datetime!(2024-01-02 00:00 UTC);
+        assert_eq!(pool.get(1, now).unwrap().id, 1);
+
+        pool.refresh(ProxyPool::new(
+            vec![
+                (proxy(1), Some(datetime!(2024-01-01 10:00 UTC))),
+                (proxy(2), Some(datetime!(2024-01-01 12:00 UTC))),
+            ],
+            HashMap::new(),
+            vec![],
+        ));
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.last_used[&1], now);
+        assert!(pool.get(3, now).is_none());
+        assert!(!pool.has_tags(1, &expr("a")));
+        assert!(pool
+            .pick(Some("example.com"), &ProxyFilter::default(), &[], now)
+            .is_some());
+    }
+}
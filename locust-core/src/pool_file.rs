@@ -0,0 +1,618 @@
+//! A proxy pool described in a YAML or TOML file, so
+//! that the proxy server can run without a database.
+
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    num::NonZeroU16,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    bans::{validate_ban_rule, BanRuleKind},
+    domains::{DomainPattern, DomainRules},
+    geo::geo_from_username,
+    models::{
+        bans::BanRule,
+        proxies::{Geo, NewProxy, Proxy, ProxyType, DEFAULT_WEIGHT},
+    },
+    pool::ProxyPool,
+    providers::ProxyFormat,
+    routing::{DomainLimit, DomainRoute, RouteAction},
+    tag_expr::TagExpr,
+};
+
+const DEFAULT_PROTOCOL: &str = "http";
+const DEFAULT_PROVIDER: &str = "file";
+
+/// The contents of a pool file.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PoolFile {
+    #[serde(default)]
+    pub proxies: Vec<FileProxy>,
+    /// Proxy lists in the formats of the providers.
+    #[serde(default)]
+    pub sources: Vec<FileSource>,
+    #[serde(default)]
+    pub domains: Vec<FileDomain>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileProxy {
+    #[serde(default = "default_protocol")]
+    pub protocol: String,
+    pub host: String,
+    pub port: i16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default = "default_provider")]
+    pub provider: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(rename = "type")]
+    pub proxy_type: Option<ProxyType>,
+    pub weight: Option<NonZeroU16>,
+    #[serde(default)]
+    pub priority: i32,
+    /// Where the proxy is located. Left out, it is read from the
+    /// username when the provider encodes it there.
+    pub country: Option<String>,
+    pub region: Option<String>,
+    pub city: Option<String>,
+    pub asn: Option<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileSource {
+    /// Relative paths are resolved against the pool file's directory.
+    pub path: PathBuf,
+    pub format: ProxyFormat,
+    /// Tags of every proxy in the list. Defaults to the
+    /// name of the format, like `locust-cli import` does.
+    pub tags: Option<Vec<String>>,
+    /// The type, weight and priority of every proxy in the list.
+    #[serde(rename = "type")]
+    pub proxy_type: Option<ProxyType>,
+    pub weight: Option<NonZeroU16>,
+    #[serde(default)]
+    pub priority: i32,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileDomain {
+    pub host: String,
+    /// Requests to the domain go through proxies with any of these tags.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Or through the proxies satisfying this expression, which
+    /// wins over `tags`. See [`crate::tag_expr`].
+    pub tag_expr: Option<String>,
+    /// Tag expressions tried in order once none of the domain's own
+    /// proxies is left. See [`crate::routing`].
+    #[serde(default)]
+    pub fallbacks: Vec<String>,
+    /// Whether any proxy is used once the fallbacks are exhausted.
+    #[serde(default = "default_fallback_any")]
+    pub fallback_any: bool,
+    /// What is done with requests to the domain, e.g. `direct`.
+    /// See [`RouteAction`].
+    pub action: Option<String>,
+    /// Overrides the server's limit on requests to each matching host.
+    #[serde(default)]
+    pub limit: DomainLimit,
+    #[serde(default)]
+    pub bans: Vec<FileBanRule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileBanRule {
+    pub kind: String,
+    pub pattern: String,
+}
+
+fn default_protocol() -> String {
+    DEFAULT_PROTOCOL.into()
+}
+
+fn default_provider() -> String {
+    DEFAULT_PROVIDER.into()
+}
+
+fn default_fallback_any() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileFormat {
+    Yaml,
+    Toml,
+}
+
+impl FileFormat {
+    /// Picks the format by the extension of the file.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "yaml" | "yml" => Some(FileFormat::Yaml),
+            "toml" => Some(FileFormat::Toml),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PoolFileError {
+    Io(PathBuf, io::Error),
+    UnknownFormat(PathBuf),
+    Parse(String),
+    InvalidDomain(String, String),
+    InvalidTagExpr(String, String),
+    InvalidAction(String, String),
+    InvalidLimit(String, String),
+    InvalidBanRule(String, String),
+}
+
+impl fmt::Display for PoolFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PoolFileError::Io(path, e) => write!(f, "error reading {}: {e}", path.display()),
+            PoolFileError::UnknownFormat(path) => write!(
+                f,
+                "unknown pool file format for {}, expected .yaml, .yml or .toml",
+                path.display()
+            ),
+            PoolFileError::Parse(e) => write!(f, "error parsing pool file: {e}"),
+            PoolFileError::InvalidDomain(host, e) => write!(f, "invalid domain {host}: {e}"),
+            PoolFileError::InvalidTagExpr(host, e) => {
+                write!(f, "invalid tag expression for {host}: {e}")
+            }
+            PoolFileError::InvalidAction(host, e) => {
+                write!(f, "invalid action for {host}: {e}")
+            }
+            PoolFileError::InvalidLimit(host, e) => {
+                write!(f, "invalid limit for {host}: {e}")
+            }
+            PoolFileError::InvalidBanRule(host, e) => {
+                write!(f, "invalid ban rule for {host}: {e}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for PoolFileError {}
+
+/// Proxy ids handed out to the proxies of a pool file. A proxy keeps
+/// its id across reloads for as long as its protocol, host, port and
+/// username stay the same, so that sessions keep pointing at it.
+#[derive(Debug, Default)]
+pub struct ProxyIds {
+    ids: HashMap<(String, String, i16, Option<String>), i32>,
+    last: i32,
+}
+
+impl ProxyIds {
+    pub fn id(&mut self, proxy: &NewProxy) -> i32 {
+        let key = (
+            proxy.protocol.clone(),
+            proxy.host.clone(),
+            proxy.port,
+            proxy.username.clone(),
+        );
+        *self.ids.entry(key).or_insert_with(|| {
+            self.last += 1;
+            self.last
+        })
+    }
+}
+
+/// A pool file with its proxy lists read.
+#[derive(Debug, Clone, Default)]
+pub struct StaticPool {
+    pub pool: ProxyPool,
+    /// Proxy tags by proxy id.
+    pub tags: HashMap<i32, Vec<String>>,
+    /// Ban rules by domain, matched like domain routes.
+    pub ban_rules: DomainRules<BanRule>,
+    /// The pool file and the proxy lists it points to,
+    /// which are the files to watch for changes.
+    pub paths: Vec<PathBuf>,
+}
+
+impl PoolFile {
+    pub fn parse(content: &str, format: FileFormat) -> Result<Self, PoolFileError> {
+        match format {
+            FileFormat::Yaml => {
+                serde_yaml::from_str(content).map_err(|e| PoolFileError::Parse(e.to_string()))
+            }
+            FileFormat::Toml => {
+                toml::from_str(content).map_err(|e| PoolFileError::Parse(e.to_string()))
+            }
+        }
+    }
+
+    /// Reads a pool file along with the proxy lists it points to.
+    pub fn load(path: &Path, ids: &mut ProxyIds) -> Result<StaticPool, PoolFileError> {
+        let format = FileFormat::from_path(path)
+            .ok_or_else(|| PoolFileError::UnknownFormat(path.to_path_buf()))?;
+        let content =
+            fs::read_to_string(path).map_err(|e| PoolFileError::Io(path.to_path_buf(), e))?;
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let mut pool = Self::parse(&content, format)?.resolve(dir, ids)?;
+        pool.paths.insert(0, path.to_path_buf());
+        Ok(pool)
+    }
+
+    /// Reads the proxy lists, relative to `dir`, and builds the pool.
+    /// Proxies listed more than once are merged, with their tags.
+    pub fn resolve(self, dir: &Path, ids: &mut ProxyIds) -> Result<StaticPool, PoolFileError> {
+        let mut listed: Vec<(NewProxy, Vec<String>)> = self
+            .proxies
+            .into_iter()
+            .map(|p| {
+                let geo = Geo {
+                    country: p.country.map(|country| country.to_ascii_uppercase()),
+                    region: p.region,
+                    city: p.city,
+                    asn: p.asn,
+                };
+                let username_geo = p.username.as_deref().map(geo_from_username);
+                let proxy = NewProxy {
+                    protocol: p.protocol,
+                    host: p.host,
+                    port: p.port,
+                    username: p.username,
+                    password: p.password,
+                    provider: p.provider,
+                    proxy_type: p.proxy_type,
+                    weight: p
+                        .weight
+                        .map_or(DEFAULT_WEIGHT, |weight| weight.get().into()),
+                    priority: p.priority,
+                    geo: geo.or(username_geo.unwrap_or_default()),
+                };
+                (proxy, p.tags)
+            })
+            .collect();
+
+        let mut paths = Vec::new();
+        for source in self.sources {
+            let path = dir.join(&source.path);
+            let content =
+                fs::read_to_string(&path).map_err(|e| PoolFileError::Io(path.clone(), e))?;
+            let tags = source
+                .tags
+                .unwrap_or_else(|| vec![source.format.as_str().to_string()]);
+            // The parsers panic on malformed lines, which must
+            // not take down a server that is reloading the pool.
+            let parser = source.format.parser();
+            let parsed = panic::catch_unwind(AssertUnwindSafe(|| parser.parse_file(&content)))
+                .map_err(|_| {
+                    PoolFileError::Parse(format!("malformed proxy list {}", path.display()))
+                })?;
+            for mut proxy in parsed {
+                proxy.proxy_type = source.proxy_type.or(proxy.proxy_type);
+                if let Some(weight) = source.weight {
+                    proxy.weight = weight.get().into();
+                }
+                proxy.priority = source.priority;
+                listed.push((proxy, tags.clone()));
+            }
+            paths.push(path);
+        }
+
+        let mut proxies: HashMap<i32, Proxy> = HashMap::new();
+        let mut tags: HashMap<i32, Vec<String>> = HashMap::new();
+        for (proxy, proxy_tags) in listed {
+            let id = ids.id(&proxy);
+            proxies.entry(id).or_insert_with(|| Proxy {
+                id,
+                protocol: proxy.protocol,
+                host: proxy.host,
+                port: proxy.port.into(),
+                username: proxy.username,
+                password: proxy.password,
+                provider: proxy.provider,
+                proxy_type: proxy.proxy_type,
+                weight: proxy.weight,
+                priority: proxy.priority,
+                exit_ip: None,
+                geo: proxy.geo,
+            });
+            let all = tags.entry(id).or_default();
+            all.extend(proxy_tags);
+            all.sort();
+            all.dedup();
+        }
+
+        let mut domains = Vec::new();
+        let mut ban_rules = Vec::new();
+        let mut rule_id = 0;
+        for domain in self.domains {
+            DomainPattern::parse(&domain.host)
+                .map_err(|e| PoolFileError::InvalidDomain(domain.host.clone(), e))?;
+            let parse = |tag_expr: &str| {
+                TagExpr::parse(tag_expr)
+                    .map_err(|e| PoolFileError::InvalidTagExpr(domain.host.clone(), e))
+            };
+            let action = match &domain.action {
+                Some(action) => RouteAction::parse(action)
+                    .map_err(|e| PoolFileError::InvalidAction(domain.host.clone(), e))?,
+                None => RouteAction::default(),
+            };
+            domain
+                .limit
+                .validate()
+                .map_err(|e| PoolFileError::InvalidLimit(domain.host.clone(), e))?;
+            let tags = match (&action, &domain.tag_expr) {
+                (RouteAction::Proxy(Some(tags)), _) => Some(tags.clone()),
+                (_, Some(tag_expr)) => Some(parse(tag_expr)?),
+                (_, None) => TagExpr::any(domain.tags),
+            };
+            let route = DomainRoute {
+                action,
+                tags,
+                fallbacks: domain
+                    .fallbacks
+                    .iter()
+                    .map(|tag_expr| parse(tag_expr))
+                    .collect::<Result<_, _>>()?,
+                fallback_any: domain.fallback_any,
+                limit: domain.limit,
+            };
+            if route.is_routed() {
+                domains.push((domain.host.clone(), route));
+            }
+
+            for rule in domain.bans {
+                let invalid = |e| PoolFileError::InvalidBanRule(domain.host.clone(), e);
+                let kind: BanRuleKind = rule.kind.parse().map_err(invalid)?;
+                validate_ban_rule(kind, &rule.pattern).map_err(invalid)?;
+                rule_id += 1;
+                let rule = BanRule {
+                    id: rule_id,
+                    host: domain.host.clone(),
+                    kind: rule.kind,
+                    pattern: rule.pattern,
+                };
+                ban_rules.push((domain.host.clone(), rule));
+            }
+        }
+
+        let mut proxies: Vec<_> = proxies.into_values().map(|p| (p, None)).collect();
+        proxies.sort_by_key(|(p, _)| p.id);
+        Ok(StaticPool {
+            pool: ProxyPool::new(proxies, tags.clone(), domains),
+            tags,
+            // Domains were all checked to be valid above.
+            ban_rules: DomainRules::compile(ban_rules).0,
+            paths,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pool::ProxyFilter;
+    use time::macros::datetime;
+
+    const YAML: &str = r#"
+proxies:
+  - host: 10.0.0.1
+    port: 8080
+    username: user
+    password: pass
+    tags: [residential]
+    type: residential
+    priority: 1
+  - protocol: https
+    host: 10.0.0.2
+    port: 8443
+sources:
+  - path: webshare.txt
+    format: webshare
+    type: datacenter
+    weight: 2
+domains:
+  - host: example.com
+    tags: [residential, webshare]
+    bans:
+      - kind: status
+        pattern: "403"
+  - host: "*.example.com"
+    tag_expr: webshare AND NOT residential
+    fallbacks: [residential]
+    fallback_any: false
+  - host: internal.example.com
+    action: direct(127.0.0.1)
+"#;
+
+    const TOML: &str = r#"
+[[proxies]]
+host = "10.0.0.1"
+port = 8080
+username = "user"
+password = "pass"
+tags = ["residential"]
+type = "residential"
+priority = 1
+
+[[proxies]]
+protocol = "https"
+host = "10.0.0.2"
+port = 8443
+
+[[sources]]
+path = "webshare.txt"
+format = "webshare"
+type = "datacenter"
+weight = 2
+
+[[domains]]
+host = "example.com"
+tags = ["residential", "webshare"]
+
+[[domains.bans]]
+kind = "status"
+pattern = "403"
+
+[[domains]]
+host = "*.example.com"
+tag_expr = "webshare AND NOT residential"
+fallbacks = ["residential"]
+fallback_any = false
+
+[[domains]]
+host = "internal.example.com"
+action = "direct(127.0.0.1)"
+"#;
+
+    #[test]
+    fn test_yaml_and_toml_match() {
+        let yaml = PoolFile::parse(YAML, FileFormat::Yaml).unwrap();
+        let toml = PoolFile::parse(TOML, FileFormat::Toml).unwrap();
+        assert_eq!(yaml, toml);
+        assert_eq!(yaml.proxies[0].protocol, "http");
+        assert_eq!(yaml.proxies[1].provider, "file");
+        assert_eq!(yaml.sources[0].format, ProxyFormat::Webshare);
+
+        assert!(PoolFile::parse("proxys: []", FileFormat::Yaml).is_err());
+        assert_eq!(
+            FileFormat::from_path(Path::new("pool.yml")),
+            Some(FileFormat::Yaml)
+        );
+        assert_eq!(FileFormat::from_path(Path::new("pool.json")), None);
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = std::env::temp_dir().join(format!("locust_pool_file_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("pool.yaml");
+        fs::write(&path, YAML).unwrap();
+        fs::write(
+            dir.join("webshare.txt"),
+            "10.0.1.1:80:u:p\n10.0.0.1:8080:user:x\n",
+        )
+        .unwrap();
+
+        let mut ids = ProxyIds::default();
+        let loaded = PoolFile::load(&path, &mut ids).unwrap();
+        assert_eq!(loaded.paths, [path.clone(), dir.join("webshare.txt")]);
+        // The second proxy of the list is already in the file.
+        assert_eq!(loaded.pool.len(), 3);
+        assert_eq!(loaded.tags[&1], ["residential", "webshare"]);
+        assert_eq!(loaded.tags[&3], ["webshare"]);
+        assert_eq!(
+            loaded.ban_rules.find("example.com").unwrap()[0].kind,
+            "status"
+        );
+        assert!(loaded.ban_rules.find("Example.com:443").is_some());
+
+        // Ban rules are matched like domain routes.
+        fs::write(
+            &path,
+            "domains: [{host: '*.a.com', bans: [{kind: status, pattern: '403'}]}]",
+        )
+        .unwrap();
+        let rules = PoolFile::load(&path, &mut ids).unwrap().ban_rules;
+        assert_eq!(rules.find("www.a.com:443").unwrap()[0].host, "*.a.com");
+        assert!(rules.find("a.com").is_none());
+
+        // So are limits, which are routes of their own.
+        fs::write(
+            &path,
+            "domains: [{host: '*.a.com', limit: {concurrency: 2, rps: 0.5}}]",
+        )
+        .unwrap();
+        let limited = PoolFile::load(&path, &mut ids).unwrap().pool;
+        let limit = DomainLimit {
+            concurrency: Some(2),
+            rps: Some(0.5),
+        };
+        assert_eq!(limited.limit("WWW.a.com:443"), limit);
+        assert_eq!(limited.limit("a.com"), DomainLimit::default());
+        fs::write(&path, YAML).unwrap();
+
+        let mut pool = loaded.pool;
+        let now = datetime!(2024-01-01 00:00 UTC);
+        let mut pick = |domain, exclude: &[i32]| {
+            pool.pick(Some(domain), &ProxyFilter::default(), exclude, now)
+                .map(|(proxy, _)| proxy.id)
+        };
+        assert_eq!(pick("example.com", &[]), Some(1));
+        assert_eq!(pick("example.com", &[1]), Some(3));
+        assert_eq!(pick("www.example.com", &[]), Some(3));
+        assert_eq!(pick("www.example.com", &[3]), Some(1));
+        assert_eq!(pick("www.example.com", &[1, 3]), None);
+        assert_eq!(
+            pool.action("internal.example.com"),
+            RouteAction::Direct(Some([127, 0, 0, 1].into()))
+        );
+        assert_eq!(pool.get(2, now).unwrap().protocol, "https");
+        let first = pool.get(1, now).unwrap();
+        assert_eq!(first.proxy_type, Some(ProxyType::Residential));
+        assert_eq!((first.weight, first.priority), (1, 1));
+        let listed = pool.get(3, now).unwrap();
+        assert_eq!(listed.proxy_type, Some(ProxyType::Datacenter));
+        assert_eq!((listed.weight, listed.priority), (2, 0));
+
+        // Ids stick to proxies across reloads.
+        fs::write(
+            dir.join("webshare.txt"),
+            "10.0.1.9:80:u:p\n10.0.1.1:80:u:p\n",
+        )
+        .unwrap();
+        let mut pool = PoolFile::load(&path, &mut ids).unwrap().pool;
+        assert_eq!(pool.get(3, now).unwrap().host, "10.0.1.1");
+        assert_eq!(pool.get(4, now).unwrap().host, "10.0.1.9");
+
+        fs::write(
+            &path,
+            "domains: [{host: a.com, bans: [{kind: body, pattern: '('}]}]",
+        )
+        .unwrap();
+        assert!(matches!(
+            PoolFile::load(&path, &mut ids),
+            Err(PoolFileError::InvalidBanRule(..))
+        ));
+        fs::write(&path, "domains: [{host: a.com, tag_expr: 'a AND'}]").unwrap();
+        assert!(matches!(
+            PoolFile::load(&path, &mut ids),
+            Err(PoolFileError::InvalidTagExpr(..))
+        ));
+        fs::write(&path, "domains: [{host: a.com, action: teleport}]").unwrap();
+        assert!(matches!(
+            PoolFile::load(&path, &mut ids),
+            Err(PoolFileError::InvalidAction(..))
+        ));
+        fs::write(&path, "domains: [{host: a.com, limit: {concurrency: 0}}]").unwrap();
+        assert!(matches!(
+            PoolFile::load(&path, &mut ids),
+            Err(PoolFileError::InvalidLimit(..))
+        ));
+        fs::write(&path, "domains: [{host: a.com, fallbacks: [a, 'OR b']}]").unwrap();
+        assert!(matches!(
+            PoolFile::load(&path, &mut ids),
+            Err(PoolFileError::InvalidTagExpr(..))
+        ));
+        fs::write(&path, "proxies: [{host: a.com, port: 80, weight: 0}]").unwrap();
+        assert!(matches!(
+            PoolFile::load(&path, &mut ids),
+            Err(PoolFileError::Parse(_))
+        ));
+        fs::write(&path, "sources: [{path: webshare.txt, format: infatica}]").unwrap();
+        assert!(matches!(
+            PoolFile::load(&path, &mut ids),
+            Err(PoolFileError::Parse(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,4 +1,7 @@
-use locust_core::models::proxies::NewProxy;
+use crate::{
+    geo::geo_from_username,
+    models::proxies::{NewProxy, DEFAULT_WEIGHT},
+};
 
 use super::ProxyFileParser;
 
@@ -23,6 +26,10 @@ impl ProxyFileParser for InfaticaParser {
                     username: Some(user.into()),
                     password: Some(pass.into()),
                     provider: "infatica".into(),
+                    proxy_type: None,
+                    weight: DEFAULT_WEIGHT,
+                    priority: 0,
+                    geo: geo_from_username(user),
                 }
             })
             .collect()
@@ -32,11 +39,12 @@ impl ProxyFileParser for InfaticaParser {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::proxies::Geo;
 
     #[test]
     fn test_infatica_parser() {
         let input = "user:pass@1.1.1.1:10001
-user:pass@1.1.1.1:10002
+user-country-de:pass@1.1.1.1:10002
 ";
         let parser = InfaticaParser {};
         let r = parser.parse_file(input);
@@ -48,14 +56,25 @@ user:pass@1.1.1.1:10002
                 username: Some("user".into()),
                 password: Some("pass".into()),
                 provider: "infatica".into(),
+                proxy_type: None,
+                weight: 1,
+                priority: 0,
+                geo: Geo::default(),
             },
             NewProxy {
                 protocol: "http".into(),
                 host: "1.1.1.1".into(),
                 port: 10002,
-                username: Some("user".into()),
+                username: Some("user-country-de".into()),
                 password: Some("pass".into()),
                 provider: "infatica".into(),
+                proxy_type: None,
+                weight: 1,
+                priority: 0,
+                geo: Geo {
+                    country: Some("DE".into()),
+                    ..Geo::default()
+                },
             },
         ];
         assert_eq!(r, exp);
@@ -0,0 +1,37 @@
+use serde::Deserialize;
+
+use crate::models::proxies::NewProxy;
+
+pub mod infatica;
+pub mod webshare;
+
+pub trait ProxyFileParser {
+    fn parse_file(&self, content: &str) -> Vec<NewProxy>;
+}
+
+/// The formats of the proxy lists that providers hand out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyFormat {
+    /// `host:port:user:pass`, see [`webshare::WebshareParser`].
+    Webshare,
+    /// `user:pass@host:port`, see [`infatica::InfaticaParser`].
+    Infatica,
+}
+
+impl ProxyFormat {
+    pub fn parser(&self) -> Box<dyn ProxyFileParser> {
+        match self {
+            ProxyFormat::Webshare => Box::new(webshare::WebshareParser),
+            ProxyFormat::Infatica => Box::new(infatica::InfaticaParser),
+        }
+    }
+
+    /// The tag given to proxies imported in this format.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ProxyFormat::Webshare => "webshare",
+            ProxyFormat::Infatica => "infatica",
+        }
+    }
+}
@@ -1,4 +1,7 @@
-use locust_core::models::proxies::NewProxy;
+use crate::{
+    geo::geo_from_username,
+    models::proxies::{NewProxy, DEFAULT_WEIGHT},
+};
 
 use super::ProxyFileParser;
 
@@ -23,6 +26,10 @@ impl ProxyFileParser for WebshareParser {
                     username: Some(user.into()),
                     password: Some(pwd.into()),
                     provider: "webshare".into(),
+                    proxy_type: None,
+                    weight: DEFAULT_WEIGHT,
+                    priority: 0,
+                    geo: geo_from_username(user),
                 }
             })
             .collect()
@@ -32,6 +39,7 @@ impl ProxyFileParser for WebshareParser {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::models::proxies::Geo;
 
     #[test]
     fn test_webshare_parser() {
@@ -48,6 +56,10 @@ mod tests {
                 username: Some("user".into()),
                 password: Some("pass".into()),
                 provider: "webshare".into(),
+                proxy_type: None,
+                weight: 1,
+                priority: 0,
+                geo: Geo::default(),
             },
             NewProxy {
                 protocol: "http".into(),
@@ -56,6 +68,10 @@ mod tests {
                 username: Some("user1".into()),
                 password: Some("pass1".into()),
                 provider: "webshare".into(),
+                proxy_type: None,
+                weight: 1,
+                priority: 0,
+                geo: Geo::default(),
             },
         ];
         assert_eq!(r, exp);
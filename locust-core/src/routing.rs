@@ -0,0 +1,365 @@
+//! What is done with requests to a domain, and how their proxies
+//! are picked: through the proxies satisfying the domain's tag
+//! expression, or else its tags, then through its fallbacks in
+//! order, and then through any proxy, unless the domain has no
+//! fallback.
+
+use std::{collections::HashMap, fmt, net::IpAddr};
+
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+
+use crate::tag_expr::TagExpr;
+
+/// What is done with requests to a domain, e.g. `proxy`,
+/// `proxy(residential AND us)`, `direct`, `direct(10.0.0.5)`,
+/// `block` or `block(internal only)`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RouteAction {
+    /// Sent through a proxy, satisfying the expression when given
+    /// instead of the domain's tag expression or tags.
+    Proxy(Option<TagExpr>),
+    /// Sent straight to the origin, from the local address when given.
+    Direct(Option<IpAddr>),
+    /// Turned away, with the reason when given.
+    Block(Option<String>),
+}
+
+impl Default for RouteAction {
+    fn default() -> Self {
+        RouteAction::Proxy(None)
+    }
+}
+
+impl RouteAction {
+    pub fn parse(action: &str) -> Result<Self, String> {
+        let action = action.trim();
+        let (name, arg) = match action.split_once('(') {
+            Some((name, rest)) => {
+                let arg = rest
+                    .strip_suffix(')')
+                    .ok_or("missing closing parenthesis")?;
+                (name.trim(), Some(arg.trim()))
+            }
+            None => (action, None),
+        };
+        match (name.to_ascii_lowercase().as_str(), arg) {
+            ("proxy", None) => Ok(RouteAction::Proxy(None)),
+            ("proxy", Some(tags)) => Ok(RouteAction::Proxy(Some(TagExpr::parse(tags)?))),
+            ("direct", None) => Ok(RouteAction::Direct(None)),
+            ("direct", Some(addr)) => match addr.parse() {
+                Ok(addr) => Ok(RouteAction::Direct(Some(addr))),
+                Err(e) => Err(format!("invalid local address {addr}: {e}")),
+            },
+            ("block", None) => Ok(RouteAction::Block(None)),
+            // The reason is sent back in a header.
+            ("block", Some(reason))
+                if !reason.is_empty()
+                    && reason.chars().all(|c| c.is_ascii_graphic() || c == ' ') =>
+            {
+                Ok(RouteAction::Block(Some(reason.into())))
+            }
+            ("block", Some(_)) => Err("the reason must be printable ASCII".into()),
+            _ => Err(format!(
+                "unknown action {action}, expected proxy, direct or block"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for RouteAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RouteAction::Proxy(None) => f.write_str("proxy"),
+            RouteAction::Proxy(Some(tags)) => write!(f, "proxy({tags})"),
+            RouteAction::Direct(None) => f.write_str("direct"),
+            RouteAction::Direct(Some(addr)) => write!(f, "direct({addr})"),
+            RouteAction::Block(None) => f.write_str("block"),
+            RouteAction::Block(Some(reason)) => write!(f, "block({reason})"),
+        }
+    }
+}
+
+/// How many requests to a domain may be in flight at once and how
+/// many may start each second, each instead of the server's default
+/// domain limit when set.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DomainLimit {
+    pub concurrency: Option<u32>,
+    pub rps: Option<f64>,
+}
+
+impl DomainLimit {
+    pub fn is_set(&self) -> bool {
+        self.concurrency.is_some() || self.rps.is_some()
+    }
+
+    /// Checks that the limit allows requests at all.
+    pub fn validate(&self) -> Result<(), String> {
+        match (self.concurrency, self.rps) {
+            (Some(0), _) => Err("the concurrency must be at least 1".into()),
+            (_, Some(rps)) if !(rps.is_finite() && rps > 0.0) => {
+                Err("the rate must be a positive number".into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The proxies requests to a domain go through.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DomainRoute {
+    /// Whether requests go through a proxy at all. When they do not,
+    /// the rest of the route is left unused.
+    pub action: RouteAction,
+    /// The proxies of the domain. Without any, requests
+    /// go straight to the fallbacks.
+    pub tags: Option<TagExpr>,
+    /// Tried in order once none of the domain's proxies is left.
+    pub fallbacks: Vec<TagExpr>,
+    /// Whether any proxy is used once the fallbacks are exhausted.
+    /// Otherwise the request is turned away.
+    pub fallback_any: bool,
+    /// Overrides the server's domain limit for each matching host.
+    pub limit: DomainLimit,
+}
+
+impl DomainRoute {
+    /// Whether the route changes anything about routing or limits.
+    pub fn is_routed(&self) -> bool {
+        self.action != RouteAction::Proxy(None)
+            || self.tags.is_some()
+            || !self.fallbacks.is_empty()
+            || !self.fallback_any
+            || self.limit.is_set()
+    }
+
+    /// The tag expressions to try in order, along
+    /// with the fallback each one is.
+    pub fn tiers(&self) -> impl Iterator<Item = (&TagExpr, Option<Fallback>)> {
+        let fallbacks = self
+            .fallbacks
+            .iter()
+            .enumerate()
+            .map(|(i, tags)| (tags, Some(Fallback::Chain(i + 1))));
+        self.tags.iter().map(|tags| (tags, None)).chain(fallbacks)
+    }
+}
+
+/// The fallback of a domain that a proxy was picked from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fallback {
+    /// The fallback at this position of the chain, from 1.
+    Chain(usize),
+    /// Any proxy, once the chain is exhausted.
+    Any,
+}
+
+impl Fallback {
+    pub fn label(&self) -> String {
+        match self {
+            Fallback::Chain(position) => position.to_string(),
+            Fallback::Any => "any".into(),
+        }
+    }
+}
+
+/// A domain along with one of the tags mapped to it, if any.
+#[derive(Debug, Clone, FromRow)]
+pub struct DomainTagRow {
+    pub host: String,
+    pub tag_expr: Option<String>,
+    pub fallback_any: bool,
+    pub action: Option<String>,
+    pub max_concurrency: Option<i32>,
+    pub max_rps: Option<f64>,
+    pub tag: Option<String>,
+}
+
+/// Builds the route of every domain from its rows and the hosts and
+/// expressions of the fallbacks, in order. The expression of a
+/// `proxy` action wins over the domain's expression, which wins over
+/// its tags. Actions, expressions and limits are checked when they
+/// are set, and the ones that are invalid anyway are left out. Domains that
+/// change nothing about routing are too.
+pub fn domain_routes(
+    rows: Vec<DomainTagRow>,
+    fallbacks: Vec<(String, String)>,
+) -> Vec<(String, DomainRoute)> {
+    let mut domains: HashMap<String, (DomainTagRow, Vec<String>)> = HashMap::new();
+    for mut row in rows {
+        let tag = row.tag.take();
+        let (_, tags) = domains
+            .entry(row.host.clone())
+            .or_insert_with(|| (row, Vec::new()));
+        tags.extend(tag);
+    }
+    let mut chains: HashMap<String, Vec<TagExpr>> = HashMap::new();
+    for (host, tag_expr) in fallbacks {
+        if let Ok(tag_expr) = TagExpr::parse(&tag_expr) {
+            chains.entry(host).or_default().push(tag_expr);
+        }
+    }
+
+    let mut routes: Vec<_> = domains
+        .into_iter()
+        .map(|(host, (row, tags))| {
+            let action = row
+                .action
+                .and_then(|action| RouteAction::parse(&action).ok())
+                .unwrap_or_default();
+            let tags = match (&action, row.tag_expr) {
+                (RouteAction::Proxy(Some(tags)), _) => Some(tags.clone()),
+                (_, Some(tag_expr)) => TagExpr::parse(&tag_expr).ok(),
+                (_, None) => TagExpr::any(tags),
+            };
+            let fallbacks = chains.remove(&host).unwrap_or_default();
+            let limit = DomainLimit {
+                concurrency: row.max_concurrency.and_then(|n| u32::try_from(n).ok()),
+                rps: row.max_rps,
+            };
+            let route = DomainRoute {
+                action,
+                tags,
+                fallbacks,
+                fallback_any: row.fallback_any,
+                limit: Some(limit)
+                    .filter(|limit| limit.validate().is_ok())
+                    .unwrap_or_default(),
+            };
+            (host, route)
+        })
+        .filter(|(_, route)| route.is_routed())
+        .collect();
+    routes.sort_by(|(a, _), (b, _)| a.cmp(b));
+    routes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_domain_routes() {
+        let row =
+            |host: &str, tag_expr: Option<&str>, fallback_any, tag: Option<&str>| DomainTagRow {
+                host: host.into(),
+                tag_expr: tag_expr.map(String::from),
+                fallback_any,
+                action: None,
+                max_concurrency: None,
+                max_rps: None,
+                tag: tag.map(String::from),
+            };
+        let with_action = |action: &str, row: DomainTagRow| DomainTagRow {
+            action: Some(action.into()),
+            ..row
+        };
+        let with_limit = |max_concurrency, max_rps, row: DomainTagRow| DomainTagRow {
+            max_concurrency,
+            max_rps,
+            ..row
+        };
+        let fallback = |host: &str, tag_expr: &str| (host.to_string(), tag_expr.to_string());
+        let routes = domain_routes(
+            vec![
+                row("a.com", None, true, Some("x")),
+                row("a.com", None, true, Some("y")),
+                row("b.com", Some("x AND NOT y"), false, Some("z")),
+                row("c.com", None, true, None),
+                row("d.com", Some("x AND"), true, None),
+                row("e.com", None, true, None),
+                with_action("proxy(z)", row("f.com", Some("x"), true, Some("y"))),
+                with_action("direct", row("g.com", None, true, None)),
+                with_action("teleport", row("h.com", None, true, None)),
+                with_limit(Some(2), Some(0.5), row("i.com", None, true, None)),
+                with_limit(Some(0), None, row("j.com", None, true, None)),
+            ],
+            vec![
+                fallback("b.com", "squid"),
+                fallback("b.com", "webshare"),
+                fallback("e.com", "x OR"),
+                fallback("e.com", "y"),
+            ],
+        );
+        let actions: Vec<_> = routes
+            .iter()
+            .map(|(_, route)| route.action.to_string())
+            .collect();
+        assert_eq!(
+            actions,
+            ["proxy", "proxy", "proxy", "proxy(z)", "direct", "proxy"]
+        );
+        let limit = DomainLimit {
+            concurrency: Some(2),
+            rps: Some(0.5),
+        };
+        assert_eq!(routes[5].1.limit, limit);
+        let routes: Vec<_> = routes
+            .into_iter()
+            .map(|(host, route)| {
+                let tiers: Vec<_> = route
+                    .tiers()
+                    .map(|(tags, fallback)| (tags.to_string(), fallback))
+                    .collect();
+                (host, tiers, route.fallback_any)
+            })
+            .collect();
+        let tier = |tags: &str, fallback| (tags.to_string(), fallback);
+        assert_eq!(
+            routes,
+            [
+                ("a.com".to_string(), vec![tier("x OR y", None)], true),
+                (
+                    "b.com".to_string(),
+                    vec![
+                        tier("x AND NOT y", None),
+                        tier("squid", Some(Fallback::Chain(1))),
+                        tier("webshare", Some(Fallback::Chain(2))),
+                    ],
+                    false
+                ),
+                (
+                    "e.com".to_string(),
+                    vec![tier("y", Some(Fallback::Chain(1)))],
+                    true
+                ),
+                ("f.com".to_string(), vec![tier("z", None)], true),
+                ("g.com".to_string(), vec![], true),
+                ("i.com".to_string(), vec![], true),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_route_action() {
+        for action in [
+            "proxy",
+            "proxy(residential AND NOT infatica)",
+            "direct",
+            "direct(10.0.0.5)",
+            "direct(::1)",
+            "block",
+            "block(internal only)",
+        ] {
+            assert_eq!(RouteAction::parse(action).unwrap().to_string(), action);
+        }
+        assert_eq!(
+            RouteAction::parse(" Direct ( 10.0.0.5 ) ").unwrap(),
+            RouteAction::Direct(Some([10, 0, 0, 5].into()))
+        );
+        for invalid in [
+            "",
+            "teleport",
+            "proxy(a AND)",
+            "proxy()",
+            "direct(example.com)",
+            "block(",
+            "block()",
+            "block(caf\u{e9})",
+        ] {
+            assert!(RouteAction::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,391 @@
+//! Storage of proxies, their tags and sessions, behind a trait
+//! so that it can live in Postgres or in an embedded SQLite file.
+
+use std::{collections::HashMap, env, sync::Arc};
+
+use async_trait::async_trait;
+use sqlx::Error;
+use time::OffsetDateTime;
+
+use crate::{
+    get_conn_string,
+    models::proxies::{NewProxy, Proxy, ProxySession},
+    pool::ProxyPool,
+};
+
+pub mod postgres;
+pub mod sqlite;
+
+pub use postgres::PgStore;
+pub use sqlite::SqliteStore;
+
+/// The operations of [`crate::crud::proxies`], for any backend.
+/// See the functions there for what each one does.
+#[async_trait]
+pub trait ProxyStore: Send + Sync {
+    async fn get_proxy_by_id(&self, id: i32) -> Result<Proxy, Error>;
+    async fn get_proxy_pool(&self) -> Result<ProxyPool, Error>;
+    async fn set_proxies_last_used(&self, used: &[(i32, OffsetDateTime)]) -> Result<(), Error>;
+    async fn get_all_proxies(&self) -> Result<Vec<Proxy>, Error>;
+    async fn get_proxies_by_tags(&self, tags: &[&str]) -> Result<Vec<Proxy>, Error>;
+    async fn delete_proxies_by_ids(&self, ids: &[i32]) -> Result<(), Error>;
+    async fn delete_proxies_by_tags(&self, tags: &[&str]) -> Result<(), Error>;
+    async fn add_proxies(&self, proxies: &[NewProxy], tags: &[&str]) -> Result<(), Error>;
+    async fn add_proxy_tags(&self, proxy_id: i32, tags: &[&str]) -> Result<(), Error>;
+    async fn remove_proxy_tags(&self, proxy_id: i32, tags: &[&str]) -> Result<(), Error>;
+    async fn get_proxy_tags(&self, proxy_id: i32) -> Result<Vec<String>, Error>;
+    async fn get_proxies_tags(&self, proxy_ids: &[i32])
+        -> Result<HashMap<i32, Vec<String>>, Error>;
+    async fn get_proxy_session(&self, id: i32) -> Result<ProxySession, Error>;
+    async fn create_proxy_session(&self, proxy_id: i32) -> Result<ProxySession, Error>;
+    async fn reserve_session_ids(&self, count: i32) -> Result<Vec<i32>, Error>;
+    async fn add_proxy_sessions(&self, sessions: &[ProxySession]) -> Result<(), Error>;
+    async fn get_proxy_sessions(&self, limit: i64, offset: i64)
+        -> Result<Vec<ProxySession>, Error>;
+    async fn delete_proxy_session(&self, id: i32) -> Result<(), Error>;
+    async fn count_proxy_sessions(&self) -> Result<i64, Error>;
+}
+
+/// The URL of the database, from `LOCUST_DATABASE_URL`
+/// or else the `POSTGRES_*` variables.
+pub fn database_url() -> String {
+    env::var("LOCUST_DATABASE_URL").unwrap_or_else(|_| get_conn_string())
+}
+
+/// Whether a database URL points to Postgres.
+pub fn is_postgres(url: &str) -> bool {
+    url.starts_with("postgres://") || url.starts_with("postgresql://")
+}
+
+/// Connects to the store at `url`, picking the backend by its scheme.
+/// SQLite databases are created and migrated as needed.
+pub async fn connect(url: &str) -> Result<Arc<dyn ProxyStore>, Error> {
+    if is_postgres(url) {
+        Ok(Arc::new(PgStore::connect(url).await?))
+    } else if url.starts_with("sqlite:") {
+        let store = SqliteStore::connect(url).await?;
+        store.migrate().await?;
+        Ok(Arc::new(store))
+    } else {
+        let scheme = url.split(':').next().unwrap_or_default();
+        Err(Error::Configuration(
+            format!("unsupported database scheme: {scheme}").into(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        crud::exits::{get_proxy_exits, set_proxy_exit},
+        models::proxies::{Geo, ProxyType},
+        pool::ProxyFilter,
+        routing::RouteAction,
+    };
+    use futures::future::BoxFuture;
+    use sqlx::{
+        postgres::{PgConnectOptions, PgPoolOptions},
+        Executor,
+    };
+    use std::str::FromStr;
+    use time::macros::datetime;
+
+    fn new_proxy(host: &str) -> NewProxy {
+        NewProxy {
+            protocol: "http".into(),
+            host: host.into(),
+            port: 8080,
+            username: Some("user".into()),
+            password: Some("pass".into()),
+            provider: "local".into(),
+            proxy_type: None,
+            weight: 1,
+            priority: 0,
+            geo: Default::default(),
+        }
+    }
+
+    fn ids(proxies: &[Proxy]) -> Vec<i32> {
+        let mut ids: Vec<_> = proxies.iter().map(|p| p.id).collect();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Picks a proxy from a freshly loaded pool, the way the server does.
+    async fn pick(store: &dyn ProxyStore, domain: Option<&str>, exclude: &[i32]) -> Option<i32> {
+        let mut pool = store.get_proxy_pool().await.unwrap();
+        let now = OffsetDateTime::now_utc();
+        let picked = pool.pick(domain, &ProxyFilter::default(), exclude, now);
+        picked.map(|(proxy, _)| proxy.id)
+    }
+
+    /// Runs every store operation against `store`. Domains are set up
+    /// with `execute`, which runs a statement on the same database.
+    async fn crud_suite(
+        store: &dyn ProxyStore,
+        execute: impl Fn(&'static str) -> BoxFuture<'static, ()>,
+    ) {
+        store
+            .add_proxies(&[new_proxy("a1"), new_proxy("a2"), new_proxy("a3")], &["a"])
+            .await
+            .unwrap();
+        let geo = Geo {
+            country: Some("DE".into()),
+            region: None,
+            city: Some("Berlin".into()),
+            asn: Some(3320),
+        };
+        let located = NewProxy {
+            proxy_type: Some(ProxyType::Mobile),
+            weight: 3,
+            priority: -1,
+            geo: geo.clone(),
+            ..new_proxy("b1")
+        };
+        store.add_proxies(&[located], &["b"]).await.unwrap();
+        let all = store.get_all_proxies().await.unwrap();
+        let hosts: Vec<_> = all.iter().map(|p| p.host.as_str()).collect();
+        assert_eq!(hosts, ["a1", "a2", "a3", "b1"]);
+        let [p1, p2, p3, p4] = [all[0].id, all[1].id, all[2].id, all[3].id];
+        assert_eq!(all[0].password.as_deref(), Some("pass"));
+        assert_eq!(all[3].geo, geo);
+        assert_eq!(all[3].proxy_type, Some(ProxyType::Mobile));
+        assert_eq!((all[3].weight, all[3].priority), (3, -1));
+        assert_eq!((all[0].proxy_type, all[0].weight), (None, 1));
+        assert_eq!(all[0].exit_ip, None);
+
+        // Tags
+        let tagged = store.get_proxies_by_tags(&["a"]).await.unwrap();
+        assert_eq!(ids(&tagged), [p1, p2, p3]);
+        store.add_proxy_tags(p4, &["a", "c"]).await.unwrap();
+        store.add_proxy_tags(p4, &["c"]).await.unwrap();
+        assert_eq!(store.get_proxy_tags(p4).await.unwrap(), ["a", "b", "c"]);
+        store.remove_proxy_tags(p4, &["a"]).await.unwrap();
+        assert_eq!(store.get_proxy_tags(p4).await.unwrap(), ["b", "c"]);
+        let tags = store.get_proxies_tags(&[p1, p4, 999]).await.unwrap();
+        assert_eq!(tags.len(), 2);
+        assert_eq!(tags[&p1], ["a"]);
+        assert_eq!(tags[&p4], ["b", "c"]);
+
+        // Picking proxies
+        execute("INSERT INTO locust_domains (host) VALUES ('example.com')").await;
+        execute(
+            "INSERT INTO locust_domain_tag_map (domain_id, tag_id)
+             SELECT d.id, t.id FROM locust_domains as d, locust_tags as t
+             WHERE d.host = 'example.com' AND t.name = 'b'",
+        )
+        .await;
+        assert_eq!(pick(store, Some("example.com"), &[]).await, Some(p4));
+        execute("INSERT INTO locust_domains (host) VALUES ('*.example.com')").await;
+        execute(
+            "INSERT INTO locust_domain_tag_map (domain_id, tag_id)
+             SELECT d.id, t.id FROM locust_domains as d, locust_tags as t
+             WHERE d.host = '*.example.com' AND t.name = 'c'",
+        )
+        .await;
+        store.add_proxy_tags(p1, &["c"]).await.unwrap();
+        let proxy = pick(store, Some("www.example.com:8443"), &[]).await;
+        assert_eq!(proxy, Some(p1));
+        store.remove_proxy_tags(p1, &["c"]).await.unwrap();
+        execute(
+            "INSERT INTO locust_domains (host, tag_expr)
+             VALUES ('api.example.com', 'a AND NOT (b OR c)')",
+        )
+        .await;
+        store.add_proxy_tags(p2, &["c"]).await.unwrap();
+        let proxy = pick(store, Some("api.example.com"), &[p1]).await;
+        assert_eq!(proxy, Some(p3));
+        store.remove_proxy_tags(p2, &["c"]).await.unwrap();
+        execute(
+            "INSERT INTO locust_domains (host, tag_expr, fallback_any)
+             VALUES ('example.net', 'd', false)",
+        )
+        .await;
+        execute(
+            "INSERT INTO locust_domain_fallbacks (domain_id, position, tag_expr)
+             SELECT id, 0, 'b' FROM locust_domains WHERE host = 'example.net'",
+        )
+        .await;
+        assert_eq!(pick(store, Some("example.net"), &[]).await, Some(p4));
+        assert_eq!(pick(store, Some("example.net"), &[p4]).await, None);
+        execute(
+            "INSERT INTO locust_domains (host, tag_expr, fallback_any)
+             VALUES ('example.de', 'country:de AND asn:3320 AND NOT city:paris', false)",
+        )
+        .await;
+        assert_eq!(pick(store, Some("example.de"), &[]).await, Some(p4));
+        assert_eq!(pick(store, Some("example.de"), &[p4]).await, None);
+        let proxy = pick(store, Some("example.com"), &[p4]).await;
+        assert!(proxy.is_some_and(|id| id != p4));
+        assert_eq!(pick(store, None, &[p1, p2, p3]).await, Some(p4));
+        assert_eq!(pick(store, None, &[p1, p2, p3, p4]).await, None);
+        // Proxies of a higher priority come first, whenever they were used.
+        execute("UPDATE locust_proxies SET priority = 1 WHERE host = 'a2'").await;
+        assert_eq!(pick(store, None, &[]).await, Some(p2));
+        assert_eq!(pick(store, Some("api.example.com"), &[]).await, Some(p2));
+        execute("UPDATE locust_proxies SET priority = 0").await;
+
+        store
+            .set_proxies_last_used(&[
+                (p1, datetime!(2024-01-01 10:00 UTC)),
+                (p2, datetime!(2024-01-01 09:00 UTC)),
+                (p3, datetime!(2024-01-01 12:00 UTC)),
+                (p4, datetime!(2024-01-01 11:00 UTC)),
+            ])
+            .await
+            .unwrap();
+        // Among proxies of the same weight, the least recently used.
+        assert_eq!(pick(store, None, &[p4]).await, Some(p2));
+
+        execute("UPDATE locust_domains SET action = 'block(no)' WHERE host = '*.example.com'")
+            .await;
+        let pool = store.get_proxy_pool().await.unwrap();
+        assert_eq!(
+            pool.action("www.example.com"),
+            RouteAction::Block(Some("no".into()))
+        );
+        execute("INSERT INTO locust_domains (host, max_concurrency, max_rps) VALUES ('example.org', 3, 0.5)")
+            .await;
+        let mut pool = store.get_proxy_pool().await.unwrap();
+        assert_eq!(pool.limit("example.org").concurrency, Some(3));
+        assert_eq!(pool.limit("example.org").rps, Some(0.5));
+        assert_eq!(pool.len(), 4);
+        let now = datetime!(2024-01-02 00:00 UTC);
+        let mut pick = |domain| {
+            pool.pick(Some(domain), &ProxyFilter::default(), &[], now)
+                .unwrap()
+                .0
+                .id
+        };
+        assert_eq!(pick("example.com"), p4);
+        assert_eq!(pick("api.example.com"), p2);
+        assert_eq!(pick("example.net"), p4);
+
+        // Deleting proxies
+        store.delete_proxies_by_ids(&[p1]).await.unwrap();
+        assert_eq!(store.get_proxy_by_id(p1).await.unwrap().host, "a1");
+        let mut pool = store.get_proxy_pool().await.unwrap();
+        assert_eq!(pool.len(), 3);
+        assert_eq!(pool.get(p1, now), None);
+        assert_eq!(pool.get(p2, now).unwrap().host, "a2");
+        store.delete_proxies_by_tags(&["a"]).await.unwrap();
+        assert_eq!(ids(&store.get_all_proxies().await.unwrap()), [p4]);
+
+        // Sessions
+        let first = store.create_proxy_session(p4).await.unwrap();
+        assert_eq!(store.get_proxy_session(first.id).await.unwrap(), first);
+        let reserved = store.reserve_session_ids(3).await.unwrap();
+        assert_eq!(reserved.len(), 3);
+        assert!(reserved.iter().all(|id| *id > first.id));
+        let sessions: Vec<_> = reserved
+            .iter()
+            .map(|&id| ProxySession { id, proxy_id: p4 })
+            .collect();
+        store.add_proxy_sessions(&sessions).await.unwrap();
+        store.add_proxy_sessions(&sessions[..1]).await.unwrap();
+        let last = store.create_proxy_session(p4).await.unwrap();
+        assert!(reserved.iter().all(|id| *id < last.id));
+
+        assert_eq!(store.count_proxy_sessions().await.unwrap(), 5);
+        let page = store.get_proxy_sessions(2, 0).await.unwrap();
+        assert_eq!(page[0], last);
+        assert_eq!(page.len(), 2);
+        store.delete_proxy_session(first.id).await.unwrap();
+        assert!(matches!(
+            store.delete_proxy_session(first.id).await,
+            Err(Error::RowNotFound)
+        ));
+        assert!(matches!(
+            store.get_proxy_session(first.id).await,
+            Err(Error::RowNotFound)
+        ));
+        assert_eq!(store.count_proxy_sessions().await.unwrap(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_store() {
+        let store = SqliteStore::connect("sqlite::memory:").await.unwrap();
+        store.migrate().await.unwrap();
+        let pool = store.pool().clone();
+        crud_suite(&store, |sql| {
+            let pool = pool.clone();
+            Box::pin(async move {
+                pool.execute(sql).await.unwrap();
+            })
+        })
+        .await;
+    }
+
+    /// Runs against the Postgres at `LOCUST_TEST_DATABASE_URL`, in a
+    /// schema of its own that is dropped afterwards. Skipped when
+    /// the variable is not set.
+    #[tokio::test]
+    async fn test_postgres_store() {
+        let Ok(url) = env::var("LOCUST_TEST_DATABASE_URL") else {
+            eprintln!("LOCUST_TEST_DATABASE_URL is not set, skipping");
+            return;
+        };
+        let schema = format!("locust_test_{}", std::process::id());
+        let admin = PgPoolOptions::new().connect(&url).await.unwrap();
+        admin
+            .execute(
+                format!("DROP SCHEMA IF EXISTS {schema} CASCADE; CREATE SCHEMA {schema}").as_str(),
+            )
+            .await
+            .unwrap();
+
+        let options = PgConnectOptions::from_str(&url)
+            .unwrap()
+            .options([("search_path", schema.as_str())]);
+        let pool = PgPoolOptions::new().connect_with(options).await.unwrap();
+        for migration in [
+            include_str!("../../../migrations/V1__init.sql"),
+            include_str!("../../../migrations/V2__sessions.sql"),
+            include_str!("../../../migrations/V4__proxy_health.sql"),
+            include_str!("../../../migrations/V8__pool_notify.sql"),
+            include_str!("../../../migrations/V9__domain_tag_expr.sql"),
+            include_str!("../../../migrations/V10__domain_fallbacks.sql"),
+            include_str!("../../../migrations/V11__domain_actions.sql"),
+            include_str!("../../../migrations/V12__proxy_geo.sql"),
+            include_str!("../../../migrations/V13__proxy_priority.sql"),
+            include_str!("../../../migrations/V14__proxy_exit_ip.sql"),
+            include_str!("../../../migrations/V15__domain_limits.sql"),
+        ] {
+            pool.execute(migration).await.unwrap();
+        }
+
+        let store = PgStore::new(pool.clone());
+        crud_suite(&store, |sql| {
+            let pool = pool.clone();
+            Box::pin(async move {
+                pool.execute(sql).await.unwrap();
+            })
+        })
+        .await;
+
+        // Exit addresses, which only Postgres records.
+        store.add_proxies(&[new_proxy("c1")], &["c"]).await.unwrap();
+        let live = ids(&store.get_all_proxies().await.unwrap());
+        let [p4, p5] = [live[0], live[1]];
+        set_proxy_exit(&pool, p4, "192.0.2.1").await.unwrap();
+        set_proxy_exit(&pool, p5, "192.0.2.1").await.unwrap();
+        let exits = get_proxy_exits(&pool).await.unwrap();
+        assert_eq!(exits[0].exit_ip.as_deref(), Some("192.0.2.1"));
+        assert!(exits[0].date_exit_seen.is_some());
+        assert_eq!(exits[0].shared_with, [p5]);
+        assert_eq!(exits[1].shared_with, [p4]);
+        store.delete_proxies_by_ids(&[p5]).await.unwrap();
+        let exits = get_proxy_exits(&pool).await.unwrap();
+        assert_eq!(exits.len(), 1);
+        assert!(!exits[0].is_shared());
+        let proxy = store.get_proxy_by_id(p4).await.unwrap();
+        assert_eq!(proxy.exit_ip.as_deref(), Some("192.0.2.1"));
+
+        pool.close().await;
+        admin
+            .execute(format!("DROP SCHEMA {schema} CASCADE").as_str())
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use sqlx::{postgres::PgPoolOptions, Error, PgPool};
+use time::OffsetDateTime;
+
+use super::ProxyStore;
+use crate::{
+    crud::proxies,
+    models::proxies::{NewProxy, Proxy, ProxySession},
+    pool::ProxyPool,
+};
+
+/// The Postgres store, backed by the functions in [`crate::crud::proxies`].
+#[derive(Debug, Clone)]
+pub struct PgStore {
+    pool: PgPool,
+}
+
+impl PgStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn connect(url: &str) -> Result<Self, Error> {
+        Ok(Self::new(PgPoolOptions::new().connect(url).await?))
+    }
+
+    pub fn pool(&self) -> &PgPool {
+        &self.pool
+    }
+}
+
+#[async_trait]
+impl ProxyStore for PgStore {
+    async fn get_proxy_by_id(&self, id: i32) -> Result<Proxy, Error> {
+        proxies::get_proxy_by_id(&self.pool, id).await
+    }
+
+    async fn get_proxy_pool(&self) -> Result<ProxyPool, Error> {
+        proxies::get_proxy_pool(&self.pool).await
+    }
+
+    async fn set_proxies_last_used(&self, used: &[(i32, OffsetDateTime)]) -> Result<(), Error> {
+        proxies::set_proxies_last_used(&self.pool, used).await
+    }
+
+    async fn get_all_proxies(&self) -> Result<Vec<Proxy>, Error> {
+        proxies::get_all_proxies(&self.pool).await
+    }
+
+    async fn get_proxies_by_tags(&self, tags: &[&str]) -> Result<Vec<Proxy>, Error> {
+        proxies::get_proxies_by_tags(&self.pool, tags).await
+    }
+
+    async fn delete_proxies_by_ids(&self, ids: &[i32]) -> Result<(), Error> {
+        proxies::delete_proxies_by_ids(&self.pool, ids).await
+    }
+
+    async fn delete_proxies_by_tags(&self, tags: &[&str]) -> Result<(), Error> {
+        proxies::delete_proxies_by_tags(&self.pool, tags).await
+    }
+
+    async fn add_proxies(&self, new: &[NewProxy], tags: &[&str]) -> Result<(), Error> {
+        proxies::add_proxies(&self.pool, new, tags).await
+    }
+
+    async fn add_proxy_tags(&self, proxy_id: i32, tags: &[&str]) -> Result<(), Error> {
+        proxies::add_proxy_tags(&self.pool, proxy_id, tags).await
+    }
+
+    async fn remove_proxy_tags(&self, proxy_id: i32, tags: &[&str]) -> Result<(), Error> {
+        proxies::remove_proxy_tags(&self.pool, proxy_id, tags).await
+    }
+
+    async fn get_proxy_tags(&self, proxy_id: i32) -> Result<Vec<String>, Error> {
+        proxies::get_proxy_tags(&self.pool, proxy_id).await
+    }
+
+    async fn get_proxies_tags(
+        &self,
+        proxy_ids: &[i32],
+    ) -> Result<HashMap<i32, Vec<String>>, Error> {
+        proxies::get_proxies_tags(&self.pool, proxy_ids).await
+    }
+
+    async fn get_proxy_session(&self, id: i32) -> Result<ProxySession, Error> {
+        proxies::get_proxy_session(&self.pool, id).await
+    }
+
+    async fn create_proxy_session(&self, proxy_id: i32) -> Result<ProxySession, Error> {
+        proxies::create_proxy_session(&self.pool, proxy_id).await
+    }
+
+    async fn reserve_session_ids(&self, count: i32) -> Result<Vec<i32>, Error> {
+        proxies::reserve_session_ids(&self.pool, count).await
+    }
+
+    async fn add_proxy_sessions(&self, sessions: &[ProxySession]) -> Result<(), Error> {
+        proxies::add_proxy_sessions(&self.pool, sessions).await
+    }
+
+    async fn get_proxy_sessions(
+        &self,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<ProxySession>, Error> {
+        proxies::get_proxy_sessions(&self.pool, limit, offset).await
+    }
+
+    async fn delete_proxy_session(&self, id: i32) -> Result<(), Error> {
+        proxies::delete_proxy_session(&self.pool, id).await
+    }
+
+    async fn count_proxy_sessions(&self) -> Result<i64, Error> {
+        proxies::count_proxy_sessions(&self.pool).await
+    }
+}
@@ -0,0 +1,473 @@
+use std::{collections::HashMap, str::FromStr};
+
+use async_trait::async_trait;
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions, SqliteRow},
+    Error, FromRow, Row, Sqlite, SqlitePool, Transaction,
+};
+use time::OffsetDateTime;
+
+use super::ProxyStore;
+use crate::{
+    models::proxies::{NewProxy, Proxy, ProxySession},
+    pool::ProxyPool,
+    routing::{domain_routes, DomainRoute},
+};
+
+const PROXY_COLUMNS: &str =
+    "p.id, p.protocol, p.host, p.port, p.username, p.password, p.provider, \
+    p.type, p.weight, p.priority, p.exit_ip, p.country, p.region, p.city, p.asn";
+
+/// A store in an embedded SQLite database, for
+/// deployments that can do without Postgres.
+#[derive(Debug, Clone)]
+pub struct SqliteStore {
+    pool: SqlitePool,
+}
+
+impl SqliteStore {
+    /// Opens the database at `url` (e.g. `sqlite://locust.db` or
+    /// `sqlite::memory:`), creating the file if it does not exist.
+    pub async fn connect(url: &str) -> Result<Self, Error> {
+        let options = SqliteConnectOptions::from_str(url)?
+            .create_if_missing(true)
+            .foreign_keys(true);
+        // An in-memory database is gone once its last connection
+        // closes, so one connection is kept open for good.
+        let pool = if url.contains(":memory:") {
+            SqlitePoolOptions::new()
+                .min_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None)
+                .connect_with(options)
+                .await?
+        } else {
+            SqlitePoolOptions::new().connect_with(options).await?
+        };
+        Ok(Self { pool })
+    }
+
+    pub fn pool(&self) -> &SqlitePool {
+        &self.pool
+    }
+
+    /// Applies the migrations in `locust-core/migrations/sqlite`.
+    pub async fn migrate(&self) -> Result<(), Error> {
+        sqlx::migrate!("./migrations/sqlite")
+            .run(&self.pool)
+            .await
+            .map_err(|e| Error::Migrate(Box::new(e)))
+    }
+
+    /// See [`crate::crud::domains::get_domain_routes`].
+    async fn get_domain_routes(&self) -> Result<Vec<(String, DomainRoute)>, Error> {
+        let rows = sqlx::query_as(
+            r#"
+                SELECT d.host, d.tag_expr, d.fallback_any, d.action,
+                    d.max_concurrency, d.max_rps, t.name as tag
+                FROM locust_domains as d
+                LEFT JOIN locust_domain_tag_map as dtm ON d.id = dtm.domain_id
+                LEFT JOIN locust_tags as t ON t.id = dtm.tag_id
+                WHERE d.date_deleted IS NULL
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        let fallbacks = sqlx::query_as(
+            r#"
+                SELECT d.host, f.tag_expr
+                FROM locust_domain_fallbacks as f
+                JOIN locust_domains as d ON d.id = f.domain_id
+                WHERE d.date_deleted IS NULL
+                ORDER BY f.domain_id, f.position
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(domain_routes(rows, fallbacks))
+    }
+
+    async fn update_proxy_last_used(&self, id: i32) -> Result<(), Error> {
+        sqlx::query("UPDATE locust_proxies SET date_last_used = ? WHERE id = ?")
+            .bind(OffsetDateTime::now_utc())
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+/// Placeholders for binding a list of `n` values.
+fn list(n: usize) -> String {
+    vec!["?"; n].join(", ")
+}
+
+/// Inserts the given tags if they do not exist yet and
+/// returns their ids, in the same order as the input.
+async fn upsert_tags(tx: &mut Transaction<'_, Sqlite>, tags: &[&str]) -> Result<Vec<i32>, Error> {
+    let mut tag_ids = Vec::with_capacity(tags.len());
+    for tag in tags {
+        let id = sqlx::query_scalar(
+            r#"
+                INSERT INTO locust_tags (name)
+                VALUES (?) ON CONFLICT (name) DO UPDATE
+                SET name = excluded.name
+                RETURNING id
+            "#,
+        )
+        .bind(tag)
+        .fetch_one(&mut **tx)
+        .await?;
+        tag_ids.push(id);
+    }
+
+    Ok(tag_ids)
+}
+
+#[async_trait]
+impl ProxyStore for SqliteStore {
+    async fn get_proxy_by_id(&self, id: i32) -> Result<Proxy, Error> {
+        let proxy = sqlx::query_as::<_, Proxy>(&format!(
+            "SELECT {PROXY_COLUMNS} FROM locust_proxies as p WHERE p.id = ?"
+        ))
+        .bind(id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        self.update_proxy_last_used(id).await?;
+        Ok(proxy)
+    }
+
+    async fn get_proxy_pool(&self) -> Result<ProxyPool, Error> {
+        let rows: Vec<SqliteRow> = sqlx::query(&format!(
+            r#"
+                SELECT {PROXY_COLUMNS}, p.date_last_used
+                FROM locust_proxies as p
+                WHERE p.date_deleted IS NULL
+                AND p.date_quarantined IS NULL
+            "#
+        ))
+        .fetch_all(&self.pool)
+        .await?;
+        let proxies = rows
+            .iter()
+            .map(|row| Ok((Proxy::from_row(row)?, row.try_get("date_last_used")?)))
+            .collect::<Result<_, Error>>()?;
+
+        let rows: Vec<(i32, String)> = sqlx::query_as(
+            r#"
+                SELECT ptm.proxy_id, t.name
+                FROM locust_proxy_tag_map as ptm
+                JOIN locust_tags as t ON ptm.tag_id = t.id
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        let mut tags: HashMap<i32, Vec<String>> = HashMap::new();
+        for (proxy_id, name) in rows {
+            tags.entry(proxy_id).or_default().push(name);
+        }
+
+        let domains = self.get_domain_routes().await?;
+        Ok(ProxyPool::new(proxies, tags, domains))
+    }
+
+    async fn set_proxies_last_used(&self, used: &[(i32, OffsetDateTime)]) -> Result<(), Error> {
+        let mut tx = self.pool.begin().await?;
+        for (id, date) in used {
+            sqlx::query("UPDATE locust_proxies SET date_last_used = ? WHERE id = ?")
+                .bind(date)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await
+    }
+
+    async fn get_all_proxies(&self) -> Result<Vec<Proxy>, Error> {
+        sqlx::query_as::<_, Proxy>(&format!(
+            r#"
+                SELECT {PROXY_COLUMNS} FROM locust_proxies as p
+                WHERE p.date_deleted IS NULL
+                ORDER BY p.id
+            "#
+        ))
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn get_proxies_by_tags(&self, tags: &[&str]) -> Result<Vec<Proxy>, Error> {
+        let sql = format!(
+            r#"
+                SELECT {PROXY_COLUMNS}
+                FROM locust_proxies as p
+                JOIN locust_proxy_tag_map as ptm ON p.id = ptm.proxy_id
+                JOIN locust_tags as t ON ptm.tag_id = t.id
+                WHERE t.name IN ({})
+                AND p.date_deleted IS NULL
+            "#,
+            list(tags.len())
+        );
+        let mut query = sqlx::query_as::<_, Proxy>(&sql);
+        for tag in tags {
+            query = query.bind(tag);
+        }
+        query.fetch_all(&self.pool).await
+    }
+
+    async fn delete_proxies_by_ids(&self, ids: &[i32]) -> Result<(), Error> {
+        let sql = format!(
+            "UPDATE locust_proxies SET date_deleted = ? WHERE id IN ({})",
+            list(ids.len())
+        );
+        let mut query = sqlx::query(&sql).bind(OffsetDateTime::now_utc());
+        for id in ids {
+            query = query.bind(id);
+        }
+        query.execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn delete_proxies_by_tags(&self, tags: &[&str]) -> Result<(), Error> {
+        let sql = format!(
+            r#"
+                UPDATE locust_proxies
+                SET date_deleted = ?
+                WHERE date_deleted IS NULL
+                AND id IN (
+                    SELECT ptm.proxy_id
+                    FROM locust_proxy_tag_map as ptm
+                    JOIN locust_tags as t ON ptm.tag_id = t.id
+                    WHERE t.name IN ({})
+                )
+            "#,
+            list(tags.len())
+        );
+        let mut query = sqlx::query(&sql).bind(OffsetDateTime::now_utc());
+        for tag in tags {
+            query = query.bind(tag);
+        }
+        query.execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn add_proxies(&self, proxies: &[NewProxy], tags: &[&str]) -> Result<(), Error> {
+        let mut tx = self.pool.begin().await?;
+        let tag_ids = upsert_tags(&mut tx, tags).await?;
+        let now = OffsetDateTime::now_utc();
+
+        for proxy in proxies {
+            let proxy_id: i32 = sqlx::query_scalar(
+                r#"
+                    INSERT INTO locust_proxies
+                    (protocol, host, port, username, password, provider,
+                     type, weight, priority, country, region, city, asn,
+                     date_created, date_modified)
+                    VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+                    RETURNING id
+                "#,
+            )
+            .bind(&proxy.protocol)
+            .bind(&proxy.host)
+            .bind(proxy.port)
+            .bind(&proxy.username)
+            .bind(&proxy.password)
+            .bind(&proxy.provider)
+            .bind(proxy.proxy_type.map(|t| t.as_str()))
+            .bind(proxy.weight)
+            .bind(proxy.priority)
+            .bind(&proxy.geo.country)
+            .bind(&proxy.geo.region)
+            .bind(&proxy.geo.city)
+            .bind(proxy.geo.asn)
+            .bind(now)
+            .bind(now)
+            .fetch_one(&mut *tx)
+            .await?;
+
+            for tag_id in &tag_ids {
+                sqlx::query("INSERT INTO locust_proxy_tag_map (proxy_id, tag_id) VALUES (?, ?)")
+                    .bind(proxy_id)
+                    .bind(tag_id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+        }
+
+        tx.commit().await
+    }
+
+    async fn add_proxy_tags(&self, proxy_id: i32, tags: &[&str]) -> Result<(), Error> {
+        let mut tx = self.pool.begin().await?;
+        let tag_ids = upsert_tags(&mut tx, tags).await?;
+        for tag_id in &tag_ids {
+            sqlx::query(
+                r#"
+                    INSERT INTO locust_proxy_tag_map (proxy_id, tag_id)
+                    SELECT ?1, ?2
+                    WHERE NOT EXISTS (
+                        SELECT 1 FROM locust_proxy_tag_map
+                        WHERE proxy_id = ?1 AND tag_id = ?2
+                    )
+                "#,
+            )
+            .bind(proxy_id)
+            .bind(tag_id)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await
+    }
+
+    async fn remove_proxy_tags(&self, proxy_id: i32, tags: &[&str]) -> Result<(), Error> {
+        let sql = format!(
+            r#"
+                DELETE FROM locust_proxy_tag_map
+                WHERE proxy_id = ?
+                AND tag_id IN (
+                    SELECT id FROM locust_tags WHERE name IN ({})
+                )
+            "#,
+            list(tags.len())
+        );
+        let mut query = sqlx::query(&sql).bind(proxy_id);
+        for tag in tags {
+            query = query.bind(tag);
+        }
+        query.execute(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn get_proxy_tags(&self, proxy_id: i32) -> Result<Vec<String>, Error> {
+        sqlx::query_scalar(
+            r#"
+                SELECT t.name
+                FROM locust_tags as t
+                JOIN locust_proxy_tag_map as ptm ON t.id = ptm.tag_id
+                WHERE ptm.proxy_id = ?
+                ORDER BY t.name
+            "#,
+        )
+        .bind(proxy_id)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn get_proxies_tags(
+        &self,
+        proxy_ids: &[i32],
+    ) -> Result<HashMap<i32, Vec<String>>, Error> {
+        let sql = format!(
+            r#"
+                SELECT ptm.proxy_id, t.name
+                FROM locust_tags as t
+                JOIN locust_proxy_tag_map as ptm ON t.id = ptm.tag_id
+                WHERE ptm.proxy_id IN ({})
+                ORDER BY ptm.proxy_id, t.name
+            "#,
+            list(proxy_ids.len())
+        );
+        let mut query = sqlx::query_as::<_, (i32, String)>(&sql);
+        for id in proxy_ids {
+            query = query.bind(id);
+        }
+
+        let mut tags: HashMap<i32, Vec<String>> = HashMap::new();
+        for (proxy_id, name) in query.fetch_all(&self.pool).await? {
+            tags.entry(proxy_id).or_default().push(name);
+        }
+        Ok(tags)
+    }
+
+    async fn get_proxy_session(&self, id: i32) -> Result<ProxySession, Error> {
+        sqlx::query_as::<_, ProxySession>("SELECT id, proxy_id FROM locust_sessions WHERE id = ?")
+            .bind(id)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    async fn create_proxy_session(&self, proxy_id: i32) -> Result<ProxySession, Error> {
+        sqlx::query_as::<_, ProxySession>(
+            "INSERT INTO locust_sessions (proxy_id) VALUES (?) RETURNING id, proxy_id",
+        )
+        .bind(proxy_id)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    /// SQLite has no sequences, so the ids are taken by moving
+    /// the `AUTOINCREMENT` counter of the sessions table ahead.
+    async fn reserve_session_ids(&self, count: i32) -> Result<Vec<i32>, Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"
+                INSERT INTO sqlite_sequence (name, seq)
+                SELECT 'locust_sessions', 0
+                WHERE NOT EXISTS (
+                    SELECT 1 FROM sqlite_sequence WHERE name = 'locust_sessions'
+                )
+            "#,
+        )
+        .execute(&mut *tx)
+        .await?;
+        let last: i32 = sqlx::query_scalar(
+            r#"
+                UPDATE sqlite_sequence SET seq = seq + ?
+                WHERE name = 'locust_sessions'
+                RETURNING seq
+            "#,
+        )
+        .bind(count)
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok((last - count + 1..=last).collect())
+    }
+
+    async fn add_proxy_sessions(&self, sessions: &[ProxySession]) -> Result<(), Error> {
+        let mut tx = self.pool.begin().await?;
+        for session in sessions {
+            sqlx::query("INSERT OR IGNORE INTO locust_sessions (id, proxy_id) VALUES (?, ?)")
+                .bind(session.id)
+                .bind(session.proxy_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await
+    }
+
+    async fn get_proxy_sessions(
+        &self,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<ProxySession>, Error> {
+        sqlx::query_as::<_, ProxySession>(
+            "SELECT id, proxy_id FROM locust_sessions ORDER BY id DESC LIMIT ? OFFSET ?",
+        )
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    async fn delete_proxy_session(&self, id: i32) -> Result<(), Error> {
+        let res = sqlx::query("DELETE FROM locust_sessions WHERE id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+
+        if res.rows_affected() == 0 {
+            return Err(Error::RowNotFound);
+        }
+        Ok(())
+    }
+
+    async fn count_proxy_sessions(&self) -> Result<i64, Error> {
+        sqlx::query_scalar("SELECT count(*) FROM locust_sessions")
+            .fetch_one(&self.pool)
+            .await
+    }
+}
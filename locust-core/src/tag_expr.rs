@@ -0,0 +1,394 @@
+//! Boolean expressions over proxy tags, which pick the proxies
+//! used for a domain or a single request, e.g.
+//! `residential AND us AND NOT infatica`.
+//!
+//! `NOT` binds tighter than `AND`, which binds tighter than `OR`,
+//! and parentheses group. The operators are case insensitive,
+//! so tags cannot be named `and`, `or` or `not`.
+//!
+//! Besides tags, expressions can match where proxies are located,
+//! with `country:us`, `region:california`, `city:new_york` and
+//! `asn:7922`. Names are case insensitive, with `_` for spaces.
+
+use std::fmt;
+
+use crate::models::proxies::Geo;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TagExpr {
+    Tag(String),
+    /// An attribute of the location of proxies, with the value it
+    /// must have. Proxies where it is unknown never match.
+    Attr(Attr, String),
+    Not(Box<TagExpr>),
+    And(Box<TagExpr>, Box<TagExpr>),
+    Or(Box<TagExpr>, Box<TagExpr>),
+}
+
+/// The attributes of [`Geo`] that expressions can match.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Attr {
+    Country,
+    Region,
+    City,
+    Asn,
+}
+
+impl Attr {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Attr::Country => "country",
+            Attr::Region => "region",
+            Attr::City => "city",
+            Attr::Asn => "asn",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        [Attr::Country, Attr::Region, Attr::City, Attr::Asn]
+            .into_iter()
+            .find(|attr| attr.as_str().eq_ignore_ascii_case(name))
+    }
+
+    /// Checks the value, returning it as it is matched.
+    fn value(&self, value: &str) -> Result<String, String> {
+        match self {
+            Attr::Country if value.len() == 2 && value.chars().all(|c| c.is_ascii_alphabetic()) => {
+                Ok(value.to_ascii_uppercase())
+            }
+            Attr::Country => Err(format!("expected a two letter country code, found {value}")),
+            Attr::Asn => match value.parse::<u32>() {
+                Ok(asn) => Ok(asn.to_string()),
+                Err(_) => Err(format!("expected an AS number, found {value}")),
+            },
+            Attr::Region | Attr::City if !value.is_empty() => Ok(normalize_name(value)),
+            Attr::Region | Attr::City => Err(format!("expected a {} name", self.as_str())),
+        }
+    }
+
+    /// Whether the location has the attribute with a value
+    /// as returned by [`Attr::value`].
+    fn matches(&self, geo: &Geo, value: &str) -> bool {
+        match self {
+            Attr::Country => geo
+                .country
+                .as_deref()
+                .is_some_and(|country| country.eq_ignore_ascii_case(value)),
+            Attr::Region => geo
+                .region
+                .as_deref()
+                .is_some_and(|region| normalize_name(region) == value),
+            Attr::City => geo
+                .city
+                .as_deref()
+                .is_some_and(|city| normalize_name(city) == value),
+            Attr::Asn => geo.asn.is_some_and(|asn| asn.to_string() == value),
+        }
+    }
+}
+
+/// Lower cases a region or city name, with `_` for spaces.
+fn normalize_name(name: &str) -> String {
+    name.to_lowercase().replace(' ', "_")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Tag(String),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Tag(tag) => write!(f, "tag {tag}"),
+            Token::And => f.write_str("AND"),
+            Token::Or => f.write_str("OR"),
+            Token::Not => f.write_str("NOT"),
+            Token::Open => f.write_str("("),
+            Token::Close => f.write_str(")"),
+        }
+    }
+}
+
+fn tokenize(expr: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let flush = |word: &mut String, tokens: &mut Vec<Token>| {
+        if word.is_empty() {
+            return;
+        }
+        let token = match word.to_ascii_uppercase().as_str() {
+            "AND" => Token::And,
+            "OR" => Token::Or,
+            "NOT" => Token::Not,
+            _ => Token::Tag(word.clone()),
+        };
+        tokens.push(token);
+        word.clear();
+    };
+    for c in expr.chars() {
+        match c {
+            '(' | ')' => {
+                flush(&mut word, &mut tokens);
+                tokens.push(if c == '(' { Token::Open } else { Token::Close });
+            }
+            c if c.is_whitespace() => flush(&mut word, &mut tokens),
+            c => word.push(c),
+        }
+    }
+    flush(&mut word, &mut tokens);
+    tokens
+}
+
+/// A recursive descent parser, one level per precedence.
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        let found = self.peek() == Some(token);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn or(&mut self) -> Result<TagExpr, String> {
+        let mut expr = self.and()?;
+        while self.eat(&Token::Or) {
+            expr = TagExpr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<TagExpr, String> {
+        let mut expr = self.not()?;
+        while self.eat(&Token::And) {
+            expr = TagExpr::And(Box::new(expr), Box::new(self.not()?));
+        }
+        Ok(expr)
+    }
+
+    fn not(&mut self) -> Result<TagExpr, String> {
+        if self.eat(&Token::Not) {
+            return Ok(TagExpr::Not(Box::new(self.not()?)));
+        }
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<TagExpr, String> {
+        let token = self.peek().cloned();
+        self.pos += 1;
+        match token {
+            Some(Token::Tag(tag)) => match tag.split_once(':') {
+                Some((name, value)) => match Attr::parse(name) {
+                    Some(attr) => Ok(TagExpr::Attr(attr, attr.value(value)?)),
+                    None => Ok(TagExpr::Tag(tag)),
+                },
+                None => Ok(TagExpr::Tag(tag)),
+            },
+            Some(Token::Open) => {
+                let expr = self.or()?;
+                if !self.eat(&Token::Close) {
+                    return Err("missing closing parenthesis".into());
+                }
+                Ok(expr)
+            }
+            Some(token) => Err(format!("expected a tag, found {token}")),
+            None => Err("expected a tag, found the end".into()),
+        }
+    }
+}
+
+impl TagExpr {
+    pub fn parse(expr: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            tokens: tokenize(expr),
+            pos: 0,
+        };
+        let parsed = parser.or()?;
+        match parser.peek() {
+            None => Ok(parsed),
+            Some(token) => Err(format!("unexpected {token}")),
+        }
+    }
+
+    /// Matches proxies with any of the tags, like the tags mapped to
+    /// a domain do. `None` when there are no tags.
+    pub fn any<S: Into<String>>(tags: impl IntoIterator<Item = S>) -> Option<Self> {
+        tags.into_iter()
+            .map(|tag| TagExpr::Tag(tag.into()))
+            .reduce(TagExpr::or)
+    }
+
+    pub fn or(self, other: Self) -> Self {
+        TagExpr::Or(Box::new(self), Box::new(other))
+    }
+
+    /// Whether a proxy with the given tags and
+    /// location satisfies the expression.
+    pub fn matches<S: AsRef<str>>(&self, tags: &[S], geo: &Geo) -> bool {
+        match self {
+            TagExpr::Tag(tag) => tags.iter().any(|t| t.as_ref() == tag),
+            TagExpr::Attr(attr, value) => attr.matches(geo, value),
+            TagExpr::Not(expr) => !expr.matches(tags, geo),
+            TagExpr::And(a, b) => a.matches(tags, geo) && b.matches(tags, geo),
+            TagExpr::Or(a, b) => a.matches(tags, geo) || b.matches(tags, geo),
+        }
+    }
+
+    /// How tightly the expression binds, for printing parentheses.
+    fn precedence(&self) -> u8 {
+        match self {
+            TagExpr::Or(..) => 0,
+            TagExpr::And(..) => 1,
+            TagExpr::Not(_) | TagExpr::Tag(_) | TagExpr::Attr(..) => 2,
+        }
+    }
+
+    fn fmt_operand(&self, f: &mut fmt::Formatter<'_>, parent: u8) -> fmt::Result {
+        if self.precedence() < parent {
+            write!(f, "({self})")
+        } else {
+            write!(f, "{self}")
+        }
+    }
+}
+
+impl fmt::Display for TagExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TagExpr::Tag(tag) => f.write_str(tag),
+            TagExpr::Attr(attr, value) => write!(f, "{}:{value}", attr.as_str()),
+            TagExpr::Not(expr) => {
+                f.write_str("NOT ")?;
+                expr.fmt_operand(f, 2)
+            }
+            TagExpr::And(a, b) => {
+                a.fmt_operand(f, 1)?;
+                f.write_str(" AND ")?;
+                b.fmt_operand(f, 2)
+            }
+            TagExpr::Or(a, b) => {
+                a.fmt_operand(f, 0)?;
+                f.write_str(" OR ")?;
+                b.fmt_operand(f, 1)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(name: &str) -> Box<TagExpr> {
+        Box::new(TagExpr::Tag(name.into()))
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            TagExpr::parse("residential AND us AND NOT infatica").unwrap(),
+            TagExpr::And(
+                Box::new(TagExpr::And(tag("residential"), tag("us"))),
+                Box::new(TagExpr::Not(tag("infatica"))),
+            )
+        );
+        // AND binds tighter than OR.
+        assert_eq!(
+            TagExpr::parse("a or b and c").unwrap(),
+            TagExpr::Or(tag("a"), Box::new(TagExpr::And(tag("b"), tag("c"))))
+        );
+        assert_eq!(
+            TagExpr::parse("(a OR b)AND c").unwrap(),
+            TagExpr::And(Box::new(TagExpr::Or(tag("a"), tag("b"))), tag("c"))
+        );
+        assert_eq!(
+            TagExpr::parse("NOT NOT a").unwrap(),
+            TagExpr::Not(Box::new(TagExpr::Not(tag("a"))))
+        );
+
+        for invalid in ["", "a AND", "(a OR b", "a b", "AND a", "a )", "()"] {
+            assert!(TagExpr::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_display_round_trips() {
+        for expr in [
+            "a",
+            "a AND b OR c",
+            "a AND (b OR c)",
+            "NOT (a OR b) AND c",
+            "a OR b OR c",
+            "NOT NOT a",
+            "a AND (b AND c)",
+        ] {
+            let parsed = TagExpr::parse(expr).unwrap();
+            assert_eq!(parsed.to_string(), expr);
+            assert_eq!(TagExpr::parse(&parsed.to_string()).unwrap(), parsed);
+        }
+        assert_eq!(
+            TagExpr::parse("(a) and ((b))").unwrap().to_string(),
+            "a AND b"
+        );
+    }
+
+    #[test]
+    fn test_matches() {
+        let unknown = Geo::default();
+        let expr = TagExpr::parse("residential AND us AND NOT infatica").unwrap();
+        assert!(expr.matches(&["residential", "us", "webshare"], &unknown));
+        assert!(!expr.matches(&["residential", "us", "infatica"], &unknown));
+        assert!(!expr.matches(&["residential"], &unknown));
+
+        let any = TagExpr::any(["a", "b"]).unwrap();
+        assert!(any.matches(&["b"], &unknown));
+        assert!(!any.matches::<&str>(&[], &unknown));
+        assert!(TagExpr::any::<&str>([]).is_none());
+    }
+
+    #[test]
+    fn test_attrs() {
+        let expr = TagExpr::parse("residential AND Country:us AND (city:New_York OR asn:7922)");
+        let expr = expr.unwrap();
+        assert_eq!(
+            expr.to_string(),
+            "residential AND country:US AND (city:new_york OR asn:7922)"
+        );
+        let geo = Geo {
+            country: Some("US".into()),
+            region: None,
+            city: Some("New York".into()),
+            asn: None,
+        };
+        assert!(expr.matches(&["residential"], &geo));
+        let geo = Geo {
+            city: Some("Boston".into()),
+            asn: Some(7922),
+            ..geo
+        };
+        assert!(expr.matches(&["residential"], &geo));
+        assert!(!expr.matches(&["residential"], &Geo::default()));
+        // Tags that only look like attributes are still tags.
+        assert_eq!(
+            TagExpr::parse("team:scraping").unwrap(),
+            TagExpr::Tag("team:scraping".into())
+        );
+        for invalid in ["country:usa", "asn:AS7922", "city:"] {
+            assert!(TagExpr::parse(invalid).is_err(), "{invalid}");
+        }
+    }
+}
@@ -0,0 +1,17 @@
+-- Tag expressions tried in order once none of a domain's own proxies
+-- is left, e.g. squid, then webshare. Once they are exhausted too,
+-- any proxy is used unless fallback_any is off, in which case the
+-- request is turned away.
+ALTER TABLE locust_domains ADD COLUMN fallback_any boolean NOT NULL DEFAULT true;
+
+CREATE TABLE IF NOT EXISTS locust_domain_fallbacks (
+  id SERIAL PRIMARY KEY,
+  domain_id integer NOT NULL REFERENCES locust_domains(id),
+  position integer NOT NULL,
+  tag_expr varchar NOT NULL,
+  UNIQUE (domain_id, position)
+);
+
+CREATE TRIGGER locust_domain_fallbacks_notify
+  AFTER INSERT OR UPDATE OR DELETE ON locust_domain_fallbacks
+  FOR EACH STATEMENT EXECUTE FUNCTION locust_notify_pool_change();
@@ -0,0 +1,5 @@
+-- What is done with requests to a domain: `proxy`, optionally with a
+-- tag expression as in `proxy(residential AND us)`, `direct`, optionally
+-- from a local address as in `direct(10.0.0.5)`, or `block`, optionally
+-- with a reason as in `block(internal only)`. NULL is the same as `proxy`.
+ALTER TABLE locust_domains ADD COLUMN action varchar NULL;
@@ -0,0 +1,12 @@
+-- Where proxies are located, as far as is known: an upper case
+-- ISO 3166 country code, region and city names, and the number of
+-- the autonomous system. Filled in on import from the usernames of
+-- providers that encode it, or from a local geo database.
+ALTER TABLE locust_proxies
+  ADD COLUMN country varchar NULL,
+  ADD COLUMN region varchar NULL,
+  ADD COLUMN city varchar NULL,
+  ADD COLUMN asn bigint NULL;
+
+CREATE INDEX idx_proxies_country ON locust_proxies(country);
+CREATE INDEX idx_proxies_asn ON locust_proxies(asn);
@@ -0,0 +1,8 @@
+-- Where the addresses of a proxy come from: `datacenter`, `residential`,
+-- `mobile` or `isp`, NULL when unknown. Proxies are picked from the
+-- highest priority first, and by weight among proxies of the same
+-- priority.
+ALTER TABLE locust_proxies
+  ADD COLUMN type varchar NULL,
+  ADD COLUMN weight integer NOT NULL DEFAULT 1 CHECK (weight > 0),
+  ADD COLUMN priority integer NOT NULL DEFAULT 0;
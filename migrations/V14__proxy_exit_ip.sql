@@ -0,0 +1,21 @@
+-- The address targets see requests through a proxy coming from, which
+-- for rotating and backconnect providers is not its host, and when it
+-- was last seen. Discovered through an echo endpoint.
+ALTER TABLE locust_proxies
+  ADD COLUMN exit_ip varchar NULL,
+  ADD COLUMN date_exit_seen timestamptz NULL;
+
+CREATE INDEX idx_proxies_exit_ip ON locust_proxies(exit_ip);
+
+-- Seeing the same exit IP again does not change what the pool looks
+-- like, so it is left out of the changes that reload it, see V8.
+DROP TRIGGER locust_proxies_update_notify ON locust_proxies;
+CREATE TRIGGER locust_proxies_update_notify
+  AFTER UPDATE ON locust_proxies
+  FOR EACH ROW
+  WHEN (
+    to_jsonb(OLD) - '{date_modified,date_last_used,date_last_checked,last_check_status,last_check_latency,check_failures,date_exit_seen}'::text[]
+    IS DISTINCT FROM
+    to_jsonb(NEW) - '{date_modified,date_last_used,date_last_checked,last_check_status,last_check_latency,check_failures,date_exit_seen}'::text[]
+  )
+  EXECUTE FUNCTION locust_notify_pool_change();
@@ -0,0 +1,5 @@
+-- How many requests to a domain may be in flight at once and how many
+-- may start each second, each instead of the server's default domain
+-- limit when set.
+ALTER TABLE locust_domains ADD COLUMN max_concurrency integer NULL;
+ALTER TABLE locust_domains ADD COLUMN max_rps double precision NULL;
@@ -0,0 +1,22 @@
+CREATE TABLE IF NOT EXISTS locust_request_history (
+  date_created timestamptz NOT NULL,
+  proxy_id integer NOT NULL,
+  session_id integer,
+  domain varchar,
+  status smallint,
+  error_class varchar,
+  response_time integer NOT NULL,
+  total_time integer NOT NULL,
+  request_bytes bigint NOT NULL,
+  response_bytes bigint NOT NULL,
+  username varchar
+) PARTITION BY RANGE (date_created);
+
+-- Daily partitions are created ahead of time by the server.
+-- This one only catches rows that fall outside of them.
+CREATE TABLE IF NOT EXISTS locust_request_history_default
+  PARTITION OF locust_request_history DEFAULT;
+
+CREATE INDEX idx_request_history_date ON locust_request_history(date_created);
+CREATE INDEX idx_request_history_proxy_id ON locust_request_history(proxy_id, date_created);
+CREATE INDEX idx_request_history_domain ON locust_request_history(domain, date_created);
@@ -0,0 +1,8 @@
+ALTER TABLE locust_proxies
+  ADD COLUMN IF NOT EXISTS date_last_checked timestamptz NULL,
+  ADD COLUMN IF NOT EXISTS last_check_status smallint NULL,
+  ADD COLUMN IF NOT EXISTS last_check_latency integer NULL,
+  ADD COLUMN IF NOT EXISTS check_failures integer NOT NULL DEFAULT 0,
+  ADD COLUMN IF NOT EXISTS date_quarantined timestamptz NULL;
+
+CREATE INDEX idx_date_quarantined ON locust_proxies(date_quarantined);
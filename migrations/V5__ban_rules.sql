@@ -0,0 +1,20 @@
+CREATE TABLE IF NOT EXISTS locust_ban_rules (
+  id SERIAL PRIMARY KEY,
+  host varchar NOT NULL,
+  kind varchar NOT NULL,
+  pattern varchar NOT NULL,
+  date_created timestamp DEFAULT now()
+);
+
+CREATE INDEX idx_ban_rules_host ON locust_ban_rules(host);
+
+CREATE TABLE IF NOT EXISTS locust_proxy_domain_scores (
+  proxy_id integer NOT NULL,
+  domain varchar NOT NULL,
+  successes bigint NOT NULL DEFAULT 0,
+  failures bigint NOT NULL DEFAULT 0,
+  bans bigint NOT NULL DEFAULT 0,
+  date_modified timestamptz NOT NULL DEFAULT now(),
+  PRIMARY KEY (proxy_id, domain),
+  CONSTRAINT proxy_id_fkey FOREIGN KEY (proxy_id) REFERENCES locust_proxies(id)
+);
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS locust_proxy_breakers (
+  proxy_id integer NOT NULL,
+  -- Empty for the breaker of the proxy as a whole.
+  domain varchar NOT NULL DEFAULT '',
+  state varchar NOT NULL,
+  consecutive_failures integer NOT NULL DEFAULT 0,
+  date_opened timestamptz,
+  date_modified timestamptz NOT NULL DEFAULT now(),
+  PRIMARY KEY (proxy_id, domain),
+  CONSTRAINT proxy_id_fkey FOREIGN KEY (proxy_id) REFERENCES locust_proxies(id)
+);
@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS locust_proxy_cooldowns (
+  proxy_id integer NOT NULL,
+  domain varchar NOT NULL,
+  -- Rate limits in a row, which the backoff grows with.
+  strikes integer NOT NULL DEFAULT 1,
+  date_until timestamptz NOT NULL,
+  date_modified timestamptz NOT NULL DEFAULT now(),
+  PRIMARY KEY (proxy_id, domain),
+  CONSTRAINT proxy_id_fkey FOREIGN KEY (proxy_id) REFERENCES locust_proxies(id)
+);
+
+CREATE INDEX idx_proxy_cooldowns_domain_until ON locust_proxy_cooldowns(domain, date_until);
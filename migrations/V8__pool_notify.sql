@@ -0,0 +1,43 @@
+-- Tells proxy servers to reload their in-memory pool whenever
+-- the proxies, tags or domains change. The payload is the name
+-- of the table that changed.
+CREATE OR REPLACE FUNCTION locust_notify_pool_change() RETURNS trigger AS $$
+BEGIN
+  PERFORM pg_notify('locust_pool_changed', TG_TABLE_NAME);
+  RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+-- Usage and health check bookkeeping on proxies
+-- does not change what the pool looks like.
+CREATE TRIGGER locust_proxies_notify
+  AFTER INSERT OR DELETE ON locust_proxies
+  FOR EACH STATEMENT EXECUTE FUNCTION locust_notify_pool_change();
+CREATE TRIGGER locust_proxies_update_notify
+  AFTER UPDATE ON locust_proxies
+  FOR EACH ROW
+  WHEN (
+    to_jsonb(OLD) - '{date_modified,date_last_used,date_last_checked,last_check_status,last_check_latency,check_failures}'::text[]
+    IS DISTINCT FROM
+    to_jsonb(NEW) - '{date_modified,date_last_used,date_last_checked,last_check_status,last_check_latency,check_failures}'::text[]
+  )
+  EXECUTE FUNCTION locust_notify_pool_change();
+
+CREATE TRIGGER locust_tags_notify
+  AFTER INSERT OR UPDATE OR DELETE ON locust_tags
+  FOR EACH STATEMENT EXECUTE FUNCTION locust_notify_pool_change();
+CREATE TRIGGER locust_proxy_tag_map_notify
+  AFTER INSERT OR UPDATE OR DELETE ON locust_proxy_tag_map
+  FOR EACH STATEMENT EXECUTE FUNCTION locust_notify_pool_change();
+CREATE TRIGGER locust_domains_notify
+  AFTER INSERT OR UPDATE OR DELETE ON locust_domains
+  FOR EACH STATEMENT EXECUTE FUNCTION locust_notify_pool_change();
+CREATE TRIGGER locust_domain_tag_map_notify
+  AFTER INSERT OR UPDATE OR DELETE ON locust_domain_tag_map
+  FOR EACH STATEMENT EXECUTE FUNCTION locust_notify_pool_change();
+
+-- Sessions are cached as well, so deleting
+-- one has to reach every proxy server.
+CREATE TRIGGER locust_sessions_notify
+  AFTER DELETE ON locust_sessions
+  FOR EACH STATEMENT EXECUTE FUNCTION locust_notify_pool_change();
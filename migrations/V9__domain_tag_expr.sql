@@ -0,0 +1,4 @@
+-- A boolean expression over proxy tags, e.g. `residential AND us AND
+-- NOT infatica`, that the proxies for a domain must satisfy. When set,
+-- it is used instead of the tags in locust_domain_tag_map.
+ALTER TABLE locust_domains ADD COLUMN tag_expr varchar NULL;
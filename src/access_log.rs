@@ -0,0 +1,350 @@
+use http::{HeaderMap, Uri};
+use serde::Serialize;
+use std::{
+    env, fmt,
+    io::{self, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Mutex,
+};
+use time::{format_description::well_known::Rfc3339, macros::format_description, OffsetDateTime};
+use tracing::warn;
+use tracing_appender::{
+    non_blocking::{NonBlocking, WorkerGuard},
+    rolling::{RollingFileAppender, Rotation},
+};
+
+/// Headers whose values never make it into logs.
+const SENSITIVE_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+];
+
+const REDACTED: &str = "REDACTED";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// One JSON object per line.
+    Json,
+    /// The Apache/NCSA Combined Log Format, with Locust's
+    /// routing fields appended as `key=value` pairs.
+    Combined,
+}
+
+/// Which query parameter values are redacted from logged URLs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryRedaction {
+    None,
+    All,
+    Keys(Vec<String>),
+}
+
+impl QueryRedaction {
+    /// Parses `all`, `none`, or a comma separated list of
+    /// query parameter names.
+    pub fn parse(s: &str) -> Self {
+        match s.trim() {
+            "" | "none" => QueryRedaction::None,
+            "all" => QueryRedaction::All,
+            keys => QueryRedaction::Keys(
+                keys.split(',')
+                    .map(|k| k.trim().to_lowercase())
+                    .filter(|k| !k.is_empty())
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Reads `LOCUST_ACCESS_LOG_REDACT_QUERY`, which applies to the
+    /// diagnostic logs as well as the access log.
+    pub fn from_env() -> Self {
+        env::var("LOCUST_ACCESS_LOG_REDACT_QUERY")
+            .map(|s| Self::parse(&s))
+            .unwrap_or(QueryRedaction::None)
+    }
+
+    pub fn redact(&self, uri: &Uri) -> String {
+        let query = match (self, uri.query()) {
+            (QueryRedaction::None, _) | (_, None) => return uri.to_string(),
+            (_, Some(query)) => query,
+        };
+
+        let redacted: Vec<String> = query
+            .split('&')
+            .map(|pair| {
+                let key = pair.split('=').next().unwrap_or_default();
+                let hide = match self {
+                    QueryRedaction::All => true,
+                    QueryRedaction::Keys(keys) => keys.iter().any(|k| k.eq_ignore_ascii_case(key)),
+                    QueryRedaction::None => false,
+                };
+                if hide {
+                    format!("{key}={REDACTED}")
+                } else {
+                    pair.to_string()
+                }
+            })
+            .collect();
+
+        let full = uri.to_string();
+        let base = full.split('?').next().unwrap_or_default();
+        format!("{base}?{}", redacted.join("&"))
+    }
+}
+
+/// Formats headers for debug logs with the values of
+/// sensitive headers redacted.
+pub struct RedactedHeaders<'a>(pub &'a HeaderMap);
+
+impl fmt::Debug for RedactedHeaders<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.0.iter().map(|(name, value)| {
+                let value = if SENSITIVE_HEADERS.contains(&name.as_str()) {
+                    REDACTED
+                } else {
+                    value.to_str().unwrap_or("<binary>")
+                };
+                (name.as_str(), value)
+            }))
+            .finish()
+    }
+}
+
+/// A single completed request.
+#[derive(Debug, Clone, Serialize)]
+pub struct AccessRecord {
+    #[serde(with = "time::serde::rfc3339")]
+    pub timestamp: OffsetDateTime,
+    pub client_addr: SocketAddr,
+    /// The user the client authenticated as, if any.
+    pub user: Option<String>,
+    pub method: String,
+    pub url: String,
+    pub status: u16,
+    pub bytes: u64,
+    pub duration_ms: u32,
+    pub proxy_id: i32,
+    pub provider: String,
+    pub session_id: i32,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl AccessRecord {
+    fn to_combined(&self) -> String {
+        let ts_format = format_description!(
+            "[day]/[month repr:short]/[year]:[hour]:[minute]:[second] [offset_hour sign:mandatory][offset_minute]"
+        );
+        let ts = self
+            .timestamp
+            .format(&ts_format)
+            .unwrap_or_else(|_| self.timestamp.format(&Rfc3339).unwrap_or_default());
+        let quoted = |s: &Option<String>| match s {
+            Some(s) => format!("\"{}\"", s.replace('"', "\\\"")),
+            None => "\"-\"".into(),
+        };
+
+        format!(
+            "{} - {} [{}] \"{} {} HTTP/1.1\" {} {} {} {} proxy_id={} provider={} session_id={} duration_ms={}",
+            self.client_addr.ip(),
+            self.user.as_deref().unwrap_or("-"),
+            ts,
+            self.method,
+            self.url,
+            self.status,
+            self.bytes,
+            quoted(&self.referer),
+            quoted(&self.user_agent),
+            self.proxy_id,
+            self.provider,
+            self.session_id,
+            self.duration_ms,
+        )
+    }
+}
+
+/// Writes one record per proxied request, separately from the
+/// diagnostic logs.
+pub struct AccessLog {
+    format: AccessLogFormat,
+    redaction: QueryRedaction,
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLog {
+    pub fn new(
+        format: AccessLogFormat,
+        redaction: QueryRedaction,
+        writer: impl Write + Send + 'static,
+    ) -> Self {
+        Self {
+            format,
+            redaction,
+            writer: Mutex::new(Box::new(writer)),
+        }
+    }
+
+    /// Builds the access log from the environment. Returns `None` when
+    /// `LOCUST_ACCESS_LOG` is unset. It can be set to `stdout`, or to a
+    /// directory in which rotating `access.log` files are written.
+    ///
+    /// The returned guard must be held on to for logs to be flushed.
+    pub fn from_env() -> Option<(Self, WorkerGuard)> {
+        let target = env::var("LOCUST_ACCESS_LOG").ok()?;
+        let format = match env::var("LOCUST_ACCESS_LOG_FORMAT").as_deref() {
+            Ok("combined") => AccessLogFormat::Combined,
+            Ok("json") | Err(_) => AccessLogFormat::Json,
+            Ok(other) => {
+                warn!("unknown access log format {other}, using json");
+                AccessLogFormat::Json
+            }
+        };
+        let redaction = QueryRedaction::from_env();
+
+        let (writer, guard): (NonBlocking, WorkerGuard) = match target.as_str() {
+            "stdout" => tracing_appender::non_blocking(io::stdout()),
+            dir => {
+                let rotation = match env::var("LOCUST_ACCESS_LOG_ROTATION").as_deref() {
+                    Ok("hourly") => Rotation::HOURLY,
+                    Ok("never") => Rotation::NEVER,
+                    _ => Rotation::DAILY,
+                };
+                let appender = RollingFileAppender::new(rotation, PathBuf::from(dir), "access.log");
+                tracing_appender::non_blocking(appender)
+            }
+        };
+
+        Some((Self::new(format, redaction, writer), guard))
+    }
+
+    pub fn redact_uri(&self, uri: &Uri) -> String {
+        self.redaction.redact(uri)
+    }
+
+    pub fn log(&self, record: &AccessRecord) {
+        let line = match self.format {
+            AccessLogFormat::Json => match serde_json::to_string(record) {
+                Ok(line) => line,
+                Err(e) => {
+                    warn!("error serializing access record: {e}");
+                    return;
+                }
+            },
+            AccessLogFormat::Combined => record.to_combined(),
+        };
+
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(e) = writeln!(writer, "{line}") {
+            warn!("error writing access log: {e}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Buffer {
+        fn contents(&self) -> String {
+            String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+        }
+    }
+
+    fn record(log: &AccessLog) -> AccessRecord {
+        AccessRecord {
+            timestamp: OffsetDateTime::from_unix_timestamp(971_211_336).unwrap(),
+            client_addr: "10.0.0.1:5000".parse().unwrap(),
+            user: Some("scraper".into()),
+            method: "GET".into(),
+            url: log.redact_uri(&"http://example.com/a?q=shoes&token=abc".parse().unwrap()),
+            status: 200,
+            bytes: 512,
+            duration_ms: 42,
+            proxy_id: 7,
+            provider: "webshare".into(),
+            session_id: 3,
+            referer: None,
+            user_agent: Some("curl/8.0".into()),
+        }
+    }
+
+    #[test]
+    fn test_json_format() {
+        let buf = Buffer::default();
+        let log = AccessLog::new(
+            AccessLogFormat::Json,
+            QueryRedaction::parse("token"),
+            buf.clone(),
+        );
+        log.log(&record(&log));
+
+        let line: serde_json::Value = serde_json::from_str(buf.contents().trim()).unwrap();
+        assert_eq!(line["timestamp"], "2000-10-10T20:55:36Z");
+        assert_eq!(line["client_addr"], "10.0.0.1:5000");
+        assert_eq!(line["url"], "http://example.com/a?q=shoes&token=REDACTED");
+        assert_eq!(line["status"], 200);
+        assert_eq!(line["proxy_id"], 7);
+        assert_eq!(line["session_id"], 3);
+    }
+
+    #[test]
+    fn test_combined_format() {
+        let buf = Buffer::default();
+        let log = AccessLog::new(AccessLogFormat::Combined, QueryRedaction::All, buf.clone());
+        log.log(&record(&log));
+
+        assert_eq!(
+            buf.contents(),
+            "10.0.0.1 - scraper [10/Oct/2000:20:55:36 +0000] \"GET http://example.com/a?q=REDACTED&token=REDACTED HTTP/1.1\" 200 512 \"-\" \"curl/8.0\" proxy_id=7 provider=webshare session_id=3 duration_ms=42\n"
+        );
+    }
+
+    #[test]
+    fn test_query_redaction() {
+        let uri: Uri = "https://example.com/path?a=1&Key=2&b".parse().unwrap();
+        assert_eq!(
+            QueryRedaction::None.redact(&uri),
+            "https://example.com/path?a=1&Key=2&b"
+        );
+        assert_eq!(
+            QueryRedaction::parse("key, b").redact(&uri),
+            "https://example.com/path?a=1&Key=REDACTED&b=REDACTED"
+        );
+        assert_eq!(
+            QueryRedaction::parse("all").redact(&"/no-query".parse().unwrap()),
+            "/no-query"
+        );
+    }
+
+    #[test]
+    fn test_redacted_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert("cookie", "_lcst_sess=1".parse().unwrap());
+        headers.insert("proxy-authorization", "Basic dXNlcjpwYXNz".parse().unwrap());
+        headers.insert("accept", "*/*".parse().unwrap());
+
+        let out = format!("{:?}", RedactedHeaders(&headers));
+        assert!(out.contains(r#""accept": "*/*""#));
+        assert!(out.contains(r#""cookie": "REDACTED""#));
+        assert!(!out.contains("dXNlcjpwYXNz"));
+    }
+}
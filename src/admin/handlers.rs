@@ -0,0 +1,460 @@
+use super::{reject, AdminError};
+use crate::stats::{Stats, StatsSnapshot};
+
+use locust_core::{
+    bans::{validate_ban_rule, BanRuleKind},
+    crud::{
+        bans::{
+            add_ban_rule as insert_ban_rule, delete_ban_rule as delete_ban_rule_by_id,
+            get_ban_rules,
+        },
+        domains::{
+            add_domain_tags as add_tags_to_domain, delete_domain as delete_domain_by_host,
+            get_domain as get_domain_by_host, get_domains,
+            remove_domain_tags as remove_tags_from_domain, set_domain_action, set_domain_fallbacks,
+            set_domain_limit, set_domain_tag_expr,
+        },
+        history::get_request_history,
+        proxies::{
+            add_proxies as insert_proxies, add_proxy_tags as add_tags_to_proxy,
+            count_proxy_sessions, create_proxy_session, delete_proxies_by_ids,
+            delete_proxies_by_tags, delete_proxy_session, find_proxy_by_id, get_all_proxies,
+            get_proxies_by_tags, get_proxy_session, get_proxy_sessions, get_proxy_tags,
+            remove_proxy_tags as remove_tags_from_proxy,
+        },
+        scores::get_proxy_domain_scores,
+        tags::{
+            create_tag as insert_tag, delete_tag as delete_tag_by_name, get_tag_pool_sizes,
+            get_tags,
+        },
+    },
+    domains::DomainPattern,
+    models::{
+        history::RequestHistoryFilter,
+        proxies::{NewProxy, Proxy},
+        tags::TagPoolSize,
+    },
+    routing::{DomainLimit, RouteAction},
+    tag_expr::TagExpr,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use std::sync::Arc;
+use warp::{http::StatusCode, reply, Rejection, Reply};
+
+const DEFAULT_PAGE_SIZE: i64 = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct TagsQuery {
+    /// Comma separated tag names.
+    tags: Option<String>,
+}
+
+impl TagsQuery {
+    fn tags(&self) -> Vec<&str> {
+        self.tags
+            .as_deref()
+            .map(|t| {
+                t.split(',')
+                    .map(str::trim)
+                    .filter(|t| !t.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagsBody {
+    tags: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewProxiesBody {
+    proxies: Vec<NewProxy>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewTagBody {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TagExprBody {
+    tag_expr: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ActionBody {
+    action: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FallbacksBody {
+    fallbacks: Vec<String>,
+    #[serde(default = "default_fallback_any")]
+    fallback_any: bool,
+}
+
+fn default_fallback_any() -> bool {
+    true
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewBanRuleBody {
+    kind: String,
+    pattern: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewSessionBody {
+    proxy_id: i32,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PageQuery {
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryQuery {
+    proxy_id: Option<i32>,
+    domain: Option<String>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    since: Option<time::OffsetDateTime>,
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    until: Option<time::OffsetDateTime>,
+    #[serde(default)]
+    failed: bool,
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+#[derive(Serialize)]
+struct ProxyDetail {
+    #[serde(flatten)]
+    proxy: Proxy,
+    tags: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct StatsBody {
+    #[serde(flatten)]
+    live: StatsSnapshot,
+    sessions_total: i64,
+    pool_sizes: Vec<TagPoolSize>,
+}
+
+fn as_strs(tags: &[String]) -> Vec<&str> {
+    tags.iter().map(AsRef::as_ref).collect()
+}
+
+fn no_content() -> impl Reply {
+    reply::with_status(reply::reply(), StatusCode::NO_CONTENT)
+}
+
+pub async fn list_proxies(query: TagsQuery, db: Arc<PgPool>) -> Result<impl Reply, Rejection> {
+    let tags = query.tags();
+    let proxies = if tags.is_empty() {
+        get_all_proxies(&db).await
+    } else {
+        get_proxies_by_tags(&db, &tags).await
+    }
+    .map_err(reject)?;
+
+    Ok(reply::json(&proxies))
+}
+
+pub async fn add_proxies(body: NewProxiesBody, db: Arc<PgPool>) -> Result<impl Reply, Rejection> {
+    insert_proxies(&db, &body.proxies, &as_strs(&body.tags))
+        .await
+        .map_err(reject)?;
+
+    Ok(reply::with_status(
+        reply::json(&body.proxies.len()),
+        StatusCode::CREATED,
+    ))
+}
+
+pub async fn delete_proxies(query: TagsQuery, db: Arc<PgPool>) -> Result<impl Reply, Rejection> {
+    let tags = query.tags();
+    if tags.is_empty() {
+        return Err(reject(AdminError::BadRequest(
+            "at least one tag is required".into(),
+        )));
+    }
+
+    delete_proxies_by_tags(&db, &tags).await.map_err(reject)?;
+    Ok(no_content())
+}
+
+pub async fn get_proxy(id: i32, db: Arc<PgPool>) -> Result<impl Reply, Rejection> {
+    let proxy = find_proxy_by_id(&db, id).await.map_err(reject)?;
+    let tags = get_proxy_tags(&db, id).await.map_err(reject)?;
+
+    Ok(reply::json(&ProxyDetail { proxy, tags }))
+}
+
+pub async fn delete_proxy(id: i32, db: Arc<PgPool>) -> Result<impl Reply, Rejection> {
+    delete_proxies_by_ids(&db, &[id]).await.map_err(reject)?;
+    Ok(no_content())
+}
+
+pub async fn add_proxy_tags(
+    id: i32,
+    body: TagsBody,
+    db: Arc<PgPool>,
+) -> Result<impl Reply, Rejection> {
+    add_tags_to_proxy(&db, id, &as_strs(&body.tags))
+        .await
+        .map_err(reject)?;
+    Ok(no_content())
+}
+
+pub async fn remove_proxy_tags(
+    id: i32,
+    query: TagsQuery,
+    db: Arc<PgPool>,
+) -> Result<impl Reply, Rejection> {
+    remove_tags_from_proxy(&db, id, &query.tags())
+        .await
+        .map_err(reject)?;
+    Ok(no_content())
+}
+
+pub async fn list_tags(db: Arc<PgPool>) -> Result<impl Reply, Rejection> {
+    let tags = get_tags(&db).await.map_err(reject)?;
+    Ok(reply::json(&tags))
+}
+
+pub async fn create_tag(body: NewTagBody, db: Arc<PgPool>) -> Result<impl Reply, Rejection> {
+    let tag = insert_tag(&db, &body.name).await.map_err(reject)?;
+    Ok(reply::with_status(reply::json(&tag), StatusCode::CREATED))
+}
+
+pub async fn delete_tag(name: String, db: Arc<PgPool>) -> Result<impl Reply, Rejection> {
+    delete_tag_by_name(&db, &name).await.map_err(reject)?;
+    Ok(no_content())
+}
+
+pub async fn list_domains(db: Arc<PgPool>) -> Result<impl Reply, Rejection> {
+    let domains = get_domains(&db).await.map_err(reject)?;
+    Ok(reply::json(&domains))
+}
+
+pub async fn get_domain(host: String, db: Arc<PgPool>) -> Result<impl Reply, Rejection> {
+    let domain = get_domain_by_host(&db, &host).await.map_err(reject)?;
+    Ok(reply::json(&domain))
+}
+
+pub async fn delete_domain(host: String, db: Arc<PgPool>) -> Result<impl Reply, Rejection> {
+    delete_domain_by_host(&db, &host).await.map_err(reject)?;
+    Ok(no_content())
+}
+
+pub async fn add_domain_tags(
+    host: String,
+    body: TagsBody,
+    db: Arc<PgPool>,
+) -> Result<impl Reply, Rejection> {
+    DomainPattern::parse(&host).map_err(|e| reject(AdminError::BadRequest(e)))?;
+    add_tags_to_domain(&db, &host, &as_strs(&body.tags))
+        .await
+        .map_err(reject)?;
+    Ok(no_content())
+}
+
+pub async fn remove_domain_tags(
+    host: String,
+    query: TagsQuery,
+    db: Arc<PgPool>,
+) -> Result<impl Reply, Rejection> {
+    remove_tags_from_domain(&db, &host, &query.tags())
+        .await
+        .map_err(reject)?;
+    Ok(no_content())
+}
+
+pub async fn set_domain_tag_expression(
+    host: String,
+    body: TagExprBody,
+    db: Arc<PgPool>,
+) -> Result<impl Reply, Rejection> {
+    DomainPattern::parse(&host).map_err(|e| reject(AdminError::BadRequest(e)))?;
+    let tag_expr = TagExpr::parse(&body.tag_expr).map_err(|e| reject(AdminError::BadRequest(e)))?;
+    set_domain_tag_expr(&db, &host, Some(&tag_expr.to_string()))
+        .await
+        .map_err(reject)?;
+    Ok(no_content())
+}
+
+pub async fn clear_domain_tag_expression(
+    host: String,
+    db: Arc<PgPool>,
+) -> Result<impl Reply, Rejection> {
+    set_domain_tag_expr(&db, &host, None)
+        .await
+        .map_err(reject)?;
+    Ok(no_content())
+}
+
+pub async fn set_domain_route_action(
+    host: String,
+    body: ActionBody,
+    db: Arc<PgPool>,
+) -> Result<impl Reply, Rejection> {
+    DomainPattern::parse(&host).map_err(|e| reject(AdminError::BadRequest(e)))?;
+    let action = RouteAction::parse(&body.action).map_err(|e| reject(AdminError::BadRequest(e)))?;
+    set_domain_action(&db, &host, Some(&action.to_string()))
+        .await
+        .map_err(reject)?;
+    Ok(no_content())
+}
+
+pub async fn clear_domain_route_action(
+    host: String,
+    db: Arc<PgPool>,
+) -> Result<impl Reply, Rejection> {
+    set_domain_action(&db, &host, None).await.map_err(reject)?;
+    Ok(no_content())
+}
+
+pub async fn set_domain_route_limit(
+    host: String,
+    body: DomainLimit,
+    db: Arc<PgPool>,
+) -> Result<impl Reply, Rejection> {
+    DomainPattern::parse(&host).map_err(|e| reject(AdminError::BadRequest(e)))?;
+    body.validate()
+        .map_err(|e| reject(AdminError::BadRequest(e)))?;
+    set_domain_limit(&db, &host, body).await.map_err(reject)?;
+    Ok(no_content())
+}
+
+pub async fn clear_domain_route_limit(
+    host: String,
+    db: Arc<PgPool>,
+) -> Result<impl Reply, Rejection> {
+    set_domain_limit(&db, &host, DomainLimit::default())
+        .await
+        .map_err(reject)?;
+    Ok(no_content())
+}
+
+pub async fn set_domain_fallback_chain(
+    host: String,
+    body: FallbacksBody,
+    db: Arc<PgPool>,
+) -> Result<impl Reply, Rejection> {
+    DomainPattern::parse(&host).map_err(|e| reject(AdminError::BadRequest(e)))?;
+    let fallbacks = body
+        .fallbacks
+        .iter()
+        .map(|tag_expr| TagExpr::parse(tag_expr).map(|tag_expr| tag_expr.to_string()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| reject(AdminError::BadRequest(e)))?;
+    set_domain_fallbacks(&db, &host, &as_strs(&fallbacks), body.fallback_any)
+        .await
+        .map_err(reject)?;
+    Ok(no_content())
+}
+
+pub async fn list_ban_rules(host: String, db: Arc<PgPool>) -> Result<impl Reply, Rejection> {
+    let rules = get_ban_rules(&db, &host).await.map_err(reject)?;
+    Ok(reply::json(&rules))
+}
+
+pub async fn add_ban_rule(
+    host: String,
+    body: NewBanRuleBody,
+    db: Arc<PgPool>,
+) -> Result<impl Reply, Rejection> {
+    let kind: BanRuleKind = body
+        .kind
+        .parse()
+        .map_err(|e| reject(AdminError::BadRequest(e)))?;
+    validate_ban_rule(kind, &body.pattern).map_err(|e| reject(AdminError::BadRequest(e)))?;
+    let rule = insert_ban_rule(&db, &host, kind, &body.pattern)
+        .await
+        .map_err(reject)?;
+    Ok(reply::with_status(reply::json(&rule), StatusCode::CREATED))
+}
+
+pub async fn delete_ban_rule(id: i32, db: Arc<PgPool>) -> Result<impl Reply, Rejection> {
+    delete_ban_rule_by_id(&db, id).await.map_err(reject)?;
+    Ok(no_content())
+}
+
+pub async fn list_domain_scores(host: String, db: Arc<PgPool>) -> Result<impl Reply, Rejection> {
+    let scores = get_proxy_domain_scores(&db, &host).await.map_err(reject)?;
+    Ok(reply::json(&scores))
+}
+
+pub async fn list_sessions(query: PageQuery, db: Arc<PgPool>) -> Result<impl Reply, Rejection> {
+    let sessions = get_proxy_sessions(
+        &db,
+        query.limit.unwrap_or(DEFAULT_PAGE_SIZE),
+        query.offset.unwrap_or(0),
+    )
+    .await
+    .map_err(reject)?;
+    Ok(reply::json(&sessions))
+}
+
+pub async fn create_session(
+    body: NewSessionBody,
+    db: Arc<PgPool>,
+) -> Result<impl Reply, Rejection> {
+    // Make sure the proxy exists first so that a bad or deleted id is a 404.
+    find_proxy_by_id(&db, body.proxy_id).await.map_err(reject)?;
+    let session = create_proxy_session(&db, body.proxy_id)
+        .await
+        .map_err(reject)?;
+    Ok(reply::with_status(
+        reply::json(&session),
+        StatusCode::CREATED,
+    ))
+}
+
+pub async fn get_session(id: i32, db: Arc<PgPool>) -> Result<impl Reply, Rejection> {
+    let session = get_proxy_session(&db, id).await.map_err(reject)?;
+    Ok(reply::json(&session))
+}
+
+pub async fn delete_session(id: i32, db: Arc<PgPool>) -> Result<impl Reply, Rejection> {
+    delete_proxy_session(&db, id).await.map_err(reject)?;
+    Ok(no_content())
+}
+
+pub async fn list_history(query: HistoryQuery, db: Arc<PgPool>) -> Result<impl Reply, Rejection> {
+    let filter = RequestHistoryFilter {
+        proxy_id: query.proxy_id,
+        domain: query.domain,
+        since: query.since,
+        until: query.until,
+        failed: query.failed,
+    };
+    let records = get_request_history(
+        &db,
+        &filter,
+        query.limit.unwrap_or(DEFAULT_PAGE_SIZE),
+        query.offset.unwrap_or(0),
+    )
+    .await
+    .map_err(reject)?;
+    Ok(reply::json(&records))
+}
+
+pub async fn get_stats(db: Arc<PgPool>, stats: Arc<Stats>) -> Result<impl Reply, Rejection> {
+    let sessions_total = count_proxy_sessions(&db).await.map_err(reject)?;
+    let pool_sizes = get_tag_pool_sizes(&db).await.map_err(reject)?;
+
+    Ok(reply::json(&StatsBody {
+        live: stats.snapshot(),
+        sessions_total,
+        pool_sizes,
+    }))
+}
@@ -0,0 +1,160 @@
+use bytes::{Bytes, BytesMut};
+use futures::{stream, StreamExt};
+use http::header::CONTENT_ENCODING;
+use hyper::{body::HttpBody, Body, Response};
+use locust_core::{
+    bans::{BanMatch, BanRules},
+    domains::normalize_host,
+};
+use moka::future::Cache;
+use std::{env, sync::Arc, time::Duration};
+use tracing::{info_span, warn, Instrument};
+
+use crate::pool::PoolCache;
+
+/// How long the ban rules of a domain are cached for.
+const RULES_TTL: Duration = Duration::from_secs(30);
+const DEFAULT_BODY_SCAN_KB: usize = 64;
+
+/// Classifies responses as banned according to the
+/// ban rules configured for their domain.
+pub struct BanDetector {
+    pool: Arc<PoolCache>,
+    rules: Cache<String, Arc<BanRules>>,
+    /// How much of a body is scanned by body rules.
+    body_limit: usize,
+    /// How many times a banned request is retried
+    /// through a different proxy.
+    retries: u8,
+}
+
+impl BanDetector {
+    pub fn new(pool: Arc<PoolCache>, body_limit: usize, retries: u8) -> Self {
+        Self {
+            pool,
+            rules: Cache::builder().time_to_live(RULES_TTL).build(),
+            body_limit,
+            retries,
+        }
+    }
+
+    /// Reads the size of the scanned body from `LOCUST_BAN_BODY_SCAN_KB`
+    /// and the number of retries from `LOCUST_BAN_RETRIES`.
+    pub fn from_env(pool: Arc<PoolCache>) -> Self {
+        let body_scan_kb = env::var("LOCUST_BAN_BODY_SCAN_KB")
+            .map(|v| v.parse().expect("Invalid LOCUST_BAN_BODY_SCAN_KB"))
+            .unwrap_or(DEFAULT_BODY_SCAN_KB);
+        let retries = env::var("LOCUST_BAN_RETRIES")
+            .map(|v| v.parse().expect("Invalid LOCUST_BAN_RETRIES"))
+            .unwrap_or(0);
+        Self::new(pool, body_scan_kb * 1024, retries)
+    }
+
+    pub fn retries(&self) -> u8 {
+        self.retries
+    }
+
+    /// Gets the compiled ban rules of a domain. Rules that fail
+    /// to load or compile are logged and left out.
+    pub async fn rules_for(&self, host: &str) -> Arc<BanRules> {
+        let key = normalize_host(host);
+        self.rules
+            .get_with(key, async move {
+                match self.pool.ban_rules(host).await {
+                    Ok(rules) => {
+                        let (compiled, invalid) = BanRules::compile(&rules);
+                        for (rule, e) in invalid {
+                            warn!("skipping invalid ban rule {}: {e}", rule.id);
+                        }
+                        Arc::new(compiled)
+                    }
+                    Err(e) => {
+                        warn!("error getting ban rules for {host}: {e}");
+                        Arc::default()
+                    }
+                }
+            })
+            .await
+    }
+
+    /// Checks a response against the rules, reading the start of its
+    /// body when a body rule needs it. The response is handed back
+    /// with its body intact.
+    pub async fn check(
+        &self,
+        rules: &BanRules,
+        res: Response<Body>,
+    ) -> (Response<Body>, Option<BanMatch>) {
+        if rules.is_empty() {
+            return (res, None);
+        }
+        if let Some(ban) = rules.check_head(res.status(), res.headers()) {
+            return (res, Some(ban));
+        }
+
+        // Compressed bodies can't be matched against.
+        let encoded = res
+            .headers()
+            .get(CONTENT_ENCODING)
+            .is_some_and(|v| v != "identity");
+        if !rules.needs_body() || encoded {
+            return (res, None);
+        }
+
+        let (parts, body) = res.into_parts();
+        let (prefix, body) = peek_body(body, self.body_limit)
+            .instrument(info_span!("ban_body_scan"))
+            .await;
+        let ban = rules.check_body(&prefix);
+        (Response::from_parts(parts, body), ban)
+    }
+}
+
+/// Reads up to `limit` bytes from the start of a body. Returns
+/// them along with a body that yields the whole content again.
+async fn peek_body(mut body: Body, limit: usize) -> (Bytes, Body) {
+    let mut prefix = BytesMut::new();
+    while prefix.len() < limit {
+        match body.data().await {
+            Some(Ok(chunk)) => prefix.extend_from_slice(&chunk),
+            Some(Err(e)) => {
+                let prefix = prefix.freeze();
+                let rest = stream::iter([Ok(prefix.clone()), Err(e)]);
+                return (prefix, Body::wrap_stream(rest));
+            }
+            None => {
+                let prefix = prefix.freeze();
+                return (prefix.clone(), Body::from(prefix));
+            }
+        }
+    }
+
+    let prefix = prefix.freeze();
+    let rest = stream::once(futures::future::ready(Ok(prefix.clone()))).chain(body);
+    (prefix, Body::wrap_stream(rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::body::to_bytes;
+
+    fn chunked(chunks: &'static [&'static str]) -> Body {
+        Body::wrap_stream(stream::iter(
+            chunks
+                .iter()
+                .map(|c| Ok::<_, std::io::Error>(Bytes::from_static(c.as_bytes()))),
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_peek_body_keeps_content() {
+        let (prefix, body) = peek_body(chunked(&["abc", "def", "ghi"]), 4).await;
+        assert_eq!(&prefix[..], b"abcdef");
+        assert_eq!(&to_bytes(body).await.unwrap()[..], b"abcdefghi");
+
+        let (prefix, body) = peek_body(chunked(&["abc"]), 1024).await;
+        assert_eq!(&prefix[..], b"abc");
+        assert_eq!(&to_bytes(body).await.unwrap()[..], b"abc");
+    }
+}
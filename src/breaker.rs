@@ -0,0 +1,508 @@
+use crate::{
+    upstream::ErrorClass,
+    worker::{DBJob, DBJobSender},
+};
+use locust_core::models::breakers::ProxyBreaker;
+use std::{
+    collections::HashMap,
+    env, fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use time::OffsetDateTime;
+use tracing::{info, warn};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    /// Requests go through as usual.
+    Closed,
+    /// The proxy is left out of rotation.
+    Open,
+    /// A few probe requests are let through to
+    /// find out whether the proxy has recovered.
+    HalfOpen,
+}
+
+impl BreakerState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open => "open",
+            BreakerState::HalfOpen => "half_open",
+        }
+    }
+}
+
+impl fmt::Display for BreakerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BreakerConfig {
+    /// Failures in a row that open a breaker. Zero disables this.
+    pub failures: u32,
+    /// Share of failed requests within a window that opens a breaker.
+    pub error_rate: f64,
+    /// Requests needed within a window before its error rate counts.
+    pub min_requests: u32,
+    pub window: Duration,
+    /// How long a breaker stays open before letting probes through.
+    pub open_for: Duration,
+    /// Probes let through at once while half-open. All of
+    /// them have to succeed for the breaker to close again.
+    pub probes: u32,
+    /// Whether every proxy also gets a breaker per domain.
+    pub per_domain: bool,
+}
+
+impl Default for BreakerConfig {
+    fn default() -> Self {
+        Self {
+            failures: 5,
+            error_rate: 0.5,
+            min_requests: 20,
+            window: Duration::from_secs(60),
+            open_for: Duration::from_secs(30),
+            probes: 1,
+            per_domain: false,
+        }
+    }
+}
+
+impl BreakerConfig {
+    pub fn from_env() -> Self {
+        fn var<T: std::str::FromStr>(name: &str) -> Option<T> {
+            env::var(name)
+                .ok()
+                .map(|v| v.parse().unwrap_or_else(|_| panic!("Invalid {name}")))
+        }
+
+        let default = Self::default();
+        Self {
+            failures: var("LOCUST_BREAKER_FAILURES").unwrap_or(default.failures),
+            error_rate: var("LOCUST_BREAKER_ERROR_RATE").unwrap_or(default.error_rate),
+            min_requests: var("LOCUST_BREAKER_MIN_REQUESTS").unwrap_or(default.min_requests),
+            window: var("LOCUST_BREAKER_WINDOW_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(default.window),
+            open_for: var("LOCUST_BREAKER_OPEN_SECS")
+                .map(Duration::from_secs)
+                .unwrap_or(default.open_for),
+            probes: var::<u32>("LOCUST_BREAKER_PROBES")
+                .unwrap_or(default.probes)
+                .max(1),
+            per_domain: var("LOCUST_BREAKER_PER_DOMAIN").unwrap_or(default.per_domain),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Breaker {
+    state: BreakerState,
+    consecutive_failures: u32,
+    window_start: Instant,
+    requests: u32,
+    failures: u32,
+    opened_at: Option<(Instant, OffsetDateTime)>,
+    probes_in_flight: u32,
+    probe_successes: u32,
+}
+
+impl Breaker {
+    fn new(now: Instant) -> Self {
+        Self {
+            state: BreakerState::Closed,
+            consecutive_failures: 0,
+            window_start: now,
+            requests: 0,
+            failures: 0,
+            opened_at: None,
+            probes_in_flight: 0,
+            probe_successes: 0,
+        }
+    }
+
+    /// Whether a request may be sent at `now`.
+    fn allows(&self, now: Instant, config: &BreakerConfig) -> bool {
+        match self.state {
+            BreakerState::Closed => true,
+            BreakerState::Open => self
+                .opened_at
+                .is_some_and(|(at, _)| now.duration_since(at) >= config.open_for),
+            BreakerState::HalfOpen => self.probes_in_flight < config.probes,
+        }
+    }
+
+    /// Lets a request through, moving a breaker that has been open for
+    /// long enough to half-open. Returns whether the request is a probe.
+    fn admit(&mut self, now: Instant, config: &BreakerConfig) -> bool {
+        if self.state == BreakerState::Open && self.allows(now, config) {
+            self.state = BreakerState::HalfOpen;
+            self.probes_in_flight = 0;
+            self.probe_successes = 0;
+        }
+        if self.state == BreakerState::HalfOpen {
+            self.probes_in_flight += 1;
+            return true;
+        }
+        false
+    }
+
+    /// Counts the outcome of a request that was let through.
+    fn record(&mut self, ok: bool, probe: bool, now: Instant, config: &BreakerConfig) {
+        if probe {
+            if self.state != BreakerState::HalfOpen {
+                return;
+            }
+            self.probes_in_flight = self.probes_in_flight.saturating_sub(1);
+            if !ok {
+                self.open(now);
+            } else {
+                self.probe_successes += 1;
+                if self.probe_successes >= config.probes {
+                    *self = Self::new(now);
+                }
+            }
+            return;
+        }
+
+        // Requests that were let through before the
+        // breaker opened don't count towards closing it.
+        if self.state != BreakerState::Closed {
+            return;
+        }
+        if now.duration_since(self.window_start) >= config.window {
+            self.window_start = now;
+            self.requests = 0;
+            self.failures = 0;
+        }
+        self.requests += 1;
+        if ok {
+            self.consecutive_failures = 0;
+            return;
+        }
+        self.failures += 1;
+        self.consecutive_failures += 1;
+
+        let too_many_in_a_row = config.failures > 0 && self.consecutive_failures >= config.failures;
+        let error_rate = f64::from(self.failures) / f64::from(self.requests);
+        let too_many_failing =
+            self.requests >= config.min_requests && error_rate >= config.error_rate;
+        if too_many_in_a_row || too_many_failing {
+            self.open(now);
+        }
+    }
+
+    /// Gives back the slot of a probe that never got an outcome.
+    fn release(&mut self, probe: bool) {
+        if probe && self.state == BreakerState::HalfOpen {
+            self.probes_in_flight = self.probes_in_flight.saturating_sub(1);
+        }
+    }
+
+    fn open(&mut self, now: Instant) {
+        self.state = BreakerState::Open;
+        self.opened_at = Some((now, OffsetDateTime::now_utc()));
+        self.probes_in_flight = 0;
+        self.probe_successes = 0;
+    }
+}
+
+/// A breaker is kept per proxy, and per proxy and domain
+/// when configured to.
+type Key = (i32, Option<String>);
+
+/// In-process circuit breakers for the upstream proxies. A proxy
+/// that keeps failing is opened and left out of selection for a
+/// while, after which a trickle of probe requests decides whether
+/// it is closed again.
+///
+/// State changes are logged and stored in the database, so that
+/// they can be looked at from the CLI.
+pub struct CircuitBreakers {
+    config: BreakerConfig,
+    breakers: Mutex<HashMap<Key, Breaker>>,
+    trips: AtomicU64,
+    db_job_chan: DBJobSender,
+}
+
+impl CircuitBreakers {
+    pub fn new(config: BreakerConfig, db_job_chan: DBJobSender) -> Self {
+        Self {
+            config,
+            breakers: Mutex::new(HashMap::new()),
+            trips: AtomicU64::new(0),
+            db_job_chan,
+        }
+    }
+
+    /// The proxies that should not be picked for a request to `domain`.
+    pub fn blocked(&self, domain: Option<&str>) -> Vec<i32> {
+        let now = Instant::now();
+        let breakers = self.breakers.lock().unwrap();
+        let mut ids: Vec<i32> = breakers
+            .iter()
+            .filter(|((_, d), _)| d.is_none() || d.as_deref() == domain)
+            .filter(|(_, breaker)| !breaker.allows(now, &self.config))
+            .map(|((id, _), _)| *id)
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        ids
+    }
+
+    /// Lets a request to `domain` through a proxy. Its outcome
+    /// is to be recorded with the returned permit.
+    pub fn admit(self: &Arc<Self>, proxy_id: i32, domain: Option<&str>) -> Permit {
+        let domain = domain.filter(|_| self.config.per_domain).map(String::from);
+        let mut probes = Vec::with_capacity(2);
+        let now = Instant::now();
+        let mut breakers = self.breakers.lock().unwrap();
+        for key in keys(proxy_id, &domain) {
+            let breaker = breakers.entry(key.clone()).or_insert(Breaker::new(now));
+            let before = breaker.state;
+            probes.push(breaker.admit(now, &self.config));
+            self.report(&key, before, breaker);
+        }
+
+        Permit {
+            breakers: Arc::clone(self),
+            proxy_id,
+            domain,
+            probes,
+            done: false,
+        }
+    }
+
+    /// How many times a breaker has opened.
+    pub fn trips(&self) -> u64 {
+        self.trips.load(Ordering::Relaxed)
+    }
+
+    /// The breakers that are not closed.
+    pub fn states(&self) -> Vec<(i32, Option<String>, BreakerState)> {
+        let breakers = self.breakers.lock().unwrap();
+        let mut states: Vec<_> = breakers
+            .iter()
+            .filter(|(_, b)| b.state != BreakerState::Closed)
+            .map(|((id, domain), b)| (*id, domain.clone(), b.state))
+            .collect();
+        states.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+        states
+    }
+
+    fn finish(&self, permit: &Permit, ok: Option<(bool, bool)>) {
+        let now = Instant::now();
+        let mut breakers = self.breakers.lock().unwrap();
+        for (key, probe) in keys(permit.proxy_id, &permit.domain)
+            .into_iter()
+            .zip(&permit.probes)
+        {
+            let Some(breaker) = breakers.get_mut(&key) else {
+                continue;
+            };
+            let before = breaker.state;
+            match ok {
+                // Bans only count against the proxy for the domain.
+                Some((ok, banned)) => {
+                    let ok = ok || (banned && key.1.is_none());
+                    breaker.record(ok, *probe, now, &self.config)
+                }
+                None => breaker.release(*probe),
+            }
+            self.report(&key, before, breaker);
+        }
+    }
+
+    fn report(&self, (proxy_id, domain): &Key, before: BreakerState, breaker: &Breaker) {
+        if before == breaker.state {
+            return;
+        }
+        let domain = domain.clone().unwrap_or_default();
+        match breaker.state {
+            BreakerState::Open => {
+                self.trips.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    domain,
+                    "circuit breaker of proxy {proxy_id} opened after {} failures in a row",
+                    breaker.consecutive_failures
+                );
+            }
+            state => info!(domain, "circuit breaker of proxy {proxy_id} is {state}"),
+        }
+
+        let job = DBJob::BreakerChanged(ProxyBreaker {
+            proxy_id: *proxy_id,
+            domain,
+            state: breaker.state.as_str().into(),
+            consecutive_failures: breaker.consecutive_failures as i32,
+            date_opened: breaker.opened_at.map(|(_, at)| at),
+            date_modified: OffsetDateTime::now_utc(),
+        });
+        if let Err(e) = self.db_job_chan.send(job) {
+            warn!("Error sending breaker job: {e}");
+        }
+    }
+}
+
+fn keys(proxy_id: i32, domain: &Option<String>) -> Vec<Key> {
+    let mut keys = vec![(proxy_id, None)];
+    if let Some(domain) = domain {
+        keys.push((proxy_id, Some(domain.clone())));
+    }
+    keys
+}
+
+/// A request let through the breakers of a proxy. Dropping it
+/// without recording an outcome leaves the breakers as they were.
+pub struct Permit {
+    breakers: Arc<CircuitBreakers>,
+    proxy_id: i32,
+    domain: Option<String>,
+    probes: Vec<bool>,
+    done: bool,
+}
+
+impl Permit {
+    /// Records how the request went. Any error counts as a failure,
+    /// except for bans, which only count against the breaker of the
+    /// proxy for the domain.
+    pub fn record(mut self, error_class: Option<ErrorClass>) {
+        let outcome = (
+            error_class.is_none(),
+            error_class == Some(ErrorClass::Banned),
+        );
+        self.breakers.finish(&self, Some(outcome));
+        self.done = true;
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if !self.done {
+            self.breakers.finish(self, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        stats::Stats,
+        worker::{channel, WorkerConfig},
+    };
+
+    fn config() -> BreakerConfig {
+        BreakerConfig {
+            failures: 3,
+            error_rate: 0.5,
+            min_requests: 10,
+            window: Duration::from_secs(60),
+            open_for: Duration::from_secs(30),
+            probes: 2,
+            per_domain: true,
+        }
+    }
+
+    #[test]
+    fn test_opens_on_failures_in_a_row() {
+        let config = config();
+        let now = Instant::now();
+        let mut breaker = Breaker::new(now);
+        for ok in [false, false, true, false, false] {
+            breaker.record(ok, false, now, &config);
+        }
+        assert_eq!(breaker.state, BreakerState::Closed);
+        breaker.record(false, false, now, &config);
+        assert_eq!(breaker.state, BreakerState::Open);
+        assert!(!breaker.allows(now, &config));
+    }
+
+    #[test]
+    fn test_opens_on_error_rate() {
+        let config = config();
+        let now = Instant::now();
+        let mut breaker = Breaker::new(now);
+        for i in 0..9 {
+            breaker.record(i % 2 == 0, false, now, &config);
+        }
+        // Too few requests in the window so far.
+        assert_eq!(breaker.state, BreakerState::Closed);
+        breaker.record(false, false, now, &config);
+        assert_eq!(breaker.state, BreakerState::Open);
+
+        // A new window starts from scratch.
+        let mut breaker = Breaker::new(now);
+        for i in 0..9 {
+            breaker.record(i % 2 == 0, false, now, &config);
+        }
+        let later = now + config.window;
+        breaker.record(false, false, later, &config);
+        assert_eq!(breaker.state, BreakerState::Closed);
+    }
+
+    #[test]
+    fn test_half_open_probes() {
+        let config = config();
+        let now = Instant::now();
+        let mut breaker = Breaker::new(now);
+        breaker.open(now);
+        assert!(!breaker.admit(now, &config));
+
+        let later = now + config.open_for;
+        assert!(breaker.allows(later, &config));
+        assert!(breaker.admit(later, &config));
+        assert!(breaker.admit(later, &config));
+        assert_eq!(breaker.state, BreakerState::HalfOpen);
+        // Only a trickle of probes gets through.
+        assert!(!breaker.allows(later, &config));
+
+        breaker.record(true, true, later, &config);
+        assert_eq!(breaker.state, BreakerState::HalfOpen);
+        breaker.record(true, true, later, &config);
+        assert_eq!(breaker.state, BreakerState::Closed);
+
+        // A failed probe opens it again.
+        breaker.open(later);
+        let much_later = later + config.open_for;
+        assert!(breaker.admit(much_later, &config));
+        breaker.record(false, true, much_later, &config);
+        assert_eq!(breaker.state, BreakerState::Open);
+        assert!(!breaker.allows(much_later, &config));
+    }
+
+    #[tokio::test]
+    async fn test_bans_open_domain_breakers() {
+        let (tx, _rx) = channel(&WorkerConfig::default(), Arc::new(Stats::default()));
+        let breakers = Arc::new(CircuitBreakers::new(config(), tx));
+        for _ in 0..3 {
+            breakers
+                .admit(1, Some("a.com"))
+                .record(Some(ErrorClass::Banned));
+        }
+        assert_eq!(breakers.blocked(Some("a.com")), vec![1]);
+        assert!(breakers.blocked(Some("b.com")).is_empty());
+
+        for _ in 0..3 {
+            breakers
+                .admit(2, Some("b.com"))
+                .record(Some(ErrorClass::Timeout));
+        }
+        assert_eq!(breakers.blocked(Some("a.com")), vec![1, 2]);
+        assert_eq!(breakers.trips(), 3);
+        assert_eq!(
+            breakers.states(),
+            vec![
+                (1, Some("a.com".into()), BreakerState::Open),
+                (2, None, BreakerState::Open),
+                (2, Some("b.com".into()), BreakerState::Open),
+            ]
+        );
+    }
+}
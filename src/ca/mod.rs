@@ -3,7 +3,10 @@ mod rcgen_authority;
 
 use async_trait::async_trait;
 use http::uri::Authority;
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
 use tokio_rustls::rustls::ServerConfig;
 
 pub use rcgen_authority::*;
@@ -20,4 +23,36 @@ const NOT_BEFORE_OFFSET: i64 = 60;
 pub trait CertificateAuthority: Send + Sync + 'static {
     /// Generate ServerConfig for use with rustls.
     async fn gen_server_config(&self, authority: &Authority) -> Arc<ServerConfig>;
+
+    /// Hit and miss counts of the server config cache.
+    fn cache_stats(&self) -> CacheStats;
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+#[derive(Debug, Default)]
+struct CacheCounters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CacheCounters {
+    fn hit(&self) {
+        self.hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
 }
@@ -1,4 +1,6 @@
-use crate::ca::{CertificateAuthority, CACHE_TTL, NOT_BEFORE_OFFSET, TTL_SECS};
+use crate::ca::{
+    CacheCounters, CacheStats, CertificateAuthority, CACHE_TTL, NOT_BEFORE_OFFSET, TTL_SECS,
+};
 use async_trait::async_trait;
 use http::uri::Authority;
 use moka::future::Cache;
@@ -47,6 +49,7 @@ pub struct OpensslAuthority {
     ca_cert: X509,
     hash: MessageDigest,
     cache: Cache<Authority, Arc<ServerConfig>>,
+    cache_counters: Arc<CacheCounters>,
 }
 
 #[allow(dead_code)]
@@ -67,6 +70,7 @@ impl OpensslAuthority {
                 .max_capacity(cache_size)
                 .time_to_live(Duration::from_secs(CACHE_TTL))
                 .build(),
+            cache_counters: Arc::default(),
         }
     }
 
@@ -113,8 +117,10 @@ impl CertificateAuthority for OpensslAuthority {
     async fn gen_server_config(&self, authority: &Authority) -> Arc<ServerConfig> {
         if let Some(server_cfg) = self.cache.get(authority).await {
             debug!("Using cached server config");
+            self.cache_counters.hit();
             return server_cfg;
         }
+        self.cache_counters.miss();
         debug!("Generating server config");
 
         let certs = vec![self
@@ -141,4 +147,8 @@ impl CertificateAuthority for OpensslAuthority {
 
         server_cfg
     }
+
+    fn cache_stats(&self) -> CacheStats {
+        self.cache_counters.stats()
+    }
 }
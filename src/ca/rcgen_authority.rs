@@ -1,5 +1,5 @@
 use crate::{
-    ca::{CertificateAuthority, CACHE_TTL, NOT_BEFORE_OFFSET, TTL_SECS},
+    ca::{CacheCounters, CacheStats, CertificateAuthority, CACHE_TTL, NOT_BEFORE_OFFSET, TTL_SECS},
     error::Error,
 };
 use async_trait::async_trait;
@@ -49,6 +49,7 @@ pub struct RcgenAuthority {
     private_key: rustls::PrivateKey,
     ca_cert: rustls::Certificate,
     cache: Cache<Authority, Arc<ServerConfig>>,
+    cache_counters: Arc<CacheCounters>,
 }
 
 impl RcgenAuthority {
@@ -70,6 +71,7 @@ impl RcgenAuthority {
                 .max_capacity(cache_size)
                 .time_to_live(std::time::Duration::from_secs(CACHE_TTL))
                 .build(),
+            cache_counters: Arc::default(),
         };
 
         ca.validate()?;
@@ -125,8 +127,10 @@ impl CertificateAuthority for RcgenAuthority {
     async fn gen_server_config(&self, authority: &Authority) -> Arc<ServerConfig> {
         if let Some(server_cfg) = self.cache.get(authority).await {
             debug!("Using cached server config");
+            self.cache_counters.hit();
             return server_cfg;
         }
+        self.cache_counters.miss();
         debug!("Generating server config");
 
         let certs = vec![self.gen_cert(authority)];
@@ -147,4 +151,8 @@ impl CertificateAuthority for RcgenAuthority {
 
         server_cfg
     }
+
+    fn cache_stats(&self) -> CacheStats {
+        self.cache_counters.stats()
+    }
 }
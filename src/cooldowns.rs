@@ -0,0 +1,182 @@
+use crate::worker::{DBJob, DBJobSender};
+
+use locust_core::{
+    cooldowns::CooldownConfig, crud::cooldowns::get_proxy_cooldowns,
+    models::cooldowns::ProxyCooldown,
+};
+use sqlx::PgPool;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use time::OffsetDateTime;
+use tokio::time::interval;
+use tracing::warn;
+
+/// How often the cooldowns other servers stored are loaded.
+const COOLDOWNS_REFRESH: Duration = Duration::from_secs(5);
+
+/// Cooldowns by proxy id and domain.
+type CooldownMap = HashMap<(i32, String), ProxyCooldown>;
+
+/// The proxies cooling down from rate limits, kept in memory so that
+/// picking a proxy does not go to the database. With a database, new
+/// cooldowns are stored by the DB workers, and the ones other servers
+/// stored are loaded every few seconds so that every server sees them.
+pub struct Cooldowns {
+    config: CooldownConfig,
+    db_job_chan: DBJobSender,
+    memory: Arc<Mutex<CooldownMap>>,
+}
+
+impl Cooldowns {
+    pub fn new(config: CooldownConfig, db_job_chan: DBJobSender) -> Self {
+        Self {
+            config,
+            db_job_chan,
+            memory: Arc::default(),
+        }
+    }
+
+    /// Keeps loading the cooldowns stored in the database in the background.
+    pub fn spawn_refresh(&self, db: Arc<PgPool>) {
+        let memory = Arc::clone(&self.memory);
+        let max = self.config.max;
+        tokio::spawn(async move {
+            let mut timer = interval(COOLDOWNS_REFRESH);
+            loop {
+                timer.tick().await;
+                let now = OffsetDateTime::now_utc();
+                match get_proxy_cooldowns(&db, now - max).await {
+                    Ok(stored) => merge(&mut memory.lock().unwrap(), stored, now, max),
+                    Err(e) => warn!("error loading proxy cooldowns: {e}"),
+                }
+            }
+        });
+    }
+
+    /// Gets the proxies that are cooling down for a domain.
+    pub fn get(&self, domain: &str) -> Vec<ProxyCooldown> {
+        let now = OffsetDateTime::now_utc();
+        let mut cooldowns: Vec<_> = self
+            .memory
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|c| c.domain == domain && c.date_until > now)
+            .cloned()
+            .collect();
+        cooldowns.sort_by_key(|c| c.date_until);
+        cooldowns
+    }
+
+    /// Puts a proxy in cooldown for a domain after it got rate limited.
+    /// Without a `Retry-After`, the cooldown backs off exponentially
+    /// with the rate limits the proxy got in a row.
+    pub fn add(&self, proxy_id: i32, domain: &str, retry_after: Option<Duration>) -> ProxyCooldown {
+        let now = OffsetDateTime::now_utc();
+        let mut memory = self.memory.lock().unwrap();
+        prune(&mut memory, now, self.config.max);
+
+        let key = (proxy_id, domain.to_string());
+        let previous = memory.get(&key);
+        let strikes = self.config.strikes(previous, now);
+        let mut date_until = now + self.config.cooldown(strikes, retry_after);
+        if let Some(previous) = previous {
+            date_until = date_until.max(previous.date_until);
+        }
+        let cooldown = ProxyCooldown {
+            proxy_id,
+            domain: domain.to_string(),
+            strikes,
+            date_until,
+        };
+        memory.insert(key, cooldown.clone());
+        drop(memory);
+
+        if let Err(e) = self
+            .db_job_chan
+            .send(DBJob::CooldownAdded(cooldown.clone()))
+        {
+            warn!("Error sending cooldown job: {e}");
+        }
+        cooldown
+    }
+}
+
+/// Forgets the cooldowns that stopped counting towards the next one.
+fn prune(memory: &mut CooldownMap, now: OffsetDateTime, max: Duration) {
+    memory.retain(|_, c| c.date_until + max > now);
+}
+
+/// Adds the stored cooldowns, keeping the one that lasts
+/// longer when a proxy is cooling down on both sides.
+fn merge(memory: &mut CooldownMap, stored: Vec<ProxyCooldown>, now: OffsetDateTime, max: Duration) {
+    for cooldown in stored {
+        let key = (cooldown.proxy_id, cooldown.domain.clone());
+        match memory.get(&key) {
+            Some(current) if current.date_until >= cooldown.date_until => {}
+            _ => {
+                memory.insert(key, cooldown);
+            }
+        }
+    }
+    prune(memory, now, max);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        stats::Stats,
+        worker::{channel, WorkerConfig},
+    };
+
+    #[tokio::test]
+    async fn test_cooldowns() {
+        let (tx, rx) = channel(&WorkerConfig::default(), Arc::new(Stats::default()));
+        let cooldowns = Cooldowns::new(CooldownConfig::default(), tx);
+        let first = cooldowns.add(1, "example.com", None);
+        let second = cooldowns.add(1, "example.com", None);
+        assert_eq!((first.strikes, second.strikes), (1, 2));
+        assert!(second.date_until > first.date_until);
+        cooldowns.add(2, "example.com", Some(Duration::ZERO));
+        cooldowns.add(3, "other.com", None);
+
+        // The cooldown of proxy 2 is already over.
+        assert_eq!(cooldowns.get("example.com"), [second]);
+
+        // New cooldowns are stored by the DB workers.
+        let mut rx = rx.lock().await;
+        match rx.recv().await {
+            Some(DBJob::CooldownAdded(cooldown)) => assert_eq!(cooldown, first),
+            _ => panic!("expected a cooldown"),
+        }
+    }
+
+    #[test]
+    fn test_merge_stored_cooldowns() {
+        let now = OffsetDateTime::now_utc();
+        let max = Duration::from_secs(900);
+        let cooldown = |proxy_id, secs: i64| ProxyCooldown {
+            proxy_id,
+            domain: "example.com".into(),
+            strikes: 1,
+            date_until: now + time::Duration::seconds(secs),
+        };
+        let mut memory = CooldownMap::new();
+        memory.insert((1, "example.com".into()), cooldown(1, 60));
+        memory.insert((2, "example.com".into()), cooldown(2, 10));
+        merge(
+            &mut memory,
+            vec![cooldown(1, 30), cooldown(2, 20), cooldown(3, -1000)],
+            now,
+            max,
+        );
+        assert_eq!(memory[&(1, "example.com".into())], cooldown(1, 60));
+        assert_eq!(memory[&(2, "example.com".into())], cooldown(2, 20));
+        // Long over, so it no longer counts towards strikes.
+        assert!(!memory.contains_key(&(3, "example.com".into())));
+    }
+}
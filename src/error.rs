@@ -8,6 +8,8 @@ pub enum Error {
     Tls(#[from] rcgen::Error),
     #[error("network error")]
     Network(#[from] hyper::Error),
+    #[error("admin server error")]
+    Admin(#[from] warp::Error),
     #[error("unable to decode body")]
     Decode,
     #[error("unknown error")]
@@ -0,0 +1,104 @@
+use locust_core::{
+    crud::exits::get_proxy_exits,
+    exits::{discover_exits, ExitConfig},
+    health::CheckUrl,
+};
+use sqlx::PgPool;
+use std::{env, sync::Arc, time::Duration};
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::leader::{Leadership, EXIT_CHECK_LOCK};
+
+const DEFAULT_INTERVAL_SECS: u64 = 3600;
+const DEFAULT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_CONCURRENCY: usize = 32;
+
+/// Periodically discovers the address every proxy exits from through
+/// an echo endpoint, warning about proxies that share one.
+pub struct ExitChecker {
+    db: Arc<PgPool>,
+    config: ExitConfig,
+    every: Duration,
+}
+
+impl ExitChecker {
+    pub fn new(db: Arc<PgPool>, config: ExitConfig, every: Duration) -> Self {
+        Self { db, config, every }
+    }
+
+    /// Builds the checker from `LOCUST_EXIT_CHECK_URL`, an echo
+    /// endpoint, and the optional `LOCUST_EXIT_CHECK_INTERVAL_SECS`,
+    /// `LOCUST_EXIT_CHECK_TIMEOUT_SECS` and
+    /// `LOCUST_EXIT_CHECK_CONCURRENCY`. Returns `None` when
+    /// no echo endpoint is configured.
+    pub fn from_env(db: Arc<PgPool>) -> Option<Self> {
+        fn var<T: std::str::FromStr>(name: &str, default: T) -> T {
+            env::var(name)
+                .map(|v| v.parse().unwrap_or_else(|_| panic!("Invalid {name}")))
+                .unwrap_or(default)
+        }
+
+        let url: CheckUrl = env::var("LOCUST_EXIT_CHECK_URL")
+            .ok()?
+            .parse()
+            .expect("Invalid LOCUST_EXIT_CHECK_URL");
+        let config = ExitConfig {
+            url,
+            timeout: Duration::from_secs(var(
+                "LOCUST_EXIT_CHECK_TIMEOUT_SECS",
+                DEFAULT_TIMEOUT_SECS,
+            )),
+            concurrency: var("LOCUST_EXIT_CHECK_CONCURRENCY", DEFAULT_CONCURRENCY),
+        };
+        let every = Duration::from_secs(var(
+            "LOCUST_EXIT_CHECK_INTERVAL_SECS",
+            DEFAULT_INTERVAL_SECS,
+        ));
+
+        Some(Self::new(db, config, every))
+    }
+
+    pub async fn start(self) {
+        info!("Discovering proxy exit IPs through {}", self.config.url);
+        // Only one server checks at a time, or each
+        // would record its own round of checks.
+        let mut leadership = Leadership::new(Arc::clone(&self.db), EXIT_CHECK_LOCK);
+        let mut timer = interval(self.every);
+        timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            timer.tick().await;
+            if leadership.is_leader().await {
+                self.run().instrument(info_span!("exit_check")).await;
+            }
+        }
+    }
+
+    async fn run(&self) {
+        let results = match discover_exits(&self.db, &self.config).await {
+            Ok(results) => results,
+            Err(e) => {
+                warn!("error discovering exit IPs: {e}");
+                return;
+            }
+        };
+        let failed = results.iter().filter(|r| r.exit.is_err()).count();
+        info!("checked {} proxy exits, {failed} failed", results.len());
+
+        let exits = match get_proxy_exits(&self.db).await {
+            Ok(exits) => exits,
+            Err(e) => {
+                warn!("error fetching exit IPs: {e}");
+                return;
+            }
+        };
+        for exit in exits.iter().filter(|e| e.is_shared()) {
+            warn!(
+                proxy.id = exit.proxy_id,
+                "proxy shares exit IP {} with proxies {:?}",
+                exit.exit_ip.as_deref().unwrap_or_default(),
+                exit.shared_with
+            );
+        }
+    }
+}
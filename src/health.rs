@@ -0,0 +1,100 @@
+use locust_core::health::{check_proxies, CheckConfig, CheckUrl};
+use sqlx::PgPool;
+use std::{env, sync::Arc, time::Duration};
+use tokio::time::{interval, MissedTickBehavior};
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::leader::{Leadership, HEALTH_CHECK_LOCK};
+
+const DEFAULT_INTERVAL_SECS: u64 = 60;
+const DEFAULT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_QUARANTINE_AFTER: i32 = 3;
+const DEFAULT_CONCURRENCY: usize = 32;
+
+/// Periodically probes every proxy in the pool through a check
+/// URL, quarantining the ones that keep failing and reinstating
+/// them once they pass again.
+pub struct HealthChecker {
+    db: Arc<PgPool>,
+    config: CheckConfig,
+    every: Duration,
+}
+
+impl HealthChecker {
+    pub fn new(db: Arc<PgPool>, config: CheckConfig, every: Duration) -> Self {
+        Self { db, config, every }
+    }
+
+    /// Builds the checker from `LOCUST_HEALTH_CHECK_URL`, and the
+    /// optional `LOCUST_HEALTH_CHECK_INTERVAL_SECS`,
+    /// `LOCUST_HEALTH_CHECK_TIMEOUT_SECS`,
+    /// `LOCUST_HEALTH_CHECK_FAILURES` and
+    /// `LOCUST_HEALTH_CHECK_CONCURRENCY`. Returns `None` when
+    /// no check URL is configured.
+    pub fn from_env(db: Arc<PgPool>) -> Option<Self> {
+        fn var<T: std::str::FromStr>(name: &str, default: T) -> T {
+            env::var(name)
+                .map(|v| v.parse().unwrap_or_else(|_| panic!("Invalid {name}")))
+                .unwrap_or(default)
+        }
+
+        let url: CheckUrl = env::var("LOCUST_HEALTH_CHECK_URL")
+            .ok()?
+            .parse()
+            .expect("Invalid LOCUST_HEALTH_CHECK_URL");
+        let config = CheckConfig {
+            url,
+            timeout: Duration::from_secs(var(
+                "LOCUST_HEALTH_CHECK_TIMEOUT_SECS",
+                DEFAULT_TIMEOUT_SECS,
+            )),
+            quarantine_after: var("LOCUST_HEALTH_CHECK_FAILURES", DEFAULT_QUARANTINE_AFTER),
+            concurrency: var("LOCUST_HEALTH_CHECK_CONCURRENCY", DEFAULT_CONCURRENCY),
+        };
+        let every = Duration::from_secs(var(
+            "LOCUST_HEALTH_CHECK_INTERVAL_SECS",
+            DEFAULT_INTERVAL_SECS,
+        ));
+
+        Some(Self::new(db, config, every))
+    }
+
+    pub async fn start(self) {
+        info!("Checking proxy health against {}", self.config.url);
+        // Only one server checks at a time, or each
+        // would record its own round of checks.
+        let mut leadership = Leadership::new(Arc::clone(&self.db), HEALTH_CHECK_LOCK);
+        let mut timer = interval(self.every);
+        timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            timer.tick().await;
+            if leadership.is_leader().await {
+                self.run().instrument(info_span!("health_check")).await;
+            }
+        }
+    }
+
+    async fn run(&self) {
+        let results = match check_proxies(&self.db, &self.config).await {
+            Ok(results) => results,
+            Err(e) => {
+                warn!("error checking proxy health: {e}");
+                return;
+            }
+        };
+
+        let failed = results.iter().filter(|r| !r.check.is_healthy()).count();
+        info!("checked {} proxies, {failed} failed", results.len());
+        for result in results {
+            let id = result.proxy.id;
+            if result.quarantined() {
+                warn!(
+                    proxy.id = id,
+                    "quarantined proxy after {} failed checks", result.health.check_failures
+                );
+            } else if result.reinstated() {
+                info!(proxy.id = id, "reinstated proxy");
+            }
+        }
+    }
+}
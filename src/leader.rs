@@ -0,0 +1,116 @@
+use sqlx::{Connection, PgConnection, PgPool};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// Advisory lock keys of the tasks a single server runs at a time.
+pub const HEALTH_CHECK_LOCK: i64 = 0x6c6f_6375_7374_0001;
+pub const EXIT_CHECK_LOCK: i64 = 0x6c6f_6375_7374_0002;
+
+/// Elects a single server to run a task with a Postgres advisory lock.
+/// The lock is held on a connection of its own for as long as the
+/// server runs, and released by Postgres when that connection goes
+/// away, so that another server takes over.
+pub struct Leadership {
+    db: Arc<PgPool>,
+    key: i64,
+    conn: Option<PgConnection>,
+}
+
+impl Leadership {
+    pub fn new(db: Arc<PgPool>, key: i64) -> Self {
+        Self {
+            db,
+            key,
+            conn: None,
+        }
+    }
+
+    /// Whether this server holds the lock, trying to
+    /// take it when it does not.
+    pub async fn is_leader(&mut self) -> bool {
+        if let Some(conn) = &mut self.conn {
+            match conn.ping().await {
+                Ok(_) => return true,
+                Err(e) => {
+                    warn!(
+                        "lost the connection holding advisory lock {:#x}: {e}",
+                        self.key
+                    );
+                    self.conn = None;
+                }
+            }
+        }
+
+        let mut conn = match self.db.acquire().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                warn!("error connecting to take the lock: {e}");
+                return false;
+            }
+        };
+        match sqlx::query_scalar("SELECT pg_try_advisory_lock($1)")
+            .bind(self.key)
+            .fetch_one(&mut *conn)
+            .await
+        {
+            Ok(true) => {
+                info!("took advisory lock {:#x}, running here", self.key);
+                // Taken out of the pool, so that the lock is released
+                // with the connection rather than handed on with it.
+                self.conn = Some(conn.detach());
+                true
+            }
+            Ok(false) => false,
+            Err(e) => {
+                warn!("error taking the lock: {e}");
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sqlx::postgres::PgPoolOptions;
+    use std::env;
+
+    /// Runs against the Postgres at `LOCUST_TEST_DATABASE_URL`.
+    /// Skipped when the variable is not set.
+    #[tokio::test]
+    async fn test_single_leader() {
+        let Ok(url) = env::var("LOCUST_TEST_DATABASE_URL") else {
+            eprintln!("LOCUST_TEST_DATABASE_URL is not set, skipping");
+            return;
+        };
+        let key = std::process::id() as i64;
+        let connect = || async {
+            Arc::new(
+                PgPoolOptions::new()
+                    .max_connections(2)
+                    .connect(&url)
+                    .await
+                    .unwrap(),
+            )
+        };
+        let mut first = Leadership::new(connect().await, key);
+        let mut second = Leadership::new(connect().await, key);
+
+        assert!(first.is_leader().await);
+        assert!(!second.is_leader().await);
+        assert!(first.is_leader().await);
+
+        // Another server takes over once the leader is gone,
+        // as soon as Postgres notices.
+        drop(first);
+        let mut took_over = false;
+        for _ in 0..50 {
+            if second.is_leader().await {
+                took_over = true;
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+        assert!(took_over);
+    }
+}
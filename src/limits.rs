@@ -0,0 +1,566 @@
+use crate::stats::Stats;
+
+use locust_core::{domains::normalize_host, routing::DomainLimit};
+use std::{
+    collections::HashMap,
+    env, fmt,
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::{sleep_until, timeout_at, Instant},
+};
+
+/// How often limiters of domains and proxies
+/// that see no traffic are dropped.
+const LIMITER_IDLE: Duration = Duration::from_secs(600);
+const DEFAULT_WAIT_MS: u64 = 5000;
+
+/// What a limit applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    Domain,
+    Proxy,
+    /// A proxy requesting a single domain.
+    Pair,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Domain => "domain",
+            Scope::Proxy => "proxy",
+            Scope::Pair => "pair",
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The limits of a single domain, proxy or pair. Either one can be left unset.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limit {
+    /// Requests in flight at the same time.
+    pub concurrency: Option<usize>,
+    /// Requests started per second, with bursts of up to as many.
+    pub rps: Option<f64>,
+}
+
+impl Limit {
+    fn from_env(prefix: &str) -> Self {
+        let var = |name: &str| env::var(format!("LOCUST_LIMIT_{prefix}_{name}")).ok();
+        Self {
+            concurrency: var("CONCURRENCY").map(|v| {
+                v.parse()
+                    .unwrap_or_else(|_| panic!("Invalid LOCUST_LIMIT_{prefix}_CONCURRENCY"))
+            }),
+            rps: var("RPS").map(|v| {
+                v.parse()
+                    .ok()
+                    .filter(|rps: &f64| *rps > 0.0)
+                    .unwrap_or_else(|| panic!("Invalid LOCUST_LIMIT_{prefix}_RPS"))
+            }),
+        }
+    }
+
+    fn is_unlimited(&self) -> bool {
+        self.concurrency.is_none() && self.rps.is_none()
+    }
+
+    /// The limit with what a domain route sets in place of this one.
+    fn with(self, route: DomainLimit) -> Self {
+        Self {
+            concurrency: route.concurrency.map(|n| n as usize).or(self.concurrency),
+            rps: route.rps.or(self.rps),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct LimitConfig {
+    pub domain: Limit,
+    pub proxy: Limit,
+    pub pair: Limit,
+    /// How long a request may queue for the limits before
+    /// it is turned away.
+    pub wait: Duration,
+}
+
+impl LimitConfig {
+    /// Reads `LOCUST_LIMIT_{DOMAIN,PROXY,PAIR}_{CONCURRENCY,RPS}`
+    /// and the queuing time from `LOCUST_LIMIT_WAIT_MS`.
+    pub fn from_env() -> Self {
+        let wait = env::var("LOCUST_LIMIT_WAIT_MS")
+            .map(|v| v.parse().expect("Invalid LOCUST_LIMIT_WAIT_MS"))
+            .unwrap_or(DEFAULT_WAIT_MS);
+        Self {
+            domain: Limit::from_env("DOMAIN"),
+            proxy: Limit::from_env("PROXY"),
+            pair: Limit::from_env("PAIR"),
+            wait: Duration::from_millis(wait),
+        }
+    }
+}
+
+/// A generic cell rate algorithm, which spaces requests out by
+/// `interval` while letting `burst` of them through at once.
+struct Rate {
+    interval: Duration,
+    tolerance: Duration,
+    /// When the next request would be on schedule.
+    tat: Instant,
+}
+
+impl Rate {
+    fn new(rps: f64) -> Self {
+        let burst = rps.floor().max(1.0) as u32;
+        let interval = Duration::from_secs_f64(1.0 / rps);
+        Self {
+            interval,
+            tolerance: interval * (burst - 1),
+            tat: Instant::now(),
+        }
+    }
+
+    /// Books a slot for a request, returning when it may start. Nothing
+    /// is booked when that would be after the deadline.
+    fn reserve(&mut self, now: Instant, deadline: Instant) -> Option<Instant> {
+        let tat = self.tat.max(now);
+        let at = tat
+            .checked_sub(self.tolerance)
+            .map_or(now, |at| at.max(now));
+        if at > deadline {
+            return None;
+        }
+        self.tat = tat + self.interval;
+        Some(at)
+    }
+}
+
+struct Limiter {
+    limit: Limit,
+    slots: Option<Arc<Semaphore>>,
+    rate: Option<Mutex<Rate>>,
+}
+
+impl Limiter {
+    fn new(limit: Limit) -> Self {
+        Self {
+            limit,
+            slots: limit.concurrency.map(|n| Arc::new(Semaphore::new(n))),
+            rate: limit.rps.map(|rps| Mutex::new(Rate::new(rps))),
+        }
+    }
+
+    /// Whether dropping the limiter would lose nothing: no request
+    /// holds or waits for one of its slots, and its rate has caught up.
+    fn is_idle(self: &Arc<Self>, now: Instant) -> bool {
+        let slots_free = match (&self.slots, self.limit.concurrency) {
+            (Some(slots), Some(n)) => slots.available_permits() == n,
+            _ => true,
+        };
+        let caught_up = match &self.rate {
+            Some(rate) => rate.lock().unwrap().tat <= now,
+            None => true,
+        };
+        Arc::strong_count(self) == 1 && slots_free && caught_up
+    }
+
+    /// Waits until the limit lets a request through,
+    /// failing if that does not happen by the deadline.
+    async fn acquire(&self, deadline: Instant) -> Result<Option<OwnedSemaphorePermit>, ()> {
+        let slot = match &self.slots {
+            Some(slots) => {
+                let slot = timeout_at(deadline, Arc::clone(slots).acquire_owned())
+                    .await
+                    .map_err(|_| ())?
+                    .expect("Limiter semaphore closed");
+                Some(slot)
+            }
+            None => None,
+        };
+        if let Some(rate) = &self.rate {
+            let at = rate
+                .lock()
+                .unwrap()
+                .reserve(Instant::now(), deadline)
+                .ok_or(())?;
+            sleep_until(at).await;
+        }
+        Ok(slot)
+    }
+}
+
+/// Concurrency slots held by a request. They are
+/// given back once it is dropped.
+#[derive(Default)]
+pub struct LimitPermit(Vec<OwnedSemaphorePermit>);
+
+impl LimitPermit {
+    pub fn merge(&mut self, other: LimitPermit) {
+        self.0.extend(other.0);
+    }
+}
+
+/// Limiters by what they limit. Idle ones are dropped every
+/// `LIMITER_IDLE`, so that a request still holding a slot
+/// never outlives the limiter it counts against.
+struct Limiters<K> {
+    limiters: Mutex<HashMap<K, Arc<Limiter>>>,
+    next_prune: Mutex<Instant>,
+}
+
+impl<K: Hash + Eq> Limiters<K> {
+    fn new() -> Self {
+        Self {
+            limiters: Mutex::new(HashMap::new()),
+            next_prune: Mutex::new(Instant::now() + LIMITER_IDLE),
+        }
+    }
+
+    /// Gets the limiter of the key, starting a new one when the
+    /// limit has changed since. Requests already let through by
+    /// the old one keep counting against it.
+    fn get(&self, key: K, limit: Limit) -> Arc<Limiter> {
+        let now = Instant::now();
+        let mut limiters = self.limiters.lock().unwrap();
+        let mut next_prune = self.next_prune.lock().unwrap();
+        if now >= *next_prune {
+            limiters.retain(|_, limiter| !limiter.is_idle(now));
+            *next_prune = now + LIMITER_IDLE;
+        }
+        let limiter = limiters
+            .entry(key)
+            .or_insert_with(|| Arc::new(Limiter::new(limit)));
+        if limiter.limit != limit {
+            *limiter = Arc::new(Limiter::new(limit));
+        }
+        Arc::clone(limiter)
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.limiters.lock().unwrap().len()
+    }
+}
+
+/// Politeness limits on requests per domain, per proxy
+/// and per proxy requesting a domain.
+pub struct Limits {
+    config: LimitConfig,
+    stats: Arc<Stats>,
+    domains: Limiters<String>,
+    proxies: Limiters<i32>,
+    pairs: Limiters<(i32, String)>,
+}
+
+impl Limits {
+    pub fn new(config: LimitConfig, stats: Arc<Stats>) -> Self {
+        Self {
+            config,
+            stats,
+            domains: Limiters::new(),
+            proxies: Limiters::new(),
+            pairs: Limiters::new(),
+        }
+    }
+
+    /// When a request that starts queuing now is turned away.
+    pub fn deadline(&self) -> Instant {
+        Instant::now() + self.config.wait
+    }
+
+    /// Waits for the limits of a domain, before a proxy is picked for
+    /// it. Those set on its route win over the configured ones.
+    pub async fn acquire_domain(
+        &self,
+        domain: Option<&str>,
+        route: DomainLimit,
+        deadline: Instant,
+    ) -> Result<LimitPermit, Scope> {
+        let mut permit = LimitPermit::default();
+        let limit = self.config.domain.with(route);
+        if let Some(domain) = domain.filter(|_| !limit.is_unlimited()) {
+            let limiter = self.domains.get(normalize_host(domain), limit);
+            self.wait(&limiter, Scope::Domain, deadline, &mut permit)
+                .await?;
+        }
+        Ok(permit)
+    }
+
+    /// Waits for the limits of a proxy, and of the proxy
+    /// requesting the domain, once it has been picked.
+    pub async fn acquire_proxy(
+        &self,
+        proxy_id: i32,
+        domain: Option<&str>,
+        deadline: Instant,
+    ) -> Result<LimitPermit, Scope> {
+        let mut permit = LimitPermit::default();
+        if !self.config.proxy.is_unlimited() {
+            let limiter = self.proxies.get(proxy_id, self.config.proxy);
+            self.wait(&limiter, Scope::Proxy, deadline, &mut permit)
+                .await?;
+        }
+        if let Some(domain) = domain.filter(|_| !self.config.pair.is_unlimited()) {
+            let limiter = self
+                .pairs
+                .get((proxy_id, normalize_host(domain)), self.config.pair);
+            self.wait(&limiter, Scope::Pair, deadline, &mut permit)
+                .await?;
+        }
+        Ok(permit)
+    }
+
+    async fn wait(
+        &self,
+        limiter: &Limiter,
+        scope: Scope,
+        deadline: Instant,
+        permit: &mut LimitPermit,
+    ) -> Result<(), Scope> {
+        let _queued = self.stats.start_limit_wait();
+        match limiter.acquire(deadline).await {
+            Ok(slot) => {
+                permit.0.extend(slot);
+                Ok(())
+            }
+            Err(()) => {
+                self.stats.record_limited(scope.as_str());
+                Err(scope)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(domain: Limit, proxy: Limit, pair: Limit) -> Limits {
+        let config = LimitConfig {
+            domain,
+            proxy,
+            pair,
+            wait: Duration::from_millis(500),
+        };
+        Limits::new(config, Arc::new(Stats::default()))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_concurrency_limit_queues_until_deadline() {
+        let limit = Limit {
+            concurrency: Some(1),
+            rps: None,
+        };
+        let limits = limits(limit, Limit::default(), Limit::default());
+
+        let first = limits
+            .acquire_domain(
+                Some("example.com"),
+                DomainLimit::default(),
+                limits.deadline(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            limits
+                .acquire_domain(
+                    Some("example.com"),
+                    DomainLimit::default(),
+                    limits.deadline()
+                )
+                .await
+                .err(),
+            Some(Scope::Domain)
+        );
+        // Other domains are limited separately.
+        assert!(limits
+            .acquire_domain(
+                Some("example.org"),
+                DomainLimit::default(),
+                limits.deadline()
+            )
+            .await
+            .is_ok());
+
+        // A request queues until the slot is given back.
+        let deadline = limits.deadline();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            drop(first);
+        });
+        assert!(limits
+            .acquire_domain(Some("example.com"), DomainLimit::default(), deadline)
+            .await
+            .is_ok());
+        assert_eq!(
+            limits.stats.snapshot().requests_limited.get("domain"),
+            Some(&1)
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_domain_limit_overrides() {
+        let limit = Limit {
+            concurrency: Some(1),
+            rps: None,
+        };
+        let limits = limits(limit, Limit::default(), Limit::default());
+        let route = DomainLimit {
+            concurrency: Some(2),
+            rps: None,
+        };
+
+        // Hosts are limited by their normalized name.
+        let _held = limits
+            .acquire_domain(Some("Example.com"), route, limits.deadline())
+            .await
+            .unwrap();
+        let _held = limits
+            .acquire_domain(Some("example.com."), route, limits.deadline())
+            .await
+            .unwrap();
+        assert_eq!(
+            limits
+                .acquire_domain(Some("example.com"), route, limits.deadline())
+                .await
+                .err(),
+            Some(Scope::Domain)
+        );
+        assert_eq!(limits.domains.len(), 1);
+
+        // Without an override the configured limit applies.
+        let _held = limits
+            .acquire_domain(
+                Some("example.org"),
+                DomainLimit::default(),
+                limits.deadline(),
+            )
+            .await
+            .unwrap();
+        assert!(limits
+            .acquire_domain(
+                Some("example.org"),
+                DomainLimit::default(),
+                limits.deadline()
+            )
+            .await
+            .is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_limiters_in_use_are_kept() {
+        let limit = Limit {
+            concurrency: Some(1),
+            rps: None,
+        };
+        let limits = limits(limit, Limit::default(), Limit::default());
+
+        let held = limits
+            .acquire_domain(
+                Some("example.com"),
+                DomainLimit::default(),
+                limits.deadline(),
+            )
+            .await
+            .unwrap();
+        let idle = limits
+            .acquire_domain(
+                Some("example.org"),
+                DomainLimit::default(),
+                limits.deadline(),
+            )
+            .await
+            .unwrap();
+        drop(idle);
+        tokio::time::advance(LIMITER_IDLE).await;
+
+        // The idle limiter is dropped, while the one whose slot is
+        // still held keeps turning requests away.
+        assert_eq!(
+            limits
+                .acquire_domain(
+                    Some("example.com"),
+                    DomainLimit::default(),
+                    limits.deadline()
+                )
+                .await
+                .err(),
+            Some(Scope::Domain)
+        );
+        assert_eq!(limits.domains.len(), 1);
+        drop(held);
+        assert!(limits
+            .acquire_domain(
+                Some("example.com"),
+                DomainLimit::default(),
+                limits.deadline()
+            )
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rate_limit_spaces_requests() {
+        let limit = Limit {
+            concurrency: None,
+            rps: Some(2.0),
+        };
+        let limits = limits(Limit::default(), limit, Limit::default());
+        let start = Instant::now();
+
+        // A burst of two goes through at once, then requests
+        // are spaced out until the deadline is reached.
+        let deadline = start + Duration::from_millis(600);
+        for _ in 0..2 {
+            limits.acquire_proxy(1, None, deadline).await.unwrap();
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        limits.acquire_proxy(1, None, deadline).await.unwrap();
+        assert_eq!(start.elapsed(), Duration::from_millis(500));
+        assert_eq!(
+            limits.acquire_proxy(1, None, deadline).await.err(),
+            Some(Scope::Proxy)
+        );
+        assert!(limits.acquire_proxy(2, None, deadline).await.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_pair_limit() {
+        let limit = Limit {
+            concurrency: Some(1),
+            rps: None,
+        };
+        let limits = limits(Limit::default(), Limit::default(), limit);
+
+        let _held = limits
+            .acquire_proxy(1, Some("example.com"), limits.deadline())
+            .await
+            .unwrap();
+        let deadline = limits.deadline();
+        assert_eq!(
+            limits
+                .acquire_proxy(1, Some("example.com"), deadline)
+                .await
+                .err(),
+            Some(Scope::Pair)
+        );
+        assert!(limits
+            .acquire_proxy(2, Some("example.com"), deadline)
+            .await
+            .is_ok());
+        assert!(limits
+            .acquire_proxy(1, Some("example.org"), deadline)
+            .await
+            .is_ok());
+        // Without a domain only the proxy limit applies.
+        assert!(limits.acquire_proxy(1, None, deadline).await.is_ok());
+    }
+}
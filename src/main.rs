@@ -1,12 +1,44 @@
+mod access_log;
+mod admin;
+mod bans;
+mod breaker;
 mod ca;
+mod cooldowns;
 mod error;
+mod exits;
+mod health;
+mod leader;
+mod limits;
 mod metrics;
+mod overrides;
+mod pool;
 mod rewind;
+mod routing_headers;
 mod service;
+mod stats;
+mod telemetry;
+mod upstream;
+mod users;
 mod worker;
 
-use crate::metrics::TelegrafClient;
-use crate::worker::DBWorker;
+use crate::access_log::{AccessLog, QueryRedaction};
+use crate::admin::AdminServer;
+use crate::bans::BanDetector;
+use crate::breaker::{BreakerConfig, CircuitBreakers};
+use crate::cooldowns::Cooldowns;
+use crate::exits::ExitChecker;
+use crate::health::HealthChecker;
+use crate::limits::{LimitConfig, Limits};
+use crate::metrics::{
+    MetricClients, PrometheusClient, PrometheusExporter, PrometheusMetrics, TelegrafClient,
+};
+use crate::overrides::OverridePermissions;
+use crate::pool::PoolCache;
+use crate::routing_headers::RoutingHeaderConfig;
+use crate::service::{Service, ServiceContext};
+use crate::stats::Stats;
+use crate::users::ProxyUsers;
+use crate::worker::{DBWorker, WorkerConfig};
 use ca::RcgenAuthority;
 use futures::Future;
 use hyper::{
@@ -14,49 +46,54 @@ use hyper::{
     service::{make_service_fn, service_fn},
     Server,
 };
-use locust_core::new_pool;
-use rustls_pemfile as pemfile;
-use sqlx::PgPool;
-use std::{
-    convert::Infallible,
-    env,
-    net::SocketAddr,
-    sync::{mpsc, Arc},
-    thread, time,
+use locust_core::{
+    cooldowns::CooldownConfig,
+    diversity::DiversityConfig,
+    new_pool,
+    storage::{self, PgStore, ProxyStore},
 };
-use tokio::runtime::Runtime;
+use rustls_pemfile as pemfile;
+use std::{convert::Infallible, env, net::SocketAddr, sync::Arc, time::Duration};
 use tracing::*;
 use worker::DBJob;
 
+/// How often to create request history partitions
+/// and drop the expired ones.
+const MAINTAIN_HISTORY_INTERVAL: Duration = Duration::from_secs(3600);
+
+const DEFAULT_ADMIN_ADDR: &str = "0.0.0.0:3001";
+const PROXY_ADDR: ([u8; 4], u16) = ([0, 0, 0, 0], 3000);
+
 async fn shutdown_signal() {
     tokio::signal::ctrl_c()
         .await
         .expect("Failed to install CTRL+C signal handler");
 }
 
+#[derive(Clone)]
 struct ServiceWrapper {
-    ca: Arc<RcgenAuthority>,
-    db: Arc<PgPool>,
-    db_job_chan: mpsc::Sender<DBJob>,
+    ctx: Arc<ServiceContext<RcgenAuthority>>,
 }
 
 impl ServiceWrapper {
+    /// Serves the proxy on `addr`. With `report_routing`, every
+    /// response reports how its request was routed.
     pub async fn start<F: Future<Output = ()>>(
         self,
+        addr: SocketAddr,
+        report_routing: bool,
         shutdown_signal: F,
     ) -> Result<(), error::Error> {
-        let make_service = make_service_fn(move |_conn: &AddrStream| {
-            let ca = Arc::clone(&self.ca);
-            let db = Arc::clone(&self.db);
-            let chan = self.db_job_chan.clone();
+        let make_service = make_service_fn(move |conn: &AddrStream| {
+            let ctx = Arc::clone(&self.ctx);
+            let client_addr = conn.remote_addr();
             async move {
                 Ok::<_, Infallible>(service_fn(move |req| {
-                    service::Service::new(Arc::clone(&ca), Arc::clone(&db), chan.clone()).proxy(req)
+                    Service::new(Arc::clone(&ctx), client_addr, report_routing).proxy(req)
                 }))
             }
         });
 
-        let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
         Server::try_bind(&addr)?
             .http1_preserve_header_case(true)
             .http1_title_case_headers(true)
@@ -69,7 +106,12 @@ impl ServiceWrapper {
 
 #[tokio::main]
 async fn main() {
-    tracing_subscriber::fmt::init();
+    let _telemetry = telemetry::init().expect("Failed to set up tracing");
+    // The guard flushes buffered access log lines on shutdown.
+    let (access_log, _access_log_guard) = match AccessLog::from_env() {
+        Some((log, guard)) => (Some(Arc::new(log)), Some(guard)),
+        None => (None, None),
+    };
 
     let mut private_key_bytes: &[u8] = include_bytes!("ca/locust.key");
     let mut ca_cert_bytes: &[u8] = include_bytes!("ca/locust.cer");
@@ -91,41 +133,170 @@ async fn main() {
 
     let ca_auth = ca::RcgenAuthority::new(private_key, ca_cert, 1_000)
         .expect("Failed to create Certificate Authority");
-    let db_pool = new_pool().await.expect("Error creating db pool");
-    let db_pool_arc = Arc::new(db_pool);
-    let (tx, rx) = mpsc::channel();
+    let ca = Arc::new(ca_auth);
+    let stats = Arc::new(Stats::default());
+    let worker_config = WorkerConfig::from_env();
+    let (tx, rx) = worker::channel(&worker_config, Arc::clone(&stats));
+    let breakers = Arc::new(CircuitBreakers::new(BreakerConfig::from_env(), tx.clone()));
+    // With a pool file, the server runs without a database. With
+    // SQLite, only proxies, tags, domains and sessions are stored.
+    let (db_pool, store, pool) = match env::var("LOCUST_POOL_FILE") {
+        Ok(path) => {
+            let pool = PoolCache::load_file(path.into(), tx.clone())
+                .unwrap_or_else(|e| panic!("Error loading pool file: {e}"));
+            (None, None, pool)
+        }
+        Err(_) => {
+            let url = storage::database_url();
+            let (db_pool, store): (_, Arc<dyn ProxyStore>) = if storage::is_postgres(&url) {
+                let db_pool = new_pool().await.expect("Error creating db pool");
+                (
+                    Some(Arc::new(db_pool.clone())),
+                    Arc::new(PgStore::new(db_pool)),
+                )
+            } else {
+                info!("request history, scores, health checks and the admin API need Postgres and are turned off");
+                let store = storage::connect(&url)
+                    .await
+                    .expect("Error opening the database");
+                (None, store)
+            };
+            let pool = PoolCache::load(Arc::clone(&store), db_pool.clone(), tx.clone())
+                .await
+                .expect("Error loading proxy pool");
+            (db_pool, Some(store), pool)
+        }
+    };
+    pool.set_diversity(DiversityConfig::from_env());
+    let pool = Arc::new(pool);
+    pool.spawn_listener();
 
     // @TODO: config out metrics client options.
-    let telegraf_client = match env::var("TELEGRAF_ADDR") {
-        Ok(addr) => Some(TelegrafClient::new(addr.as_ref())),
-        Err(_) => None,
-    };
+    let mut metric_clients = MetricClients::default();
+    if let Ok(addr) = env::var("TELEGRAF_ADDR") {
+        metric_clients.push(TelegrafClient::new(addr.as_ref()));
+    }
+
+    if let Ok(addr) = env::var("PROMETHEUS_ADDR") {
+        let addr: SocketAddr = addr.parse().expect("Invalid prometheus address");
+        let metrics =
+            Arc::new(PrometheusMetrics::new().expect("Failed to create prometheus metrics"));
+        metric_clients.push(PrometheusClient::new(Arc::clone(&metrics)));
+
+        let exporter = PrometheusExporter::new(
+            metrics,
+            Arc::clone(&stats),
+            Arc::clone(&ca),
+            db_pool.clone(),
+            Arc::clone(&pool),
+            Arc::clone(&breakers),
+        );
+        tokio::spawn(async move {
+            if let Err(e) = exporter.start(addr, shutdown_signal()).await {
+                error!("{}", e);
+            }
+        });
+    }
+    let metric_clients = (!metric_clients.is_empty()).then_some(metric_clients);
+
+    DBWorker::new(
+        db_pool.clone(),
+        store,
+        rx,
+        metric_clients,
+        Arc::clone(&stats),
+        worker_config,
+    )
+    .spawn();
 
-    // @TODO: could probably make a worker pool instead of a single worker.
-    let mut worker = DBWorker::new(Arc::clone(&db_pool_arc), rx, telegraf_client);
-    thread::spawn(move || {
-        let rt = Runtime::new().unwrap();
-        rt.block_on(worker.start());
-    });
-
-    let calc_timer_tx = tx.clone();
-    thread::spawn(move || loop {
-        // Calc next proxies every 5 minutes.
-        // @TODO: this will happen across horizontal services.. Thats ok?
-        thread::sleep(time::Duration::from_secs(300));
-        if let Err(e) = calc_timer_tx.send(DBJob::CalcNextProxies {}) {
-            warn!("error sending calc proxies job {e}");
+    if db_pool.is_some() {
+        let history_timer_tx = tx.clone();
+        // Maintained once at startup, so that today's
+        // partition exists before any request is recorded.
+        if let Err(e) = history_timer_tx.send(DBJob::MaintainHistory {}) {
+            warn!("error sending history maintenance job {e}");
         }
-    });
+        tokio::spawn(async move {
+            let mut timer = tokio::time::interval_at(
+                tokio::time::Instant::now() + MAINTAIN_HISTORY_INTERVAL,
+                MAINTAIN_HISTORY_INTERVAL,
+            );
+            loop {
+                timer.tick().await;
+                if let Err(e) = history_timer_tx.send(DBJob::MaintainHistory {}) {
+                    warn!("error sending history maintenance job {e}");
+                }
+            }
+        });
+    }
+
+    // Health and exit checks and the admin API work on the
+    // proxies in the database, so there are none without one.
+    if let Some(db_pool) = &db_pool {
+        if let Some(checker) = HealthChecker::from_env(Arc::clone(db_pool)) {
+            tokio::spawn(checker.start());
+        }
+        if let Some(checker) = ExitChecker::from_env(Arc::clone(db_pool)) {
+            tokio::spawn(checker.start());
+        }
+
+        // The admin API is only served when a token is configured
+        // to authenticate its callers with.
+        if let Ok(token) = env::var("LOCUST_ADMIN_TOKEN") {
+            let addr: SocketAddr = env::var("LOCUST_ADMIN_ADDR")
+                .unwrap_or(DEFAULT_ADMIN_ADDR.into())
+                .parse()
+                .expect("Invalid admin address");
+            let admin = AdminServer::new(Arc::clone(db_pool), Arc::clone(&stats), token);
+            tokio::spawn(async move {
+                if let Err(e) = admin.start(addr, shutdown_signal()).await {
+                    error!("{}", e);
+                }
+            });
+        }
+    } else if env::var("LOCUST_ADMIN_TOKEN").is_ok() {
+        warn!("the admin API is not served without Postgres");
+    }
+
+    let cooldowns = Cooldowns::new(CooldownConfig::from_env(), tx.clone());
+    if let Some(db_pool) = db_pool {
+        cooldowns.spawn_refresh(db_pool);
+    }
 
+    let routing_headers = RoutingHeaderConfig::from_env();
     let wrapper = ServiceWrapper {
-        ca: Arc::new(ca_auth),
-        db: Arc::clone(&db_pool_arc),
-        db_job_chan: tx,
+        ctx: Arc::new(ServiceContext {
+            ca,
+            db_job_chan: tx,
+            bans: BanDetector::from_env(Arc::clone(&pool)),
+            pool,
+            limits: Limits::new(LimitConfig::from_env(), Arc::clone(&stats)),
+            stats,
+            access_log,
+            redaction: QueryRedaction::from_env(),
+            breakers,
+            cooldowns,
+            users: ProxyUsers::from_env(),
+            overrides: OverridePermissions::from_env(),
+            routing_headers: routing_headers.clone(),
+        }),
     };
 
+    if let Some(addr) = routing_headers.addr {
+        let wrapper = wrapper.clone();
+        info!("Reporting routing on {addr}");
+        tokio::spawn(async move {
+            if let Err(e) = wrapper.start(addr, true, shutdown_signal()).await {
+                error!("{}", e);
+            }
+        });
+    }
+
     info!("Starting up proxy server!");
-    if let Err(e) = wrapper.start(shutdown_signal()).await {
+    if let Err(e) = wrapper
+        .start(PROXY_ADDR.into(), false, shutdown_signal())
+        .await
+    {
         error!("{}", e);
     }
 }
@@ -1,7 +1,12 @@
+mod prometheus;
+
 use telegraf::Metric;
 
-#[derive(Debug)]
+pub use self::prometheus::{PrometheusClient, PrometheusExporter, PrometheusMetrics};
+
+#[derive(Debug, thiserror::Error)]
 pub enum MetricsError {
+    #[error("error writing metric: {0}")]
     WriteError(String),
 }
 
@@ -9,15 +14,65 @@ pub trait MetricClient {
     fn send_proxy_metric(&mut self, metric: &ProxyMetric) -> Result<(), MetricsError>;
 }
 
+/// The outcome of a single proxied request. Durations are in
+/// milliseconds.
 #[derive(Metric)]
 #[measurement = "proxy_metrics"]
 pub struct ProxyMetric {
     #[telegraf(tag)]
     pub domain: String,
     pub proxy_id: i32,
+    #[telegraf(tag)]
+    pub provider: String,
+    /// The proxy's tags, separated by `|`.
+    #[telegraf(tag)]
+    pub tags: Option<String>,
+    /// Time to first byte of the response.
     pub response_time: u32,
+    /// Time until the response body was fully sent to the client.
+    pub total_time: u32,
+    /// Time to open the connection to the upstream proxy.
+    pub connect_time: Option<u32>,
+    /// Time to tunnel through the proxy and complete the TLS
+    /// handshake with the origin, for HTTPS requests.
+    pub tls_handshake_time: Option<u32>,
+    pub request_bytes: u64,
+    pub response_bytes: u64,
+    /// Missing when the request failed before a response came back.
     #[telegraf(tag)]
-    pub status: u16,
+    pub status: Option<u16>,
+    #[telegraf(tag)]
+    pub error_class: Option<&'static str>,
+}
+
+/// Fans every metric out to a set of clients, so that
+/// several metrics sinks can be used at the same time.
+#[derive(Default)]
+pub struct MetricClients(Vec<Box<dyn MetricClient + Send>>);
+
+impl MetricClients {
+    pub fn push(&mut self, client: impl MetricClient + Send + 'static) {
+        self.0.push(Box::new(client));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl MetricClient for MetricClients {
+    fn send_proxy_metric(&mut self, metric: &ProxyMetric) -> Result<(), MetricsError> {
+        // A failing client should not keep the
+        // metric from reaching the other ones.
+        let mut result = Ok(());
+        for client in &mut self.0 {
+            if let Err(e) = client.send_proxy_metric(metric) {
+                result = Err(e);
+            }
+        }
+
+        result
+    }
 }
 
 pub struct TelegrafClient {
@@ -41,3 +96,51 @@ impl MetricClient for TelegrafClient {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    struct FailingClient;
+
+    impl MetricClient for FailingClient {
+        fn send_proxy_metric(&mut self, _metric: &ProxyMetric) -> Result<(), MetricsError> {
+            Err(MetricsError::WriteError("down".into()))
+        }
+    }
+
+    struct RecordingClient(Arc<Mutex<Vec<i32>>>);
+
+    impl MetricClient for RecordingClient {
+        fn send_proxy_metric(&mut self, metric: &ProxyMetric) -> Result<(), MetricsError> {
+            self.0.lock().unwrap().push(metric.proxy_id);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_metric_clients_fan_out() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let mut clients = MetricClients::default();
+        clients.push(FailingClient);
+        clients.push(RecordingClient(Arc::clone(&seen)));
+
+        let metric = ProxyMetric {
+            domain: "example.com".into(),
+            proxy_id: 7,
+            provider: "webshare".into(),
+            tags: None,
+            response_time: 10,
+            total_time: 12,
+            connect_time: None,
+            tls_handshake_time: None,
+            request_bytes: 0,
+            response_bytes: 0,
+            status: Some(200),
+            error_class: None,
+        };
+        assert!(clients.send_proxy_metric(&metric).is_err());
+        assert_eq!(*seen.lock().unwrap(), vec![7]);
+    }
+}
@@ -0,0 +1,508 @@
+use super::{MetricClient, MetricsError, ProxyMetric};
+use crate::{
+    breaker::{BreakerState, CircuitBreakers},
+    ca::CertificateAuthority,
+    pool::PoolCache,
+    stats::Stats,
+};
+
+use ::prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use locust_core::crud::{health::count_quarantined_proxies, tags::get_tag_pool_sizes};
+use sqlx::PgPool;
+use std::{future::Future, net::SocketAddr, sync::Arc};
+use tracing::warn;
+use warp::Filter;
+
+/// Response time buckets, in seconds. Proxied requests are
+/// slow compared to regular services so this skews higher
+/// than the client library defaults.
+const RESPONSE_TIME_BUCKETS: &[f64] = &[
+    0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 180.0,
+];
+
+/// The Prometheus collectors for the server. Per-request
+/// metrics are pushed in through [`PrometheusClient`] while
+/// the gauges are refreshed every time they are scraped.
+pub struct PrometheusMetrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    response_time: HistogramVec,
+    total_time: HistogramVec,
+    connect_time: HistogramVec,
+    tls_handshake_time: HistogramVec,
+    request_bytes: IntCounterVec,
+    response_bytes: IntCounterVec,
+    in_flight: IntGauge,
+    sessions_created: IntCounter,
+    sessions: IntGauge,
+    cert_cache_hits: IntCounter,
+    cert_cache_misses: IntCounter,
+    db_jobs_queued: IntGauge,
+    db_jobs_dropped: IntCounter,
+    db_batches_flushed: IntCounter,
+    limit_queued: IntGauge,
+    requests_limited: IntCounterVec,
+    fallbacks: IntCounterVec,
+    pool_size: IntGaugeVec,
+    quarantined: IntGauge,
+    breaker_state: IntGaugeVec,
+    breaker_trips: IntCounter,
+}
+
+impl PrometheusMetrics {
+    pub fn new() -> Result<Self, ::prometheus::Error> {
+        let registry = Registry::new_custom(Some("locust".into()), None)?;
+
+        let requests = IntCounterVec::new(
+            Opts::new("proxy_requests_total", "Proxied requests by outcome"),
+            &[
+                "status",
+                "error_class",
+                "domain",
+                "proxy_id",
+                "provider",
+                "tags",
+            ],
+        )?;
+        let response_time = HistogramVec::new(
+            HistogramOpts::new(
+                "proxy_response_time_seconds",
+                "Time until the first byte of the upstream response",
+            )
+            .buckets(RESPONSE_TIME_BUCKETS.to_vec()),
+            &["domain", "provider"],
+        )?;
+        let total_time = HistogramVec::new(
+            HistogramOpts::new(
+                "proxy_total_time_seconds",
+                "Time until the response was fully sent to the client",
+            )
+            .buckets(RESPONSE_TIME_BUCKETS.to_vec()),
+            &["domain", "provider"],
+        )?;
+        let connect_time = HistogramVec::new(
+            HistogramOpts::new(
+                "proxy_connect_time_seconds",
+                "Time to open a connection to the upstream proxy",
+            )
+            .buckets(RESPONSE_TIME_BUCKETS.to_vec()),
+            &["provider"],
+        )?;
+        let tls_handshake_time = HistogramVec::new(
+            HistogramOpts::new(
+                "proxy_tls_handshake_time_seconds",
+                "Time to tunnel through the proxy and handshake with the origin",
+            )
+            .buckets(RESPONSE_TIME_BUCKETS.to_vec()),
+            &["provider"],
+        )?;
+        let request_bytes = IntCounterVec::new(
+            Opts::new(
+                "proxy_request_bytes_total",
+                "Request body bytes sent upstream",
+            ),
+            &["domain", "provider"],
+        )?;
+        let response_bytes = IntCounterVec::new(
+            Opts::new(
+                "proxy_response_bytes_total",
+                "Response body bytes sent back to clients",
+            ),
+            &["domain", "provider"],
+        )?;
+        let in_flight = IntGauge::new("requests_in_flight", "Requests currently being proxied")?;
+        let sessions_created = IntCounter::new(
+            "sessions_created_total",
+            "Proxy sessions created by this instance",
+        )?;
+        let sessions = IntGauge::new("sessions", "Proxy sessions stored")?;
+        let cert_cache_hits = IntCounter::new(
+            "cert_cache_hits_total",
+            "Server configs served from the certificate cache",
+        )?;
+        let cert_cache_misses = IntCounter::new(
+            "cert_cache_misses_total",
+            "Server configs that had to be generated",
+        )?;
+        let db_jobs_queued = IntGauge::new(
+            "db_jobs_queued",
+            "Jobs waiting to be picked up by the DB workers",
+        )?;
+        let db_jobs_dropped = IntCounter::new(
+            "db_jobs_dropped_total",
+            "Jobs dropped because the DB job queue was full",
+        )?;
+        let db_batches_flushed = IntCounter::new(
+            "db_batches_flushed_total",
+            "Batches of proxy responses processed by the DB workers",
+        )?;
+        let limit_queued = IntGauge::new(
+            "requests_queued_for_limits",
+            "Requests waiting on a concurrency or rate limit",
+        )?;
+        let requests_limited = IntCounterVec::new(
+            Opts::new(
+                "requests_limited_total",
+                "Requests turned away by a concurrency or rate limit",
+            ),
+            &["scope"],
+        )?;
+        let fallbacks = IntCounterVec::new(
+            Opts::new(
+                "proxy_fallbacks_total",
+                "Proxies picked from a fallback of the domain",
+            ),
+            &["domain", "fallback"],
+        )?;
+        let pool_size = IntGaugeVec::new(Opts::new("pool_size", "Live proxies per tag"), &["tag"])?;
+        let quarantined = IntGauge::new(
+            "proxies_quarantined",
+            "Proxies taken out of rotation by failed health checks",
+        )?;
+        let breaker_state = IntGaugeVec::new(
+            Opts::new(
+                "circuit_breaker_state",
+                "Circuit breakers that are not closed, 1 when half-open and 2 when open",
+            ),
+            &["proxy_id", "domain"],
+        )?;
+        let breaker_trips = IntCounter::new(
+            "circuit_breaker_trips_total",
+            "Times a circuit breaker has opened",
+        )?;
+
+        registry.register(Box::new(requests.clone()))?;
+        registry.register(Box::new(response_time.clone()))?;
+        registry.register(Box::new(total_time.clone()))?;
+        registry.register(Box::new(connect_time.clone()))?;
+        registry.register(Box::new(tls_handshake_time.clone()))?;
+        registry.register(Box::new(request_bytes.clone()))?;
+        registry.register(Box::new(response_bytes.clone()))?;
+        registry.register(Box::new(in_flight.clone()))?;
+        registry.register(Box::new(sessions_created.clone()))?;
+        registry.register(Box::new(sessions.clone()))?;
+        registry.register(Box::new(cert_cache_hits.clone()))?;
+        registry.register(Box::new(cert_cache_misses.clone()))?;
+        registry.register(Box::new(db_jobs_queued.clone()))?;
+        registry.register(Box::new(db_jobs_dropped.clone()))?;
+        registry.register(Box::new(db_batches_flushed.clone()))?;
+        registry.register(Box::new(limit_queued.clone()))?;
+        registry.register(Box::new(requests_limited.clone()))?;
+        registry.register(Box::new(fallbacks.clone()))?;
+        registry.register(Box::new(pool_size.clone()))?;
+        registry.register(Box::new(quarantined.clone()))?;
+        registry.register(Box::new(breaker_state.clone()))?;
+        registry.register(Box::new(breaker_trips.clone()))?;
+
+        Ok(Self {
+            registry,
+            requests,
+            response_time,
+            total_time,
+            connect_time,
+            tls_handshake_time,
+            request_bytes,
+            response_bytes,
+            in_flight,
+            sessions_created,
+            sessions,
+            cert_cache_hits,
+            cert_cache_misses,
+            db_jobs_queued,
+            db_jobs_dropped,
+            db_batches_flushed,
+            limit_queued,
+            requests_limited,
+            fallbacks,
+            pool_size,
+            quarantined,
+            breaker_state,
+            breaker_trips,
+        })
+    }
+
+    fn observe(&self, metric: &ProxyMetric) {
+        let status = metric.status.map(|s| s.to_string()).unwrap_or_default();
+        self.requests
+            .with_label_values(&[
+                &status,
+                metric.error_class.unwrap_or_default(),
+                &metric.domain,
+                &metric.proxy_id.to_string(),
+                &metric.provider,
+                metric.tags.as_deref().unwrap_or_default(),
+            ])
+            .inc();
+
+        let by_domain = [metric.domain.as_str(), metric.provider.as_str()];
+        self.response_time
+            .with_label_values(&by_domain)
+            .observe(millis_to_secs(metric.response_time));
+        self.total_time
+            .with_label_values(&by_domain)
+            .observe(millis_to_secs(metric.total_time));
+        self.request_bytes
+            .with_label_values(&by_domain)
+            .inc_by(metric.request_bytes);
+        self.response_bytes
+            .with_label_values(&by_domain)
+            .inc_by(metric.response_bytes);
+
+        if let Some(ms) = metric.connect_time {
+            self.connect_time
+                .with_label_values(&[&metric.provider])
+                .observe(millis_to_secs(ms));
+        }
+        if let Some(ms) = metric.tls_handshake_time {
+            self.tls_handshake_time
+                .with_label_values(&[&metric.provider])
+                .observe(millis_to_secs(ms));
+        }
+    }
+
+    /// Encodes every registered metric in the Prometheus
+    /// text exposition format.
+    pub fn encode(&self) -> String {
+        let mut buf = Vec::new();
+        let encoder = TextEncoder::new();
+        if let Err(e) = encoder.encode(&self.registry.gather(), &mut buf) {
+            warn!("error encoding prometheus metrics: {e}");
+        }
+
+        String::from_utf8(buf).unwrap_or_default()
+    }
+}
+
+fn millis_to_secs(ms: u32) -> f64 {
+    f64::from(ms) / 1000.0
+}
+
+/// Catches a counter up to a total that is tracked elsewhere.
+fn sync_counter(counter: &IntCounter, total: u64) {
+    counter.inc_by(total.saturating_sub(counter.get()));
+}
+
+/// A [`MetricClient`] that records proxy metrics
+/// into Prometheus collectors.
+pub struct PrometheusClient {
+    metrics: Arc<PrometheusMetrics>,
+}
+
+impl PrometheusClient {
+    pub fn new(metrics: Arc<PrometheusMetrics>) -> Self {
+        Self { metrics }
+    }
+}
+
+impl MetricClient for PrometheusClient {
+    fn send_proxy_metric(&mut self, metric: &ProxyMetric) -> Result<(), MetricsError> {
+        self.metrics.observe(metric);
+        Ok(())
+    }
+}
+
+/// Serves the `/metrics` endpoint for Prometheus to scrape.
+pub struct PrometheusExporter<CA> {
+    metrics: Arc<PrometheusMetrics>,
+    stats: Arc<Stats>,
+    ca: Arc<CA>,
+    db: Option<Arc<PgPool>>,
+    pool: Arc<PoolCache>,
+    breakers: Arc<CircuitBreakers>,
+}
+
+impl<CA> PrometheusExporter<CA>
+where
+    CA: CertificateAuthority,
+{
+    pub fn new(
+        metrics: Arc<PrometheusMetrics>,
+        stats: Arc<Stats>,
+        ca: Arc<CA>,
+        db: Option<Arc<PgPool>>,
+        pool: Arc<PoolCache>,
+        breakers: Arc<CircuitBreakers>,
+    ) -> Self {
+        Self {
+            metrics,
+            stats,
+            ca,
+            db,
+            pool,
+            breakers,
+        }
+    }
+
+    pub async fn start<F: Future<Output = ()> + Send + 'static>(
+        self,
+        addr: SocketAddr,
+        shutdown_signal: F,
+    ) -> Result<(), crate::error::Error> {
+        let exporter = Arc::new(self);
+        let route = warp::path("metrics")
+            .and(warp::path::end())
+            .and(warp::get())
+            .then(move || {
+                let exporter = Arc::clone(&exporter);
+                async move { exporter.scrape().await }
+            });
+
+        let (addr, server) =
+            warp::serve(route).try_bind_with_graceful_shutdown(addr, shutdown_signal)?;
+        tracing::info!("Prometheus metrics listening on {addr}");
+        server.await;
+        Ok(())
+    }
+
+    async fn scrape(&self) -> String {
+        self.refresh_live();
+        self.refresh_pool().await;
+        self.metrics.encode()
+    }
+
+    fn refresh_live(&self) {
+        let snapshot = self.stats.snapshot();
+        self.metrics.in_flight.set(snapshot.in_flight);
+        self.metrics.db_jobs_queued.set(snapshot.db_jobs_queued);
+        sync_counter(&self.metrics.sessions_created, snapshot.sessions_created);
+        sync_counter(&self.metrics.db_jobs_dropped, snapshot.db_jobs_dropped);
+        sync_counter(
+            &self.metrics.db_batches_flushed,
+            snapshot.db_batches_flushed,
+        );
+        self.metrics.limit_queued.set(snapshot.limit_queued);
+        for (scope, total) in snapshot.requests_limited {
+            sync_counter(
+                &self.metrics.requests_limited.with_label_values(&[scope]),
+                total,
+            );
+        }
+        for (domain, fallbacks) in snapshot.fallbacks {
+            for (fallback, total) in fallbacks {
+                sync_counter(
+                    &self
+                        .metrics
+                        .fallbacks
+                        .with_label_values(&[&domain, &fallback]),
+                    total,
+                );
+            }
+        }
+
+        let cache = self.ca.cache_stats();
+        sync_counter(&self.metrics.cert_cache_hits, cache.hits);
+        sync_counter(&self.metrics.cert_cache_misses, cache.misses);
+
+        sync_counter(&self.metrics.breaker_trips, self.breakers.trips());
+        // Reset so that closed breakers stop being reported.
+        self.metrics.breaker_state.reset();
+        for (proxy_id, domain, state) in self.breakers.states() {
+            let value = match state {
+                BreakerState::Closed => 0,
+                BreakerState::HalfOpen => 1,
+                BreakerState::Open => 2,
+            };
+            self.metrics
+                .breaker_state
+                .with_label_values(&[&proxy_id.to_string(), domain.as_deref().unwrap_or_default()])
+                .set(value);
+        }
+    }
+
+    /// Refreshes the gauges about the pool, from Postgres when there
+    /// is one, and from the pool in memory otherwise. Proxies are only
+    /// quarantined by health checks, which need Postgres.
+    async fn refresh_pool(&self) {
+        match self.pool.count_sessions().await {
+            Ok(n) => self.metrics.sessions.set(n),
+            Err(e) => warn!("error counting sessions for metrics: {e}"),
+        }
+
+        let Some(db) = &self.db else {
+            self.set_pool_sizes(self.pool.tag_sizes());
+            self.metrics.quarantined.set(0);
+            return;
+        };
+        match get_tag_pool_sizes(db).await {
+            Ok(sizes) => self.set_pool_sizes(sizes.into_iter().map(|s| (s.name, s.proxies))),
+            Err(e) => warn!("error getting pool sizes for metrics: {e}"),
+        }
+
+        match count_quarantined_proxies(db).await {
+            Ok(n) => self.metrics.quarantined.set(n),
+            Err(e) => warn!("error counting quarantined proxies for metrics: {e}"),
+        }
+    }
+
+    fn set_pool_sizes(&self, sizes: impl IntoIterator<Item = (String, i64)>) {
+        // Reset so that deleted tags stop being reported.
+        self.metrics.pool_size.reset();
+        for (tag, proxies) in sizes {
+            self.metrics
+                .pool_size
+                .with_label_values(&[&tag])
+                .set(proxies);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prometheus_client_records_requests() {
+        let metrics = Arc::new(PrometheusMetrics::new().unwrap());
+        let mut client = PrometheusClient::new(Arc::clone(&metrics));
+        let metric = ProxyMetric {
+            domain: "example.com".into(),
+            proxy_id: 3,
+            provider: "webshare".into(),
+            tags: Some("webshare|us".into()),
+            response_time: 1500,
+            total_time: 3000,
+            connect_time: Some(20),
+            tls_handshake_time: None,
+            request_bytes: 10,
+            response_bytes: 2048,
+            status: Some(200),
+            error_class: None,
+        };
+        client.send_proxy_metric(&metric).unwrap();
+        client.send_proxy_metric(&metric).unwrap();
+        client
+            .send_proxy_metric(&ProxyMetric {
+                status: None,
+                error_class: Some("timeout"),
+                response_bytes: 0,
+                ..metric
+            })
+            .unwrap();
+
+        let out = metrics.encode();
+        assert!(out.contains(
+            r#"locust_proxy_requests_total{domain="example.com",error_class="",provider="webshare",proxy_id="3",status="200",tags="webshare|us"} 2"#
+        ));
+        assert!(out.contains(
+            r#"locust_proxy_requests_total{domain="example.com",error_class="timeout",provider="webshare",proxy_id="3",status="",tags="webshare|us"} 1"#
+        ));
+        assert!(out.contains(
+            r#"locust_proxy_response_time_seconds_bucket{domain="example.com",provider="webshare",le="2.5"} 3"#
+        ));
+        assert!(out.contains(
+            r#"locust_proxy_response_bytes_total{domain="example.com",provider="webshare"} 4096"#
+        ));
+        assert!(out.contains(r#"locust_proxy_connect_time_seconds_count{provider="webshare"} 3"#));
+    }
+
+    #[test]
+    fn test_sync_counter() {
+        let counter = IntCounter::new("c", "c").unwrap();
+        sync_counter(&counter, 5);
+        sync_counter(&counter, 7);
+        assert_eq!(counter.get(), 7);
+    }
+}
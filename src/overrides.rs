@@ -0,0 +1,375 @@
+use http::HeaderMap;
+use locust_core::{pool::ProxyFilter, tag_expr::TagExpr};
+use std::{collections::HashMap, env, fmt, time::Duration};
+
+/// Who may override which part of routing,
+/// e.g. `*=tags;scraper=tags,proxy-id,timeout;ops=all`.
+const OVERRIDES_VAR: &str = "LOCUST_OVERRIDES";
+/// The longest timeout a request can ask for, in seconds.
+const MAX_TIMEOUT_VAR: &str = "LOCUST_OVERRIDES_MAX_TIMEOUT_SECS";
+const DEFAULT_MAX_TIMEOUT: Duration = Duration::from_secs(3600);
+
+/// A part of routing that clients can override for a single
+/// request, through a header that is never forwarded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Override {
+    /// A tag expression the proxy must satisfy.
+    Tags,
+    /// The proxy to use.
+    ProxyId,
+    /// The provider the proxy must be from.
+    Provider,
+    /// The country the proxy must be in.
+    Country,
+    /// The autonomous system the proxy must be in.
+    Asn,
+    /// Seconds to wait for the upstream response.
+    Timeout,
+    /// Whether to pick a new proxy regardless of the session cookie.
+    NewSession,
+}
+
+impl Override {
+    pub const ALL: [Override; 7] = [
+        Override::Tags,
+        Override::ProxyId,
+        Override::Provider,
+        Override::Country,
+        Override::Asn,
+        Override::Timeout,
+        Override::NewSession,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Override::Tags => "tags",
+            Override::ProxyId => "proxy-id",
+            Override::Provider => "provider",
+            Override::Country => "country",
+            Override::Asn => "asn",
+            Override::Timeout => "timeout",
+            Override::NewSession => "new-session",
+        }
+    }
+
+    pub fn header(&self) -> &'static str {
+        match self {
+            Override::Tags => "x-locust-tags",
+            Override::ProxyId => "x-locust-proxy-id",
+            Override::Provider => "x-locust-provider",
+            Override::Country => "x-locust-country",
+            Override::Asn => "x-locust-asn",
+            Override::Timeout => "x-locust-timeout",
+            Override::NewSession => "x-locust-new-session",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|o| o.as_str() == name)
+    }
+}
+
+impl fmt::Display for Override {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The overrides of a request.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Overrides {
+    pub filter: ProxyFilter,
+    pub timeout: Option<Duration>,
+    pub new_session: bool,
+}
+
+impl Overrides {
+    /// These overrides, falling back to `other` for the ones not
+    /// set, e.g. those of the CONNECT request of a tunnel.
+    pub fn or(self, other: &Overrides) -> Overrides {
+        let filter = ProxyFilter {
+            tags: self.filter.tags.or_else(|| other.filter.tags.clone()),
+            proxy_id: self.filter.proxy_id.or(other.filter.proxy_id),
+            provider: self
+                .filter
+                .provider
+                .or_else(|| other.filter.provider.clone()),
+            country: self.filter.country.or_else(|| other.filter.country.clone()),
+            asn: self.filter.asn.or(other.filter.asn),
+        };
+        Overrides {
+            filter,
+            timeout: self.timeout.or(other.timeout),
+            new_session: self.new_session || other.new_session,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OverrideError {
+    Invalid(Override, String),
+    /// The user is not allowed to use the override.
+    Forbidden(Override),
+}
+
+impl fmt::Display for OverrideError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OverrideError::Invalid(o, e) => write!(f, "invalid {} header: {e}", o.header()),
+            OverrideError::Forbidden(o) => write!(f, "{} header not allowed", o.header()),
+        }
+    }
+}
+
+/// The overrides each user is allowed to use, by the username of
+/// their proxy credentials. Users without an entry of their own get
+/// the ones of `*`. Unless configured, anyone may pass tags.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OverridePermissions {
+    users: HashMap<String, Vec<Override>>,
+    /// Longer timeouts are turned away.
+    max_timeout: Duration,
+}
+
+impl Default for OverridePermissions {
+    fn default() -> Self {
+        Self {
+            users: HashMap::from([("*".into(), vec![Override::Tags])]),
+            max_timeout: DEFAULT_MAX_TIMEOUT,
+        }
+    }
+}
+
+impl OverridePermissions {
+    pub fn from_env() -> Self {
+        let mut permissions = match env::var(OVERRIDES_VAR) {
+            Ok(var) => Self::parse(&var).unwrap_or_else(|e| panic!("Invalid {OVERRIDES_VAR}: {e}")),
+            Err(_) => Self::default(),
+        };
+        if let Ok(secs) = env::var(MAX_TIMEOUT_VAR) {
+            let secs = secs
+                .parse()
+                .unwrap_or_else(|_| panic!("Invalid {MAX_TIMEOUT_VAR}"));
+            permissions.max_timeout = Duration::from_secs(secs);
+        }
+        permissions
+    }
+
+    pub(crate) fn parse(var: &str) -> Result<Self, String> {
+        let mut users = HashMap::new();
+        for entry in var.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+            let (user, names) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("expected user=overrides, got {entry}"))?;
+            let mut overrides = Vec::new();
+            for name in names.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+                match name {
+                    "all" => overrides.extend(Override::ALL),
+                    "none" => {}
+                    name => overrides.push(
+                        Override::parse(name).ok_or_else(|| format!("unknown override {name}"))?,
+                    ),
+                }
+            }
+            users.insert(user.trim().to_string(), overrides);
+        }
+        Ok(Self {
+            users,
+            max_timeout: DEFAULT_MAX_TIMEOUT,
+        })
+    }
+
+    pub fn allows(&self, user: Option<&str>, o: Override) -> bool {
+        user.and_then(|user| self.users.get(user))
+            .or_else(|| self.users.get("*"))
+            .is_some_and(|overrides| overrides.contains(&o))
+    }
+
+    /// Removes every override header, so that none is forwarded,
+    /// and parses the ones the user is allowed to use.
+    pub fn take(
+        &self,
+        user: Option<&str>,
+        headers: &mut HeaderMap,
+    ) -> Result<Overrides, OverrideError> {
+        let values: Vec<_> = Override::ALL
+            .into_iter()
+            .filter_map(|o| Some((o, headers.remove(o.header())?)))
+            .collect();
+        let mut overrides = Overrides::default();
+        for (o, value) in values {
+            if !self.allows(user, o) {
+                return Err(OverrideError::Forbidden(o));
+            }
+            let invalid = |e: String| OverrideError::Invalid(o, e);
+            let value = value.to_str().map_err(|e| invalid(e.to_string()))?.trim();
+            match o {
+                Override::Tags => {
+                    overrides.filter.tags = Some(TagExpr::parse(value).map_err(invalid)?)
+                }
+                Override::ProxyId => {
+                    overrides.filter.proxy_id = Some(
+                        value
+                            .parse()
+                            .map_err(|_| invalid("expected a proxy id".into()))?,
+                    )
+                }
+                Override::Provider if !value.is_empty() => {
+                    overrides.filter.provider = Some(value.into())
+                }
+                Override::Provider => return Err(invalid("expected a provider".into())),
+                Override::Country
+                    if value.len() == 2 && value.chars().all(|c| c.is_ascii_alphabetic()) =>
+                {
+                    overrides.filter.country = Some(value.to_ascii_uppercase())
+                }
+                Override::Country => {
+                    return Err(invalid("expected a two letter country code".into()))
+                }
+                Override::Asn => {
+                    let asn = value.parse::<u32>();
+                    let asn = asn.map_err(|_| invalid("expected an AS number".into()))?;
+                    overrides.filter.asn = Some(asn.into())
+                }
+                Override::Timeout => {
+                    let timeout = value
+                        .parse()
+                        .ok()
+                        .filter(|secs: &f64| *secs > 0.0)
+                        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                        .ok_or_else(|| invalid("expected a number of seconds".into()))?;
+                    if timeout > self.max_timeout {
+                        let max = self.max_timeout.as_secs();
+                        return Err(invalid(format!("expected at most {max} seconds")));
+                    }
+                    overrides.timeout = Some(timeout)
+                }
+                Override::NewSession => {
+                    overrides.new_session = match value.to_ascii_lowercase().as_str() {
+                        "1" | "true" => true,
+                        "0" | "false" => false,
+                        _ => return Err(invalid("expected true or false".into())),
+                    }
+                }
+            }
+        }
+        Ok(overrides)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::HeaderValue;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.parse().unwrap(), HeaderValue::from_static(value)))
+            .collect()
+    }
+
+    #[test]
+    fn test_permissions() {
+        let default = OverridePermissions::default();
+        assert!(default.allows(None, Override::Tags));
+        assert!(default.allows(Some("anyone"), Override::Tags));
+        assert!(!default.allows(Some("anyone"), Override::ProxyId));
+
+        let permissions =
+            OverridePermissions::parse("*=none; scraper=tags,proxy-id,timeout; ops=all").unwrap();
+        assert!(!permissions.allows(None, Override::Tags));
+        assert!(permissions.allows(Some("scraper"), Override::ProxyId));
+        assert!(!permissions.allows(Some("scraper"), Override::Country));
+        assert!(permissions.allows(Some("ops"), Override::NewSession));
+        // Users without an entry of their own may use nothing.
+        assert!(!OverridePermissions::parse("ops=all")
+            .unwrap()
+            .allows(Some("scraper"), Override::Tags));
+
+        assert!(OverridePermissions::parse("ops").is_err());
+        assert!(OverridePermissions::parse("ops=teleport").is_err());
+    }
+
+    #[test]
+    fn test_take() {
+        let permissions = OverridePermissions::parse("*=tags;ops=all").unwrap();
+        let mut req = headers(&[
+            ("x-locust-tags", "residential AND NOT infatica"),
+            ("x-locust-proxy-id", "42"),
+            ("x-locust-provider", "webshare"),
+            ("x-locust-country", "us"),
+            ("x-locust-asn", "7922"),
+            ("x-locust-timeout", "2.5"),
+            ("x-locust-new-session", "true"),
+            ("accept", "*/*"),
+        ]);
+        let overrides = permissions.take(Some("ops"), &mut req.clone()).unwrap();
+        assert_eq!(
+            overrides,
+            Overrides {
+                filter: ProxyFilter {
+                    tags: Some(TagExpr::parse("residential AND NOT infatica").unwrap()),
+                    proxy_id: Some(42),
+                    provider: Some("webshare".into()),
+                    country: Some("US".into()),
+                    asn: Some(7922),
+                },
+                timeout: Some(Duration::from_millis(2500)),
+                new_session: true,
+            }
+        );
+
+        // Every override header is stripped, even when turned away.
+        assert_eq!(
+            permissions.take(Some("scraper"), &mut req),
+            Err(OverrideError::Forbidden(Override::ProxyId))
+        );
+        assert_eq!(req, headers(&[("accept", "*/*")]));
+
+        for (name, value) in [
+            ("x-locust-tags", "a AND"),
+            ("x-locust-proxy-id", "first"),
+            ("x-locust-country", "usa"),
+            ("x-locust-asn", "AS7922"),
+            ("x-locust-timeout", "0"),
+            ("x-locust-timeout", "-1"),
+            ("x-locust-timeout", "1e30"),
+            ("x-locust-timeout", "inf"),
+            ("x-locust-timeout", "NaN"),
+            ("x-locust-timeout", "3601"),
+            ("x-locust-new-session", "maybe"),
+        ] {
+            let result = permissions.take(Some("ops"), &mut headers(&[(name, value)]));
+            assert!(
+                matches!(result, Err(OverrideError::Invalid(..))),
+                "{name}: {value}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_or() {
+        let tunnel = Overrides {
+            filter: ProxyFilter {
+                provider: Some("webshare".into()),
+                proxy_id: Some(1),
+                ..ProxyFilter::default()
+            },
+            timeout: Some(Duration::from_secs(5)),
+            new_session: true,
+        };
+        let req = Overrides {
+            filter: ProxyFilter {
+                proxy_id: Some(2),
+                ..ProxyFilter::default()
+            },
+            ..Overrides::default()
+        };
+        let merged = req.or(&tunnel);
+        assert_eq!(merged.filter.proxy_id, Some(2));
+        assert_eq!(merged.filter.provider.as_deref(), Some("webshare"));
+        assert_eq!(merged.timeout, Some(Duration::from_secs(5)));
+        assert!(merged.new_session);
+    }
+}
@@ -0,0 +1,407 @@
+use crate::worker::{DBJob, DBJobSender};
+
+use locust_core::{
+    crud::bans::get_host_ban_rules,
+    diversity::DiversityConfig,
+    domains::DomainRules,
+    models::{
+        bans::BanRule,
+        proxies::{Proxy, ProxySession},
+    },
+    pool::{ProxyFilter, ProxyPool},
+    pool_file::{PoolFile, PoolFileError, ProxyIds},
+    routing::{DomainLimit, Fallback, RouteAction},
+    storage::ProxyStore,
+};
+use moka::future::Cache;
+use sqlx::{postgres::PgListener, PgPool};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicI32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+use time::OffsetDateTime;
+use tokio::{
+    sync::Mutex as AsyncMutex,
+    time::{interval_at, sleep, sleep_until, Instant},
+};
+use tracing::{info, warn};
+
+/// The channel the triggers added in `V8__pool_notify.sql` notify on.
+const POOL_CHANNEL: &str = "locust_pool_changed";
+/// The payload of notifications about deleted sessions.
+const SESSIONS_TABLE: &str = "locust_sessions";
+/// How often the pool is reloaded regardless of notifications.
+const POOL_REFRESH: Duration = Duration::from_secs(60);
+const LISTEN_RETRY: Duration = Duration::from_secs(5);
+/// How long changes are gathered for before the pool is reloaded,
+/// so that a burst of them, like an import, reloads it once.
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(500);
+/// How many session ids are reserved at once.
+const SESSION_ID_BLOCK: i32 = 100;
+const SESSIONS_CAPACITY: u64 = 100_000;
+const SESSIONS_IDLE: Duration = Duration::from_secs(60 * 60);
+/// How often a pool file is checked for changes.
+const POOL_FILE_POLL: Duration = Duration::from_secs(2);
+/// How often the pool is reloaded from a store that
+/// does not notify of changes, like SQLite.
+const STORE_POLL: Duration = Duration::from_secs(5);
+
+/// Where the pool is loaded from.
+enum PoolSource {
+    /// A [`ProxyStore`], along with the Postgres database when it is
+    /// one, which notifies of changes and holds the ban rules.
+    Store {
+        store: Arc<dyn ProxyStore>,
+        db: Option<Arc<PgPool>>,
+    },
+    /// A pool file, see [`PoolFile`]. Sessions only live in memory.
+    File {
+        path: PathBuf,
+        ids: Mutex<ProxyIds>,
+        ban_rules: Mutex<DomainRules<BanRule>>,
+        /// The files the pool was last loaded from.
+        paths: Mutex<Vec<PathBuf>>,
+        last_session_id: AtomicI32,
+    },
+}
+
+/// Holds the proxy pool and sessions in memory, so that picking a
+/// proxy does not go to the database. The pool is reloaded when
+/// Postgres notifies of changes to proxies, tags or domains, or every
+/// few seconds from SQLite, and uses and new sessions are stored by
+/// the DB workers.
+///
+/// Without a database, the pool is read from a file instead,
+/// and reloaded whenever the file changes.
+pub struct PoolCache {
+    source: PoolSource,
+    db_job_chan: DBJobSender,
+    pool: Mutex<ProxyPool>,
+    /// Proxy ids by session id.
+    sessions: Cache<i32, i32>,
+    session_ids: AsyncMutex<Vec<i32>>,
+}
+
+impl PoolCache {
+    /// Loads the pool from a store. `db` is the same database
+    /// when it is Postgres, and `None` otherwise.
+    pub async fn load(
+        store: Arc<dyn ProxyStore>,
+        db: Option<Arc<PgPool>>,
+        db_job_chan: DBJobSender,
+    ) -> Result<Self, sqlx::Error> {
+        let pool = store.get_proxy_pool().await?;
+        info!("loaded {} proxies into the pool", pool.len());
+        Ok(Self::new(
+            PoolSource::Store { store, db },
+            db_job_chan,
+            pool,
+        ))
+    }
+
+    /// Loads the pool from a YAML or TOML pool file.
+    pub fn load_file(path: PathBuf, db_job_chan: DBJobSender) -> Result<Self, PoolFileError> {
+        let mut ids = ProxyIds::default();
+        let loaded = PoolFile::load(&path, &mut ids)?;
+        info!(
+            "loaded {} proxies into the pool from {}",
+            loaded.pool.len(),
+            path.display()
+        );
+        let source = PoolSource::File {
+            path,
+            ids: Mutex::new(ids),
+            ban_rules: Mutex::new(loaded.ban_rules),
+            paths: Mutex::new(loaded.paths),
+            last_session_id: AtomicI32::new(0),
+        };
+        Ok(Self::new(source, db_job_chan, loaded.pool))
+    }
+
+    fn new(source: PoolSource, db_job_chan: DBJobSender, pool: ProxyPool) -> Self {
+        Self {
+            source,
+            db_job_chan,
+            pool: Mutex::new(pool),
+            sessions: Cache::builder()
+                .max_capacity(SESSIONS_CAPACITY)
+                .time_to_idle(SESSIONS_IDLE)
+                .build(),
+            session_ids: AsyncMutex::new(Vec::new()),
+        }
+    }
+
+    /// See [`ProxyPool::set_diversity`].
+    pub fn set_diversity(&self, config: DiversityConfig) {
+        self.pool.lock().unwrap().set_diversity(config);
+    }
+
+    /// Keeps the pool up to date in the background.
+    pub fn spawn_listener(self: &Arc<Self>) {
+        match &self.source {
+            PoolSource::Store { db: Some(db), .. } => {
+                tokio::spawn(Arc::clone(self).listen(Arc::clone(db)));
+            }
+            PoolSource::Store { db: None, .. } => {
+                tokio::spawn(Arc::clone(self).poll());
+            }
+            PoolSource::File { .. } => {
+                tokio::spawn(Arc::clone(self).watch());
+            }
+        }
+    }
+
+    async fn listen(self: Arc<Self>, db: Arc<PgPool>) {
+        loop {
+            let mut listener = match PgListener::connect_with(&db).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    warn!("error connecting to listen for pool changes: {e}");
+                    sleep(LISTEN_RETRY).await;
+                    continue;
+                }
+            };
+            if let Err(e) = listener.listen(POOL_CHANNEL).await {
+                warn!("error listening for pool changes: {e}");
+                sleep(LISTEN_RETRY).await;
+                continue;
+            }
+            // Changes made while not listening were missed.
+            self.reload().await;
+
+            let mut refresh = interval_at(Instant::now() + POOL_REFRESH, POOL_REFRESH);
+            let mut reload_at: Option<Instant> = None;
+            loop {
+                let debounced = sleep_until(reload_at.unwrap_or_else(Instant::now));
+                tokio::select! {
+                    notification = listener.try_recv() => match notification {
+                        Ok(Some(n)) if n.payload() == SESSIONS_TABLE => {
+                            self.sessions.invalidate_all();
+                        }
+                        Ok(Some(_)) => {
+                            reload_at.get_or_insert_with(|| Instant::now() + RELOAD_DEBOUNCE);
+                        }
+                        // The listener reconnects on the next receive.
+                        Ok(None) => {
+                            warn!("lost connection listening for pool changes");
+                            self.reload().await;
+                        }
+                        Err(e) => {
+                            warn!("error receiving pool changes: {e}");
+                            break;
+                        }
+                    },
+                    _ = debounced, if reload_at.is_some() => {
+                        reload_at = None;
+                        self.reload().await;
+                    }
+                    _ = refresh.tick() => self.reload().await,
+                }
+            }
+            sleep(LISTEN_RETRY).await;
+        }
+    }
+
+    /// Reloads the pool from a store that does not notify of changes.
+    async fn poll(self: Arc<Self>) {
+        let mut poll = interval_at(Instant::now() + STORE_POLL, STORE_POLL);
+        loop {
+            poll.tick().await;
+            self.reload().await;
+        }
+    }
+
+    /// Reloads the pool file whenever it, or one of the
+    /// proxy lists it points to, is modified.
+    async fn watch(self: Arc<Self>) {
+        let PoolSource::File { paths, .. } = &self.source else {
+            return;
+        };
+        let mut modified = modified_times(&paths.lock().unwrap());
+        let mut poll = interval_at(Instant::now() + POOL_FILE_POLL, POOL_FILE_POLL);
+        loop {
+            poll.tick().await;
+            let current = modified_times(&paths.lock().unwrap());
+            if current != modified {
+                self.reload().await;
+                // Sources may have been added or removed.
+                modified = modified_times(&paths.lock().unwrap());
+            }
+        }
+    }
+
+    async fn reload(&self) {
+        match &self.source {
+            PoolSource::Store { store, .. } => match store.get_proxy_pool().await {
+                Ok(fresh) => {
+                    info!("reloaded {} proxies into the pool", fresh.len());
+                    self.pool.lock().unwrap().refresh(fresh);
+                }
+                Err(e) => warn!("error reloading the proxy pool: {e}"),
+            },
+            PoolSource::File {
+                path,
+                ids,
+                ban_rules,
+                paths,
+                ..
+            } => {
+                let loaded = PoolFile::load(path, &mut ids.lock().unwrap());
+                match loaded {
+                    Ok(loaded) => {
+                        info!("reloaded {} proxies into the pool", loaded.pool.len());
+                        self.pool.lock().unwrap().refresh(loaded.pool);
+                        *ban_rules.lock().unwrap() = loaded.ban_rules;
+                        *paths.lock().unwrap() = loaded.paths;
+                    }
+                    // The pool stays as it was until the file is fixed.
+                    Err(e) => warn!("error reloading the pool file: {e}"),
+                }
+            }
+        }
+    }
+
+    /// Gets the ban rules that apply to a host, from Postgres or from
+    /// the pool file. They are those of the most specific domain
+    /// matching it, like domain routes. SQLite stores have none.
+    pub async fn ban_rules(&self, host: &str) -> Result<Vec<BanRule>, sqlx::Error> {
+        match &self.source {
+            PoolSource::Store { db: Some(db), .. } => get_host_ban_rules(db, host).await,
+            PoolSource::Store { db: None, .. } => Ok(Vec::new()),
+            PoolSource::File { ban_rules, .. } => Ok(ban_rules
+                .lock()
+                .unwrap()
+                .find(host)
+                .map(<[BanRule]>::to_vec)
+                .unwrap_or_default()),
+        }
+    }
+
+    /// Picks a proxy for the domain, or matching the filter, that
+    /// is not in `exclude`, along with the fallback it was picked
+    /// from. See [`ProxyPool::pick`].
+    pub fn select(
+        &self,
+        domain: Option<&str>,
+        filter: &ProxyFilter,
+        exclude: &[i32],
+    ) -> Option<(Proxy, Option<Fallback>)> {
+        let now = OffsetDateTime::now_utc();
+        let picked = self
+            .pool
+            .lock()
+            .unwrap()
+            .pick(domain, filter, exclude, now)?;
+        self.record_use(picked.0.id, now);
+        Some(picked)
+    }
+
+    /// Gets a proxy that is neither deleted nor quarantined.
+    pub fn live_proxy(&self, id: i32) -> Option<Proxy> {
+        let now = OffsetDateTime::now_utc();
+        let proxy = self.pool.lock().unwrap().get(id, now)?;
+        self.record_use(proxy.id, now);
+        Some(proxy)
+    }
+
+    /// What is done with requests to the host. See [`ProxyPool::action`].
+    pub fn action(&self, host: &str) -> RouteAction {
+        self.pool.lock().unwrap().action(host)
+    }
+
+    /// The limit on requests to the host. See [`ProxyPool::limit`].
+    pub fn limit(&self, host: &str) -> DomainLimit {
+        self.pool.lock().unwrap().limit(host)
+    }
+
+    /// Whether a proxy matches the filter. See [`ProxyPool::matches`].
+    pub fn matches(&self, id: i32, filter: &ProxyFilter) -> bool {
+        self.pool.lock().unwrap().matches(id, filter)
+    }
+
+    fn record_use(&self, proxy_id: i32, at: OffsetDateTime) {
+        if let PoolSource::File { .. } = self.source {
+            return;
+        }
+        if let Err(e) = self.db_job_chan.send(DBJob::ProxyUsed { proxy_id, at }) {
+            warn!("Error sending proxy used job: {e}");
+        }
+    }
+
+    /// See [`ProxyPool::tag_sizes`].
+    pub fn tag_sizes(&self) -> BTreeMap<String, i64> {
+        self.pool.lock().unwrap().tag_sizes()
+    }
+
+    /// Counts the sessions in the store, or the ones held
+    /// in memory with a pool file.
+    pub async fn count_sessions(&self) -> Result<i64, sqlx::Error> {
+        match &self.source {
+            PoolSource::Store { store, .. } => store.count_proxy_sessions().await,
+            PoolSource::File { .. } => {
+                self.sessions.run_pending_tasks().await;
+                Ok(self.sessions.entry_count() as i64)
+            }
+        }
+    }
+
+    /// Looks up a session, going to the database for
+    /// the ones this instance has not seen yet.
+    pub async fn session(&self, id: i32) -> Result<ProxySession, sqlx::Error> {
+        if let Some(proxy_id) = self.sessions.get(&id).await {
+            return Ok(ProxySession { id, proxy_id });
+        }
+        let PoolSource::Store { store, .. } = &self.source else {
+            return Err(sqlx::Error::RowNotFound);
+        };
+        let session = store.get_proxy_session(id).await?;
+        self.sessions.insert(session.id, session.proxy_id).await;
+        Ok(session)
+    }
+
+    /// Creates a session with a reserved id. It can be used right
+    /// away, and is stored by the DB workers shortly after.
+    pub async fn create_session(&self, proxy_id: i32) -> Result<ProxySession, sqlx::Error> {
+        let store = match &self.source {
+            PoolSource::Store { store, .. } => store,
+            PoolSource::File {
+                last_session_id, ..
+            } => {
+                let id = last_session_id.fetch_add(1, Ordering::Relaxed) + 1;
+                self.sessions.insert(id, proxy_id).await;
+                return Ok(ProxySession { id, proxy_id });
+            }
+        };
+        let id = {
+            let mut ids = self.session_ids.lock().await;
+            if ids.is_empty() {
+                *ids = store.reserve_session_ids(SESSION_ID_BLOCK).await?;
+                // Hand them out in order.
+                ids.reverse();
+            }
+            ids.pop().expect("No session ids reserved")
+        };
+        let session = ProxySession { id, proxy_id };
+        self.sessions.insert(id, proxy_id).await;
+        if let Err(e) = self
+            .db_job_chan
+            .send(DBJob::SessionCreated(session.clone()))
+        {
+            warn!("Error sending session created job: {e}");
+        }
+        Ok(session)
+    }
+}
+
+fn modified_times(paths: &[PathBuf]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
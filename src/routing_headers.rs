@@ -0,0 +1,153 @@
+use http::{HeaderMap, HeaderValue};
+use std::{env, net::SocketAddr};
+
+/// Usernames whose responses carry routing headers, separated by commas,
+/// or `*` for everyone.
+const USERS_VAR: &str = "LOCUST_ROUTING_HEADERS_USERS";
+/// An additional address to listen on, where every response carries
+/// routing headers.
+const ADDR_VAR: &str = "LOCUST_ROUTING_HEADERS_ADDR";
+
+const PROXY_ID_HEADER: &str = "x-locust-proxy-id";
+const PROVIDER_HEADER: &str = "x-locust-provider";
+const SESSION_HEADER: &str = "x-locust-session";
+const ATTEMPTS_HEADER: &str = "x-locust-attempts";
+const UPSTREAM_TIME_HEADER: &str = "x-locust-upstream-time";
+
+/// Which responses report how their request was routed.
+/// Nothing is reported unless configured.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RoutingHeaderConfig {
+    /// Listened on in addition to the main address.
+    pub addr: Option<SocketAddr>,
+    pub users: Users,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Users {
+    #[default]
+    None,
+    All,
+    Only(Vec<String>),
+}
+
+impl RoutingHeaderConfig {
+    pub fn from_env() -> Self {
+        Self {
+            addr: env::var(ADDR_VAR)
+                .ok()
+                .map(|addr| addr.parse().expect("Invalid LOCUST_ROUTING_HEADERS_ADDR")),
+            users: env::var(USERS_VAR)
+                .map(|users| Users::parse(&users))
+                .unwrap_or_default(),
+        }
+    }
+
+    /// Whether responses to the user get routing headers.
+    pub fn reports_to(&self, user: Option<&str>) -> bool {
+        match &self.users {
+            Users::None => false,
+            Users::All => true,
+            Users::Only(users) => user.is_some_and(|user| users.iter().any(|u| u == user)),
+        }
+    }
+}
+
+impl Users {
+    fn parse(users: &str) -> Self {
+        let users: Vec<_> = users
+            .split(',')
+            .map(str::trim)
+            .filter(|user| !user.is_empty())
+            .map(String::from)
+            .collect();
+        if users.iter().any(|user| user == "*") {
+            Users::All
+        } else if users.is_empty() {
+            Users::None
+        } else {
+            Users::Only(users)
+        }
+    }
+}
+
+/// How a request was routed, kept in the extensions of its
+/// response until it is reported or dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Routing {
+    /// The upstream proxy, unless the request went direct.
+    pub proxy_id: Option<i32>,
+    pub provider: String,
+    pub session_id: Option<i32>,
+    /// Counting the ones retried after a ban.
+    pub attempts: u32,
+    /// Milliseconds until the upstream response headers.
+    pub upstream_time: u32,
+}
+
+impl Routing {
+    pub fn write(&self, headers: &mut HeaderMap) {
+        if let Some(id) = self.proxy_id {
+            headers.insert(PROXY_ID_HEADER, id.into());
+        }
+        if let Ok(provider) = HeaderValue::from_str(&self.provider) {
+            headers.insert(PROVIDER_HEADER, provider);
+        }
+        if let Some(id) = self.session_id {
+            headers.insert(SESSION_HEADER, id.into());
+        }
+        headers.insert(ATTEMPTS_HEADER, self.attempts.into());
+        headers.insert(UPSTREAM_TIME_HEADER, self.upstream_time.into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reports_to() {
+        let config = |users| RoutingHeaderConfig {
+            addr: None,
+            users: Users::parse(users),
+        };
+        assert!(!config("").reports_to(Some("scraper")));
+        assert!(config("*").reports_to(None));
+        let only = config("scraper, ops");
+        assert!(only.reports_to(Some("ops")));
+        assert!(!only.reports_to(Some("other")));
+        assert!(!only.reports_to(None));
+    }
+
+    #[test]
+    fn test_write() {
+        let mut headers = HeaderMap::new();
+        Routing {
+            proxy_id: Some(4),
+            provider: "webshare".into(),
+            session_id: Some(17),
+            attempts: 2,
+            upstream_time: 350,
+        }
+        .write(&mut headers);
+        let header = |name| headers.get(name).unwrap().to_str().unwrap();
+        assert_eq!(header(PROXY_ID_HEADER), "4");
+        assert_eq!(header(PROVIDER_HEADER), "webshare");
+        assert_eq!(header(SESSION_HEADER), "17");
+        assert_eq!(header(ATTEMPTS_HEADER), "2");
+        assert_eq!(header(UPSTREAM_TIME_HEADER), "350");
+
+        let mut headers = HeaderMap::new();
+        Routing {
+            proxy_id: None,
+            provider: "direct".into(),
+            session_id: None,
+            attempts: 1,
+            upstream_time: 20,
+        }
+        .write(&mut headers);
+        assert!(!headers.contains_key(PROXY_ID_HEADER));
+        assert!(!headers.contains_key(SESSION_HEADER));
+        assert_eq!(headers.len(), 3);
+    }
+}
@@ -1,42 +1,72 @@
-use crate::{ca::CertificateAuthority, rewind::Rewind, worker::DBJob};
+use crate::{
+    access_log::{AccessLog, AccessRecord, QueryRedaction, RedactedHeaders},
+    bans::BanDetector,
+    breaker::CircuitBreakers,
+    ca::CertificateAuthority,
+    cooldowns::Cooldowns,
+    limits::{LimitPermit, Limits, Scope},
+    overrides::{OverrideError, OverridePermissions, Overrides},
+    pool::PoolCache,
+    rewind::Rewind,
+    routing_headers::{Routing, RoutingHeaderConfig},
+    stats::Stats,
+    telemetry::set_remote_parent,
+    upstream::{
+        build_client, build_direct_client, classify_error, classify_status, connect_direct,
+        meter_body, ConnectTimings, ErrorClass,
+    },
+    users::ProxyUsers,
+    worker::{DBJob, DBJobSender, ProxyResponse},
+};
 
 use cookie::Cookie;
+use headers::{authorization::Basic, HeaderMapExt, ProxyAuthorization};
 use http::{
-    header::{COOKIE, SET_COOKIE},
+    header::{COOKIE, PROXY_AUTHORIZATION, REFERER, RETRY_AFTER, SET_COOKIE, USER_AGENT},
     uri::{Authority, Scheme},
     HeaderValue,
 };
 use hyper::{
-    client::HttpConnector, header::Entry, server::conn::Http, service::service_fn, Body, Client,
-    Method, Request, Response, StatusCode, Uri,
+    body::HttpBody, header::Entry, server::conn::Http, service::service_fn, Body, Method, Request,
+    Response, StatusCode, Uri,
 };
-use hyper_proxy::{Intercept, Proxy, ProxyConnector};
-use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
 use locust_core::{
-    crud::proxies::{
-        create_proxy_session, get_general_proxy, get_proxy_by_domain, get_proxy_by_id,
-        get_proxy_session,
-    },
-    models,
+    client::authorize,
+    cooldowns::rate_limit,
+    models::{self, cooldowns::ProxyCooldown},
+    pool::ProxyFilter,
+    routing::RouteAction,
 };
-use sqlx::PgPool;
 use std::{
     convert::Infallible,
     future::Future,
-    sync::{mpsc, Arc},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
+use time::OffsetDateTime;
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite},
-    net::TcpStream,
     task::JoinHandle,
     time::timeout,
 };
 use tokio_rustls::TlsAcceptor;
-use tracing::{error, info, info_span, warn, Instrument, Span};
+use tracing::{error, field, info, info_span, warn, Instrument, Span};
 
 const SESSION_KEY: &str = "_lcst_sess";
 const DEFAULT_TIMEOUT_SECS: u64 = 180;
+/// Set on responses that matched a ban rule, to the kind of rule.
+const BANNED_HEADER: &str = "x-locust-banned";
+/// Set on responses turned away by a limit, to the scope of the limit.
+const LIMITED_HEADER: &str = "x-locust-limited";
+/// Set on responses to blocked domains, to the reason they are blocked.
+const BLOCKED_HEADER: &str = "x-locust-blocked";
+/// Set on responses to requests with an override their user is not
+/// allowed to use, to the override.
+const FORBIDDEN_HEADER: &str = "x-locust-forbidden";
 
 fn bad_request() -> Response<Body> {
     Response::builder()
@@ -52,18 +82,45 @@ fn spawn_with_trace<T: Send + Sync + 'static>(
     tokio::spawn(fut.instrument(span))
 }
 
+/// The parts of the proxy service shared by every connection.
+pub struct ServiceContext<CA> {
+    pub ca: Arc<CA>,
+    pub db_job_chan: DBJobSender,
+    pub pool: Arc<PoolCache>,
+    pub stats: Arc<Stats>,
+    pub access_log: Option<Arc<AccessLog>>,
+    /// What is redacted from the query of URIs in the diagnostic logs.
+    pub redaction: QueryRedaction,
+    pub bans: BanDetector,
+    pub breakers: Arc<CircuitBreakers>,
+    pub cooldowns: Cooldowns,
+    pub limits: Limits,
+    pub users: ProxyUsers,
+    pub overrides: OverridePermissions,
+    pub routing_headers: RoutingHeaderConfig,
+}
+
 pub struct Service<CA> {
-    ca: Arc<CA>,
-    db: Arc<PgPool>,
-    db_job_chan: mpsc::Sender<DBJob>,
+    ctx: Arc<ServiceContext<CA>>,
+    client_addr: SocketAddr,
+    /// The user the client authenticated as, if any.
+    user: Option<String>,
+    /// The overrides of the CONNECT request, for the
+    /// requests decrypted from its tunnel.
+    tunnel_overrides: Arc<Overrides>,
+    /// Whether every response of the listener reports how
+    /// its request was routed, regardless of the user.
+    report_routing: bool,
 }
 
 impl<CA> Clone for Service<CA> {
     fn clone(&self) -> Self {
         Self {
-            ca: Arc::clone(&self.ca),
-            db: Arc::clone(&self.db),
-            db_job_chan: self.db_job_chan.clone(),
+            ctx: Arc::clone(&self.ctx),
+            client_addr: self.client_addr,
+            user: self.user.clone(),
+            tunnel_overrides: Arc::clone(&self.tunnel_overrides),
+            report_routing: self.report_routing,
         }
     }
 }
@@ -72,11 +129,17 @@ impl<CA> Service<CA>
 where
     CA: CertificateAuthority,
 {
-    pub fn new(ca: Arc<CA>, db: Arc<PgPool>, db_job_chan: mpsc::Sender<DBJob>) -> Self {
+    pub fn new(
+        ctx: Arc<ServiceContext<CA>>,
+        client_addr: SocketAddr,
+        report_routing: bool,
+    ) -> Self {
         Self {
-            ca,
-            db,
-            db_job_chan,
+            ctx,
+            client_addr,
+            user: None,
+            tunnel_overrides: Arc::default(),
+            report_routing,
         }
     }
 
@@ -85,129 +148,676 @@ where
     /// of the request.
     ///
     /// Modifies the request with required information for the Locust service and stores
-    /// metrics and metadata about proxy responses.
-    pub async fn proxy(self, req: Request<Body>) -> Result<Response<Body>, Infallible> {
-        info!("REQUEST: {req:?}");
+    /// metrics and metadata about proxy responses. Responses report how their request
+    /// was routed in headers when enabled for the listener or the user.
+    pub async fn proxy(mut self, mut req: Request<Body>) -> Result<Response<Body>, Infallible> {
+        info!(
+            method = %req.method(),
+            uri = %self.ctx.redaction.redact(req.uri()),
+            headers = ?RedactedHeaders(req.headers()),
+            "REQUEST"
+        );
+        // Requests decrypted from a CONNECT tunnel carry no proxy
+        // credentials of their own, so the tunnel's user is kept.
+        // Clients whose credentials do not check out are anonymous.
+        if let Some(auth) = req.headers().typed_get::<ProxyAuthorization<Basic>>() {
+            self.user = self.ctx.users.authenticate(&auth);
+            if self.user.is_none() {
+                warn!("proxy credentials of {} rejected", auth.0.username());
+            }
+        }
+        // The credentials are for this proxy, never for the upstream.
+        req.headers_mut().remove(PROXY_AUTHORIZATION);
         if req.method() == Method::CONNECT {
             Ok(self.process_connect(req))
         } else if hyper_tungstenite::is_upgrade_request(&req) {
             error!("received a socket request: unimplemented");
             Ok(bad_request())
         } else {
-            let req = normalize_request(req);
-            // @TODO: remove the session cookie after we extract it
-            let maybe_session = extract_session_cookie(&req);
-            let host: Option<String> = req.uri().host().map(Into::into);
-            let (upstream_proxy, session_id) = match maybe_session {
-                // If we dont already have a session, get a proxy
-                // from the db and create a new session with it.
-                None => self.get_proxy_and_create_session(host.clone()).await,
-
-                // If we already have a session going then look it up
-                // and look up the proxy associated with it.
-                Some(id) => {
+            let span = info_span!(
+                "proxy_request",
+                otel.kind = "server",
+                http.method = %req.method(),
+                domain = req.uri().host().unwrap_or_default(),
+                proxy.id = field::Empty,
+                session.id = field::Empty,
+                http.status_code = field::Empty,
+                error.class = field::Empty,
+            );
+            set_remote_parent(&span, req.headers());
+            let report =
+                self.report_routing || self.ctx.routing_headers.reports_to(self.user.as_deref());
+            let mut res = self.proxy_request(req, span.clone()).instrument(span).await;
+            if let Some(routing) = res.extensions_mut().remove::<Routing>() {
+                if report {
+                    routing.write(res.headers_mut());
+                }
+            }
+            Ok(res)
+        }
+    }
+
+    /// Proxies a plain (or already decrypted) HTTP request
+    /// through an upstream proxy. Runs within `span`.
+    async fn proxy_request(self, req: Request<Body>, span: Span) -> Response<Body> {
+        let _in_flight = self.ctx.stats.start_request();
+        let mut req = normalize_request(req);
+        let access = self.access_record(&req);
+        let user = self.user.as_deref();
+        let overrides = match self.ctx.overrides.take(user, req.headers_mut()) {
+            Ok(overrides) => overrides.or(&self.tunnel_overrides),
+            Err(e) => return reject(access, self.override_response(e)),
+        };
+        let filter = &overrides.filter;
+        let timeout = overrides
+            .timeout
+            .unwrap_or(Duration::from_secs(DEFAULT_TIMEOUT_SECS));
+        // @TODO: remove the session cookie after we extract it
+        let maybe_session = extract_session_cookie(&req).filter(|_| !overrides.new_session);
+        let host: Option<String> = req.uri().host().map(Into::into);
+        let action = host
+            .as_deref()
+            .map(|host| self.ctx.pool.action(host))
+            .unwrap_or_default();
+        if let RouteAction::Block(reason) = &action {
+            return reject(access, self.blocked_response(reason.clone()));
+        }
+        // Requests queue for the limits of their domain, and then
+        // of their proxy, until a single deadline.
+        let deadline = self.ctx.limits.deadline();
+        let route_limit = host
+            .as_deref()
+            .map(|host| self.ctx.pool.limit(host))
+            .unwrap_or_default();
+        let domain_permit = match self
+            .ctx
+            .limits
+            .acquire_domain(host.as_deref(), route_limit, deadline)
+            .instrument(info_span!("limits_wait", scope = "domain"))
+            .await
+        {
+            Ok(permit) => permit,
+            Err(scope) => return reject(access, self.limited_response(scope)),
+        };
+        if let RouteAction::Direct(local_addr) = action {
+            return self
+                .send_direct(req, local_addr, timeout, access, domain_permit, span)
+                .await;
+        }
+        // Proxies cooling down from a rate limit by
+        // this domain are not used for it.
+        let cooldowns = match &host {
+            Some(host) => self.ctx.cooldowns.get(host),
+            None => Vec::new(),
+        };
+        let mut excluded: Vec<i32> = cooldowns.iter().map(|c| c.proxy_id).collect();
+
+        let selected = match maybe_session {
+            // If we dont already have a session, get a proxy
+            // from the db and create a new session with it.
+            None => self.select_proxy(host.clone(), filter, &excluded).await,
+
+            // If we already have a session going then look it up
+            // and look up the proxy associated with it.
+            Some(id) => {
+                async {
                     info!("USING SESSION");
-                    match get_proxy_session(&self.db, id).await {
-                        Ok(sess) => {
-                            let proxy = get_proxy_by_id(&self.db, sess.proxy_id)
-                                .await
-                                .expect("error getting proxy from session");
-                            (proxy, sess.id)
-                        }
+                    match self.ctx.pool.session(id).await {
+                        Ok(sess) => match self.ctx.pool.live_proxy(sess.proxy_id) {
+                            Some(proxy) if !self.ctx.pool.matches(proxy.id, filter) => {
+                                info!("session proxy does not match the overrides");
+                                self.select_proxy(host.clone(), filter, &excluded).await
+                            }
+                            Some(proxy) if excluded.contains(&proxy.id) => {
+                                info!("session proxy is cooling down");
+                                self.select_proxy(host.clone(), filter, &excluded).await
+                            }
+                            Some(proxy)
+                                if !self
+                                    .ctx
+                                    .breakers
+                                    .blocked(host.as_deref())
+                                    .contains(&proxy.id) =>
+                            {
+                                Ok((proxy, sess.id))
+                            }
+                            Some(_) => {
+                                info!("session proxy has an open circuit breaker");
+                                self.select_proxy(host.clone(), filter, &excluded).await
+                            }
+                            // The session's proxy has since been deleted or
+                            // quarantined, so the client gets a new session.
+                            None => {
+                                info!("session proxy is unavailable");
+                                self.select_proxy(host.clone(), filter, &excluded).await
+                            }
+                        },
                         Err(sqlx::Error::RowNotFound) => {
                             warn!("session requested that does not exist");
-                            self.get_proxy_and_create_session(host.clone()).await
+                            self.select_proxy(host.clone(), filter, &excluded).await
                         }
                         Err(e) => {
                             error!("unknown error getting proxy session: {e:?}");
-                            self.get_proxy_and_create_session(host.clone()).await
+                            self.select_proxy(host.clone(), filter, &excluded).await
                         }
                     }
                 }
-            };
-            // @TODO: perhaps cache clients to various proxies? TBD how much
-            // overhead creating a client every time creates. Caching would
-            // increase memory usage but perhaps lower latency.
-            let client = build_client(&upstream_proxy);
-            let start_time = Instant::now();
-
-            // Make the upstream request, but wrap it in
-            // a timeout. If the timeout completes first,
-            // then return a gateway timeout response.
-            let mut res = match timeout(
-                Duration::from_secs(DEFAULT_TIMEOUT_SECS),
-                client.request(req),
-            )
+                .instrument(info_span!("session_lookup", session.id = id))
+                .await
+            }
+        };
+        let (upstream_proxy, session_id) = match selected {
+            Ok(selected) => selected,
+            Err(e) => {
+                let res = no_proxy_response(e, &cooldowns);
+                self.ctx.stats.record_status(res.status().as_u16());
+                return reject(access, res);
+            }
+        };
+        let https = req.uri().scheme() == Some(&Scheme::HTTPS);
+        let ban_rules = match &host {
+            Some(host) => self.ctx.bans.rules_for(host).await,
+            None => Arc::default(),
+        };
+        // Only requests without a body can be sent
+        // again through another proxy once banned.
+        let replay =
+            (self.ctx.bans.retries() > 0 && !ban_rules.is_empty() && req.body().is_end_stream())
+                .then(|| copy_request(&req));
+
+        let limit_permit = match self
+            .acquire_proxy_limits(upstream_proxy.id, host.as_deref(), deadline)
             .await
+        {
+            Ok(permit) => permit,
+            Err(scope) => return reject(access, self.limited_response(scope)),
+        };
+
+        let mut next = Some((req, upstream_proxy, session_id, limit_permit));
+        let mut retries = 0;
+        let (mut attempt, ban) = loop {
+            let (req, upstream_proxy, session_id, limit_permit) =
+                next.take().expect("No request to send");
+            let permit = self.ctx.breakers.admit(upstream_proxy.id, host.as_deref());
+            let mut attempt = self
+                .send_upstream(req, upstream_proxy, session_id, https, timeout)
+                .await;
+            attempt.limit_permit = limit_permit;
+            if let (Some(host), None) = (&host, attempt.error_class) {
+                self.cool_down_if_limited(&attempt, host);
+            }
+            let ban = match attempt.error_class {
+                None => {
+                    let (res, ban) = self.ctx.bans.check(&ban_rules, attempt.res).await;
+                    attempt.res = res;
+                    ban
+                }
+                Some(_) => None,
+            };
+            permit.record(ban.map(|_| ErrorClass::Banned).or(attempt.error_class));
+            let Some(ban) = ban else {
+                break (attempt, None);
+            };
+
+            warn!(
+                rule.id = ban.rule_id,
+                "proxy {} banned by {} rule", attempt.proxy.id, ban.kind
+            );
+            attempt.error_class = Some(ErrorClass::Banned);
+            let Some(template) = replay
+                .as_ref()
+                .filter(|_| retries < self.ctx.bans.retries())
+            else {
+                break (attempt, Some(ban));
+            };
+            excluded.push(attempt.proxy.id);
+            let (proxy, session_id) = match self.select_proxy(host.clone(), filter, &excluded).await
             {
-                Ok(res) => match res {
-                    Ok(res) => res,
-                    Err(e) => {
-                        error!("Error making request {e}");
-                        Response::builder()
-                            .status(StatusCode::INTERNAL_SERVER_ERROR)
-                            .body(hyper::Body::empty())
-                            .unwrap()
-                    }
-                },
-                Err(_) => Response::builder()
+                Ok(selected) => selected,
+                Err(e) => {
+                    warn!("no proxy left to retry banned request with: {e}");
+                    break (attempt, Some(ban));
+                }
+            };
+            let limit_permit = match self
+                .acquire_proxy_limits(proxy.id, host.as_deref(), deadline)
+                .await
+            {
+                Ok(permit) => permit,
+                Err(scope) => {
+                    warn!("{scope} limit reached before retrying banned request");
+                    break (attempt, Some(ban));
+                }
+            };
+            info!("retrying banned request through proxy {}", proxy.id);
+            // The banned attempt is still recorded
+            // even though its response is thrown away.
+            drop(self.finish_attempt(attempt, host.clone(), None, None));
+            next = Some((copy_request(template), proxy, session_id, limit_permit));
+            retries += 1;
+        };
+        attempt.limit_permit.merge(domain_permit);
+
+        info!("RESPONSE STATUS: {}", attempt.res.status());
+        span.record("proxy.id", attempt.proxy.id);
+        span.record("session.id", attempt.session_id);
+        span.record("http.status_code", attempt.res.status().as_u16());
+        if let Some(class) = attempt.error_class {
+            span.record("error.class", class.as_str());
+        }
+        self.ctx.stats.record_status(attempt.res.status().as_u16());
+
+        let session_id = attempt.session_id;
+        let routing = Routing {
+            proxy_id: Some(attempt.proxy.id),
+            provider: attempt.proxy.provider.clone(),
+            session_id: Some(session_id),
+            attempts: u32::from(retries) + 1,
+            upstream_time: attempt.response_time,
+        };
+        let mut res = self.finish_attempt(attempt, host, access, Some(span));
+        res.extensions_mut().insert(routing);
+        if let Some(ban) = ban {
+            res.headers_mut()
+                .insert(BANNED_HEADER, HeaderValue::from_static(ban.kind.as_str()));
+        }
+
+        // Instruct the client to add the session cookie
+        // so that its included in the subsequent requests
+        // and we can make sure to use the same proxy.
+        res.headers_mut().append(
+            SET_COOKIE,
+            HeaderValue::from_str(format!("{SESSION_KEY}={session_id}").as_ref()).unwrap(),
+        );
+        res
+    }
+
+    /// Starts the access log record of a request, if access logging
+    /// is on. The rest of it is filled in once it is answered.
+    fn access_record<T>(&self, req: &Request<T>) -> Option<(Arc<AccessLog>, AccessRecord)> {
+        let log = self.ctx.access_log.clone()?;
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|v: &HeaderValue| v.to_str().ok())
+                .map(String::from)
+        };
+        let record = AccessRecord {
+            timestamp: OffsetDateTime::now_utc(),
+            client_addr: self.client_addr,
+            user: self.user.clone(),
+            method: req.method().to_string(),
+            url: log.redact_uri(req.uri()),
+            status: 0,
+            bytes: 0,
+            duration_ms: 0,
+            proxy_id: 0,
+            provider: String::new(),
+            session_id: 0,
+            referer: header(REFERER),
+            user_agent: header(USER_AGENT),
+        };
+        Some((log, record))
+    }
+
+    /// Sends a request through an upstream proxy, turning
+    /// failures into synthetic error responses.
+    async fn send_upstream(
+        &self,
+        mut req: Request<Body>,
+        proxy: models::proxies::Proxy,
+        session_id: i32,
+        https: bool,
+        wait: Duration,
+    ) -> Attempt {
+        // @TODO: perhaps cache clients to various proxies? TBD how much
+        // overhead creating a client every time creates. Caching would
+        // increase memory usage but perhaps lower latency.
+        let timings = ConnectTimings::default();
+        let client = build_client(&proxy, &timings);
+        authorize(&mut req, &proxy);
+        let request_bytes = Arc::new(AtomicU64::new(0));
+        let req = {
+            let request_bytes = Arc::clone(&request_bytes);
+            req.map(|body| meter_body(body, move |n| request_bytes.store(n, Ordering::Relaxed)))
+        };
+        let started_at = OffsetDateTime::now_utc();
+        let start_time = Instant::now();
+
+        // Make the upstream request, but wrap it in
+        // a timeout. If the timeout completes first,
+        // then return a gateway timeout response.
+        let (res, error_class) = match timeout(wait, client.request(req)).await {
+            Ok(res) => match res {
+                Ok(res) => {
+                    let class = classify_status(res.status());
+                    (res, class)
+                }
+                Err(e) => {
+                    let class = classify_error(&e, &timings, https);
+                    error!("Error making request ({class}) {e}");
+                    let res = Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body(hyper::Body::empty())
+                        .unwrap();
+                    (res, Some(class))
+                }
+            },
+            Err(_) => {
+                let res = Response::builder()
                     .status(StatusCode::GATEWAY_TIMEOUT)
                     .body(hyper::Body::empty())
-                    .unwrap(),
-            };
-            let duration = start_time.elapsed().as_millis();
-            info!("RESPONSE STATUS: {}", res.status());
-            if let Err(e) = self.db_job_chan.send(DBJob::ProxyResponse {
-                proxy_id: upstream_proxy.id,
-                status: res.status(),
-                response_time: duration as u32,
-                domain: host.map(Into::into),
-            }) {
-                warn!("Error sending proxy response job: {e}");
+                    .unwrap();
+                (res, Some(ErrorClass::Timeout))
             }
+        };
 
-            // Instruct the client to add the session cookie
-            // so that its included in the subsequent requests
-            // and we can make sure to use the same proxy.
-            res.headers_mut().append(
-                SET_COOKIE,
-                HeaderValue::from_str(format!("{SESSION_KEY}={session_id}").as_ref()).unwrap(),
-            );
-            Ok(res)
+        Attempt {
+            proxy,
+            session_id,
+            res,
+            error_class,
+            timings,
+            request_bytes,
+            started_at,
+            start_time,
+            response_time: start_time.elapsed().as_millis() as u32,
+            limit_permit: LimitPermit::default(),
         }
     }
 
-    async fn get_upstream_proxy(
+    /// Sends a request straight to the origin, from `local_addr` when
+    /// given, for domains routed `direct`. Such requests get no session
+    /// and are not recorded as proxy responses. Runs within `span`.
+    async fn send_direct(
         &self,
+        req: Request<Body>,
+        local_addr: Option<IpAddr>,
+        wait: Duration,
+        access: Option<(Arc<AccessLog>, AccessRecord)>,
+        limit_permit: LimitPermit,
+        span: Span,
+    ) -> Response<Body> {
+        info!("sending request directly to the origin");
+        let client = build_direct_client(local_addr);
+        let start_time = Instant::now();
+        let mut res = match timeout(wait, client.request(req)).await {
+            Ok(Ok(res)) => res,
+            Ok(Err(e)) => {
+                error!("Error making direct request {e}");
+                span.record("error.class", ErrorClass::Upstream.as_str());
+                Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::empty())
+                    .unwrap()
+            }
+            Err(_) => {
+                span.record("error.class", ErrorClass::Timeout.as_str());
+                Response::builder()
+                    .status(StatusCode::GATEWAY_TIMEOUT)
+                    .body(Body::empty())
+                    .unwrap()
+            }
+        };
+        let status = res.status().as_u16();
+        span.record("http.status_code", status);
+        self.ctx.stats.record_status(status);
+        res.extensions_mut().insert(Routing {
+            proxy_id: None,
+            provider: "direct".into(),
+            session_id: None,
+            attempts: 1,
+            upstream_time: start_time.elapsed().as_millis() as u32,
+        });
+
+        let access = access.map(|(log, record)| {
+            let record = AccessRecord {
+                status,
+                provider: "direct".into(),
+                ..record
+            };
+            (log, record)
+        });
+        let res_body = std::mem::take(res.body_mut());
+        *res.body_mut() = meter_body(res_body, move |response_bytes| {
+            let _span = span;
+            let _limit_permit = limit_permit;
+            if let Some((log, record)) = access {
+                let elapsed = OffsetDateTime::now_utc() - record.timestamp;
+                log.log(&AccessRecord {
+                    bytes: response_bytes,
+                    duration_ms: elapsed.whole_milliseconds() as u32,
+                    ..record
+                });
+            }
+        });
+        res
+    }
+
+    /// Meters the response body of an attempt so that it is recorded,
+    /// and access logged if `access` is given, once it has streamed.
+    /// `span` is kept open until then.
+    fn finish_attempt(
+        &self,
+        attempt: Attempt,
         host: Option<String>,
-    ) -> Result<models::proxies::Proxy, sqlx::Error> {
-        match host {
-            Some(host) => get_proxy_by_domain(&self.db, &host).await,
-            None => get_general_proxy(&self.db).await,
-        }
+        access: Option<(Arc<AccessLog>, AccessRecord)>,
+        span: Option<Span>,
+    ) -> Response<Body> {
+        let Attempt {
+            proxy,
+            session_id,
+            mut res,
+            error_class,
+            timings,
+            request_bytes,
+            started_at,
+            start_time,
+            response_time,
+            limit_permit,
+        } = attempt;
+
+        // Synthetic responses for failed requests
+        // do not carry an origin status.
+        let status = match error_class {
+            Some(ErrorClass::ProxyAuthFailed | ErrorClass::Banned) | None => Some(res.status()),
+            Some(_) => None,
+        };
+        let db_job_chan = self.ctx.db_job_chan.clone();
+        let user = self.user.clone();
+        let access = access.map(|(log, record)| {
+            let record = AccessRecord {
+                status: res.status().as_u16(),
+                proxy_id: proxy.id,
+                provider: proxy.provider.clone(),
+                session_id,
+                ..record
+            };
+            (log, record)
+        });
+        let res_body = std::mem::take(res.body_mut());
+        let streaming_span = info_span!("response_streaming", bytes = field::Empty);
+        *res.body_mut() = meter_body(res_body, move |response_bytes| {
+            // The request span and the limit slots are kept
+            // until the body is done streaming as well.
+            let _span = span;
+            let _limit_permit = limit_permit;
+            streaming_span.record("bytes", response_bytes);
+            let to_millis = |d: Duration| d.as_millis() as u32;
+            if let Some((log, record)) = access {
+                let elapsed = OffsetDateTime::now_utc() - record.timestamp;
+                log.log(&AccessRecord {
+                    bytes: response_bytes,
+                    duration_ms: elapsed.whole_milliseconds() as u32,
+                    ..record
+                });
+            }
+            if let Err(e) = db_job_chan.send(DBJob::ProxyResponse(Box::new(ProxyResponse {
+                started_at,
+                proxy_id: proxy.id,
+                session_id,
+                user,
+                status,
+                error_class,
+                response_time,
+                total_time: to_millis(start_time.elapsed()),
+                connect_time: timings.proxy_connect().map(to_millis),
+                tls_handshake_time: timings.tls_handshake().map(to_millis),
+                request_bytes: request_bytes.load(Ordering::Relaxed),
+                response_bytes,
+                domain: host,
+                provider: proxy.provider,
+            }))) {
+                warn!("Error sending proxy response job: {e}");
+            }
+        });
+        res
     }
 
-    async fn get_proxy_and_create_session(
+    /// Picks a proxy that is not in `exclude`, matching the
+    /// filter if any, and creates a new session with it.
+    ///
+    /// Proxies with an open circuit breaker are avoided, unless
+    /// there is no other proxy to pick. Proxies picked from a
+    /// fallback of the domain are counted in the stats.
+    async fn select_proxy(
         &self,
         host: Option<String>,
-    ) -> (locust_core::models::proxies::Proxy, i32) {
-        let proxy = self
-            .get_upstream_proxy(host)
-            .await
-            .expect("Error getting proxy for client");
+        filter: &ProxyFilter,
+        exclude: &[i32],
+    ) -> Result<(models::proxies::Proxy, i32), sqlx::Error> {
+        let mut avoid = self.ctx.breakers.blocked(host.as_deref());
+        avoid.extend_from_slice(exclude);
+        let (proxy, fallback) = {
+            let _span = info_span!("proxy_selection").entered();
+            match self.ctx.pool.select(host.as_deref(), filter, &avoid) {
+                None if avoid.len() > exclude.len() => {
+                    warn!("every available proxy has an open circuit breaker");
+                    self.ctx.pool.select(host.as_deref(), filter, exclude)
+                }
+                picked => picked,
+            }
+        }
+        .ok_or(sqlx::Error::RowNotFound)?;
+        if let (Some(host), Some(fallback)) = (&host, fallback) {
+            let fallback = fallback.label();
+            info!(fallback, "using a fallback proxy for {host}");
+            self.ctx.stats.record_fallback(host, fallback);
+        }
         info!("CREATING SESSION");
-        let session = create_proxy_session(&self.db, proxy.id)
+        let session = self
+            .ctx
+            .pool
+            .create_session(proxy.id)
+            .instrument(info_span!("session_create", proxy.id = proxy.id))
+            .await?;
+        self.ctx.stats.record_session_created();
+        Ok((proxy, session.id))
+    }
+
+    async fn acquire_proxy_limits(
+        &self,
+        proxy_id: i32,
+        host: Option<&str>,
+        deadline: tokio::time::Instant,
+    ) -> Result<LimitPermit, Scope> {
+        self.ctx
+            .limits
+            .acquire_proxy(proxy_id, host, deadline)
+            .instrument(info_span!(
+                "limits_wait",
+                scope = "proxy",
+                proxy.id = proxy_id
+            ))
             .await
-            .expect("Error creation proxy session");
-        (proxy, session.id)
     }
 
-    fn process_connect(self, mut req: Request<Body>) -> Response<Body> {
+    /// Answers a request with override headers that are
+    /// invalid or that its user is not allowed to use.
+    fn override_response(&self, e: OverrideError) -> Response<Body> {
+        warn!("{e}");
+        let res = match e {
+            OverrideError::Invalid(..) => bad_request(),
+            OverrideError::Forbidden(o) => Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .header(FORBIDDEN_HEADER, o.as_str())
+                .body(Body::empty())
+                .expect("Failed to build response"),
+        };
+        self.ctx.stats.record_status(res.status().as_u16());
+        res
+    }
+
+    /// Answers a request to a domain routed `block`.
+    fn blocked_response(&self, reason: Option<String>) -> Response<Body> {
+        warn!("domain is blocked, turning request away");
+        self.ctx.stats.record_status(StatusCode::FORBIDDEN.as_u16());
+        Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .header(BLOCKED_HEADER, reason.as_deref().unwrap_or("blocked"))
+            .body(Body::empty())
+            .expect("Failed to build response")
+    }
+
+    /// Answers a request that could not get past a limit
+    /// before its deadline.
+    fn limited_response(&self, scope: Scope) -> Response<Body> {
+        warn!("{scope} limit reached, turning request away");
+        self.ctx
+            .stats
+            .record_status(StatusCode::TOO_MANY_REQUESTS.as_u16());
+        Response::builder()
+            .status(StatusCode::TOO_MANY_REQUESTS)
+            .header(LIMITED_HEADER, scope.as_str())
+            .body(Body::empty())
+            .expect("Failed to build response")
+    }
+
+    /// Puts the proxy of an attempt in cooldown for
+    /// the domain if the response is a rate limit.
+    fn cool_down_if_limited(&self, attempt: &Attempt, host: &str) {
+        let headers = attempt.res.headers();
+        let Some(retry_after) =
+            rate_limit(attempt.res.status(), headers, OffsetDateTime::now_utc())
+        else {
+            return;
+        };
+        let cooldown = self.ctx.cooldowns.add(attempt.proxy.id, host, retry_after);
+        info!(
+            strikes = cooldown.strikes,
+            "proxy {} rate limited, cooling down until {}", attempt.proxy.id, cooldown.date_until
+        );
+    }
+
+    /// Answers a CONNECT request and serves its tunnel. Requests
+    /// decrypted from the tunnel get access log records of their own,
+    /// while tunnels going straight to the origin get one as a whole.
+    fn process_connect(mut self, mut req: Request<Body>) -> Response<Body> {
+        let access = self.access_record(&req);
         match req.uri().authority().cloned() {
             Some(authority) => {
-                let span = info_span!("process_connect");
+                let user = self.user.as_deref();
+                match self.ctx.overrides.take(user, req.headers_mut()) {
+                    Ok(overrides) => self.tunnel_overrides = Arc::new(overrides),
+                    Err(e) => return reject(access, self.override_response(e)),
+                }
+                let direct = match self.ctx.pool.action(authority.host()) {
+                    RouteAction::Block(reason) => {
+                        return reject(access, self.blocked_response(reason))
+                    }
+                    RouteAction::Direct(local_addr) => Some(local_addr),
+                    RouteAction::Proxy(_) => None,
+                };
+                let span = info_span!("process_connect", authority = %authority);
+                set_remote_parent(&span, req.headers());
                 let fut = async move {
                     match hyper::upgrade::on(&mut req).await {
+                        // Direct tunnels are not decrypted.
+                        Ok(upgraded) if direct.is_some() => {
+                            info!("tunneling directly to the origin");
+                            let bytes = tunnel(upgraded, &authority, direct.flatten()).await;
+                            log_tunnel(access, bytes);
+                        }
                         Ok(mut upgraded) => {
                             let mut buffer = [0; 4];
                             let bytes_read = match upgraded.read(&mut buffer).await {
@@ -218,7 +828,7 @@ where
                                 }
                             };
 
-                            let mut upgraded = Rewind::new_buffered(
+                            let upgraded = Rewind::new_buffered(
                                 upgraded,
                                 bytes::Bytes::copy_from_slice(buffer[..bytes_read].as_ref()),
                             );
@@ -233,6 +843,7 @@ where
                                 return;
                             } else if buffer[..2] == *b"\x16\x03" {
                                 let server_config = self
+                                    .ctx
                                     .ca
                                     .gen_server_config(&authority)
                                     .instrument(info_span!("gen_server_config"))
@@ -264,19 +875,8 @@ where
                                 );
                             }
 
-                            let mut server = match TcpStream::connect(authority.as_ref()).await {
-                                Ok(server) => server,
-                                Err(e) => {
-                                    error!("Failed to connect to {}: {}", authority, e);
-                                    return;
-                                }
-                            };
-
-                            if let Err(e) =
-                                tokio::io::copy_bidirectional(&mut upgraded, &mut server).await
-                            {
-                                error!("Failed to tunnel to {}: {}", authority, e);
-                            }
+                            let bytes = tunnel(upgraded, &authority, None).await;
+                            log_tunnel(access, bytes);
                         }
                         Err(e) => error!("Upgrade error: {}", e),
                     };
@@ -285,7 +885,7 @@ where
                 spawn_with_trace(fut, span);
                 Response::new(Body::empty())
             }
-            None => bad_request(),
+            None => reject(access, bad_request()),
         }
     }
 
@@ -323,36 +923,105 @@ where
     }
 }
 
-/// Creates an HTTPS client that proxies traffic to the provided
-/// Proxy.
-fn build_client(
-    upstream_proxy: &models::proxies::Proxy,
-) -> Client<ProxyConnector<HttpsConnector<HttpConnector>>> {
-    let https = HttpsConnectorBuilder::new()
-        .with_webpki_roots()
-        .https_or_http()
-        .enable_http1()
-        .build();
-
-    let mut proxy = Proxy::new(
-        Intercept::All,
-        format!(
-            "{}://{}:{}",
-            upstream_proxy.protocol, upstream_proxy.host, upstream_proxy.port
-        )
-        .parse()
-        .unwrap(),
-    );
-    if let (Some(usr), Some(pwd)) = (&upstream_proxy.username, &upstream_proxy.password) {
-        let auth = headers::Authorization::basic(usr, pwd);
-        proxy.set_authorization(auth);
+/// Tunnels a connection to the origin as is, from `local_addr`
+/// when given. Returns how many bytes the origin sent back.
+async fn tunnel<I>(mut upgraded: I, authority: &Authority, local_addr: Option<IpAddr>) -> u64
+where
+    I: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut server = match connect_direct(authority.as_str(), local_addr).await {
+        Ok(server) => server,
+        Err(e) => {
+            error!("Failed to connect to {}: {}", authority, e);
+            return 0;
+        }
+    };
+
+    match tokio::io::copy_bidirectional(&mut upgraded, &mut server).await {
+        Ok((_, received)) => received,
+        Err(e) => {
+            error!("Failed to tunnel to {}: {}", authority, e);
+            0
+        }
+    }
+}
+
+/// Access logs a request that is answered without going
+/// through a proxy, and hands back its response.
+fn reject(access: Option<(Arc<AccessLog>, AccessRecord)>, res: Response<Body>) -> Response<Body> {
+    if let Some((log, record)) = access {
+        let elapsed = OffsetDateTime::now_utc() - record.timestamp;
+        log.log(&AccessRecord {
+            status: res.status().as_u16(),
+            duration_ms: elapsed.whole_milliseconds() as u32,
+            ..record
+        });
+    }
+    res
+}
+
+/// Access logs a tunnel straight to the origin once it is closed.
+fn log_tunnel(access: Option<(Arc<AccessLog>, AccessRecord)>, bytes: u64) {
+    if let Some((log, record)) = access {
+        let elapsed = OffsetDateTime::now_utc() - record.timestamp;
+        log.log(&AccessRecord {
+            status: StatusCode::OK.as_u16(),
+            bytes,
+            duration_ms: elapsed.whole_milliseconds() as u32,
+            provider: "direct".into(),
+            ..record
+        });
     }
-    let connector = ProxyConnector::from_proxy(https, proxy).unwrap();
+}
+
+/// Answers a request that no proxy could be picked for. When every
+/// proxy left is cooling down, the client is told when to retry.
+fn no_proxy_response(e: sqlx::Error, cooldowns: &[ProxyCooldown]) -> Response<Body> {
+    let res = Response::builder();
+    let res = match e {
+        sqlx::Error::RowNotFound => match cooldowns.iter().map(|c| c.date_until).min() {
+            Some(until) => {
+                warn!("every proxy is cooling down until {until}");
+                let secs = (until - OffsetDateTime::now_utc()).whole_seconds() + 1;
+                res.status(StatusCode::TOO_MANY_REQUESTS)
+                    .header(RETRY_AFTER, secs.max(1))
+            }
+            None => {
+                error!("no proxy available for request");
+                res.status(StatusCode::SERVICE_UNAVAILABLE)
+            }
+        },
+        e => {
+            error!("error getting proxy for request: {e}");
+            res.status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    };
+    res.body(Body::empty()).expect("Failed to build response")
+}
+
+/// A request sent through a single upstream proxy.
+struct Attempt {
+    proxy: models::proxies::Proxy,
+    session_id: i32,
+    res: Response<Body>,
+    error_class: Option<ErrorClass>,
+    timings: ConnectTimings,
+    request_bytes: Arc<AtomicU64>,
+    started_at: OffsetDateTime,
+    start_time: Instant,
+    response_time: u32,
+    /// Limit slots held until the response has streamed.
+    limit_permit: LimitPermit,
+}
 
-    Client::builder()
-        .http1_title_case_headers(true)
-        .http1_preserve_header_case(true)
-        .build(connector)
+/// Copies the head of a request that has no body.
+fn copy_request<T>(req: &Request<T>) -> Request<Body> {
+    let mut copy = Request::new(Body::empty());
+    *copy.method_mut() = req.method().clone();
+    *copy.uri_mut() = req.uri().clone();
+    *copy.version_mut() = req.version();
+    *copy.headers_mut() = req.headers().clone();
+    copy
 }
 
 fn normalize_request<T>(mut req: Request<T>) -> Request<T> {
@@ -381,3 +1050,142 @@ fn extract_session_cookie<T>(req: &Request<T>) -> Option<i32> {
 
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        access_log::AccessLogFormat,
+        ca::CacheStats,
+        limits::{Limit, LimitConfig},
+        worker::{self, WorkerConfig},
+    };
+    use async_trait::async_trait;
+    use locust_core::cooldowns::CooldownConfig;
+    use std::{fs, path::Path};
+    use tokio_rustls::rustls::ServerConfig;
+
+    /// Requests in these tests never get as far as a tunnel.
+    struct NoAuthority;
+
+    #[async_trait]
+    impl CertificateAuthority for NoAuthority {
+        async fn gen_server_config(&self, _: &Authority) -> Arc<ServerConfig> {
+            unreachable!("no certificates are issued in tests")
+        }
+
+        fn cache_stats(&self) -> CacheStats {
+            CacheStats::default()
+        }
+    }
+
+    /// A service over a pool without proxies, blocking `blocked.com`,
+    /// that access logs to `access.log` in `dir` as JSON.
+    fn test_service(dir: &Path, limits: LimitConfig) -> Service<NoAuthority> {
+        fs::create_dir_all(dir).unwrap();
+        let pool_path = dir.join("pool.yaml");
+        fs::write(
+            &pool_path,
+            "domains: [{host: blocked.com, action: block(tos)}]",
+        )
+        .unwrap();
+        let stats = Arc::new(Stats::default());
+        let (tx, _) = worker::channel(&WorkerConfig::default(), Arc::clone(&stats));
+        let pool = Arc::new(PoolCache::load_file(pool_path, tx.clone()).unwrap());
+        let log = fs::File::create(dir.join("access.log")).unwrap();
+        let ctx = ServiceContext {
+            ca: Arc::new(NoAuthority),
+            db_job_chan: tx.clone(),
+            bans: BanDetector::new(Arc::clone(&pool), 0, 0),
+            pool,
+            limits: Limits::new(limits, Arc::clone(&stats)),
+            stats: Arc::clone(&stats),
+            access_log: Some(Arc::new(AccessLog::new(
+                AccessLogFormat::Json,
+                QueryRedaction::All,
+                log,
+            ))),
+            redaction: QueryRedaction::All,
+            breakers: Arc::new(CircuitBreakers::new(Default::default(), tx.clone())),
+            cooldowns: Cooldowns::new(CooldownConfig::default(), tx),
+            users: ProxyUsers::default(),
+            overrides: OverridePermissions::default(),
+            routing_headers: RoutingHeaderConfig::default(),
+        };
+        Service::new(Arc::new(ctx), "10.0.0.1:5000".parse().unwrap(), false)
+    }
+
+    fn read_records(dir: &Path) -> Vec<serde_json::Value> {
+        fs::read_to_string(dir.join("access.log"))
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    fn request(method: Method, uri: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_turned_away_requests_are_logged() {
+        let dir = std::env::temp_dir().join(format!("locust_service_{}", std::process::id()));
+        let unlimited = LimitConfig {
+            domain: Limit::default(),
+            proxy: Limit::default(),
+            pair: Limit::default(),
+            wait: Duration::ZERO,
+        };
+        let service = test_service(&dir, unlimited.clone());
+
+        let res = service
+            .clone()
+            .proxy(request(Method::GET, "http://blocked.com/a?token=abc"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let res = service
+            .clone()
+            .proxy(request(Method::GET, "http://example.com/"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let res = service
+            .proxy(request(Method::CONNECT, "blocked.com:443"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let records = read_records(&dir);
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0]["url"], "http://blocked.com/a?token=REDACTED");
+        assert_eq!(records[0]["status"], 403);
+        assert_eq!(records[0]["bytes"], 0);
+        assert_eq!(records[0]["proxy_id"], 0);
+        assert_eq!(records[1]["status"], 503);
+        assert_eq!(records[2]["method"], "CONNECT");
+        assert_eq!(records[2]["status"], 403);
+
+        // Nothing is free for a domain without room for a single request.
+        let limited = LimitConfig {
+            domain: Limit {
+                concurrency: Some(0),
+                rps: None,
+            },
+            ..unlimited
+        };
+        let res = test_service(&dir, limited)
+            .proxy(request(Method::GET, "http://example.com/"))
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        let records = read_records(&dir);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["status"], 429);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
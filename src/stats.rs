@@ -0,0 +1,176 @@
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Instant,
+};
+
+/// Live, in-process counters about the traffic handled by
+/// this instance. Shared between the proxy service, which
+/// records into it, and the admin API, which reports it.
+pub struct Stats {
+    started_at: Instant,
+    requests_total: AtomicU64,
+    in_flight: AtomicI64,
+    sessions_created: AtomicU64,
+    db_jobs_queued: AtomicI64,
+    db_jobs_dropped: AtomicU64,
+    db_batches_flushed: AtomicU64,
+    limit_queued: AtomicI64,
+    responses_by_status: Mutex<BTreeMap<u16, u64>>,
+    requests_limited: Mutex<BTreeMap<&'static str, u64>>,
+    /// By domain, then by fallback.
+    fallbacks: Mutex<BTreeMap<String, BTreeMap<String, u64>>>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            started_at: Instant::now(),
+            requests_total: AtomicU64::new(0),
+            in_flight: AtomicI64::new(0),
+            sessions_created: AtomicU64::new(0),
+            db_jobs_queued: AtomicI64::new(0),
+            db_jobs_dropped: AtomicU64::new(0),
+            db_batches_flushed: AtomicU64::new(0),
+            limit_queued: AtomicI64::new(0),
+            responses_by_status: Mutex::new(BTreeMap::new()),
+            requests_limited: Mutex::new(BTreeMap::new()),
+            fallbacks: Mutex::new(BTreeMap::new()),
+        }
+    }
+}
+
+impl Stats {
+    /// Marks the start of a proxied request. The request is
+    /// counted as in flight until the returned guard is dropped.
+    pub fn start_request(&self) -> InFlightGuard<'_> {
+        self.requests_total.fetch_add(1, Ordering::Relaxed);
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard { stats: self }
+    }
+
+    pub fn record_status(&self, status: u16) {
+        let mut statuses = self.responses_by_status.lock().unwrap();
+        *statuses.entry(status).or_default() += 1;
+    }
+
+    pub fn record_session_created(&self) {
+        self.sessions_created.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_job_queued(&self) {
+        self.db_jobs_queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_job_dequeued(&self) {
+        self.db_jobs_queued.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn record_job_dropped(&self) {
+        self.db_jobs_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_batch_flushed(&self) {
+        self.db_batches_flushed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Marks a request as waiting on a concurrency or rate limit
+    /// until the returned guard is dropped.
+    pub fn start_limit_wait(&self) -> LimitWaitGuard<'_> {
+        self.limit_queued.fetch_add(1, Ordering::Relaxed);
+        LimitWaitGuard { stats: self }
+    }
+
+    /// Counts a request that was turned away by a limit of `scope`.
+    pub fn record_limited(&self, scope: &'static str) {
+        let mut limited = self.requests_limited.lock().unwrap();
+        *limited.entry(scope).or_default() += 1;
+    }
+
+    /// Counts a proxy picked from a fallback of the domain,
+    /// see [`locust_core::routing::Fallback::label`].
+    pub fn record_fallback(&self, domain: &str, fallback: String) {
+        let mut fallbacks = self.fallbacks.lock().unwrap();
+        let domain = fallbacks.entry(domain.to_string()).or_default();
+        *domain.entry(fallback).or_default() += 1;
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            requests_total: self.requests_total.load(Ordering::Relaxed),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            sessions_created: self.sessions_created.load(Ordering::Relaxed),
+            db_jobs_queued: self.db_jobs_queued.load(Ordering::Relaxed),
+            db_jobs_dropped: self.db_jobs_dropped.load(Ordering::Relaxed),
+            db_batches_flushed: self.db_batches_flushed.load(Ordering::Relaxed),
+            limit_queued: self.limit_queued.load(Ordering::Relaxed),
+            responses_by_status: self.responses_by_status.lock().unwrap().clone(),
+            requests_limited: self.requests_limited.lock().unwrap().clone(),
+            fallbacks: self.fallbacks.lock().unwrap().clone(),
+        }
+    }
+}
+
+pub struct InFlightGuard<'a> {
+    stats: &'a Stats,
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.stats.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub struct LimitWaitGuard<'a> {
+    stats: &'a Stats,
+}
+
+impl Drop for LimitWaitGuard<'_> {
+    fn drop(&mut self) {
+        self.stats.limit_queued.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StatsSnapshot {
+    pub uptime_secs: u64,
+    pub requests_total: u64,
+    pub in_flight: i64,
+    pub sessions_created: u64,
+    pub db_jobs_queued: i64,
+    pub db_jobs_dropped: u64,
+    pub db_batches_flushed: u64,
+    pub limit_queued: i64,
+    pub responses_by_status: BTreeMap<u16, u64>,
+    pub requests_limited: BTreeMap<&'static str, u64>,
+    pub fallbacks: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_flight_guard() {
+        let stats = Stats::default();
+        {
+            let _a = stats.start_request();
+            let _b = stats.start_request();
+            assert_eq!(stats.snapshot().in_flight, 2);
+        }
+        stats.record_status(200);
+        stats.record_status(200);
+        stats.record_status(502);
+
+        let snap = stats.snapshot();
+        assert_eq!(snap.in_flight, 0);
+        assert_eq!(snap.requests_total, 2);
+        assert_eq!(snap.responses_by_status.get(&200), Some(&2));
+        assert_eq!(snap.responses_by_status.get(&502), Some(&1));
+    }
+}
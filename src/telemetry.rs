@@ -0,0 +1,123 @@
+use http::HeaderMap;
+use opentelemetry::{global, propagation::Extractor, trace::TraceError, KeyValue};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{propagation::TraceContextPropagator, runtime, trace, Resource};
+use std::env;
+use tracing::Span;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+const DEFAULT_SERVICE_NAME: &str = "locust";
+
+/// Flushes any pending spans when dropped.
+pub struct TelemetryGuard {
+    otel_enabled: bool,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        if self.otel_enabled {
+            global::shutdown_tracer_provider();
+        }
+    }
+}
+
+/// Installs the global tracing subscriber. Spans are printed to stdout
+/// and, when `OTEL_EXPORTER_OTLP_ENDPOINT` is set, also exported to an
+/// OpenTelemetry collector over OTLP.
+pub fn init() -> Result<TelemetryGuard, TraceError> {
+    let otel_layer = match env::var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+        Ok(endpoint) => {
+            let service_name = env::var("OTEL_SERVICE_NAME").unwrap_or(DEFAULT_SERVICE_NAME.into());
+            let tracer =
+                opentelemetry_otlp::new_pipeline()
+                    .tracing()
+                    .with_exporter(
+                        opentelemetry_otlp::new_exporter()
+                            .tonic()
+                            .with_endpoint(endpoint),
+                    )
+                    .with_trace_config(trace::config().with_resource(Resource::new([
+                        KeyValue::new("service.name", service_name),
+                    ])))
+                    .install_batch(runtime::Tokio)?;
+            global::set_text_map_propagator(TraceContextPropagator::new());
+
+            Some(tracing_opentelemetry::layer().with_tracer(tracer))
+        }
+        Err(_) => None,
+    };
+    let otel_enabled = otel_layer.is_some();
+
+    tracing_subscriber::registry()
+        .with(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .with(tracing_subscriber::fmt::layer())
+        .with(otel_layer)
+        .init();
+
+    Ok(TelemetryGuard { otel_enabled })
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+/// Continues the trace of the client that made the request, if
+/// it sent along a `traceparent` header.
+pub fn set_remote_parent(span: &Span, headers: &HeaderMap) {
+    let cx = global::get_text_map_propagator(|p| p.extract(&HeaderExtractor(headers)));
+    span.set_parent(cx);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::trace::{TraceContextExt, TracerProvider as _};
+    use opentelemetry_sdk::{testing::trace::InMemorySpanExporter, trace::TracerProvider};
+    use tracing::info_span;
+
+    #[test]
+    fn test_remote_parent_is_continued() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let exporter = InMemorySpanExporter::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+                .parse()
+                .unwrap(),
+        );
+
+        tracing::subscriber::with_default(subscriber, || {
+            let span = info_span!("proxy_request");
+            set_remote_parent(&span, &headers);
+            let trace_id = span.context().span().span_context().trace_id();
+            assert_eq!(trace_id.to_string(), "4bf92f3577b34da6a3ce929d0e0e4736");
+            span.in_scope(|| {
+                let _child = info_span!("session_lookup").entered();
+            });
+        });
+
+        provider.force_flush();
+        let spans = exporter.get_finished_spans().unwrap();
+        let names: Vec<_> = spans.iter().map(|s| s.name.to_string()).collect();
+        assert_eq!(names, vec!["session_lookup", "proxy_request"]);
+        assert!(spans
+            .iter()
+            .all(|s| s.span_context.trace_id().to_string() == "4bf92f3577b34da6a3ce929d0e0e4736"));
+    }
+}
@@ -0,0 +1,522 @@
+use bytes::Bytes;
+use futures::Stream;
+use hyper::{
+    body::HttpBody, client::HttpConnector, service::Service, Body, Client, StatusCode, Uri,
+};
+use hyper_proxy::ProxyConnector;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use locust_core::{
+    client::{client_builder, https_connector, proxy_connector},
+    models,
+};
+use std::{
+    error::Error as StdError,
+    fmt,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::net::{lookup_host, TcpSocket, TcpStream};
+use tracing::{info_span, Instrument};
+
+pub type UpstreamClient =
+    Client<TimedConnector<ProxyConnector<TimedConnector<HttpsConnector<HttpConnector>>>>>;
+pub type DirectClient = Client<HttpsConnector<HttpConnector>>;
+
+/// Creates an HTTPS client that proxies traffic to the provided
+/// Proxy. Connection timings are recorded into `timings`.
+pub fn build_client(
+    upstream_proxy: &models::proxies::Proxy,
+    timings: &ConnectTimings,
+) -> UpstreamClient {
+    let https = TimedConnector::new(https_connector(), timings.clone(), Stage::ProxyConnect);
+    let connector = proxy_connector(https, upstream_proxy).unwrap();
+    let connector = TimedConnector::new(connector, timings.clone(), Stage::Established);
+
+    client_builder().build(connector)
+}
+
+/// Creates an HTTPS client that sends requests straight to the
+/// origin, from the given local address when there is one.
+pub fn build_direct_client(local_addr: Option<IpAddr>) -> DirectClient {
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    http.set_local_address(local_addr);
+    let https = HttpsConnectorBuilder::new()
+        .with_webpki_roots()
+        .https_or_http()
+        .enable_http1()
+        .wrap_connector(http);
+
+    client_builder().build(https)
+}
+
+/// Opens a TCP connection straight to `addr`, a host and port,
+/// from the given local address when there is one.
+pub async fn connect_direct(addr: &str, local_addr: Option<IpAddr>) -> io::Result<TcpStream> {
+    let Some(local_addr) = local_addr else {
+        return TcpStream::connect(addr).await;
+    };
+    let mut last_err = None;
+    for remote in lookup_host(addr).await? {
+        if remote.is_ipv4() != local_addr.is_ipv4() {
+            continue;
+        }
+        let socket = match remote {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        socket.bind(SocketAddr::new(local_addr, 0))?;
+        match socket.connect(remote).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("no address of {addr} can be reached from {local_addr}"),
+        )
+    }))
+}
+
+#[derive(Debug, Default)]
+struct Timings {
+    proxy_connect: Option<Duration>,
+    established: Option<Duration>,
+    /// Whether the connection to the origin is encrypted.
+    tls: bool,
+}
+
+/// How long it took to connect to an upstream proxy,
+/// shared between a client's connectors and its caller.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectTimings(Arc<Mutex<Timings>>);
+
+impl ConnectTimings {
+    /// Time taken to open the TCP connection to the proxy.
+    pub fn proxy_connect(&self) -> Option<Duration> {
+        self.0.lock().unwrap().proxy_connect
+    }
+
+    /// Time taken from being connected to the proxy to having an
+    /// encrypted connection with the origin, which covers the CONNECT
+    /// tunnel round trip as well as the TLS handshake itself. Missing
+    /// for plain HTTP requests, which have no handshake.
+    pub fn tls_handshake(&self) -> Option<Duration> {
+        let timings = self.0.lock().unwrap();
+        timings
+            .established
+            .zip(timings.proxy_connect)
+            .filter(|_| timings.tls)
+            .map(|(established, connect)| established.saturating_sub(connect))
+    }
+
+    fn proxy_connected(&self) -> bool {
+        self.0.lock().unwrap().proxy_connect.is_some()
+    }
+
+    fn record(&self, stage: Stage, elapsed: Duration, tls: bool) {
+        let mut timings = self.0.lock().unwrap();
+        match stage {
+            Stage::ProxyConnect => timings.proxy_connect = Some(elapsed),
+            Stage::Established => {
+                timings.established = Some(elapsed);
+                timings.tls = tls;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Stage {
+    /// A plain connection to the proxy is open.
+    ProxyConnect,
+    /// The connection is ready to send the request over.
+    Established,
+}
+
+/// Wraps a connector to record how long its
+/// successful connections took to establish.
+#[derive(Clone)]
+pub struct TimedConnector<C> {
+    inner: C,
+    timings: ConnectTimings,
+    stage: Stage,
+}
+
+impl<C> TimedConnector<C> {
+    pub fn new(inner: C, timings: ConnectTimings, stage: Stage) -> Self {
+        Self {
+            inner,
+            timings,
+            stage,
+        }
+    }
+}
+
+impl<C> Service<Uri> for TimedConnector<C>
+where
+    C: Service<Uri>,
+    C::Future: Send + 'static,
+{
+    type Response = C::Response;
+    type Error = C::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<C::Response, C::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let start = Instant::now();
+        let timings = self.timings.clone();
+        let stage = self.stage;
+        let tls = uri.scheme_str() == Some("https");
+        let span = match stage {
+            Stage::ProxyConnect => info_span!("proxy_connect"),
+            Stage::Established => info_span!("upstream_connect", uri = %uri),
+        };
+        let fut = self.inner.call(uri);
+        Box::pin(
+            async move {
+                let res = fut.await;
+                if res.is_ok() {
+                    timings.record(stage, start.elapsed(), tls);
+                }
+                res
+            }
+            .instrument(span),
+        )
+    }
+}
+
+/// Why a proxied request failed to get a response from the origin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    Timeout,
+    ProxyConnectRefused,
+    ProxyConnectFailed,
+    ProxyAuthFailed,
+    ProxyTunnelFailed,
+    OriginTlsFailed,
+    Upstream,
+    /// The origin answered, but a ban rule matched the response.
+    Banned,
+}
+
+impl ErrorClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorClass::Timeout => "timeout",
+            ErrorClass::ProxyConnectRefused => "proxy_connect_refused",
+            ErrorClass::ProxyConnectFailed => "proxy_connect_failed",
+            ErrorClass::ProxyAuthFailed => "proxy_auth_failed",
+            ErrorClass::ProxyTunnelFailed => "proxy_tunnel_failed",
+            ErrorClass::OriginTlsFailed => "origin_tls_failed",
+            ErrorClass::Upstream => "upstream",
+            ErrorClass::Banned => "banned",
+        }
+    }
+}
+
+impl fmt::Display for ErrorClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Classifies a response that came back from the proxy itself
+/// rather than from the origin.
+pub fn classify_status(status: StatusCode) -> Option<ErrorClass> {
+    match status {
+        StatusCode::PROXY_AUTHENTICATION_REQUIRED => Some(ErrorClass::ProxyAuthFailed),
+        _ => None,
+    }
+}
+
+/// Classifies a failed upstream request based on the error and
+/// how far the connection got before failing.
+pub fn classify_error(err: &hyper::Error, timings: &ConnectTimings, https: bool) -> ErrorClass {
+    if !err.is_connect() {
+        return ErrorClass::Upstream;
+    }
+
+    let mut refused = false;
+    let mut source: Option<&(dyn StdError + 'static)> = err.source();
+    while let Some(e) = source {
+        // hyper-proxy reports a failed CONNECT with the first
+        // bytes of the proxy's status line.
+        let msg = e.to_string();
+        if msg.contains("unsuccessful tunnel") {
+            if msg.contains(" 407") {
+                return ErrorClass::ProxyAuthFailed;
+            }
+            return ErrorClass::ProxyTunnelFailed;
+        }
+        if msg.contains("while tunnel") {
+            return ErrorClass::ProxyTunnelFailed;
+        }
+        if let Some(io) = e.downcast_ref::<io::Error>() {
+            refused |= io.kind() == io::ErrorKind::ConnectionRefused;
+        }
+        source = e.source();
+    }
+
+    if !timings.proxy_connected() {
+        if refused {
+            ErrorClass::ProxyConnectRefused
+        } else {
+            ErrorClass::ProxyConnectFailed
+        }
+    } else if https {
+        ErrorClass::OriginTlsFailed
+    } else {
+        ErrorClass::Upstream
+    }
+}
+
+type OnDone = Box<dyn FnOnce(u64) + Send>;
+
+/// A body stream that counts the bytes passing through it and
+/// reports the total once it ends, errors, or is dropped.
+struct MeteredBody {
+    inner: Body,
+    bytes: u64,
+    on_done: Option<OnDone>,
+}
+
+impl MeteredBody {
+    fn finish(&mut self) {
+        if let Some(on_done) = self.on_done.take() {
+            on_done(self.bytes);
+        }
+    }
+}
+
+impl Stream for MeteredBody {
+    type Item = Result<Bytes, hyper::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let polled = Pin::new(&mut self.inner).poll_data(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(chunk))) => self.bytes += chunk.len() as u64,
+            Poll::Ready(Some(Err(_))) | Poll::Ready(None) => self.finish(),
+            Poll::Pending => {}
+        }
+        polled
+    }
+}
+
+impl Drop for MeteredBody {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+/// Wraps a body so that `on_done` is called with its size once it
+/// has been fully streamed. Empty bodies are left untouched so that
+/// they keep being sent without a transfer encoding.
+pub fn meter_body(body: Body, on_done: impl FnOnce(u64) + Send + 'static) -> Body {
+    if body.is_end_stream() {
+        on_done(0);
+        return body;
+    }
+
+    Body::wrap_stream(MeteredBody {
+        inner: body,
+        bytes: 0,
+        on_done: Some(Box::new(on_done)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Request;
+    use std::sync::mpsc;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    fn local_proxy(port: u16) -> models::proxies::Proxy {
+        models::proxies::Proxy {
+            id: 1,
+            protocol: "http".into(),
+            host: "127.0.0.1".into(),
+            port: port as i32,
+            username: Some("user".into()),
+            password: Some("pass".into()),
+            provider: "test".into(),
+            proxy_type: None,
+            weight: 1,
+            priority: 0,
+            exit_ip: None,
+            geo: Default::default(),
+        }
+    }
+
+    /// Starts a fake proxy that answers the first request it
+    /// receives with `responses`, written one after the other.
+    async fn fake_proxy(responses: &'static [&'static [u8]]) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut buf = [0; 4096];
+            let _ = sock.read(&mut buf).await.unwrap();
+            for response in responses {
+                sock.write_all(response).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        });
+        port
+    }
+
+    async fn request_error(port: u16, uri: &str) -> ErrorClass {
+        let timings = ConnectTimings::default();
+        let client = build_client(&local_proxy(port), &timings);
+        let req = Request::get(uri).body(Body::empty()).unwrap();
+        let err = client.request(req).await.unwrap_err();
+        classify_error(&err, &timings, uri.starts_with("https"))
+    }
+
+    #[tokio::test]
+    async fn test_classify_connect_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+
+        let class = request_error(port, "https://example.com").await;
+        assert_eq!(class, ErrorClass::ProxyConnectRefused);
+    }
+
+    #[tokio::test]
+    async fn test_classify_tunnel_auth_failed() {
+        let port = fake_proxy(&[b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n"]).await;
+        let class = request_error(port, "https://example.com").await;
+        assert_eq!(class, ErrorClass::ProxyAuthFailed);
+    }
+
+    #[tokio::test]
+    async fn test_classify_origin_tls_failed() {
+        let port = fake_proxy(&[
+            b"HTTP/1.1 200 Connection established\r\n\r\n",
+            b"not tls at all",
+        ])
+        .await;
+        let class = request_error(port, "https://example.com").await;
+        assert_eq!(class, ErrorClass::OriginTlsFailed);
+    }
+
+    #[tokio::test]
+    async fn test_records_connect_timings() {
+        let port = fake_proxy(&[
+            b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\nConnection: close\r\n\r\nhello",
+        ])
+        .await;
+        let timings = ConnectTimings::default();
+        let client = build_client(&local_proxy(port), &timings);
+        let req = Request::get("http://example.com")
+            .body(Body::empty())
+            .unwrap();
+        let res = client.request(req).await.unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(timings.proxy_connect().is_some());
+        // Plain HTTP goes through the proxy without a handshake.
+        assert!(timings.tls_handshake().is_none());
+    }
+
+    /// A connector whose connections are ready right away.
+    #[derive(Clone)]
+    struct Ready;
+
+    impl Service<Uri> for Ready {
+        type Response = ();
+        type Error = io::Error;
+        type Future = futures::future::Ready<io::Result<()>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: Uri) -> Self::Future {
+            futures::future::ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_records_tls_handshake() {
+        for (uri, tls) in [("https://example.com", true), ("http://example.com", false)] {
+            let timings = ConnectTimings::default();
+            timings.record(Stage::ProxyConnect, Duration::from_millis(5), false);
+            let mut connector = TimedConnector::new(Ready, timings.clone(), Stage::Established);
+            connector.call(uri.parse().unwrap()).await.unwrap();
+            assert_eq!(timings.tls_handshake().is_some(), tls, "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connect_direct_binds_local_addr() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let local_addr = "127.0.0.1".parse().ok();
+        let stream = connect_direct(&addr, local_addr).await.unwrap();
+        let (_, peer) = listener.accept().await.unwrap();
+        assert_eq!(peer, stream.local_addr().unwrap());
+        assert_eq!(Some(peer.ip()), local_addr);
+
+        // An IPv6 address cannot reach an IPv4 origin.
+        let err = connect_direct(&addr, "::1".parse().ok()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+    }
+
+    #[tokio::test]
+    async fn test_direct_client() {
+        let port = fake_proxy(&[b"HTTP/1.1 204 No Content\r\n\r\n"]).await;
+        let client = build_direct_client("127.0.0.1".parse().ok());
+        let req = Request::get(format!("http://127.0.0.1:{port}/"))
+            .body(Body::empty())
+            .unwrap();
+        let res = client.request(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    }
+
+    #[test]
+    fn test_classify_status() {
+        assert_eq!(
+            classify_status(StatusCode::PROXY_AUTHENTICATION_REQUIRED),
+            Some(ErrorClass::ProxyAuthFailed)
+        );
+        assert_eq!(classify_status(StatusCode::NOT_FOUND), None);
+    }
+
+    #[tokio::test]
+    async fn test_meter_body() {
+        let (tx, rx) = mpsc::channel();
+        let body = meter_body(Body::from("hello world"), move |n| tx.send(n).unwrap());
+        let bytes = hyper::body::to_bytes(body).await.unwrap();
+
+        assert_eq!(bytes.len(), 11);
+        assert_eq!(rx.recv().unwrap(), 11);
+    }
+
+    #[tokio::test]
+    async fn test_meter_body_reports_on_drop() {
+        let (tx, rx) = mpsc::channel();
+        let (mut sender, body) = Body::channel();
+        sender.send_data(Bytes::from("abc")).await.unwrap();
+        let mut body = meter_body(body, move |n| tx.send(n).unwrap());
+        body.data().await.unwrap().unwrap();
+        drop(body);
+
+        assert_eq!(rx.recv().unwrap(), 3);
+    }
+}
@@ -0,0 +1,109 @@
+use headers::{authorization::Basic, ProxyAuthorization};
+use std::{collections::HashMap, env, fs};
+
+/// A file of `user:password` lines that clients
+/// authenticate against with `Proxy-Authorization`.
+const USERS_FILE_VAR: &str = "LOCUST_USERS_FILE";
+
+/// The users clients can authenticate as. Only authenticated users
+/// get the overrides and routing headers configured for them, every
+/// other client is anonymous. Without a users file nobody is.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProxyUsers {
+    passwords: HashMap<String, String>,
+}
+
+impl ProxyUsers {
+    pub fn from_env() -> Self {
+        match env::var(USERS_FILE_VAR) {
+            Ok(path) => {
+                let content = fs::read_to_string(&path)
+                    .unwrap_or_else(|e| panic!("Invalid {USERS_FILE_VAR}: {path}: {e}"));
+                Self::parse(&content).unwrap_or_else(|e| panic!("Invalid {USERS_FILE_VAR}: {e}"))
+            }
+            Err(_) => Self::default(),
+        }
+    }
+
+    /// Parses `user:password` lines, skipping blank
+    /// ones and comments starting with `#`.
+    fn parse(content: &str) -> Result<Self, String> {
+        let mut passwords = HashMap::new();
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(':') {
+                Some((user, password)) if !user.is_empty() && !password.is_empty() => {
+                    passwords.insert(user.to_string(), password.to_string());
+                }
+                _ => return Err(format!("expected user:password at line {}", i + 1)),
+            }
+        }
+        Ok(Self { passwords })
+    }
+
+    /// The user the credentials authenticate, unless
+    /// the user is unknown or the password is wrong.
+    pub fn authenticate(&self, auth: &ProxyAuthorization<Basic>) -> Option<String> {
+        let password = self.passwords.get(auth.0.username())?;
+        constant_time_eq(password.as_bytes(), auth.0.password().as_bytes())
+            .then(|| auth.0.username().to_string())
+    }
+}
+
+/// Compares without returning early, so that how long it
+/// takes says nothing about where the inputs differ.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::overrides::{Override, OverridePermissions};
+
+    fn auth(user: &str, password: &str) -> ProxyAuthorization<Basic> {
+        ProxyAuthorization(headers::Authorization::basic(user, password).0)
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let users = ProxyUsers::parse("# ops\nops:s3cret:too\n\nscraper:hunter2\n").unwrap();
+        assert_eq!(
+            users.authenticate(&auth("ops", "s3cret:too")),
+            Some("ops".into())
+        );
+        assert_eq!(
+            users.authenticate(&auth("scraper", "hunter2")),
+            Some("scraper".into())
+        );
+        assert_eq!(users.authenticate(&auth("ops", "hunter2")), None);
+        assert_eq!(users.authenticate(&auth("nobody", "hunter2")), None);
+        assert_eq!(
+            ProxyUsers::default().authenticate(&auth("ops", "s3cret:too")),
+            None
+        );
+
+        assert!(ProxyUsers::parse("ops").is_err());
+        assert!(ProxyUsers::parse("ops:").is_err());
+    }
+
+    #[test]
+    fn test_wrong_password_gets_no_privileges() {
+        let users = ProxyUsers::parse("ops:s3cret").unwrap();
+        let permissions = OverridePermissions::parse("*=tags;ops=all").unwrap();
+        let user = users.authenticate(&auth("ops", "guess"));
+        assert!(!permissions.allows(user.as_deref(), Override::ProxyId));
+        let user = users.authenticate(&auth("ops", "s3cret"));
+        assert!(permissions.allows(user.as_deref(), Override::ProxyId));
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"abc", b"abc"));
+        assert!(!constant_time_eq(b"abc", b"abd"));
+        assert!(!constant_time_eq(b"abc", b"ab"));
+    }
+}